@@ -20,8 +20,10 @@
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+use adw::prelude::MessageDialogExt;
 use adw::subclass::prelude::*;
 use gtk::glib::GString;
 use gtk::prelude::*;
@@ -29,8 +31,16 @@ use gtk::{gio, glib};
 
 use crate::config::VERSION;
 use crate::emulator;
+use crate::emulator::memory_image::{Format as ImageFormat, MemoryImage};
+#[cfg(unix)]
+use crate::emulator::port_extender::{self, PortExtender};
+use crate::emulator::port_script::{Capture, Mismatch, ScriptedPorts, Stimulus};
+use crate::emulator::trace::Trace;
+use crate::emulator::watch::Watch;
 use crate::ui::command_view;
 use crate::ui::memory_view;
+use crate::ui::panel_view;
+use crate::ui::profile_view;
 use crate::ui::stack_view;
 use crate::ui::window::MtemuWindow;
 use crate::ui::PlainCommandRepr;
@@ -39,17 +49,36 @@ use crate::utils::get_calls;
 use crate::utils::get_commands;
 use crate::utils::get_libcalls;
 
-use crate::emulator::MT1804Emulator;
-
 mod imp {
     use gtk::{
         glib::{closure_local, once_cell::sync::Lazy, MainContext},
         MultiSelection, SingleSelection,
     };
-    use std::{cell::RefCell, collections::VecDeque, rc::Rc, sync::Arc};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{HashMap, VecDeque},
+        fmt,
+        rc::Rc,
+        sync::Arc,
+    };
 
     use crate::{
-        emulator::{self, LibCall},
+        emulator::{
+            self,
+            condition::{Condition, ConditionSet},
+            coverage::Coverage,
+            history::{Entry, History},
+            memory_image::MemoryImage,
+            port::{Direction, PanelPort, Patchbay, SharedDevice, SimulatedPort, PORT_COUNT},
+            profile::Profile,
+            trace::Trace,
+            vcd::Waveform,
+            watch::{Watch, Watcher},
+            microinstruction::Operation,
+            worker::{self, Report, RunConfig, RunMode, Snapshot, StepObserver, StopReason, Stopped, Worker},
+            EmulatorError, ExecResult, Flag, LibCall,
+        },
+        project::{self, Breakpoints, Project},
         ui,
         utils::{self, *},
     };
@@ -61,19 +90,78 @@ mod imp {
         pub stack_window: RefCell<Option<u32>>,
         pub memory_window: RefCell<Option<u32>>,
         pub commands_window: RefCell<Option<u32>>,
+        pub profile_window: RefCell<Option<u32>>,
+        pub panel_window: RefCell<Option<u32>>,
         settings: gio::Settings,
-        undo_stack: RefCell<VecDeque<EmulatorStored>>,
+        // program edits, execution goes to `history`
+        undo_stack: RefCell<VecDeque<(EmulatorStored, Breakpoints)>>,
+        history: RefCell<History>,
+        // kept after recording stops so that it can still be exported
+        pub trace: RefCell<Trace>,
+        pub recording: Cell<bool>,
+        // the same for the waveform, only command steps go in
+        pub waveform: RefCell<Waveform>,
+        pub recording_waveform: Cell<bool>,
+        // adds up over resets and runs until the program changes
+        pub coverage: RefCell<Coverage>,
+        pub profile: RefCell<Profile>,
+        pub profiling: Cell<bool>,
+        pub breakpoints: RefCell<Breakpoints>,
+        conditions: RefCell<Vec<Condition>>,
+        pub watches: RefCell<Vec<Watch>>,
+        // stored with the project while "Keep with project" is on
+        pub memory_image: RefCell<Option<MemoryImage>>,
+        // worked from the debug pane, every engine set here is wired to them
+        pub ports: [SimulatedPort; PORT_COUNT],
+        // the engine's ports go through here to the plain ports or the panel
+        patchbay: Patchbay,
+        // takes every port over while it is connected
+        pub extender: RefCell<Option<SharedDevice>>,
+        // plays a stimulus into every port and captures what is written,
+        // loaded for grading so it goes before the extender
+        pub script: RefCell<Option<ScriptedPorts>>,
+        run: RefCell<Option<Run>>,
+        run_id: Cell<u32>,
+    }
+
+    // While a run is going the worker owns the emulator and the slot in
+    // `emulator` stays empty
+    struct Run {
+        id: u32,
+        worker: Worker,
+        button: gtk::ToggleButton,
     }
 
     impl Default for MtemuApplication {
         fn default() -> Self {
+            let settings = gio::Settings::new("org.bmstu.mtemu");
             Self {
                 emulator: Default::default(),
                 stack_window: Default::default(),
                 memory_window: Default::default(),
                 commands_window: Default::default(),
-                settings: gio::Settings::new("org.bmstu.mtemu"),
+                profile_window: Default::default(),
+                panel_window: Default::default(),
                 undo_stack: Default::default(),
+                history: RefCell::new(History::new(settings.uint("history-steps") as usize)),
+                trace: Default::default(),
+                recording: Default::default(),
+                waveform: Default::default(),
+                recording_waveform: Default::default(),
+                coverage: Default::default(),
+                profile: Default::default(),
+                profiling: Default::default(),
+                settings,
+                breakpoints: Default::default(),
+                conditions: Default::default(),
+                memory_image: Default::default(),
+                ports: Default::default(),
+                patchbay: Default::default(),
+                extender: Default::default(),
+                script: Default::default(),
+                watches: Default::default(),
+                run: Default::default(),
+                run_id: Default::default(),
             }
         }
     }
@@ -110,7 +198,15 @@ mod imp {
 
     #[derive(glib::SharedBoxed, Clone, Debug)]
     #[shared_boxed_type(name = "BoxedStack")]
-    pub struct BoxedStack(pub Rc<Vec<u32>>);
+    pub struct BoxedStack(pub Rc<Vec<u32>>, pub usize);
+
+    impl BoxedStack {
+        // the values and SP, which tells the calls in there apart from leftovers
+        pub fn of(emul: &emulator::Implementation) -> Self {
+            let values = emul.get_stack().into_iter().map(|val| val as u32).collect();
+            Self(Rc::new(values), emul.get_sp())
+        }
+    }
 
     #[derive(glib::SharedBoxed, Clone, Debug)]
     #[shared_boxed_type(name = "BoxedMemory")]
@@ -128,9 +224,19 @@ mod imp {
             obj.set_accels_for_action("app.cut-commands", &["<primary>x"]);
             obj.set_accels_for_action("app.paste-commands", &["<primary>v"]);
             obj.set_accels_for_action("app.undo", &["<primary>z"]);
+            obj.set_accels_for_action("app.run-to-cursor", &["<primary>F10"]);
             self.undo_stack
                 .borrow_mut()
                 .reserve(self.settings.uint("backtrace-steps") as usize);
+            let app = obj.clone();
+            self.settings.connect_changed(
+                Some("run-speed"),
+                glib::clone!(@weak app => move |settings, key| {
+                    if let Some(ref run) = *app.imp().run.borrow() {
+                        run.worker.set_speed(settings.uint(key));
+                    }
+                }),
+            );
         }
 
         fn signals() -> &'static [glib::subclass::Signal] {
@@ -191,6 +297,9 @@ mod imp {
             self.handle_builder_selection_change();
             self.handle_edit_buttons();
             self.handle_code_list_selection_change();
+            self.handle_breakpoints();
+            self.handle_conditions();
+            self.handle_state_edits();
             self.obj().emit_by_name::<()>(
                 "commands-appeared",
                 &[&BoxedCommands(Rc::new(get_commands(self.get_emulator())))],
@@ -210,23 +319,633 @@ mod imp {
     impl AdwApplicationImpl for MtemuApplication {}
     impl MtemuApplication {
         fn push_state(&self) {
+            self.push_undo();
+            // the snapshots there still have the old program in them
+            self.history.borrow_mut().clear();
+            self.coverage.borrow_mut().clear();
+            self.profile.borrow_mut().clear();
+        }
+        // keeps a copy of the emulator for app.undo to go back to
+        fn push_undo(&self) {
+            // anything that changes the emulator pauses a run first
+            self.stop_run();
+            let Some(emul) = self.get_emulator().borrow().clone() else {
+                return;
+            };
             let mut undo_stack = self.undo_stack.borrow_mut();
             if undo_stack.len() >= self.settings.uint("backtrace-steps") as usize {
                 undo_stack.pop_front();
             }
-            undo_stack.push_back(Arc::new(RefCell::new(Some(
-                self.get_emulator().borrow().as_ref().unwrap().clone(),
-            ))));
+            undo_stack.push_back((
+                Arc::new(RefCell::new(Some(emul))),
+                self.breakpoints.borrow().clone(),
+            ));
+        }
+        // A hand edit of the machine state. The program stays the same, so
+        // unlike push_state this keeps the history, coverage and profile.
+        fn edit_state(
+            &self,
+            edit: impl FnOnce(&mut emulator::Implementation) -> Result<(), EmulatorError>,
+        ) -> bool {
+            // a run holds the emulator, the edit waits for it to pause
+            self.stop_run();
+            self.push_undo();
+            let (result, snapshot) = {
+                let mut emul = self.emulator.borrow_mut();
+                let Some(ref mut emul) = *emul else {
+                    self.show_toast("There is no emulator to edit");
+                    return false;
+                };
+                let result = edit(emul);
+                (result, Snapshot::take(emul))
+            };
+            // a rejected edit puts the views back as they were
+            if let Err(ref err) = result {
+                self.pop_state();
+                self.report_error(err);
+            }
+            self.show_snapshot(&snapshot);
+            result.is_ok()
+        }
+        // before a reset, so that it can be stepped back
+        fn record_step(&self) {
+            self.stop_run();
+            if let Some(ref emul) = *self.emulator.borrow() {
+                self.history.borrow_mut().record(&**emul);
+            }
+        }
+        // a single step from the buttons, seen by the same observers as a run
+        fn observe_step(
+            &self,
+            emul: &mut emulator::Implementation,
+            mode: RunMode,
+        ) -> Result<ExecResult, EmulatorError> {
+            let mut history = self.history.borrow_mut();
+            let mut trace = self.trace.borrow_mut();
+            let mut waveform = self.waveform.borrow_mut();
+            let mut coverage = self.coverage.borrow_mut();
+            let mut profile = self.profile.borrow_mut();
+            let mut observer = StepObserver {
+                history: &mut history,
+                trace: self.recording.get().then_some(&mut *trace),
+                waveform: self.recording_waveform.get().then_some(&mut *waveform),
+                coverage: &mut coverage,
+                profile: self.profiling.get().then_some(&mut *profile),
+            };
+            observer.step(emul, mode)
+        }
+        pub fn clear_history(&self) {
+            self.history.borrow_mut().clear();
+        }
+        pub fn clear_profile(&self) {
+            self.stop_run();
+            self.profile.borrow_mut().clear();
+            self.show_profile();
+        }
+        // fills the profiler window when it is open
+        pub fn show_profile(&self) {
+            let Some(window) = self
+                .profile_window
+                .borrow()
+                .and_then(|id| self.obj().window_by_id(id))
+                .and_downcast::<ui::profile_view::ProfileWindow>()
+            else {
+                return;
+            };
+            let Some(ref emul) = *self.emulator.borrow() else {
+                return;
+            };
+            let profile = self.profile.borrow();
+            let total = profile.total();
+            // the profile counts by address, offsets and the library make
+            // that differ from the index in the program
+            let indices = project::command_addresses(&**emul)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, addr)| Some((addr?, index)))
+                .collect::<HashMap<_, _>>();
+            let hotspots = profile
+                .counts()
+                .map(|(addr, count)| {
+                    let cmd = indices.get(&addr).map(|index| emul.get_command(*index));
+                    let (name, jump) = match cmd {
+                        Some(Ok(cmd)) => (
+                            emul.command_get_name(cmd.clone()).unwrap_or_default(),
+                            emul.command_get_jump_name(cmd).unwrap_or_default(),
+                        ),
+                        _ => Default::default(),
+                    };
+                    let share = count as f64 * 100.0 / total as f64;
+                    ui::profile_view::HotspotRepr::new(addr, name, jump, count, share)
+                })
+                .collect::<gio::ListStore>();
+            let libcalls = emul.get_map_calls().unwrap_or_default();
+            let calls = profile
+                .calls()
+                .map(|(ind, cycles)| {
+                    let name = emul
+                        .get_call(ind)
+                        .ok()
+                        .and_then(|call| libcalls.iter().find(|libcall| libcall.code == call.code_))
+                        .map(|libcall| libcall.name.clone())
+                        .unwrap_or_default();
+                    ui::profile_view::CallCyclesRepr::new(ind, name, cycles)
+                })
+                .collect::<gio::ListStore>();
+            window.set_profile(hotspots, calls);
+        }
+        pub fn clear_coverage(&self) {
+            self.stop_run();
+            self.coverage.borrow_mut().clear();
+            self.show_coverage();
+        }
+        // shades the code list, see `CommandRepr::show_coverage`
+        fn show_coverage(&self) {
+            let Some(pane) = self.code_view_pane() else {
+                return;
+            };
+            let Some(model) = pane
+                .imp()
+                .code_list
+                .model()
+                .and_downcast::<MultiSelection>()
+                .and_then(|selection| selection.model())
+            else {
+                return;
+            };
+            let Some(ref emul) = *self.emulator.borrow() else {
+                return;
+            };
+            let coverage = self.coverage.borrow();
+            // the list has a row for every command in program order
+            for ind in 0..model.n_items() {
+                let Some(repr) = model.item(ind).and_downcast::<ui::code_view_pane::CommandRepr>() else {
+                    continue;
+                };
+                if let Ok(cmd) = emul.get_command(ind as usize) {
+                    repr.show_coverage(&coverage, &cmd);
+                }
+            }
+        }
+        // Restores what `rewind` picks out of the history, the program stays
+        // as it is since the history is cleared on every edit
+        fn step_back(&self, rewind: impl FnOnce(&mut History) -> Option<Entry>) -> bool {
+            self.stop_run();
+            let Some(entry) = rewind(&mut self.history.borrow_mut()) else {
+                self.show_toast("Nothing to step back to");
+                return false;
+            };
+            let restored = match *self.emulator.borrow_mut() {
+                Some(ref mut emul) => entry.restore(&mut **emul).map(|_| Snapshot::take(emul)),
+                None => return false,
+            };
+            match &restored {
+                Ok(snapshot) => self.show_snapshot(snapshot),
+                Err(err) => self.report_error(err),
+            }
+            restored.is_ok()
         }
-        fn pop_state(&self) -> Option<EmulatorStored> {
+        fn pop_state(&self) -> Option<(EmulatorStored, Breakpoints)> {
             self.undo_stack.borrow_mut().pop_back()
         }
-        pub fn set_emulator(&self, emul: emulator::OriginalImplementation) {
+        pub fn set_emulator(&self, mut emul: emulator::Implementation) {
+            self.plug_ports(false);
+            for ind in 0..PORT_COUNT {
+                if let Err(err) = emul.attach_device(ind, Some(self.patchbay.socket())) {
+                    self.show_toast(format!("Could not attach PORT{}: {}", ind, err));
+                }
+            }
             self.emulator.replace(Some(emul));
         }
+        // The panel has inputs and outputs where the debug pane lets every
+        // port go both ways. Either way the values are the same.
+        pub fn plug_ports(&self, panel: bool) {
+            let takeover = match &*self.script.borrow() {
+                Some(script) => Some(script.device()),
+                None => self.extender.borrow().clone(),
+            };
+            self.follow_script();
+            for (ind, port) in self.ports.iter().enumerate() {
+                let device = match (&takeover, panel) {
+                    (Some(device), _) => device.clone(),
+                    (None, true) => PanelPort::new(port.clone(), Direction::PANEL[ind]).device(),
+                    (None, false) => port.device(),
+                };
+                if let Err(err) = self.patchbay.connect(ind, Some(device)) {
+                    self.show_toast(format!("Could not plug PORT{}: {}", ind, err));
+                }
+            }
+        }
+        // Step Back rewinds whatever script is plugged in, a run has the
+        // history to itself and may have missed a new one
+        fn follow_script(&self) {
+            self.history.borrow_mut().set_script(self.script.borrow().clone());
+        }
+        // a reset runs the program from the start, the stimulus goes with it
+        pub fn rewind_script(&self) {
+            if let Some(script) = &*self.script.borrow() {
+                script.rewind();
+            }
+        }
+        pub fn show_ports(&self) {
+            if let Some(window) = self.main_window() {
+                window.imp().debug_pane.renew_ports(&self.ports);
+            }
+            let Some(panel_id) = *self.panel_window.borrow() else {
+                return;
+            };
+            let Some(window) = self.obj().window_by_id(panel_id) else {
+                return;
+            };
+            if let Ok(window) = window.downcast::<ui::panel_view::PanelWindow>() {
+                window.renew_ports(&self.ports);
+            }
+        }
         pub fn get_emulator(&self) -> utils::EmulatorStored {
             return self.emulator.clone();
         }
+        pub fn project(&self) -> Project {
+            Project {
+                breakpoints: self.breakpoints.borrow().clone(),
+                conditions: self.conditions.borrow().clone(),
+                watches: self.watches.borrow().clone(),
+                memory: self.memory_image.borrow().clone(),
+            }
+        }
+        pub fn set_project(&self, project: Project) {
+            self.breakpoints.replace(project.breakpoints);
+            self.conditions.replace(project.conditions);
+            self.watches.replace(project.watches);
+            if let Some(pane) = self.debug_pane() {
+                pane.set_conditions(&self.conditions.borrow());
+            }
+            self.watches_changed();
+            self.set_memory_image(project.memory);
+        }
+        // the image goes into the memory right away, None only stops keeping one
+        fn set_memory_image(&self, image: Option<MemoryImage>) {
+            if let Some(action) = self.obj().lookup_action("keep-memory-image").and_downcast::<gio::SimpleAction>() {
+                action.set_state(&image.is_some().to_variant());
+            }
+            self.memory_image.replace(image.clone());
+            let Some(image) = image else {
+                return;
+            };
+            let loaded = {
+                let mut emul = self.emulator.borrow_mut();
+                let Some(ref mut emul) = *emul else {
+                    return;
+                };
+                image.load(&mut **emul).map(|_| Snapshot::take(emul))
+            };
+            match loaded {
+                Ok(snapshot) => self.show_snapshot(&snapshot),
+                Err(err) => self.report_error(&err),
+            }
+        }
+        fn debug_pane(&self) -> Option<ui::debug_pane::DebugPane> {
+            Some(self.main_window()?.imp().debug_pane.clone())
+        }
+        pub fn code_view_pane(&self) -> Option<ui::code_view_pane::CodeViewPane> {
+            Some(self.main_window()?.imp().code_view_pane.clone())
+        }
+        fn condition_set(&self) -> ConditionSet {
+            let Some(ref emul) = *self.emulator.borrow() else {
+                return ConditionSet::default();
+            };
+            ConditionSet::new(self.conditions.borrow().clone(), &**emul)
+        }
+        fn watcher(&self) -> Watcher {
+            let Some(ref emul) = *self.emulator.borrow() else {
+                return Watcher::default();
+            };
+            Watcher::new(self.watches.borrow().clone(), &**emul)
+        }
+        pub fn watches_changed(&self) {
+            // the state lets context menus tell watched cells apart
+            let names = self.watches.borrow().iter().map(Watch::to_string).collect::<Vec<_>>();
+            if let Some(action) = self.obj().lookup_action("toggle-watch").and_downcast::<gio::SimpleAction>() {
+                action.set_state(&names.to_variant());
+            }
+            if let Some(ref run) = *self.run.borrow() {
+                run.worker.set_watches(self.watches.borrow().clone());
+            }
+        }
+        // toasts and the panes are in the main window even when the memory
+        // or stack one has the focus
+        fn main_window(&self) -> Option<MtemuWindow> {
+            self.obj()
+                .windows()
+                .into_iter()
+                .find_map(|window| window.downcast::<MtemuWindow>().ok())
+        }
+        pub fn show_toast(&self, reason: impl fmt::Display) {
+            let Some(window) = self.main_window() else {
+                return;
+            };
+            window.show_toast(&reason.to_string());
+        }
+        pub fn report_error(&self, err: &EmulatorError) {
+            let Some(window) = self.main_window() else {
+                return;
+            };
+            window.show_toast(&err.to_string());
+        }
+        fn start_run(&self, mode: RunMode, button: &gtk::ToggleButton) {
+            let config = RunConfig::new(mode).with_speed(self.settings.uint("run-speed"));
+            self.start_worker(config, button);
+        }
+        // Step Over and Step Out go as fast as possible and switch `button`
+        // on themselves, so that it can pause them like any other run
+        fn run_to_depth(&self, depth: usize, button: &gtk::ToggleButton) {
+            let mut config = RunConfig::new(RunMode::Commands);
+            config.until_depth = Some(depth);
+            self.start_worker(config, button);
+            button.set_active(true);
+        }
+        // Runs the program like the Run button does, up to the first command
+        // selected in the code list
+        pub fn run_to_cursor(&self) {
+            let (Some(code_view), Some(debug_view)) = (self.code_view_pane(), self.debug_pane())
+            else {
+                return;
+            };
+            let Some(model) = code_view.imp().code_list.model() else {
+                return;
+            };
+            // the minimum of an empty selection is past any item
+            let first = model.selection().minimum();
+            let Some(repr) = model
+                .item(first)
+                .and_downcast::<ui::code_view_pane::CommandRepr>()
+            else {
+                return self.show_toast("Select a command to run to");
+            };
+            self.stop_run();
+            let mut config =
+                RunConfig::new(RunMode::Commands).with_speed(self.settings.uint("run-speed"));
+            config.until_address = Some(repr.addr() as usize);
+            let button = debug_view.run_button();
+            self.start_worker(config, &button);
+            button.set_active(true);
+        }
+        fn start_worker(&self, mut config: RunConfig, button: &gtk::ToggleButton) {
+            // the button of a run started from elsewhere is switched on after the fact
+            let running = self.run.borrow().as_ref().map(|run| run.button.clone());
+            if running.as_ref() == Some(button) {
+                return;
+            }
+            self.stop_run();
+            let Some(emul) = self.emulator.take() else {
+                button.set_active(false);
+                return;
+            };
+            let id = self.run_id.get().wrapping_add(1);
+            self.run_id.set(id);
+            let (sender, receiver) = MainContext::channel(glib::Priority::DEFAULT);
+            let app = self.obj().clone();
+            receiver.attach(
+                None,
+                glib::clone!(@weak app => @default-return glib::ControlFlow::Break, move |report: Report| {
+                    // whatever is left over from a run that was already stopped
+                    if app.imp().run.borrow().as_ref().map(|run| run.id) != Some(id) {
+                        return glib::ControlFlow::Continue;
+                    }
+                    match report {
+                        Report::Snapshot(snapshot) => app.imp().show_snapshot(&snapshot),
+                        Report::Stopped => app.imp().stop_run(),
+                    }
+                    glib::ControlFlow::Continue
+                }),
+            );
+            config.breakpoints = self.breakpoints.borrow().addresses();
+            config.conditions = self.conditions.borrow().clone();
+            config.watches = self.watches.borrow().clone();
+            if config.mode == RunMode::Calls && !config.watches.is_empty() {
+                self.show_toast("Watches are only checked when running commands");
+            }
+            if config.mode == RunMode::Calls && self.recording_waveform.get() {
+                self.show_toast("The waveform is only recorded when running commands");
+            }
+            config.trace = self.recording.get().then(|| self.trace.take());
+            config.waveform = self.recording_waveform.get().then(|| self.waveform.take());
+            config.coverage = self.coverage.take();
+            config.profile = self.profiling.get().then(|| self.profile.take());
+            if let Some(pane) = self.debug_pane() {
+                pane.highlight_condition(None);
+            }
+            let history = self.history.take();
+            let worker = Worker::spawn(emul, history, config, move |report| {
+                let _ = sender.send(report);
+            });
+            self.run.replace(Some(Run {
+                id,
+                worker,
+                button: button.clone(),
+            }));
+        }
+        pub fn stop_run(&self) {
+            let Some(run) = self.run.take() else {
+                return;
+            };
+            let Stopped {
+                emul,
+                history,
+                trace,
+                waveform,
+                coverage,
+                profile,
+                reason,
+            } = run.worker.stop();
+            let snapshot = Snapshot::take(&emul);
+            self.emulator.replace(Some(emul));
+            self.history.replace(history);
+            self.follow_script();
+            if let Some(trace) = trace {
+                self.trace.replace(trace);
+            }
+            if let Some(waveform) = waveform {
+                self.waveform.replace(waveform);
+            }
+            self.coverage.replace(coverage);
+            if let Some(profile) = profile {
+                self.profile.replace(profile);
+            }
+            run.button.set_active(false);
+            self.show_snapshot(&snapshot);
+            self.show_coverage();
+            self.show_profile();
+            if let (StopReason::Condition(ind, _), Some(pane)) = (&reason, self.debug_pane()) {
+                pane.highlight_condition(Some(*ind));
+            }
+            match reason {
+                StopReason::Paused | StopReason::Returned(_) | StopReason::Reached(_) => {}
+                StopReason::Failed(err) => self.report_error(&err),
+                reason => self.show_toast(reason),
+            }
+        }
+        fn show_snapshot(&self, snapshot: &Snapshot) {
+            let app = self.obj();
+            app.emit_by_name::<()>(
+                "stack_changed",
+                &[&BoxedStack(Rc::new(snapshot.stack.clone()), snapshot.state.stack_pointer)],
+            );
+            app.emit_by_name::<()>(
+                "memory-changed",
+                &[&BoxedMemory(Rc::new(snapshot.memory.clone()))],
+            );
+            app.emit_by_name::<()>(
+                "state-changed",
+                &[&BoxedState(Rc::new(snapshot.state.clone()))],
+            );
+            if let Some(ref command) = snapshot.command {
+                app.emit_by_name::<()>("command-changed", &[&BoxedCommand(Rc::new(command.clone()))]);
+            }
+            let Some(ref commands_id) = *self.commands_window.borrow() else {
+                return;
+            };
+            let Some(window) = app.window_by_id(*commands_id) else {
+                return;
+            };
+            let Ok(window) = window.downcast::<ui::command_view::CommandWindow>() else {
+                return;
+            };
+            window.set_call_index(snapshot.call_index as u32);
+        }
+        fn handle_breakpoints(&self) {
+            let app = self.obj().clone();
+            let Some(window) = app.active_window() else {
+                return;
+            };
+            let Some(window) = window.downcast_ref::<MtemuWindow>() else {
+                return;
+            };
+            let app_clone = app.clone();
+            window.imp().code_view_pane.connect_closure(
+                "breakpoint-toggled",
+                false,
+                glib::closure_local!(move |_: ui::code_view_pane::CodeViewPane, addr: i32, enabled: bool| {
+                    let mut breakpoints = app_clone.imp().breakpoints.borrow_mut();
+                    breakpoints.set(addr as usize, enabled);
+                    if let Some(ref run) = *app_clone.imp().run.borrow() {
+                        run.worker.set_breakpoints(breakpoints.addresses());
+                    }
+                }),
+            );
+        }
+        fn handle_conditions(&self) {
+            let Some(debug_pane) = self.debug_pane() else {
+                return;
+            };
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "condition-added",
+                false,
+                closure_local!(move |pane: ui::debug_pane::DebugPane, text: String| {
+                    match text.parse::<Condition>() {
+                        Ok(cond) => {
+                            app_clone.imp().conditions.borrow_mut().push(cond);
+                            app_clone.imp().conditions_changed();
+                            pane.condition_accepted();
+                        }
+                        Err(err) => pane.condition_rejected(&err.to_string()),
+                    }
+                }),
+            );
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "condition-removed",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, index: u32| {
+                    {
+                        let mut conditions = app_clone.imp().conditions.borrow_mut();
+                        if index as usize >= conditions.len() {
+                            return;
+                        }
+                        conditions.remove(index as usize);
+                    }
+                    app_clone.imp().conditions_changed();
+                }),
+            );
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "condition-toggled",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, index: u32, enabled: bool| {
+                    if let Some(cond) = app_clone.imp().conditions.borrow_mut().get_mut(index as usize) {
+                        cond.enabled = enabled;
+                    }
+                    if let Some(ref run) = *app_clone.imp().run.borrow() {
+                        run.worker.set_conditions(app_clone.imp().conditions.borrow().clone());
+                    }
+                }),
+            );
+        }
+        fn handle_state_edits(&self) {
+            let Some(debug_pane) = self.debug_pane() else {
+                return;
+            };
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "register-edited",
+                false,
+                closure_local!(
+                    move |_: ui::debug_pane::DebugPane, index: u32, value: u32| {
+                        app_clone.imp().edit_state(|emul| match index {
+                            16 => emul.set_reg_q(value as u8),
+                            index => emul.set_reg(index as usize, value as u8),
+                        });
+                    }
+                ),
+            );
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "flag-toggled",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, index: u32| {
+                    let Some(&flag) = Flag::ALL.get(index as usize) else {
+                        return;
+                    };
+                    app_clone.imp().edit_state(|emul| {
+                        let set = emul.get_state().flags[index as usize] != 0;
+                        emul.set_flag(flag, !set)
+                    });
+                }),
+            );
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "mp-edited",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, value: u32| {
+                    app_clone
+                        .imp()
+                        .edit_state(|emul| emul.set_mp(value as usize));
+                }),
+            );
+            // the world outside the chip, so not undone with the state
+            let app_clone = self.obj().clone();
+            debug_pane.connect_closure(
+                "port-input-edited",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, port: u32, value: u32| {
+                    app_clone.imp().set_port_input(port, value);
+                }),
+            );
+        }
+        pub fn set_port_input(&self, port: u32, value: u32) {
+            if let Some(port) = self.ports.get(port as usize) {
+                port.set_input(value as u8);
+            }
+            self.show_ports();
+        }
+        fn conditions_changed(&self) {
+            if let Some(pane) = self.debug_pane() {
+                pane.set_conditions(&self.conditions.borrow());
+            }
+            if let Some(ref run) = *self.run.borrow() {
+                run.worker.set_conditions(self.conditions.borrow().clone());
+            }
+        }
         fn connect_repr_changed(&self) {
             let app = self.obj().clone();
             let Some(window) = app.active_window() else {
@@ -270,9 +989,14 @@ mod imp {
                     let model = cmds
                         .0
                         .iter()
-                        .map(|cmd| crate::ui::code_view_pane::CommandRepr::from_command(&app, &cmd))
+                        .map(|cmd| {
+                            let repr = crate::ui::code_view_pane::CommandRepr::from_command(&app, &cmd);
+                            repr.set_breakpoint(app.imp().breakpoints.borrow().contains(cmd.get_num()));
+                            repr
+                        })
                         .collect::<gio::ListStore>();
                     code_cmd_list.imp().instance_model(model);
+                    app.imp().show_coverage();
                     app.imp().handle_code_list_selection_change();
                 }),
             );
@@ -290,8 +1014,9 @@ mod imp {
             app.connect_closure(
                 "state-changed",
                 false,
-                glib::closure_local!(move |_: super::MtemuApplication, state: BoxedState| {
+                glib::closure_local!(move |app: super::MtemuApplication, state: BoxedState| {
                     debug_pane.renew_state(&state.0);
+                    app.imp().show_ports();
                     let Some(selection) = code_cmd_list.imp().code_list.model() else {
                         return;
                     };
@@ -347,6 +1072,25 @@ mod imp {
                         })
                         .collect::<gio::ListStore>();
                     window.set_stack(stack_repr);
+                    // the code list has the names even while a run holds the emulator
+                    let pane = app_clone.imp().code_view_pane();
+                    let commands = pane.as_ref().and_then(|pane| pane.imp().code_list.model());
+                    let frames = emulator::call_stack::frames(&stack.0, stack.1);
+                    let frames_repr = frames
+                        .iter()
+                        .enumerate()
+                        .map(|(ind, frame)| {
+                            let name = frame
+                                .call_site()
+                                .and_then(|site| pane.as_ref()?.row_of_address(site as u32))
+                                .and_then(|row| commands.as_ref()?.item(row))
+                                .and_downcast::<ui::code_view_pane::CommandRepr>()
+                                .map(|cmd| format!("{} {}", cmd.name(), cmd.jump()))
+                                .unwrap_or_default();
+                            ui::stack_view::CallFrameRepr::new(frame, name, ind == 0)
+                        })
+                        .collect::<gio::ListStore>();
+                    window.set_frames(frames_repr);
                 }),
             );
         }
@@ -411,7 +1155,10 @@ mod imp {
                     let Some(ref emul) = *emul_clone.borrow() else {
                         return;
                     };
-                    Rc::new(emul.get_command(selected))
+                    match emul.get_command(selected) {
+                        Ok(cmd) => Rc::new(cmd),
+                        Err(err) => return app.imp().report_error(&err),
+                    }
                 });
                 app.emit_by_name::<()>("command-changed", &[&cmd]);
             });
@@ -432,19 +1179,17 @@ mod imp {
             let cmd_builder_clone = cmd_builder.obj().clone();
             let cmd_editor = window.imp().code_view_pane.imp().instruction_editor.clone();
             let closure = move || {
-                let mut new_words = cmd_builder_clone
-                    .get_command()
-                    .get_words()
-                    .into_iter()
-                    .map(|word| word as i32)
-                    .collect::<Vec<i32>>();
-                let cur_cmd = cmd_editor.get_codes();
-                if !(new_words[6] == 0b1011 && new_words[5] == 0b1000) {
-                    new_words[7] = cur_cmd[7] as i32;
+                let mut new_instr =
+                    emulator::Microinstruction::from_words(&cmd_builder_clone.get_command().get_words());
+                let cur_instr = emulator::Microinstruction::from_words(&cmd_editor.get_codes());
+                // the builder picks the port itself, everything else keeps
+                // the operands typed into the editor
+                if !matches!(new_instr.operation(), Operation::SetDevicePointer(_)) {
+                    new_instr.a = cur_instr.a;
                 }
-                new_words[8] = cur_cmd[8] as i32;
-                new_words[9] = cur_cmd[9] as i32;
-                let cmd = emulator::Command::new(0, new_words.as_mut_slice());
+                new_instr.b = cur_instr.b;
+                new_instr.d = cur_instr.d;
+                let cmd = emulator::Command::new(0, &new_instr.to_command_words());
                 app.emit_by_name::<()>("command-changed", &[&BoxedCommand(Rc::new(cmd))]);
             };
             let closure_clone = closure.clone();
@@ -500,87 +1245,139 @@ mod imp {
             };
             let debug_view = &window.imp().debug_pane;
             let app_clone = app.clone();
-            let executor = move |app: &super::MtemuApplication| -> bool {
+            let executor = move |app: &super::MtemuApplication| -> Result<ExecResult, EmulatorError> {
                 let emul = app.get_emulator();
-                app.imp().push_state();
-                let prev_cmd = {
+                app.imp().stop_run();
+                let (prev_cmd, result) = {
                     let Some(ref mut emul) = *(*emul).borrow_mut() else {
-                        return false;
+                        return Ok(ExecResult::NoCommands);
                     };
-                    let cmd_count = emul.commands_count();
-                    let mut pc = emul.get_pc();
-                    // just give me the goddamn exception...
-                    if pc == usize::MAX {
-                        pc = 0;
-                    }
-                    if cmd_count == 0 || pc >= cmd_count {
-                        // TODO: emit rom end
-                        return false;
+                    // PC stays at -1 until the first step positions it
+                    let positioning = emul.get_pc() == usize::MAX;
+                    let result = app.imp().observe_step(emul, RunMode::Commands)?;
+                    if result != ExecResult::Ok {
+                        return Ok(result);
                     }
-                    let prev_cmd = emul.get_command(pc);
-                    emul.exec_one();
-                    prev_cmd
+                    let prev_cmd = match positioning {
+                        true => None,
+                        false => Some(emul.executed_command()?),
+                    };
+                    (prev_cmd, result)
                 };
-                let Some(words) = prev_cmd.get_words() else {
-                    return false;
+                app.imp().show_coverage();
+                app.imp().show_profile();
+                let prev_instr = prev_cmd.as_ref().and_then(emulator::Microinstruction::from_command);
+                let Some(prev_instr) = prev_instr else {
+                    return Ok(result);
                 };
-                match words[3] {
-                    4..=6 | 9 | 10 => {
-                        let stack = Rc::new(
-                            {
-                                let Some(ref emul) = *emul.borrow() else {
-                                    return false;
-                                };
-                                emul.get_stack()
-                            }
+                if prev_instr.touches_stack() {
+                    let stack = {
+                        let Some(ref emul) = *emul.borrow() else {
+                            return Ok(result);
+                        };
+                        BoxedStack::of(emul)
+                    };
+                    app.emit_by_name::<()>("stack_changed", &[&stack]);
+                }
+                if let Operation::StoreMemory(_) = prev_instr.operation() {
+                    let memory = Rc::new({
+                        let Some(ref emul) = *emul.borrow() else {
+                            return Ok(result);
+                        };
+                        emul.get_mem()
                             .into_iter()
                             .map(|val| val as u32)
-                            .collect::<Vec<u32>>(),
-                        );
-                        app.emit_by_name::<()>("stack_changed", &[&BoxedStack(stack)]);
-                    }
-                    _ => {}
-                }
-                match words[6] {
-                    12 => {
-                        let memory = Rc::new({
-                            let Some(ref emul) = *emul.borrow() else {
-                                return false;
-                            };
-                            emul.get_mem()
-                                .into_iter()
-                                .map(|val| val as u32)
-                                .collect::<Vec<u32>>()
-                        });
-                        app.emit_by_name::<()>("memory-changed", &[&BoxedMemory(memory)]);
-                    }
-                    _ => {}
+                            .collect::<Vec<u32>>()
+                    });
+                    app.emit_by_name::<()>("memory-changed", &[&BoxedMemory(memory)]);
                 }
                 let state = BoxedState({
                     let Some(ref emul) = *emul.borrow() else {
-                        return false;
+                        return Ok(result);
                     };
                     Rc::new(emul.get_state())
                 });
                 app.emit_by_name::<()>("state-changed", &[&state]);
-                let command = BoxedCommand({
+                let command = {
                     let Some(ref emul) = *emul.borrow() else {
-                        return false;
+                        return Ok(result);
                     };
-                    if emul.commands_count() <= state.0.program_counter {
-                        // TODO: emit rom end
-                        return false;
-                    }
-                    Rc::new(emul.get_command(state.0.program_counter))
-                });
-                app.emit_by_name::<()>("command-changed", &[&command]);
-                return true;
+                    worker::next_command(emul)
+                };
+                // the next step reports what is wrong with an empty address
+                if let Some(command) = command {
+                    app.emit_by_name::<()>("command-changed", &[&BoxedCommand(Rc::new(command))]);
+                }
+                Ok(result)
+            };
+            let step = move |app: &super::MtemuApplication, pane: &ui::debug_pane::DebugPane| {
+                let mut conditions = app.imp().condition_set();
+                let mut watcher = app.imp().watcher();
+                match executor(app) {
+                    Ok(ExecResult::Ok) => {}
+                    Ok(result) => return app.imp().show_toast(result),
+                    Err(err) => return app.imp().report_error(&err),
+                }
+                let (hit, became_true) = {
+                    let emul = app.get_emulator();
+                    let emul = emul.borrow();
+                    let Some(ref emul) = *emul else {
+                        return;
+                    };
+                    let became_true = conditions
+                        .check(&**emul)
+                        .map(|(ind, cond)| (ind, cond.source().to_owned()));
+                    (watcher.check(&**emul), became_true)
+                };
+                if let Some(hit) = hit {
+                    app.imp().show_toast(StopReason::Watch(hit));
+                }
+                pane.highlight_condition(became_true.as_ref().map(|(ind, _)| *ind));
+                if let Some((ind, source)) = became_true {
+                    app.imp().show_toast(StopReason::Condition(ind, source));
+                }
             };
             debug_view.connect_closure(
                 "step-clicked",
                 false,
-                closure_local!(move |_: glib::Object, _: &gtk::Button| {
-                    let _ = executor(&app_clone);
+                closure_local!(move |pane: ui::debug_pane::DebugPane, _: &gtk::Button| {
+                    step(&app_clone, &pane);
+                }),
+            );
+            let app_clone = app.clone();
+            debug_view.connect_closure(
+                "step-over-clicked",
+                false,
+                closure_local!(move |pane: ui::debug_pane::DebugPane, button: &gtk::ToggleButton| {
+                    app_clone.imp().stop_run();
+                    let depth = {
+                        let Some(ref emul) = *app_clone.imp().emulator.borrow() else {
+                            return;
+                        };
+                        worker::step_over_depth(emul)
+                    };
+                    match depth {
+                        Some(depth) => app_clone.imp().run_to_depth(depth, button),
+                        None => step(&app_clone, &pane),
+                    }
+                }),
+            );
+            let app_clone = app.clone();
+            debug_view.connect_closure(
+                "step-out-clicked",
+                false,
+                closure_local!(move |_: ui::debug_pane::DebugPane, button: &gtk::ToggleButton| {
+                    app_clone.imp().stop_run();
+                    let depth = {
+                        let Some(ref emul) = *app_clone.imp().emulator.borrow() else {
+                            return;
+                        };
+                        worker::step_out_depth(emul)
+                    };
+                    match depth {
+                        Some(depth) => app_clone.imp().run_to_depth(depth, button),
+                        None => app_clone.imp().show_toast("Not inside a subroutine"),
+                    }
                 }),
             );
             let app_clone = app.clone();
@@ -589,13 +1386,14 @@ mod imp {
                 false,
                 closure_local!(move |_: glib::Object, _: &gtk::Button| {
                     let emul = app_clone.get_emulator();
-                    app_clone.imp().push_state();
+                    app_clone.imp().record_step();
                     {
                         let Some(ref mut emul) = *(*emul).borrow_mut() else {
                             return;
                         };
                         emul.reset();
                     }
+                    app_clone.imp().rewind_script();
                     let state = BoxedState({
                         let Some(ref emul) = *emul.borrow() else {
                             todo!()
@@ -608,20 +1406,15 @@ mod imp {
                         state.program_counter = 0;
                         Rc::new(state)
                     });
-                    app_clone.emit_by_name::<()>("state-changed", &[&state]);
-
-                    let stack = Rc::new(
-                        {
-                            let Some(ref emul) = *emul.borrow() else {
-                                return;
-                            };
-                            emul.get_stack()
-                        }
-                        .into_iter()
-                        .map(|val| val as u32)
-                        .collect::<Vec<u32>>(),
-                    );
-                    app_clone.emit_by_name::<()>("stack_changed", &[&BoxedStack(stack)]);
+                    app_clone.emit_by_name::<()>("state-changed", &[&state]);
+
+                    let stack = {
+                        let Some(ref emul) = *emul.borrow() else {
+                            return;
+                        };
+                        BoxedStack::of(emul)
+                    };
+                    app_clone.emit_by_name::<()>("stack_changed", &[&stack]);
 
                     let command = BoxedCommand({
                         let Some(ref emul) = *emul.borrow() else {
@@ -630,7 +1423,10 @@ mod imp {
                         if emul.commands_count() == 0 {
                             return;
                         }
-                        Rc::new(emul.get_command(state.0.program_counter))
+                        match emul.get_command(state.0.program_counter) {
+                            Ok(cmd) => Rc::new(cmd),
+                            Err(err) => return app_clone.imp().report_error(&err),
+                        }
                     });
                     app_clone.emit_by_name::<()>("command-changed", &[&command]);
                 }),
@@ -640,16 +1436,37 @@ mod imp {
                 "run-toggled",
                 false,
                 closure_local!(move |_: glib::Object, button: &gtk::ToggleButton| {
-                    let context = MainContext::default();
-                    let button_clone = button.clone();
-                    context.spawn_local(glib::clone!(@weak app_clone => async move {
-                        while button_clone.is_active() {
-                            if !executor(&app_clone) {
-                                return;
-                            }
-                            glib::timeout_future(std::time::Duration::from_millis(20)).await;
-                        }
-                    }));
+                    match button.is_active() {
+                        true => app_clone.imp().start_run(RunMode::Commands, button),
+                        false => app_clone.imp().stop_run(),
+                    }
+                }),
+            );
+            let app_clone = app.clone();
+            debug_view.connect_closure(
+                "step-back-clicked",
+                false,
+                closure_local!(move |_: glib::Object, _: &gtk::Button| {
+                    app_clone.imp().step_back(History::back);
+                }),
+            );
+            let app_clone = app.clone();
+            debug_view.connect_closure(
+                "reverse-run-clicked",
+                false,
+                closure_local!(move |_: glib::Object, _: &gtk::Button| {
+                    let breakpoints = app_clone.imp().breakpoints.borrow().addresses();
+                    if !app_clone.imp().step_back(|history| history.back_to(&breakpoints)) {
+                        return;
+                    }
+                    let Some(ref emul) = *app_clone.imp().emulator.borrow() else {
+                        return;
+                    };
+                    let addr = worker::next_address(emul);
+                    match breakpoints.contains(&addr) {
+                        true => app_clone.imp().show_toast(StopReason::Breakpoint(addr)),
+                        false => app_clone.imp().show_toast("Reached the oldest recorded step"),
+                    }
                 }),
             );
         }
@@ -668,7 +1485,7 @@ mod imp {
                 "clicked",
                 false,
                 glib::closure_local!(move |_: gtk::Button| {
-                    let mut cmd_words = cmd_view_clone
+                    let cmd_words = cmd_view_clone
                         .get_codes()
                         .into_iter()
                         .map(|word: u8| word as i32)
@@ -700,10 +1517,19 @@ mod imp {
                         let Some(ref mut emul) = *(*emul).borrow_mut() else {
                             return;
                         };
-                        emul.add_command(
+                        let before = project::command_addresses(&**emul);
+                        let res = emul.add_command(
                             position as usize,
-                            &emulator::Command::new(position as i32, &mut cmd_words),
+                            &emulator::Command::new(position as i32, &cmd_words),
                         );
+                        match res {
+                            Ok(()) => app_clone.imp().breakpoints.borrow_mut().command_inserted(
+                                position as usize,
+                                &before,
+                                &project::command_addresses(&**emul),
+                            ),
+                            Err(err) => app_clone.imp().report_error(&err),
+                        }
                     }
                     app_clone.emit_by_name::<()>(
                         "commands-appeared",
@@ -741,7 +1567,16 @@ mod imp {
                             return;
                         };
                         for i in position.into_iter().enumerate() {
-                            emul.remove_command(i.1 - i.0);
+                            let before = project::command_addresses(&**emul);
+                            if let Err(err) = emul.remove_command(i.1 - i.0) {
+                                app_clone.imp().report_error(&err);
+                                break;
+                            }
+                            app_clone.imp().breakpoints.borrow_mut().command_removed(
+                                i.1 - i.0,
+                                &before,
+                                &project::command_addresses(&**emul),
+                            );
                         }
                     }
                     app_clone.emit_by_name::<()>(
@@ -758,7 +1593,7 @@ mod imp {
                 "clicked",
                 false,
                 glib::closure_local!(move |_: gtk::Button| {
-                    let mut cmd_words = cmd_view_clone
+                    let cmd_words = cmd_view_clone
                         .get_codes()
                         .into_iter()
                         .map(|word: u8| word as i32)
@@ -791,8 +1626,18 @@ mod imp {
                             return;
                         };
                         for i in position.into_iter() {
-                            emul.remove_command(i);
-                            emul.add_command(i, &emulator::Command::new(i as i32, &mut cmd_words))
+                            let before = project::command_addresses(&**emul);
+                            // a rejected command leaves the old one in place
+                            let res = emul.update_command(i, &emulator::Command::new(i as i32, &cmd_words));
+                            if let Err(err) = res {
+                                app_clone.imp().report_error(&err);
+                                break;
+                            }
+                            app_clone
+                                .imp()
+                                .breakpoints
+                                .borrow_mut()
+                                .command_replaced(&before, &project::command_addresses(&**emul));
                         }
                     }
                     app_clone.emit_by_name::<()>(
@@ -823,7 +1668,9 @@ mod imp {
                                 arg0_: call.arg0() as i32,
                                 arg1_: call.arg1() as i32,
                             };
-                            emul.add_call(call.addr() as usize, emul_repr);
+                            if let Err(err) = emul.add_call(call.addr() as usize, emul_repr) {
+                                app_clone.imp().report_error(&err);
+                            }
                         }
                         app_clone.emit_by_name::<()>(
                             "calls-appeared",
@@ -850,7 +1697,9 @@ mod imp {
                                 arg0_: call.arg0() as i32,
                                 arg1_: call.arg1() as i32,
                             };
-                            emul.update_call(call.addr() as usize, emul_repr);
+                            if let Err(err) = emul.update_call(call.addr() as usize, emul_repr) {
+                                app_clone.imp().report_error(&err);
+                            }
                         }
                         app_clone.emit_by_name::<()>(
                             "calls-appeared",
@@ -872,7 +1721,10 @@ mod imp {
                         };
                         let mut deleted = 0;
                         for call in calls.0.iter() {
-                            emul.remove_call(call.addr() as usize - deleted);
+                            if let Err(err) = emul.remove_call(call.addr() as usize - deleted) {
+                                app_clone.imp().report_error(&err);
+                                break;
+                            }
                             deleted += 1;
                         }
                     }
@@ -887,21 +1739,16 @@ mod imp {
                 "step-clicked",
                 false,
                 glib::closure_local!(move |win: ui::command_view::CommandWindow| {
-                    app_clone.imp().push_state();
-                    let (stack, memory, state) = {
+                    app_clone.imp().stop_run();
+                    let (stack, memory, state, result) = {
                         let emul = app_clone.get_emulator();
                         let Some(ref mut emul) = *emul.as_ref().borrow_mut() else {
                             return;
                         };
-                        emul.exec_one_call();
+                        let result = app_clone.imp().observe_step(emul, RunMode::Calls);
                         let index = emul.get_call_index();
                         win.set_call_index(index as u32);
-                        let stack = Rc::new(
-                            emul.get_stack()
-                            .into_iter()
-                            .map(|val| val as u32)
-                            .collect::<Vec<u32>>(),
-                        );
+                        let stack = BoxedStack::of(emul);
                         let memory = Rc::new({
                             emul.get_mem()
                                 .into_iter()
@@ -909,11 +1756,16 @@ mod imp {
                                 .collect::<Vec<u32>>()
                         });
                         let state = BoxedState(Rc::new(emul.get_state()));
-                        (stack, memory, state)
+                        (stack, memory, state, result)
                     };
-                    app_clone.emit_by_name::<()>("stack_changed", &[&BoxedStack(stack)]);
+                    app_clone.emit_by_name::<()>("stack_changed", &[&stack]);
                     app_clone.emit_by_name::<()>("memory-changed", &[&BoxedMemory(memory)]);
                     app_clone.emit_by_name::<()>("state-changed", &[&state]);
+                    match result {
+                        Ok(ExecResult::Ok) => {}
+                        Ok(result) => app_clone.imp().show_toast(result),
+                        Err(err) => app_clone.imp().report_error(&err),
+                    }
                 }),
             );
             let app_clone = self.obj().clone();
@@ -921,40 +1773,11 @@ mod imp {
                 "run-toggled",
                 false,
                 glib::closure_local!(
-                    move |win: ui::command_view::CommandWindow, but: &gtk::ToggleButton| {
-                        let context = MainContext::default();
-                        let button_clone = but.clone();
-                        context.spawn_local(glib::clone!(@weak app_clone => async move {
-                    let emul = app_clone.get_emulator();
-                    while button_clone.is_active() {
-                        app_clone.imp().push_state();
-                        let (stack, memory, state) = {
-                            let Some(ref mut emul) = *emul.as_ref().borrow_mut() else { return };
-                            emul.exec_one_call();
-                            let index = emul.get_call_index();
-                            eprintln!("call index: {}", index);
-                            win.set_call_index(index as u32);
-                            let stack = Rc::new(
-                                emul.get_stack()
-                                    .into_iter()
-                                    .map(|val| val as u32)
-                                    .collect::<Vec<u32>>(),
-                            );
-                            let memory = Rc::new({
-                                emul.get_mem()
-                                    .into_iter()
-                                    .map(|val| val as u32)
-                                    .collect::<Vec<u32>>()
-                            });
-                            let state = BoxedState(Rc::new(emul.get_state()));
-                            (stack, memory, state)
-                        };
-                        app_clone.emit_by_name::<()>("stack_changed", &[&BoxedStack(stack)]);
-                        app_clone.emit_by_name::<()>("memory-changed", &[&BoxedMemory(memory)]);
-                        app_clone.emit_by_name::<()>("state-changed", &[&state]);
-                        glib::timeout_future(std::time::Duration::from_millis(20)).await;
-                    }
-                }));
+                    move |_: ui::command_view::CommandWindow, but: &gtk::ToggleButton| {
+                        match but.is_active() {
+                            true => app_clone.imp().start_run(RunMode::Calls, but),
+                            false => app_clone.imp().stop_run(),
+                        }
                     }
                 ),
             );
@@ -963,7 +1786,7 @@ mod imp {
                 "reset-clicked",
                 false,
                 glib::closure_local!(move |win: ui::command_view::CommandWindow| {
-                    app_clone.imp().push_state();
+                    app_clone.imp().record_step();
                     let emul = app_clone.get_emulator();
                     let Some(ref mut emul) = *emul.as_ref().borrow_mut() else {
                         return;
@@ -971,6 +1794,7 @@ mod imp {
                     emul.reset();
                     let index = emul.get_call_index();
                     win.set_call_index(index as u32);
+                    app_clone.imp().rewind_script();
                 }),
             );
             let app_clone = self.obj().clone();
@@ -987,7 +1811,9 @@ mod imp {
                         let Some(ref mut emul) = *(*emul).borrow_mut() else {
                             return;
                         };
-                        emul.add_map_call(&LibCall::new(code, name.to_owned(), addr));
+                        if let Err(err) = emul.add_map_call(&LibCall::new(code, name.to_owned(), addr)) {
+                            app_clone.imp().report_error(&err);
+                        }
                     }
                     app_clone.emit_by_name::<()>(
                         "callslib-appeared",
@@ -1008,7 +1834,9 @@ mod imp {
                         let Some(ref mut emul) = *(*emul).borrow_mut() else {
                             return;
                         };
-                        emul.remove_map_call(code);
+                        if let Err(err) = emul.remove_map_call(code) {
+                            app_clone.imp().report_error(&err);
+                        }
                     }
                     app_clone.emit_by_name::<()>(
                         "callslib-appeared",
@@ -1091,7 +1919,9 @@ mod imp {
                 let Some(ref mut emul) = *self.emulator.as_ref().borrow_mut() else {
                     return;
                 };
-                emul.init_library();
+                if let Err(err) = emul.init_library() {
+                    self.report_error(&err);
+                }
             }
             self.obj().emit_by_name::<()>(
                 "commands-appeared",
@@ -1108,9 +1938,14 @@ mod imp {
         }
 
         pub fn undo(&self) {
+            self.stop_run();
+            self.history.borrow_mut().clear();
+            self.coverage.borrow_mut().clear();
+            self.profile.borrow_mut().clear();
             match self.pop_state() {
                 None => {}
-                Some(old_state) => {
+                Some((old_state, breakpoints)) => {
+                    self.breakpoints.replace(breakpoints);
                     let emul = self.get_emulator();
                     let Some(ref mut emul) = *(*emul).borrow_mut() else { return };
                     emul.swap(&mut old_state.take().unwrap())
@@ -1130,7 +1965,7 @@ impl MtemuApplication {
     pub fn new(
         application_id: &str,
         flags: &gio::ApplicationFlags,
-        emul: emulator::OriginalImplementation,
+        emul: emulator::Implementation,
     ) -> Self {
         let app: MtemuApplication = glib::Object::builder()
             .property("application-id", application_id)
@@ -1183,6 +2018,100 @@ impl MtemuApplication {
         let undo_action = gio::ActionEntry::builder("undo")
             .activate(move |app: &Self, _, _| app.undo())
             .build();
+        let record_trace_action = gio::ActionEntry::builder("record-trace")
+            .state(false.to_variant())
+            .activate(move |app: &Self, action, _| {
+                let recording = !action.state().and_then(|state| state.get::<bool>()).unwrap_or_default();
+                action.set_state(&recording.to_variant());
+                app.set_recording(recording);
+            })
+            .build();
+        let export_trace_action = gio::ActionEntry::builder("export-trace")
+            .activate(move |app: &Self, _, _| app.show_export_trace())
+            .build();
+        let record_waveform_action = gio::ActionEntry::builder("record-waveform")
+            .state(false.to_variant())
+            .activate(move |app: &Self, action, _| {
+                let recording = !action.state().and_then(|state| state.get::<bool>()).unwrap_or_default();
+                action.set_state(&recording.to_variant());
+                app.set_recording_waveform(recording);
+            })
+            .build();
+        let export_waveform_action = gio::ActionEntry::builder("export-waveform")
+            .activate(move |app: &Self, _, _| app.show_export_waveform())
+            .build();
+        let export_coverage_action = gio::ActionEntry::builder("export-coverage")
+            .activate(move |app: &Self, _, _| app.show_export_coverage())
+            .build();
+        let clear_coverage_action = gio::ActionEntry::builder("clear-coverage")
+            .activate(move |app: &Self, _, _| app.imp().clear_coverage())
+            .build();
+        let profile_action = gio::ActionEntry::builder("profile")
+            .state(false.to_variant())
+            .activate(move |app: &Self, action, _| {
+                let profiling = !action.state().and_then(|state| state.get::<bool>()).unwrap_or_default();
+                action.set_state(&profiling.to_variant());
+                app.set_profiling(profiling);
+            })
+            .build();
+        let clear_profile_action = gio::ActionEntry::builder("clear-profile")
+            .activate(move |app: &Self, _, _| app.imp().clear_profile())
+            .build();
+        let show_profile_action = gio::ActionEntry::builder("show-profile")
+            .activate(move |app: &Self, _, _| app.toggle_profile())
+            .build();
+        let show_panel_action = gio::ActionEntry::builder("show-panel")
+            .activate(move |app: &Self, _, _| app.toggle_panel())
+            .build();
+        #[cfg(unix)]
+        let connect_extender_action = gio::ActionEntry::builder("connect-extender")
+            .state(false.to_variant())
+            .activate(move |app: &Self, action, _| {
+                let connect = !action.state().and_then(|state| state.get::<bool>()).unwrap_or_default();
+                app.connect_extender(connect, action);
+            })
+            .build();
+        let load_stimulus_action = gio::ActionEntry::builder("load-stimulus")
+            .activate(move |app: &Self, _, _| app.show_load_stimulus())
+            .build();
+        let save_stimulus_action = gio::ActionEntry::builder("save-stimulus")
+            .activate(move |app: &Self, _, _| app.show_save_stimulus())
+            .build();
+        let unload_stimulus_action = gio::ActionEntry::builder("unload-stimulus")
+            .activate(move |app: &Self, _, _| app.unload_stimulus())
+            .build();
+        let save_capture_action = gio::ActionEntry::builder("save-capture")
+            .activate(move |app: &Self, _, _| app.show_save_capture())
+            .build();
+        let compare_capture_action = gio::ActionEntry::builder("compare-capture")
+            .activate(move |app: &Self, _, _| app.show_compare_capture())
+            .build();
+        let run_to_cursor_action = gio::ActionEntry::builder("run-to-cursor")
+            .activate(move |app: &Self, _, _| app.imp().run_to_cursor())
+            .build();
+        let load_memory_image_action = gio::ActionEntry::builder("load-memory-image")
+            .activate(move |app: &Self, _, _| app.show_load_memory_image())
+            .build();
+        let save_memory_image_action = gio::ActionEntry::builder("save-memory-image")
+            .activate(move |app: &Self, _, _| app.show_save_memory_image())
+            .build();
+        let keep_memory_image_action = gio::ActionEntry::builder("keep-memory-image")
+            .state(false.to_variant())
+            .activate(move |app: &Self, action, _| {
+                let keep = !action.state().and_then(|state| state.get::<bool>()).unwrap_or_default();
+                action.set_state(&keep.to_variant());
+                app.set_keep_memory_image(keep);
+            })
+            .build();
+        let toggle_watch_action = gio::ActionEntry::builder("toggle-watch")
+            .parameter_type(Some(glib::VariantTy::STRING))
+            .state(Vec::<String>::new().to_variant())
+            .activate(move |app: &Self, _, target| {
+                if let Some(target) = target.and_then(|target| target.get::<String>()) {
+                    app.toggle_watch(&target);
+                }
+            })
+            .build();
         self.add_action_entries([
             quit_action,
             about_action,
@@ -1198,7 +2127,30 @@ impl MtemuApplication {
             show_commands_action,
             init_library_action,
             undo_action,
+            record_trace_action,
+            export_trace_action,
+            record_waveform_action,
+            export_waveform_action,
+            export_coverage_action,
+            clear_coverage_action,
+            profile_action,
+            clear_profile_action,
+            show_profile_action,
+            show_panel_action,
+            load_stimulus_action,
+            save_stimulus_action,
+            unload_stimulus_action,
+            save_capture_action,
+            compare_capture_action,
+            run_to_cursor_action,
+            load_memory_image_action,
+            save_memory_image_action,
+            keep_memory_image_action,
+            toggle_watch_action,
         ]);
+        // without the action the menu item is just insensitive
+        #[cfg(unix)]
+        self.add_action_entries([connect_extender_action]);
     }
 
     fn show_about(&self) {
@@ -1247,18 +2199,13 @@ impl MtemuApplication {
         });
         self.emit_by_name::<()>("memory-changed", &[&imp::BoxedMemory(memory)]);
 
-        let stack = Rc::new(
-            {
-                let Some(ref emul) = *emul.borrow() else {
-                    return;
-                };
-                emul.get_stack()
-            }
-            .into_iter()
-            .map(|val| val as u32)
-            .collect::<Vec<u32>>(),
-        );
-        self.emit_by_name::<()>("stack-changed", &[&imp::BoxedStack(stack)]);
+        let stack = {
+            let Some(ref emul) = *emul.borrow() else {
+                return;
+            };
+            imp::BoxedStack::of(emul)
+        };
+        self.emit_by_name::<()>("stack-changed", &[&stack]);
 
         let state = {
             let Some(ref emul) = *emul.borrow() else { return };
@@ -1267,6 +2214,7 @@ impl MtemuApplication {
         self.emit_by_name::<()>("state-changed", &[&imp::BoxedState(state)]);
     }
     fn show_open_file(&self) {
+        self.imp().stop_run();
         let window = self.active_window().unwrap();
         let open_file = gtk::FileDialog::new();
         let filter = gtk::FileFilter::new();
@@ -1279,7 +2227,7 @@ impl MtemuApplication {
         open_file.open(Some(&window.clone()), gio::Cancellable::NONE, move |res| {
             let Ok(file) = res else { return };
             let path = file.path().expect("Unable to get file path");
-            let file = std::fs::File::open(path).expect("Cannot open file");
+            let file = std::fs::File::open(&path).expect("Cannot open file");
             let mut reader = BufReader::new(file);
             let mut bytes = Vec::<u8>::new();
             let _ = reader.read_to_end(&mut bytes);
@@ -1287,9 +2235,22 @@ impl MtemuApplication {
                 let Some(ref mut emul) = *emul.borrow_mut() else {
                     return;
                 };
-                emul.open_raw(&bytes);
+                if let Err(err) = emul.open_raw(&bytes) {
+                    return obj.imp().report_error(&err);
+                }
                 emul.reset();
             }
+            obj.imp().clear_history();
+            obj.imp().coverage.borrow_mut().clear();
+            obj.imp().profile.borrow_mut().clear();
+            obj.imp().rewind_script();
+            match Project::load(&path) {
+                Ok(project) => obj.imp().set_project(project),
+                Err(err) => {
+                    obj.imp().set_project(Project::default());
+                    obj.imp().show_toast(format!("Cannot read the project file: {}", err));
+                }
+            }
             obj.emit_by_name::<()>(
                 "commands-appeared",
                 &[&imp::BoxedCommands(Rc::new(get_commands(
@@ -1299,6 +2260,7 @@ impl MtemuApplication {
         });
     }
     fn show_save_file(&self) {
+        self.imp().stop_run();
         let window = self.active_window().unwrap();
         let open_file = gtk::FileDialog::new();
         let filter = gtk::FileFilter::new();
@@ -1307,11 +2269,12 @@ impl MtemuApplication {
         filters.append(&filter);
         open_file.set_filters(Some(&filters));
         let emul = self.get_emulator();
+        let obj = self.clone();
         open_file.save(Some(&window.clone()), gio::Cancellable::NONE, move |res| {
             let Ok(file) = res else { return };
             let mut path = file.path().expect("Unable to get file path");
             path.set_extension("mte");
-            let file = std::fs::File::create(path).expect("Cannot create file");
+            let file = std::fs::File::create(&path).expect("Cannot create file");
             let mut writer = BufWriter::new(file);
             let bytes = {
                 let Some(ref emul) = *emul.borrow() else {
@@ -1320,6 +2283,324 @@ impl MtemuApplication {
                 emul.export_raw()
             };
             let _ = writer.write_all(&bytes);
+            if let Err(err) = obj.imp().project().save(&path) {
+                obj.imp().show_toast(format!("Cannot write the project file: {}", err));
+            }
+        });
+    }
+    // starting a recording throws away the previous one
+    fn set_recording(&self, recording: bool) {
+        // a running worker holds the trace, it has to give it back first
+        self.imp().stop_run();
+        if recording {
+            self.imp().trace.borrow_mut().clear();
+        }
+        self.imp().recording.set(recording);
+    }
+    fn set_recording_waveform(&self, recording: bool) {
+        self.imp().stop_run();
+        if recording {
+            self.imp().waveform.borrow_mut().clear();
+        }
+        self.imp().recording_waveform.set(recording);
+    }
+    // like recording, turning profiling on starts from scratch
+    fn set_profiling(&self, profiling: bool) {
+        self.imp().stop_run();
+        if profiling {
+            self.imp().profile.borrow_mut().clear();
+        }
+        self.imp().profiling.set(profiling);
+        self.imp().show_profile();
+    }
+    fn show_export_trace(&self) {
+        self.export_trace(
+            "trace.csv",
+            &[("CSV", "*.csv"), ("JSON", "*.json")],
+            |trace, path, writer| match path.extension().is_some_and(|ext| ext == "json") {
+                true => trace.write_json(writer),
+                false => trace.write_csv(writer),
+            },
+        );
+    }
+    fn show_export_waveform(&self) {
+        self.imp().stop_run();
+        if self.imp().waveform.borrow().is_empty() {
+            self.imp().show_toast("Nothing recorded yet, turn on Record waveform and run the program");
+            return;
+        }
+        self.choose_save_path("waveform.vcd", &[("VCD", "*.vcd")], |obj, path| {
+            let waveform = obj.imp().waveform.borrow();
+            let written = std::fs::File::create(&path)
+                .and_then(|file| waveform.write_vcd(BufWriter::new(file)));
+            match written {
+                Ok(()) => obj.imp().show_toast(format!("Exported {} recorded commands", waveform.len())),
+                Err(err) => obj.imp().show_toast(format!("Cannot export the waveform: {}", err)),
+            }
+        });
+    }
+    // the trace goes out as CSV or JSON
+    fn export_trace(
+        &self,
+        initial_name: &str,
+        patterns: &[(&str, &str)],
+        write: impl Fn(&Trace, &Path, BufWriter<std::fs::File>) -> std::io::Result<()> + 'static,
+    ) {
+        self.imp().stop_run();
+        if self.imp().trace.borrow().rows().is_empty() {
+            self.imp().show_toast("Nothing recorded yet, turn on Record trace and run the program");
+            return;
+        }
+        self.choose_save_path(initial_name, patterns, move |obj, path| {
+            let trace = obj.imp().trace.borrow();
+            let written = std::fs::File::create(&path)
+                .and_then(|file| write(&trace, &path, BufWriter::new(file)));
+            match written {
+                Ok(()) => obj.imp().show_toast(format!("Exported {} recorded steps", trace.rows().len())),
+                Err(err) => obj.imp().show_toast(format!("Cannot export the trace: {}", err)),
+            }
+        });
+    }
+    fn show_export_coverage(&self) {
+        self.imp().stop_run();
+        if self.imp().coverage.borrow().is_empty() {
+            self.imp().show_toast("Nothing executed yet");
+            return;
+        }
+        self.choose_save_path(
+            "coverage.txt",
+            &[("Text", "*.txt"), ("JSON", "*.json")],
+            |obj, path| {
+                let summary = {
+                    let emul = obj.get_emulator();
+                    let emul = emul.borrow();
+                    let Some(ref emul) = *emul else {
+                        return;
+                    };
+                    obj.imp().coverage.borrow().summary(&**emul)
+                };
+                let written = std::fs::File::create(&path).and_then(|file| {
+                    let writer = BufWriter::new(file);
+                    match path.extension().is_some_and(|ext| ext == "json") {
+                        true => summary.write_json(writer),
+                        false => summary.write_text(writer),
+                    }
+                });
+                match written {
+                    Ok(()) => obj.imp().show_toast(format!(
+                        "{} of {} commands executed",
+                        summary.executed, summary.commands
+                    )),
+                    Err(err) => obj.imp().show_toast(format!("Cannot export the coverage: {}", err)),
+                }
+            },
+        );
+    }
+    fn show_load_memory_image(&self) {
+        self.imp().stop_run();
+        self.choose_open_path(&memory_image_patterns(), |obj, path| {
+            let image = std::fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|data| {
+                    MemoryImage::parse(&data, ImageFormat::of_path(&path))
+                        .map_err(|err| err.to_string())
+                });
+            let image = match image {
+                Ok(image) => image,
+                Err(err) => {
+                    obj.imp().show_toast(format!("Cannot load the memory image: {}", err));
+                    return;
+                }
+            };
+            if !obj.imp().edit_state(|emul| image.load(&mut **emul)) {
+                return;
+            }
+            if obj.imp().memory_image.borrow().is_some() {
+                obj.imp().memory_image.replace(Some(image.clone()));
+            }
+            obj.imp().show_toast(format!("Loaded {} bytes", image.bytes().len()));
+        });
+    }
+    fn show_save_memory_image(&self) {
+        self.imp().stop_run();
+        self.choose_save_path("memory.hex", &memory_image_patterns(), |obj, path| {
+            let image = {
+                let emul = obj.get_emulator();
+                let emul = emul.borrow();
+                let Some(ref emul) = *emul else {
+                    return;
+                };
+                MemoryImage::of(&**emul)
+            };
+            let written = std::fs::File::create(&path)
+                .and_then(|file| image.write(ImageFormat::of_path(&path), BufWriter::new(file)));
+            if let Err(err) = written {
+                obj.imp().show_toast(format!("Cannot save the memory image: {}", err));
+            }
+        });
+    }
+    // keeping starts from what the memory holds now
+    fn set_keep_memory_image(&self, keep: bool) {
+        self.imp().stop_run();
+        let image = match keep {
+            true => {
+                let emul = self.get_emulator();
+                let emul = emul.borrow();
+                let Some(ref emul) = *emul else {
+                    return;
+                };
+                Some(MemoryImage::of(&**emul))
+            }
+            false => None,
+        };
+        self.imp().memory_image.replace(image);
+    }
+    fn show_load_stimulus(&self) {
+        self.choose_open_path(&port_script_patterns(), |obj, path| {
+            let stimulus = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Stimulus::parse(&text).map_err(|err| err.to_string()));
+            let stimulus = match stimulus {
+                Ok(stimulus) => stimulus,
+                Err(err) => {
+                    obj.imp().show_toast(format!("Cannot load the stimulus: {}", err));
+                    return;
+                }
+            };
+            // a program that only writes is graded on its capture alone
+            match stimulus.is_empty() {
+                true => obj.imp().show_toast("Capturing the ports, the stimulus has no values"),
+                false => obj.imp().show_toast(format!("Loaded {} stimulus values", stimulus.len())),
+            }
+            obj.imp().script.replace(Some(ScriptedPorts::new(stimulus)));
+            obj.replug_ports();
+        });
+    }
+    fn show_save_stimulus(&self) {
+        let Some(script) = self.imp().script.borrow().clone() else {
+            return self.imp().show_toast("No stimulus loaded");
+        };
+        self.choose_save_path("stimulus.txt", &port_script_patterns(), move |obj, path| {
+            let written = std::fs::File::create(&path)
+                .and_then(|file| script.stimulus().write(BufWriter::new(file)));
+            if let Err(err) = written {
+                obj.imp().show_toast(format!("Cannot save the stimulus: {}", err));
+            }
+        });
+    }
+    fn unload_stimulus(&self) {
+        if self.imp().script.take().is_some() {
+            self.replug_ports();
+            self.imp().show_toast("The stimulus is unloaded");
+        }
+    }
+    fn show_save_capture(&self) {
+        let Some(script) = self.imp().script.borrow().clone() else {
+            return self.imp().show_toast("Nothing is captured without a stimulus loaded");
+        };
+        if script.capture().is_empty() {
+            return self.imp().show_toast("Nothing captured yet, run the program first");
+        }
+        self.choose_save_path("capture.txt", &port_script_patterns(), move |obj, path| {
+            let capture = script.capture();
+            let written = std::fs::File::create(&path)
+                .and_then(|file| capture.write(BufWriter::new(file)));
+            match written {
+                Ok(()) => obj.imp().show_toast(format!("Saved {} captured writes", capture.len())),
+                Err(err) => obj.imp().show_toast(format!("Cannot save the capture: {}", err)),
+            }
+        });
+    }
+    fn show_compare_capture(&self) {
+        let Some(script) = self.imp().script.borrow().clone() else {
+            return self.imp().show_toast("Nothing is captured without a stimulus loaded");
+        };
+        self.choose_open_path(&port_script_patterns(), move |obj, path| {
+            let expected = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Capture::parse(&text).map_err(|err| err.to_string()));
+            let expected = match expected {
+                Ok(expected) => expected,
+                Err(err) => {
+                    obj.imp().show_toast(format!("Cannot load the expected output: {}", err));
+                    return;
+                }
+            };
+            let capture = script.capture();
+            let mismatches = capture.compare(&expected);
+            match mismatches.is_empty() {
+                true => obj.imp().show_toast(format!(
+                    "All {} captured writes are as expected",
+                    capture.len()
+                )),
+                false => obj.show_mismatches(&mismatches),
+            }
+        });
+    }
+    // a dialog only fits so much, the first ones tell where it went wrong
+    fn show_mismatches(&self, mismatches: &[Mismatch]) {
+        const SHOWN: usize = 20;
+        let mut lines = mismatches
+            .iter()
+            .take(SHOWN)
+            .map(Mismatch::to_string)
+            .collect::<Vec<_>>();
+        if mismatches.len() > SHOWN {
+            lines.push(format!("and {} more", mismatches.len() - SHOWN));
+        }
+        let heading = format!("{} writes differ from the expected output", mismatches.len());
+        let dialog = adw::MessageDialog::new(
+            self.active_window().as_ref(),
+            Some(heading.as_str()),
+            Some(lines.join("\n").as_str()),
+        );
+        dialog.add_response("close", "_Close");
+        dialog.present();
+    }
+    fn choose_open_path(
+        &self,
+        patterns: &[(&str, &str)],
+        then: impl FnOnce(&Self, PathBuf) + 'static,
+    ) {
+        let window = self.active_window().unwrap();
+        let open_file = gtk::FileDialog::new();
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        for (name, pattern) in patterns {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some(name));
+            filter.add_pattern(pattern);
+            filters.append(&filter);
+        }
+        open_file.set_filters(Some(&filters));
+        let obj = self.clone();
+        open_file.open(Some(&window.clone()), gio::Cancellable::NONE, move |res| {
+            let Ok(file) = res else { return };
+            let path = file.path().expect("Unable to get file path");
+            then(&obj, path);
+        });
+    }
+    fn choose_save_path(
+        &self,
+        initial_name: &str,
+        patterns: &[(&str, &str)],
+        then: impl FnOnce(&Self, PathBuf) + 'static,
+    ) {
+        let window = self.active_window().unwrap();
+        let save_file = gtk::FileDialog::new();
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        for (name, pattern) in patterns {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some(name));
+            filter.add_pattern(pattern);
+            filters.append(&filter);
+        }
+        save_file.set_filters(Some(&filters));
+        save_file.set_initial_name(Some(initial_name));
+        let obj = self.clone();
+        save_file.save(Some(&window.clone()), gio::Cancellable::NONE, move |res| {
+            let Ok(file) = res else { return };
+            let path = file.path().expect("Unable to get file path");
+            then(&obj, path);
         });
     }
     fn toggle_debug_pane(&self) {
@@ -1349,24 +2630,38 @@ impl MtemuApplication {
         }
         let stack_window = {
             let window = stack_view::StackWindow::new(self);
+            let app = self.clone();
+            window.connect_closure(
+                "frame-activated",
+                false,
+                glib::closure_local!(move |_: stack_view::StackWindow, site: u32| {
+                    if let Some(pane) = app.imp().code_view_pane() {
+                        pane.show_address(site);
+                    }
+                }),
+            );
+            let app = self.clone();
+            window.connect_closure(
+                "value-edited",
+                false,
+                glib::closure_local!(move |_: stack_view::StackWindow, addr: u32, value: u32| {
+                    app.imp()
+                        .edit_state(|emul| emul.set_stack_value(addr as usize, value as usize));
+                }),
+            );
             self.add_window(&window);
             self.imp().stack_window.replace(Some(window.id()));
             window
         };
         stack_window.present();
         let emul = self.get_emulator();
-        let stack = Rc::new(
-            {
-                let Some(ref emul) = *emul.borrow() else {
-                    return;
-                };
-                emul.get_stack()
-            }
-            .into_iter()
-            .map(|val| val as u32)
-            .collect::<Vec<u32>>(),
-        );
-        self.emit_by_name::<()>("stack-changed", &[&imp::BoxedStack(stack)]);
+        let stack = {
+            let Some(ref emul) = *emul.borrow() else {
+                return;
+            };
+            imp::BoxedStack::of(emul)
+        };
+        self.emit_by_name::<()>("stack-changed", &[&stack]);
     }
     fn toggle_memory(&self) {
         if let Some(memory_id) = *self.imp().memory_window.borrow() {
@@ -1378,6 +2673,15 @@ impl MtemuApplication {
         }
         let memory_window = {
             let window = memory_view::MemoryWindow::new(self);
+            let app = self.clone();
+            window.connect_closure(
+                "value-edited",
+                false,
+                glib::closure_local!(move |_: memory_view::MemoryWindow, addr: u32, value: u32| {
+                    app.imp()
+                        .edit_state(|emul| emul.set_mem_value(addr as usize, value as u8));
+                }),
+            );
             self.add_window(&window);
             self.imp().memory_window.replace(Some(window.id()));
             window
@@ -1396,6 +2700,127 @@ impl MtemuApplication {
         self.emit_by_name::<()>("memory-changed", &[&imp::BoxedMemory(memory)]);
     }
 
+    fn toggle_profile(&self) {
+        if let Some(profile_id) = *self.imp().profile_window.borrow() {
+            if let Some(window) = self.window_by_id(profile_id) {
+                self.remove_window(&window);
+                window.destroy();
+                return;
+            }
+        }
+        let profile_window = {
+            let window = profile_view::ProfileWindow::new(self);
+            self.add_window(&window);
+            self.imp().profile_window.replace(Some(window.id()));
+            window
+        };
+        profile_window.present();
+        // stays empty while a run holds the profile, it is filled when the run stops
+        self.imp().show_profile();
+    }
+
+    fn toggle_panel(&self) {
+        if let Some(panel_id) = *self.imp().panel_window.borrow() {
+            if let Some(window) = self.window_by_id(panel_id) {
+                self.remove_window(&window);
+                window.destroy();
+                return;
+            }
+        }
+        let panel_window = {
+            let window = panel_view::PanelWindow::new(self);
+            let app = self.clone();
+            window.connect_closure(
+                "input-changed",
+                false,
+                glib::closure_local!(move |_: panel_view::PanelWindow, port: u32, value: u32| {
+                    app.imp().set_port_input(port, value);
+                }),
+            );
+            // closed from its own title bar too, the ports go back either way
+            let app = self.clone();
+            window.connect_destroy(move |_| app.imp().plug_ports(false));
+            self.add_window(&window);
+            self.imp().panel_window.replace(Some(window.id()));
+            window
+        };
+        self.imp().plug_ports(true);
+        panel_window.present();
+        self.imp().show_ports();
+    }
+
+    // There is only ever one board on a bench, the first one found is taken.
+    // Looking for it opens every tty and waits for an answer, so that happens
+    // off the main loop, `action` is switched on once it is done.
+    #[cfg(unix)]
+    fn connect_extender(&self, connect: bool, action: &gio::SimpleAction) {
+        self.imp().extender.replace(None);
+        if !connect {
+            self.replug_ports();
+            action.set_state(&false.to_variant());
+            return;
+        }
+        action.set_enabled(false);
+        let found = gio::spawn_blocking(|| {
+            port_extender::available_devices()
+                .first()
+                .map(|info| PortExtender::open(&info.path))
+        });
+        let app = self.clone();
+        let action = action.clone();
+        glib::MainContext::default().spawn_local(async move {
+            let imp = app.imp();
+            match found.await.ok().flatten() {
+                Some(Ok(extender)) => {
+                    imp.show_toast(format!(
+                        "Connected to the port extender {} at {}",
+                        extender.info().serial,
+                        extender.info().path.display()
+                    ));
+                    imp.extender.replace(Some(extender.device()));
+                }
+                Some(Err(err)) => imp.report_error(&err),
+                None => imp.show_toast("No port extender found"),
+            }
+            app.replug_ports();
+            action.set_enabled(true);
+            action.set_state(&imp.extender.borrow().is_some().to_variant());
+        });
+    }
+
+    // after what takes the ports over changed, with the panel's wiring if it
+    // is open
+    fn replug_ports(&self) {
+        let panel = self
+            .imp()
+            .panel_window
+            .borrow()
+            .and_then(|id| self.window_by_id(id))
+            .is_some();
+        self.imp().plug_ports(panel);
+    }
+
+    fn toggle_watch(&self, target: &str) {
+        let watch = match target.parse::<Watch>() {
+            Ok(watch) => watch,
+            Err(err) => return self.imp().show_toast(err),
+        };
+        {
+            let mut watches = self.imp().watches.borrow_mut();
+            match watches.iter().position(|other| *other == watch) {
+                Some(ind) => {
+                    watches.remove(ind);
+                    self.imp().show_toast(format!("Stopped watching {}", watch));
+                }
+                None => {
+                    watches.push(watch);
+                    self.imp().show_toast(format!("Watching {}", watch));
+                }
+            }
+        }
+        self.imp().watches_changed();
+    }
+
     fn cut_commands(&self) {
         let window = self.active_window().unwrap();
         let Some(window) = window.downcast_ref::<MtemuWindow>() else {
@@ -1415,9 +2840,22 @@ impl MtemuApplication {
                     let Some(ref mut emul) = *emul.borrow_mut() else {
                         return;
                     };
-                    let cmd = emul.get_command(ind as usize);
-                    emul.remove_command(ind as usize);
-                    cmd
+                    // the rows cut so far are gone from the program already
+                    let ind = ind as usize - cut_commands.len();
+                    let before = project::command_addresses(&**emul);
+                    let cmd = emul.get_command(ind);
+                    let cmd = cmd.and_then(|cmd| emul.remove_command(ind).map(|_| cmd));
+                    match cmd {
+                        Ok(cmd) => {
+                            self.imp().breakpoints.borrow_mut().command_removed(
+                                ind,
+                                &before,
+                                &project::command_addresses(&**emul),
+                            );
+                            cmd
+                        }
+                        Err(err) => return self.imp().report_error(&err),
+                    }
                 };
                 cut_commands.push(cmd);
             }
@@ -1429,7 +2867,8 @@ impl MtemuApplication {
             let new_cmd_cnt = emul.commands_count();
             let mut cmds = Vec::<emulator::Command>::with_capacity(new_cmd_cnt);
             for i in 0..new_cmd_cnt {
-                cmds.push(emul.get_command(i));
+                let Ok(cmd) = emul.get_command(i) else { break };
+                cmds.push(cmd);
             }
             Rc::new(cmds)
         });
@@ -1439,20 +2878,8 @@ impl MtemuApplication {
             return;
         };
         clipboard.set_text(&String::from_iter(cut_commands.into_iter().map(|cmd| {
-            let words = cmd.get_words().expect("Failed getting words!");
-            format!(
-                "{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}\n",
-                words[0],
-                words[1],
-                words[2],
-                words[3],
-                words[4],
-                words[5],
-                words[6],
-                words[7],
-                words[8],
-                words[9]
-            )
+            let instr = emulator::Microinstruction::from_command(&cmd).expect("Failed getting words!");
+            format!("{}\n", instr)
         })));
     }
 
@@ -1471,8 +2898,10 @@ impl MtemuApplication {
                     let Some(ref mut emul) = *emul.borrow_mut() else {
                         return;
                     };
-                    let cmd = emul.get_command(ind as usize);
-                    cmd
+                    match emul.get_command(ind as usize) {
+                        Ok(cmd) => cmd,
+                        Err(err) => return self.imp().report_error(&err),
+                    }
                 };
                 cut_commands.push(cmd);
             }
@@ -1482,20 +2911,8 @@ impl MtemuApplication {
             return;
         };
         clipboard.set_text(&String::from_iter(cut_commands.into_iter().map(|cmd| {
-            let words = cmd.get_words().expect("Failed getting words!");
-            format!(
-                "{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}\n",
-                words[0],
-                words[1],
-                words[2],
-                words[3],
-                words[4],
-                words[5],
-                words[6],
-                words[7],
-                words[8],
-                words[9]
-            )
+            let instr = emulator::Microinstruction::from_command(&cmd).expect("Failed getting words!");
+            format!("{}\n", instr)
         })));
     }
 
@@ -1514,8 +2931,10 @@ impl MtemuApplication {
                     let Some(ref mut emul) = *emul.borrow_mut() else {
                         return;
                     };
-                    let cmd = emul.get_command(ind as usize);
-                    cmd
+                    match emul.get_command(ind as usize) {
+                        Ok(cmd) => cmd,
+                        Err(err) => return self.imp().report_error(&err),
+                    }
                 };
                 cut_commands.push(cmd);
             }
@@ -1525,20 +2944,8 @@ impl MtemuApplication {
             return;
         };
         clipboard.set_text(&String::from_iter(cut_commands.into_iter().map(|cmd| {
-            let words = cmd.get_words().expect("Failed getting words!");
-            format!(
-                "{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}{:0>4b}\n",
-                words[0],
-                words[1],
-                words[2],
-                words[3],
-                words[4],
-                words[5],
-                words[6],
-                words[7],
-                words[8],
-                words[9]
-            )
+            let instr = emulator::Microinstruction::from_command(&cmd).expect("Failed getting words!");
+            format!("{}\n", instr)
         })));
     }
     
@@ -1573,16 +2980,8 @@ impl MtemuApplication {
             let Some(string) = data else { return };
             let cmd_words = string
                 .lines()
-                .filter_map(|line| {
-                    if line.len() != 40 || i32::from_str_radix(line, 2).is_err() {
-                        return None;
-                    }
-                    let mut words = Vec::<i32>::with_capacity(10);
-                    for i in (0..=line.len() - 4).step_by(4) {
-                        words.push(i32::from_str_radix(&line[i..i + 4], 2).unwrap_or(0));
-                    }
-                    Some(words)
-                })
+                .filter_map(|line| line.parse::<emulator::Microinstruction>().ok())
+                .map(|instr| instr.to_command_words().to_vec())
                 .collect::<Vec<Vec<i32>>>();
             let emul = self.get_emulator();
             let new_commands = imp::BoxedCommands({
@@ -1590,16 +2989,24 @@ impl MtemuApplication {
                     return;
                 };
                 cmd_words.into_iter().enumerate().for_each(|(ind, words)| {
-                    let mut words_boxed = Box::new(words);
-                    let cmd = emulator::Command::new(0, words_boxed.as_mut());
+                    let cmd = emulator::Command::new(0, &words);
                     // +1 is needed to insert command after selected, not before
-                    emul.add_command(selected as usize + ind + 1, &cmd);
-                    drop(words_boxed);
+                    let position = selected as usize + ind + 1;
+                    let before = project::command_addresses(&**emul);
+                    match emul.add_command(position, &cmd) {
+                        Ok(()) => self.imp().breakpoints.borrow_mut().command_inserted(
+                            position,
+                            &before,
+                            &project::command_addresses(&**emul),
+                        ),
+                        Err(err) => self.imp().report_error(&err),
+                    }
                 });
                 let new_cmd_cnt = emul.commands_count();
                 let mut cmds = Vec::<emulator::Command>::with_capacity(new_cmd_cnt);
                 for i in 0..new_cmd_cnt {
-                    cmds.push(emul.get_command(i));
+                    let Ok(cmd) = emul.get_command(i) else { break };
+                    cmds.push(cmd);
                 }
                 Rc::new(cmds)
             });
@@ -1653,7 +3060,7 @@ impl MtemuApplication {
             let Some(ref emul) = *emul.as_ref().borrow() else {
                 return;
             };
-            Rc::new(emul.get_map_calls())
+            Rc::new(emul.get_map_calls().unwrap_or_default())
         });
         self.emit_by_name::<()>("callslib-appeared", &[&libcalls]);
         let commands = imp::BoxedCalls({
@@ -1664,7 +3071,8 @@ impl MtemuApplication {
             let call_count = emul.call_count();
             let mut calls = Vec::<emulator::Call>::with_capacity(call_count);
             for i in 0..call_count {
-                calls.push(emul.get_call(i));
+                let Ok(call) = emul.get_call(i) else { break };
+                calls.push(call);
             }
             Rc::new(calls)
         });
@@ -1672,7 +3080,7 @@ impl MtemuApplication {
         self.imp().handle_command_buttons(commands_window);
     }
 
-    pub fn set_emulator(&self, emul: emulator::OriginalImplementation) {
+    pub fn set_emulator(&self, emul: emulator::Implementation) {
         self.imp().set_emulator(emul);
     }
     pub fn get_emulator(&self) -> utils::EmulatorStored {
@@ -1684,3 +3092,11 @@ impl MtemuApplication {
         window.emit_by_name::<()>("library-inited", &[]);
     }
 }
+
+fn memory_image_patterns() -> [(&'static str, &'static str); 3] {
+    ImageFormat::ALL.map(|format| (format.name(), format.pattern()))
+}
+
+fn port_script_patterns() -> [(&'static str, &'static str); 1] {
+    [("Port script", "*.txt")]
+}
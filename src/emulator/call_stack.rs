@@ -0,0 +1,88 @@
+/* emulator/call_stack.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// The microprogram stack read as return addresses. CALL, CLNZ and PUSH all
+// push the address after themselves, so every entry below SP points right
+// past the command that put it there.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    // index in the stack, the innermost frame is at SP - 1
+    pub depth: usize,
+    pub return_addr: usize,
+}
+
+impl Frame {
+    // None for an entry that could not have been pushed by a command
+    pub fn call_site(&self) -> Option<usize> {
+        self.return_addr.checked_sub(1)
+    }
+}
+
+// Innermost first
+pub fn frames(stack: &[u32], sp: usize) -> Vec<Frame> {
+    stack
+        .iter()
+        .take(sp)
+        .enumerate()
+        .rev()
+        .map(|(depth, addr)| Frame {
+            depth,
+            return_addr: *addr as usize,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, MT1804Emulator, NativeImplementation};
+
+    #[test]
+    fn finds_the_call_after_a_call() {
+        // jumps.mte calls the subroutine at 0x020 from 0x008
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[0].1).unwrap();
+        emul.reset();
+        while emul.get_pc() != 0x020 {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        }
+        let stack = emul
+            .get_stack()
+            .into_iter()
+            .map(|addr| addr as u32)
+            .collect::<Vec<_>>();
+        let frames = frames(&stack, emul.get_sp());
+        assert_eq!(frames.len(), emul.get_sp());
+        let inner = frames[0];
+        assert_eq!(inner.depth, emul.get_sp() - 1);
+        assert_eq!((inner.return_addr, inner.call_site()), (0x009, Some(0x008)));
+    }
+
+    #[test]
+    fn reads_the_stack_innermost_first() {
+        let frames = frames(&[0x000, 0x103, 0x7FF, 0x005], 3);
+        let found = frames
+            .iter()
+            .map(|frame| (frame.depth, frame.call_site()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [(2, Some(0x7FE)), (1, Some(0x102)), (0, None)]);
+    }
+}
@@ -0,0 +1,583 @@
+/* emulator/condition.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Break conditions over the machine state, C-like:
+//
+//   R3 == 0b1010        Z && !C4        mem[0x10] > 0x7F        SP == 0
+//
+// Operands are R0-R15, Q, PC, SP, MP, F, Y, the flags OVR, C4, F3, Z, G (/G)
+// and P (/P), mem[i] and stack[i], and numbers in decimal, 0x or 0b.
+// Operators from loosest to tightest: ||, &&, comparisons, & | ^, + -, and
+// the unary ! ~ -. Anything non-zero is true.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::MT1804Emulator;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Var {
+    Reg(usize),
+    Q,
+    Pc,
+    Sp,
+    Mp,
+    F,
+    Y,
+    Ovr,
+    C4,
+    F3,
+    Z,
+    G,
+    P,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UnOp {
+    Not,
+    Inv,
+    Neg,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Num(i64),
+    Var(Var),
+    Mem(Box<Expr>),
+    Stack(Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // None when the condition reads past memory or the stack
+    fn eval(&self, emul: &dyn MT1804Emulator) -> Option<i64> {
+        Some(match self {
+            Self::Num(num) => *num,
+            Self::Var(var) => match var {
+                Var::Reg(ind) => emul.get_reg(*ind).ok()? as i64,
+                Var::Q => emul.get_reg_q() as i64,
+                // PC is -1 before the first step
+                Var::Pc => emul.get_pc() as isize as i64,
+                Var::Sp => emul.get_sp() as i64,
+                Var::Mp => emul.get_mp() as i64,
+                Var::F => emul.get_f() as i64,
+                Var::Y => emul.get_y() as i64,
+                Var::Ovr => emul.get_ovr() as i64,
+                Var::C4 => emul.get_c4() as i64,
+                Var::F3 => emul.get_f3() as i64,
+                Var::Z => emul.get_z() as i64,
+                Var::G => emul.get_g() as i64,
+                Var::P => emul.get_p() as i64,
+            },
+            Self::Mem(index) => {
+                let index = usize::try_from(index.eval(emul)?).ok()?;
+                if index >= emul.get_mem_length() {
+                    return None;
+                }
+                emul.get_mem_value(index).ok()? as i64
+            }
+            Self::Stack(index) => {
+                let index = usize::try_from(index.eval(emul)?).ok()?;
+                *emul.get_stack().get(index)? as i64
+            }
+            Self::Unary(op, val) => {
+                let val = val.eval(emul)?;
+                match op {
+                    UnOp::Not => (val == 0) as i64,
+                    UnOp::Inv => !val,
+                    UnOp::Neg => val.wrapping_neg(),
+                }
+            }
+            Self::Binary(BinOp::Or, lhs, rhs) => {
+                (lhs.eval(emul)? != 0 || rhs.eval(emul)? != 0) as i64
+            }
+            Self::Binary(BinOp::And, lhs, rhs) => {
+                (lhs.eval(emul)? != 0 && rhs.eval(emul)? != 0) as i64
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(emul)?, rhs.eval(emul)?);
+                match op {
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::BitOr => lhs | rhs,
+                    BinOp::BitXor => lhs ^ rhs,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// longest first, so that "<=" is not read as "<"
+const OPERATORS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "&", "|", "^", "+", "-", "!", "~", "(", ")",
+    "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let Some(c) = rest.chars().next() else { break };
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+        // "/G" and "/P" are how the flags are written on the board
+        let word_len = rest
+            .char_indices()
+            .find(|&(ind, c)| !(c.is_ascii_alphanumeric() || c == '_' || (ind == 0 && c == '/')))
+            .map_or(rest.len(), |(ind, _)| ind);
+        if c.is_ascii_digit() {
+            let word = &rest[..word_len];
+            let (digits, radix) = match word.get(..2) {
+                Some("0x") | Some("0X") => (&word[2..], 16),
+                Some("0b") | Some("0B") => (&word[2..], 2),
+                _ => (word, 10),
+            };
+            let num = i64::from_str_radix(digits, radix).map_err(|_| ParseError {
+                position: pos,
+                message: format!("bad number \"{}\"", word),
+            })?;
+            tokens.push((pos, Token::Num(num)));
+            pos += word_len;
+        } else if c.is_ascii_alphabetic() || c == '_' || (c == '/' && word_len > 1) {
+            tokens.push((pos, Token::Ident(rest[..word_len].to_ascii_uppercase())));
+            pos += word_len;
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push((pos, Token::Op(op)));
+            pos += op.len();
+        } else {
+            return Err(ParseError {
+                position: pos,
+                message: format!("unexpected \"{}\"", c),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            position: self.tokens.get(self.pos).map_or(self.end, |tok| tok.0),
+            message: message.into(),
+        })
+    }
+
+    fn eat(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some((_, Token::Op(op))) if ops.contains(op) => {
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), ParseError> {
+        match self.eat(&[op]) {
+            Some(_) => Ok(()),
+            None => self.error(format!("expected \"{}\"", op)),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[&'static str],
+        next: fn(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Expr, ParseError> {
+        let mut lhs = next(self)?;
+        while let Some(op) = self.eat(ops) {
+            let op = match op {
+                "||" => BinOp::Or,
+                "&&" => BinOp::And,
+                "&" => BinOp::BitAnd,
+                "|" => BinOp::BitOr,
+                "^" => BinOp::BitXor,
+                "+" => BinOp::Add,
+                _ => BinOp::Sub,
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(next(self)?));
+        }
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&["||"], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&["&&"], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.bits()?;
+        let Some(op) = self.eat(&["==", "!=", "<=", ">=", "<", ">"]) else {
+            return Ok(lhs);
+        };
+        let op = match op {
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<=" => BinOp::Le,
+            ">=" => BinOp::Ge,
+            "<" => BinOp::Lt,
+            _ => BinOp::Gt,
+        };
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.bits()?)))
+    }
+
+    fn bits(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&["&", "|", "^"], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, ParseError> {
+        self.binary(&["+", "-"], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.eat(&["!", "~", "-"]) {
+            None => return self.primary(),
+            Some("!") => UnOp::Not,
+            Some("~") => UnOp::Inv,
+            Some(_) => UnOp::Neg,
+        };
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat(&["("]).is_some() {
+            let expr = self.or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let token = match self.tokens.get(self.pos) {
+            Some((_, token)) => token.clone(),
+            None => return self.error("unexpected end"),
+        };
+        let name = match token {
+            Token::Num(num) => {
+                self.pos += 1;
+                return Ok(Expr::Num(num));
+            }
+            Token::Ident(name) => name,
+            Token::Op(op) => return self.error(format!("unexpected \"{}\"", op)),
+        };
+        let var = match name.as_str() {
+            "MEM" | "STACK" => {
+                self.pos += 1;
+                self.expect("[")?;
+                let index = Box::new(self.or()?);
+                self.expect("]")?;
+                return Ok(match name.as_str() {
+                    "MEM" => Expr::Mem(index),
+                    _ => Expr::Stack(index),
+                });
+            }
+            "Q" => Var::Q,
+            "PC" => Var::Pc,
+            "SP" => Var::Sp,
+            "MP" => Var::Mp,
+            "F" => Var::F,
+            "Y" => Var::Y,
+            "OVR" => Var::Ovr,
+            "C4" => Var::C4,
+            "F3" => Var::F3,
+            "Z" => Var::Z,
+            "G" | "/G" => Var::G,
+            "P" | "/P" => Var::P,
+            reg => match reg.strip_prefix('R').and_then(|ind| ind.parse::<usize>().ok()) {
+                Some(ind) if ind < 16 => Var::Reg(ind),
+                _ => return self.error(format!("unknown name \"{}\"", name)),
+            },
+        };
+        self.pos += 1;
+        Ok(Expr::Var(var))
+    }
+}
+
+fn parse(text: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        end: text.len(),
+    };
+    if parser.tokens.is_empty() {
+        return parser.error("empty condition");
+    }
+    let expr = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return parser.error("unexpected trailing input");
+    }
+    Ok(expr)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SavedCondition", into = "SavedCondition")]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+    pub enabled: bool,
+}
+
+impl Condition {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn holds(&self, emul: &dyn MT1804Emulator) -> bool {
+        self.expr.eval(emul).is_some_and(|val| val != 0)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            source: s.trim().to_owned(),
+            expr: parse(s)?,
+            enabled: true,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCondition {
+    source: String,
+    enabled: bool,
+}
+
+impl TryFrom<SavedCondition> for Condition {
+    type Error = ParseError;
+
+    fn try_from(saved: SavedCondition) -> Result<Self, Self::Error> {
+        let mut cond = saved.source.parse::<Condition>()?;
+        cond.enabled = saved.enabled;
+        Ok(cond)
+    }
+}
+
+impl From<Condition> for SavedCondition {
+    fn from(cond: Condition) -> Self {
+        Self {
+            source: cond.source,
+            enabled: cond.enabled,
+        }
+    }
+}
+
+// Remembers which conditions held after the last step. Only a change to
+// true counts, so that a condition that already holds does not fire on
+// every step.
+#[derive(Clone, Debug, Default)]
+pub struct ConditionSet {
+    conditions: Vec<Condition>,
+    held: Vec<bool>,
+}
+
+impl ConditionSet {
+    pub fn new(conditions: Vec<Condition>, emul: &dyn MT1804Emulator) -> Self {
+        let held = Self::holding(&conditions, emul);
+        Self { conditions, held }
+    }
+
+    fn holding(conditions: &[Condition], emul: &dyn MT1804Emulator) -> Vec<bool> {
+        conditions
+            .iter()
+            .map(|cond| cond.enabled && cond.holds(emul))
+            .collect()
+    }
+
+    // call after every executed command, the first condition that became
+    // true comes back
+    pub fn check(&mut self, emul: &dyn MT1804Emulator) -> Option<(usize, &Condition)> {
+        let holds = Self::holding(&self.conditions, emul);
+        let became_true = holds
+            .iter()
+            .zip(&self.held)
+            .position(|(now, before)| *now && !before);
+        self.held = holds;
+        became_true.map(|ind| (ind, &self.conditions[ind]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Flag, NativeImplementation};
+
+    fn eval(text: &str, emul: &dyn MT1804Emulator) -> Option<i64> {
+        parse(text).unwrap().eval(emul)
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        let err = text.parse::<Condition>().unwrap_err();
+        (err.position, err.message)
+    }
+
+    #[test]
+    fn registers_flags_and_memory() {
+        let mut emul = NativeImplementation::new();
+        assert_eq!(eval("R3 == 0b1010", &emul), Some(0));
+        emul.set_reg(3, 0b1010).unwrap();
+        assert_eq!(eval("R3 == 0b1010", &emul), Some(1));
+        assert_eq!(eval("r3 == 10 && R4 == 0", &emul), Some(1));
+
+        emul.set_flag(Flag::Z, true).unwrap();
+        assert_eq!(eval("Z && !C4", &emul), Some(1));
+        emul.set_flag(Flag::C4, true).unwrap();
+        assert_eq!(eval("Z && !C4", &emul), Some(0));
+        emul.set_flag(Flag::G, true).unwrap();
+        assert_eq!(eval("/G == G", &emul), Some(1));
+
+        assert_eq!(eval("mem[0x10] > 0x7F", &emul), Some(0));
+        emul.set_mem_value(0x10, 0x80).unwrap();
+        assert_eq!(eval("mem[0x10] > 0x7F", &emul), Some(1));
+        assert_eq!(eval("mem[0x0F + 1]", &emul), Some(0x80));
+
+        assert_eq!(eval("SP == 0", &emul), Some(1));
+        emul.set_sp(1).unwrap();
+        assert_eq!(eval("SP == 0", &emul), Some(0));
+        // PC is -1 until the first step
+        assert_eq!(eval("PC", &emul), Some(-1));
+    }
+
+    #[test]
+    fn precedence() {
+        let emul = NativeImplementation::new();
+        assert_eq!(eval("1 || 0 && 0", &emul), Some(1));
+        assert_eq!(eval("(1 || 0) && 0", &emul), Some(0));
+        assert_eq!(eval("1 + 2 == 3", &emul), Some(1));
+        assert_eq!(eval("6 & 3 == 2", &emul), Some(1));
+        assert_eq!(eval("1 | 2 ^ 3", &emul), Some(0));
+        assert_eq!(eval("5 - 2 - 1", &emul), Some(2));
+        assert_eq!(eval("-1 + 3", &emul), Some(2));
+        assert_eq!(eval("!0 + 1", &emul), Some(2));
+        assert_eq!(eval("~0b1010 & 0xF", &emul), Some(0b0101));
+    }
+
+    #[test]
+    fn errors_point_at_their_column() {
+        assert_eq!(error(""), (0, "empty condition".to_owned()));
+        assert_eq!(error("R3 == 0b12"), (6, "bad number \"0b12\"".to_owned()));
+        assert_eq!(error("R16 == 0"), (0, "unknown name \"R16\"".to_owned()));
+        assert_eq!(error("R3 == $"), (6, "unexpected \"$\"".to_owned()));
+        assert_eq!(error("(R3 == 1"), (8, "expected \")\"".to_owned()));
+        assert_eq!(error("mem[1"), (5, "expected \"]\"".to_owned()));
+        assert_eq!(
+            error("R3 == 1 R4"),
+            (8, "unexpected trailing input".to_owned())
+        );
+        assert_eq!(error("R3 =="), (5, "unexpected end".to_owned()));
+        let err = "R3 == )".parse::<Condition>().unwrap_err();
+        assert_eq!(err.to_string(), "unexpected \")\" at column 7");
+    }
+
+    #[test]
+    fn out_of_range_reads_are_never_true() {
+        let emul = NativeImplementation::new();
+        let past_memory = format!("mem[{}]", emul.get_mem_length());
+        assert_eq!(eval(&past_memory, &emul), None);
+        assert_eq!(eval("mem[-1] == 0", &emul), None);
+        assert_eq!(eval("stack[100] == 0", &emul), None);
+        assert_eq!(eval("stack[0] == 0 || mem[300] == 0", &emul), Some(1));
+        let cond = "!mem[1000]".parse::<Condition>().unwrap();
+        assert!(!cond.holds(&emul));
+    }
+
+    #[test]
+    fn fires_once_when_it_becomes_true() {
+        let mut emul = NativeImplementation::new();
+        let mut disabled = "R1 == 1".parse::<Condition>().unwrap();
+        disabled.enabled = false;
+        let conditions = vec![
+            disabled,
+            "R1 == 1".parse().unwrap(),
+            "R2 == 0".parse().unwrap(),
+        ];
+        let mut set = ConditionSet::new(conditions, &emul);
+        // R2 == 0 held from the start
+        assert_eq!(set.check(&emul), None);
+        emul.set_reg(1, 1).unwrap();
+        assert_eq!(set.check(&emul).map(|(ind, _)| ind), Some(1));
+        assert_eq!(set.check(&emul), None);
+        emul.set_reg(1, 0).unwrap();
+        emul.set_reg(2, 1).unwrap();
+        assert_eq!(set.check(&emul), None);
+        emul.set_reg(2, 0).unwrap();
+        let (ind, cond) = set.check(&emul).unwrap();
+        assert_eq!((ind, cond.source()), (2, "R2 == 0"));
+    }
+}
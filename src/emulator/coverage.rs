@@ -0,0 +1,309 @@
+/* emulator/coverage.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Microprogram coverage: how often every address ran and, for conditional
+// jumps, whether the jump was ever taken and ever fell through. Everything
+// is keyed by command address. Only command steps are counted: the engine
+// runs a call of the calls window as a whole and only its last command
+// could be seen.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use super::microinstruction::JumpType;
+use super::{Command, MT1804Emulator, Microinstruction};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Branches {
+    pub taken: usize,
+    pub fell_through: usize,
+}
+
+impl Branches {
+    pub fn complete(&self) -> bool {
+        self.taken > 0 && self.fell_through > 0
+    }
+}
+
+fn is_conditional(cmd: &Command) -> bool {
+    // an offset has the address of the command before it and a JNZ in its
+    // words, but it never runs
+    if cmd.is_offset() {
+        return false;
+    }
+    let Some(instr) = Microinstruction::from_command(cmd) else {
+        return false;
+    };
+    matches!(
+        instr.jump,
+        JumpType::Jnz
+            | JumpType::Jz
+            | JumpType::Jf3
+            | JumpType::Jovr
+            | JumpType::Jc4
+            | JumpType::Jsnz
+            | JumpType::Jsnc4
+            | JumpType::Clnz
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Missed,
+    // executed, but a conditional jump only ever went one way
+    Partial,
+    Full,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    hits: BTreeMap<usize, usize>,
+    branches: BTreeMap<usize, Branches>,
+}
+
+impl Coverage {
+    pub fn after_command(&mut self, emul: &dyn MT1804Emulator) {
+        let Ok(cmd) = emul.executed_command() else {
+            return;
+        };
+        let addr = cmd.get_num();
+        *self.hits.entry(addr).or_default() += 1;
+        let Some(instr) = Microinstruction::from_command(&cmd).filter(|_| is_conditional(&cmd))
+        else {
+            return;
+        };
+        let next = emul.get_pc();
+        let fell_through = next == addr + 1;
+        // JSNZ and JSNC4 go to the stack top, the others to the address in
+        // the command, and when that is the next one both ways look the same
+        let taken = match instr.jump {
+            JumpType::Jsnz | JumpType::Jsnc4 => !fell_through,
+            _ => !fell_through || next == instr.next_addr as usize,
+        };
+        let branches = self.branches.entry(addr).or_default();
+        if taken {
+            branches.taken += 1;
+        }
+        if fell_through {
+            branches.fell_through += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    pub fn hits(&self, addr: usize) -> usize {
+        self.hits.get(&addr).copied().unwrap_or_default()
+    }
+
+    pub fn branches(&self, addr: usize) -> Option<Branches> {
+        self.branches.get(&addr).copied()
+    }
+
+    pub fn level(&self, cmd: &Command) -> Level {
+        let addr = cmd.get_num();
+        match (self.hits(addr), is_conditional(cmd)) {
+            (0, _) => Level::Missed,
+            (_, false) => Level::Full,
+            (_, true)
+                if self
+                    .branches(addr)
+                    .is_some_and(|branches| branches.complete()) =>
+            {
+                Level::Full
+            }
+            (_, true) => Level::Partial,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.hits.clear();
+        self.branches.clear();
+    }
+
+    pub fn summary(&self, emul: &dyn MT1804Emulator) -> Summary {
+        let mut summary = Summary::default();
+        for index in 0..emul.commands_count() {
+            let Ok(cmd) = emul.get_command(index) else {
+                continue;
+            };
+            if cmd.is_offset() {
+                continue;
+            }
+            let addr = cmd.get_num();
+            let level = self.level(&cmd);
+            let conditional = is_conditional(&cmd);
+            let entry = SummaryEntry {
+                address: addr,
+                name: emul.command_get_name(cmd.clone()).unwrap_or_default(),
+                jump: emul.command_get_jump_name(cmd).unwrap_or_default(),
+                hits: self.hits(addr),
+                branches: conditional.then(|| self.branches(addr).unwrap_or_default()),
+            };
+            summary.commands += 1;
+            if entry.hits > 0 {
+                summary.executed += 1;
+            }
+            if let Some(branches) = entry.branches {
+                summary.branches += 1;
+                if branches.complete() {
+                    summary.complete_branches += 1;
+                }
+            }
+            if level != Level::Full {
+                summary.gaps.push(entry);
+            }
+        }
+        summary
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SummaryEntry {
+    pub address: usize,
+    pub name: String,
+    pub jump: String,
+    pub hits: usize,
+    // None for commands without a conditional jump
+    pub branches: Option<Branches>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Summary {
+    pub commands: usize,
+    pub executed: usize,
+    pub branches: usize,
+    pub complete_branches: usize,
+    // everything never executed or with a branch direction never seen
+    pub gaps: Vec<SummaryEntry>,
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    match whole {
+        0 => 100.0,
+        whole => part as f64 * 100.0 / whole as f64,
+    }
+}
+
+impl Summary {
+    pub fn write_text(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "Commands executed: {} of {} ({:.1}%)",
+            self.executed,
+            self.commands,
+            percent(self.executed, self.commands)
+        )?;
+        writeln!(
+            out,
+            "Conditional jumps taken both ways: {} of {} ({:.1}%)",
+            self.complete_branches,
+            self.branches,
+            percent(self.complete_branches, self.branches)
+        )?;
+        for entry in &self.gaps {
+            let problem = match entry.branches {
+                _ if entry.hits == 0 => "never executed".to_owned(),
+                Some(Branches { taken: 0, .. }) => "jump never taken".to_owned(),
+                Some(Branches {
+                    fell_through: 0, ..
+                }) => "jump never fell through".to_owned(),
+                _ => continue,
+            };
+            writeln!(
+                out,
+                "0x{:03X}\t{}\t{}\t{}",
+                entry.address, entry.name, entry.jump, problem
+            )?;
+        }
+        out.flush()
+    }
+
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, NativeImplementation};
+
+    // library.mte starts with an offset to 0xF00, so no address is an index
+    fn library_coverage() -> (NativeImplementation, Coverage) {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[3].1).unwrap();
+        emul.reset();
+        emul.exec_one().unwrap();
+        let mut coverage = Coverage::default();
+        for _ in 0..2000 {
+            assert_eq!(emul.exec_one().unwrap(), ExecResult::Ok);
+            coverage.after_command(&emul);
+        }
+        (emul, coverage)
+    }
+
+    #[test]
+    fn summary_goes_by_address() {
+        let (emul, coverage) = library_coverage();
+        let commands = (0..emul.commands_count())
+            .map(|index| emul.get_command(index).unwrap())
+            .filter(|cmd| !cmd.is_offset())
+            .collect::<Vec<_>>();
+        let executed = commands
+            .iter()
+            .filter(|cmd| coverage.hits(cmd.get_num()) > 0)
+            .count();
+        assert!(executed > 0);
+        let summary = coverage.summary(&emul);
+        assert_eq!(summary.commands, commands.len());
+        assert_eq!(summary.executed, executed);
+        for gap in &summary.gaps {
+            let cmd = commands.iter().find(|cmd| cmd.get_num() == gap.address);
+            assert_ne!(coverage.level(cmd.unwrap()), Level::Full);
+            assert_eq!(gap.hits, coverage.hits(gap.address));
+        }
+    }
+
+    #[test]
+    fn levels_follow_the_branches() {
+        let (emul, coverage) = library_coverage();
+        for index in 0..emul.commands_count() {
+            let cmd = emul.get_command(index).unwrap();
+            if cmd.is_offset() {
+                continue;
+            }
+            let addr = cmd.get_num();
+            let expected = match (coverage.hits(addr), coverage.branches(addr)) {
+                (0, _) => Level::Missed,
+                (_, Some(branches)) if !branches.complete() => Level::Partial,
+                _ => Level::Full,
+            };
+            assert_eq!(coverage.level(&cmd), expected, "0x{:03X}", addr);
+        }
+        let mut coverage = coverage;
+        coverage.clear();
+        assert!(coverage.is_empty());
+    }
+}
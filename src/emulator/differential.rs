@@ -0,0 +1,307 @@
+/* emulator/differential.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Lockstep comparison of two engines. Both get the same program, then
+// exec_one is called on each and everything observable is compared after
+// every step until the first mismatch.
+
+use std::fmt;
+
+use super::trace::FLAG_NAMES;
+use super::{MT1804Emulator, State};
+
+pub const SAMPLES: [(&str, &[u8]); 4] = [
+    ("jumps.mte", include_bytes!("samples/jumps.mte")),
+    ("alu.mte", include_bytes!("samples/alu.mte")),
+    ("memory.mte", include_bytes!("samples/memory.mte")),
+    ("library.mte", include_bytes!("samples/library.mte")),
+];
+
+pub const MAX_STEPS: usize = 1 << 14;
+
+#[derive(Debug)]
+pub struct Divergence {
+    pub step: usize,
+    pub address: usize,
+    pub command: String,
+    pub field: String,
+    pub left: String,
+    pub right: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {} at 0x{:03X} ({}): {} differs, {} != {}",
+            self.step, self.address as i32, self.command, self.field, self.left, self.right
+        )
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Snapshot {
+    state: State,
+    stack: Vec<i32>,
+    memory: Vec<i32>,
+    call_index: usize,
+    prev_index: usize,
+    last_call_cycles: usize,
+}
+
+impl Snapshot {
+    fn take(emul: &dyn MT1804Emulator) -> Self {
+        Self {
+            state: emul.get_state(),
+            stack: emul.get_stack(),
+            memory: emul.get_mem(),
+            call_index: emul.get_call_index(),
+            prev_index: emul.get_prev_index(),
+            last_call_cycles: emul.get_last_call_cycles(),
+        }
+    }
+
+    fn diff(&self, oth: &Snapshot) -> Option<(String, String, String)> {
+        let (l, r) = (&self.state, &oth.state);
+        let fields = [
+            ("PC", format!("{}", l.program_counter as i32), format!("{}", r.program_counter as i32)),
+            ("SP", l.stack_pointer.to_string(), r.stack_pointer.to_string()),
+            ("MP", l.multiplexor_value.to_string(), r.multiplexor_value.to_string()),
+            ("F", l.func_output.to_string(), r.func_output.to_string()),
+            ("Y", l.func_value.to_string(), r.func_value.to_string()),
+            ("call index", format!("{}", self.call_index as i32), format!("{}", oth.call_index as i32)),
+            ("previous command", format!("{}", self.prev_index as i32), format!("{}", oth.prev_index as i32)),
+            ("call cycles", self.last_call_cycles.to_string(), oth.last_call_cycles.to_string()),
+        ];
+        for (name, left, right) in fields {
+            if left != right {
+                return Some((name.to_owned(), left, right));
+            }
+        }
+        for (i, name) in FLAG_NAMES.iter().enumerate() {
+            if l.flags[i] != r.flags[i] {
+                return Some((name.to_string(), l.flags[i].to_string(), r.flags[i].to_string()));
+            }
+        }
+        let regs = Self::first_mismatch(&l.registers, &r.registers, |i| match i {
+            16 => "Q".to_owned(),
+            i => format!("R{}", i),
+        });
+        regs.or_else(|| Self::first_mismatch(&self.stack, &oth.stack, |i| format!("stack[{}]", i)))
+            .or_else(|| Self::first_mismatch(&self.memory, &oth.memory, |i| format!("mem[0x{:02X}]", i)))
+    }
+
+    fn first_mismatch<T: PartialEq + ToString>(
+        left: &[T],
+        right: &[T],
+        name: impl Fn(usize) -> String,
+    ) -> Option<(String, String, String)> {
+        if left.len() != right.len() {
+            return Some((
+                format!("{} length", name(0).split('[').next().unwrap_or_default()),
+                left.len().to_string(),
+                right.len().to_string(),
+            ));
+        }
+        left.iter()
+            .zip(right.iter())
+            .position(|(l, r)| l != r)
+            .map(|i| (name(i), left[i].to_string(), right[i].to_string()))
+    }
+}
+
+pub fn compare_program(
+    program: &[u8],
+    left: &mut dyn MT1804Emulator,
+    right: &mut dyn MT1804Emulator,
+    max_steps: usize,
+) -> Result<usize, Divergence> {
+    let loaded = (left.open_raw(program), right.open_raw(program));
+    if loaded.0.is_err() || loaded.1.is_err() {
+        let describe = |res: Result<(), super::EmulatorError>| match res {
+            Ok(()) => "loaded".to_owned(),
+            Err(err) => err.to_string(),
+        };
+        return Err(Divergence {
+            step: 0,
+            address: -1i32 as usize,
+            command: "<program>".to_owned(),
+            field: "loading".to_owned(),
+            left: describe(loaded.0),
+            right: describe(loaded.1),
+        });
+    }
+    left.reset();
+    right.reset();
+    if left.export_raw() != right.export_raw() {
+        return Err(Divergence {
+            step: 0,
+            address: -1i32 as usize,
+            command: "<program>".to_owned(),
+            field: "loaded program".to_owned(),
+            left: format!("{} bytes", left.export_raw().len()),
+            right: format!("{} bytes", right.export_raw().len()),
+        });
+    }
+    compare(left, right, max_steps)
+}
+
+/// Steps both emulators until they diverge, returning the number of steps
+/// done. Stops early once neither of them changes anymore.
+pub fn compare(
+    left: &mut dyn MT1804Emulator,
+    right: &mut dyn MT1804Emulator,
+    max_steps: usize,
+) -> Result<usize, Divergence> {
+    let mut prev = Snapshot::take(left);
+    if let Some((field, l, r)) = prev.diff(&Snapshot::take(right)) {
+        return Err(Divergence {
+            step: 0,
+            address: left.get_pc(),
+            command: "<reset>".to_owned(),
+            field,
+            left: l,
+            right: r,
+        });
+    }
+    for step in 1..=max_steps {
+        let address = left.get_pc();
+        let l_result = left.exec_one().map_err(|err| err.to_string());
+        let r_result = right.exec_one().map_err(|err| err.to_string());
+        let (l_snap, r_snap) = (Snapshot::take(left), Snapshot::take(right));
+        let mismatch = if l_result != r_result {
+            Some(("result".to_owned(), format!("{:?}", l_result), format!("{:?}", r_result)))
+        } else {
+            l_snap.diff(&r_snap)
+        };
+        if let Some((field, l, r)) = mismatch {
+            return Err(Divergence {
+                step,
+                address,
+                command: left
+                    .executed_command()
+                    .and_then(|cmd| left.command_get_name(cmd))
+                    .unwrap_or_else(|err| err.to_string()),
+                field,
+                left: l,
+                right: r,
+            });
+        }
+        if l_snap == prev {
+            return Ok(step);
+        }
+        prev = l_snap;
+    }
+    Ok(max_steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Command, ExecResult, NativeImplementation, OriginalImplementation};
+
+    // Where every sample ends up on the native engine: the number of
+    // exec_one calls up to End, PC, R0-R15 and Q, and the memory cells that
+    // aren't zero. They are pinned so that a change in the engine shows up
+    // in a plain `cargo test`, but nothing here checks them against the
+    // managed engine. That is corpus_matches_original below, and CI never
+    // runs it. library.mte never ends, it is taken after MAX_STEPS.
+    type EndState = (usize, usize, [u8; 17], &'static [(usize, i32)]);
+    const END_STATES: [EndState; 4] = [
+        (37, 30, [0, 14, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[]),
+        (260, 257, [11, 8, 6, 4, 6, 10, 0, 4, 10, 2, 0, 4, 6, 0, 2, 6, 0], &[]),
+        (
+            47,
+            44,
+            [10, 5, 3, 12, 0, 0, 0, 0, 0, 0, 12, 10, 0, 0, 0, 0, 0],
+            &[(0, 202), (18, 53), (53, 172)],
+        ),
+        (
+            MAX_STEPS,
+            0xF0E,
+            [2, 1, 4, 3, 14, 13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 13],
+            &[(0, 18), (1, 52), (2, 222)],
+        ),
+    ];
+
+    fn run(emul: &mut dyn MT1804Emulator) -> usize {
+        for step in 1..=MAX_STEPS {
+            if emul.exec_one().unwrap() != ExecResult::Ok {
+                return step;
+            }
+        }
+        MAX_STEPS
+    }
+
+    #[test]
+    fn corpus_ends_in_known_states() {
+        let samples = SAMPLES.into_iter().zip(END_STATES);
+        for ((name, program), (steps, pc, registers, memory)) in samples {
+            let mut emul = NativeImplementation::new();
+            emul.open_raw(program).unwrap();
+            emul.reset();
+            assert_eq!(run(&mut emul), steps, "{}: steps", name);
+            assert_eq!(emul.get_pc(), pc, "{}: PC", name);
+            assert_eq!(emul.get_state().registers, registers, "{}: registers", name);
+            let cells = emul
+                .get_mem()
+                .into_iter()
+                .enumerate()
+                .filter(|(_, value)| *value != 0)
+                .collect::<Vec<_>>();
+            assert_eq!(cells, memory, "{}: memory", name);
+        }
+    }
+
+    #[test]
+    fn reports_first_diverging_step() {
+        let mut left = NativeImplementation::new();
+        let mut right = NativeImplementation::new();
+        left.open_raw(SAMPLES[1].1).unwrap();
+        right.open_raw(SAMPLES[1].1).unwrap();
+        // R3 = 1 instead of R3 = 8, the first step only positions PC
+        let words = [0, 0, 0, 2, 3, 7, 0, 0, 3, 1];
+        right.update_command(3, &Command::new(3, &words)).unwrap();
+        let div = compare(&mut left, &mut right, MAX_STEPS).unwrap_err();
+        assert_eq!(div.step, 5);
+        assert_eq!(div.address, 3);
+        assert_eq!(div.field, "F");
+        let cmd = left.get_command(3).unwrap();
+        assert_eq!(div.command, left.command_get_name(cmd).unwrap());
+    }
+
+    // The only check against the managed engine. It needs the runtime, so it
+    // is run by hand from a meson build tree, on its own:
+    //
+    //     cargo test corpus_matches_original -- --ignored --test-threads=1
+    //
+    // Only one instance may be created in the process, destroying it tears
+    // down the runtime and it can't be brought up again.
+    #[test]
+    #[ignore]
+    fn corpus_matches_original() {
+        let mut original = OriginalImplementation::new();
+        for (name, program) in SAMPLES {
+            let mut native = NativeImplementation::new();
+            if let Err(div) = compare_program(program, &mut original, &mut native, MAX_STEPS) {
+                panic!("{}: {}", name, div);
+            }
+        }
+    }
+}
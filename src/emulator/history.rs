@@ -0,0 +1,215 @@
+/* emulator/history.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Execution history for stepping backwards. An entry is the execution
+// state the engine hands out from before a step: registers, flags, PC, SP,
+// MP, stack, memory and the call index. The program is not in it, which is
+// why the history has to be dropped whenever the program is edited: those
+// go to the undo stack instead. The ports are not in it either, but a script
+// plugged into them is rewound along, so a replayed read takes the same
+// value again.
+
+use std::collections::{HashSet, VecDeque};
+
+use super::port_script::{ScriptPosition, ScriptedPorts};
+use super::{EmulatorError, MT1804Emulator};
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pc: usize,
+    state: Vec<i32>,
+    script: Option<(ScriptedPorts, ScriptPosition)>,
+}
+
+impl Entry {
+    pub fn take(emul: &dyn MT1804Emulator, script: Option<&ScriptedPorts>) -> Self {
+        Self {
+            pc: emul.get_pc(),
+            state: emul.get_exec_state(),
+            script: script.map(|script| (script.clone(), script.position())),
+        }
+    }
+
+    // where the step went from, usize::MAX before the first one
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn restore(&self, emul: &mut dyn MT1804Emulator) -> Result<(), EmulatorError> {
+        emul.set_exec_state(&self.state)?;
+        if let Some((script, position)) = &self.script {
+            script.seek(position);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    steps: VecDeque<Entry>,
+    limit: usize,
+    // the script plugged into the ports, if there is one
+    script: Option<ScriptedPorts>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            steps: VecDeque::new(),
+            limit,
+            script: None,
+        }
+    }
+
+    pub fn set_script(&mut self, script: Option<ScriptedPorts>) {
+        self.script = script;
+    }
+
+    // call right before the step
+    pub fn record(&mut self, emul: &dyn MT1804Emulator) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.steps.len() >= self.limit {
+            self.steps.pop_front();
+        }
+        self.steps
+            .push_back(Entry::take(emul, self.script.as_ref()));
+    }
+
+    pub fn back(&mut self) -> Option<Entry> {
+        self.steps.pop_back()
+    }
+
+    // Goes back at least one step and stops before the first command it
+    // finds on a breakpoint, the oldest state there is when none is found
+    pub fn back_to(&mut self, breakpoints: &HashSet<usize>) -> Option<Entry> {
+        let mut entry = self.steps.pop_back()?;
+        while !breakpoints.contains(&entry.pc()) {
+            let Some(prev) = self.steps.pop_back() else {
+                break;
+            };
+            entry = prev;
+        }
+        Some(entry)
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::port::{Access, PortDevice, Width};
+    use crate::emulator::port_script::Stimulus;
+    use crate::emulator::NativeImplementation;
+
+    fn sample(index: usize) -> NativeImplementation {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[index].1).unwrap();
+        emul.reset();
+        emul
+    }
+
+    // memory.mte stores to memory and alu.mte goes through the registers
+    #[test]
+    fn stepping_back_restores_the_state() {
+        for index in [1, 2] {
+            let mut emul = sample(index);
+            let mut history = History::new(usize::MAX);
+            let mut taken = Vec::new();
+            for _ in 0..40 {
+                taken.push((emul.get_state(), emul.get_stack(), emul.get_mem()));
+                history.record(&emul);
+                emul.exec_one().unwrap();
+            }
+            while let Some(entry) = history.back() {
+                entry.restore(&mut emul).unwrap();
+                let now = (emul.get_state(), emul.get_stack(), emul.get_mem());
+                assert_eq!(now, taken.pop().unwrap());
+                assert_eq!(emul.get_pc(), entry.pc());
+            }
+        }
+    }
+
+    #[test]
+    fn replays_the_same_after_going_back() {
+        let mut emul = sample(2);
+        let mut history = History::new(usize::MAX);
+        for _ in 0..10 {
+            emul.exec_one().unwrap();
+        }
+        let mut reference = emul.clone();
+        history.record(&emul);
+        for _ in 0..20 {
+            emul.exec_one().unwrap();
+        }
+        history.back().unwrap().restore(&mut emul).unwrap();
+        for _ in 0..30 {
+            assert_eq!(emul.exec_one().unwrap(), reference.exec_one().unwrap());
+            assert_eq!(emul.get_exec_state(), reference.get_exec_state());
+        }
+    }
+
+    #[test]
+    fn rewinds_the_script() {
+        let mut emul = sample(1);
+        let script = ScriptedPorts::new(Stimulus::parse("PORT0 0  01 02").unwrap());
+        let mut device = script.clone();
+        let access = Access {
+            port: 0,
+            addr: 0,
+            width: Width::Full8,
+        };
+        let mut history = History::new(usize::MAX);
+        history.set_script(Some(script.clone()));
+        history.record(&emul);
+        assert_eq!(device.read(access), Ok(0x01));
+        device.write(access, 0x01).unwrap();
+        history.back().unwrap().restore(&mut emul).unwrap();
+        // the replayed read takes the same value and the write is gone
+        assert!(script.capture().is_empty());
+        assert_eq!(device.read(access), Ok(0x01));
+    }
+
+    #[test]
+    fn rejects_a_foreign_state() {
+        let mut emul = sample(1);
+        let mut state = emul.get_exec_state();
+        state.pop();
+        assert!(emul.set_exec_state(&state).is_err());
+    }
+
+    #[test]
+    fn keeps_only_the_last_steps() {
+        let mut emul = sample(1);
+        let mut history = History::new(3);
+        for _ in 0..10 {
+            history.record(&emul);
+            emul.exec_one().unwrap();
+        }
+        assert_eq!(history.steps.len(), 3);
+        history.clear();
+        assert!(history.back().is_none());
+        assert!(History::new(0).back_to(&HashSet::new()).is_none());
+    }
+}
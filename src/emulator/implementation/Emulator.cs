@@ -2,17 +2,17 @@
 using System.Collections.Generic;
 using System.IO;
 using System.Linq;
+using System.Runtime.CompilerServices;
 using System.Text;
 
 namespace mtemu
 {
     partial class Emulator
     {
-        private PortExtender portExtender_;
-
         private int prevPc_;
         private int pc_ = 0;        // Pointer command
         private int callIndex_ = 0;
+        private int lastCallCycles_ = 0;    // Commands the last ExecOneCall took
         private bool end_;
 
         private List<Command> commands_ = new List<Command>();
@@ -73,11 +73,72 @@ namespace mtemu
             return clone;
         }
 
+        // Everything a step reads or writes, for stepping back: the scalars
+        // in field order, then the registers, the stack and memory
+        private const int execScalars_ = 29;
+
+        public int[] GetExecState()
+        {
+            var scalars = new int[] {
+                prevPc_, pc_, callIndex_, lastCallCycles_, end_ ? 1 : 0,
+                sp_, regQ_, (int)inc_, mp_, devPtr_,
+                prevRegA_, prevRegB_, prevRegQ_, r_, s_, f_, y_,
+                z_ ? 1 : 0, f3_ ? 1 : 0, c4_ ? 1 : 0, ovr_ ? 1 : 0, g_ ? 1 : 0, p_ ? 1 : 0,
+                prevZ_ ? 1 : 0, prevF3_ ? 1 : 0, prevC4_ ? 1 : 0,
+                prevOvr_ ? 1 : 0, prevG_ ? 1 : 0, prevP_ ? 1 : 0,
+            };
+            return scalars.Concat(regCommon_).Concat(stack_).Concat(memory_).ToArray();
+        }
+
+        public bool SetExecState(int[] state)
+        {
+            if (state.Length != execScalars_ + regCommon_.Length + stack_.Length + memory_.Length) {
+                return false;
+            }
+            prevPc_ = state[0];
+            pc_ = state[1];
+            callIndex_ = state[2];
+            lastCallCycles_ = state[3];
+            end_ = state[4] != 0;
+            sp_ = state[5];
+            regQ_ = state[6];
+            inc_ = (MemNextPoint)state[7];
+            mp_ = state[8];
+            devPtr_ = state[9];
+            prevRegA_ = state[10];
+            prevRegB_ = state[11];
+            prevRegQ_ = state[12];
+            r_ = state[13];
+            s_ = state[14];
+            f_ = state[15];
+            y_ = state[16];
+            z_ = state[17] != 0;
+            f3_ = state[18] != 0;
+            c4_ = state[19] != 0;
+            ovr_ = state[20] != 0;
+            g_ = state[21] != 0;
+            p_ = state[22] != 0;
+            prevZ_ = state[23] != 0;
+            prevF3_ = state[24] != 0;
+            prevC4_ = state[25] != 0;
+            prevOvr_ = state[26] != 0;
+            prevG_ = state[27] != 0;
+            prevP_ = state[28] != 0;
+            int at = execScalars_;
+            Array.Copy(state, at, regCommon_, 0, regCommon_.Length);
+            at += regCommon_.Length;
+            Array.Copy(state, at, stack_, 0, stack_.Length);
+            at += stack_.Length;
+            Array.Copy(state, at, memory_, 0, memory_.Length);
+            return true;
+        }
+
         public void Reset()
         {
             prevPc_ = -1;
             pc_ = -1;
             callIndex_ = 0;
+            lastCallCycles_ = 0;
             end_ = false;
 
             sp_ = 0;
@@ -117,9 +178,8 @@ namespace mtemu
             InitCalls();
             Reset();
         }
-        public Emulator(PortExtender portExtender)
+        public Emulator()
         {
-            portExtender_ = portExtender;
             Reset();
             Console.WriteLine("Inited emulator");
         }
@@ -802,6 +862,22 @@ namespace mtemu
             }
         }
 
+        // The devices on the ports live in the application, the shim registers
+        // these when it starts the runtime. They return null or what went wrong
+        [MethodImpl(MethodImplOptions.InternalCall)]
+        private static extern string WriteDevicePort(int port, int addr, int width, int value);
+
+        [MethodImpl(MethodImplOptions.InternalCall)]
+        private static extern string ReadDevicePort(int port, int addr, int width, out int value);
+
+        private static void CheckDevice(string error)
+        {
+            if (error != null)
+            {
+                throw new InvalidOperationException(error);
+            }
+        }
+
         // 4 бита адреса устройства, подключенного к порту
         private int GetDeviceAddress()
         {
@@ -905,16 +981,11 @@ namespace mtemu
                                     break;
                             }
 
-                            portExtender_.WritePort(Addr, Port, pointerType, tmp_w);
+                            CheckDevice(WriteDevicePort((int)Port >> 2, Addr, (int)Port & 3, tmp_w));
                         }
                         else
                         {
-                            System.Windows.Forms.MessageBox.Show(
-                                "Вы - болван, у Вас проблема с кодом (генетическим), проверьтесь у врача!\nТакие упорствующие индивиды не должны размножаться!",
-                                "Невозможно записать в это устройство",
-                                System.Windows.Forms.MessageBoxButtons.OK,
-                                System.Windows.Forms.MessageBoxIcon.Error
-                            );
+                            throw new InvalidOperationException("Cannot write to this device, no port is selected");
                         }
                         break;
                     }
@@ -924,8 +995,9 @@ namespace mtemu
                         int Addr = GetDeviceAddress();
                         if (Port != PortExtender.Port.PORT_UNKNOWN)
                         {
-                            byte tmp_r;
-                            tmp_r = portExtender_.ReadPort(Addr, Port, pointerType);
+                            int value;
+                            CheckDevice(ReadDevicePort((int)Port >> 2, Addr, (int)Port & 3, out value));
+                            byte tmp_r = (byte)value;
                             switch (pointerType)
                             {
                                 case DataPointerType.LOW_4_BIT:
@@ -942,12 +1014,7 @@ namespace mtemu
                         }
                         else
                         {
-                            System.Windows.Forms.MessageBox.Show(
-                                "Вы - болван, у Вас проблема с кодом (генетическим), проверьтесь у врача!\nТакие упорствующие индивиды не должны размножаться!",
-                                "Невозможно прочитать из этого устройства",
-                                System.Windows.Forms.MessageBoxButtons.OK,
-                                System.Windows.Forms.MessageBoxIcon.Error
-                            );
+                            throw new InvalidOperationException("Cannot read from this device, no port is selected");
                         }
                         break;
                     }
@@ -1025,6 +1092,7 @@ namespace mtemu
 
         public ResultCode ExecOneCall()
         {
+            lastCallCycles_ = 0;
             if (callIndex_ >= calls_.Count)
                 return ResultCode.End;
             int oldIndex = callIndex_;
@@ -1035,6 +1103,7 @@ namespace mtemu
             for (int i = 0; i < maxAutoCount_; ++i)
             {
                 ResultCode rc = ExecOne();
+                lastCallCycles_ = i + 1;
                 if (rc != ResultCode.Ok)
                 {
                     return rc;
@@ -1088,6 +1157,11 @@ namespace mtemu
             return callIndex_;
         }
 
+        public int GetLastCallCycles()
+        {
+            return lastCallCycles_;
+        }
+
         public void SetCallIndex(int value)
         {
             callIndex_ = value;
@@ -1098,6 +1172,61 @@ namespace mtemu
             return pc_ = value;
         }
 
+        // Setters for editing the state by hand, values are cut to the width
+        // of what they go into and a bad index throws like the getters do
+        public void SetRegValue(int index, int value)
+        {
+            regCommon_[index] = value & 0xF;
+        }
+
+        public void SetRegQ(int value)
+        {
+            regQ_ = value & 0xF;
+        }
+
+        // flags go in the same order as the state shows them
+        public void SetFlag(int index, bool value)
+        {
+            switch (index)
+            {
+                case 0:
+                    ovr_ = value;
+                    break;
+                case 1:
+                    c4_ = value;
+                    break;
+                case 2:
+                    f3_ = value;
+                    break;
+                case 3:
+                    z_ = value;
+                    break;
+                case 4:
+                    g_ = value;
+                    break;
+                case 5:
+                    p_ = value;
+                    break;
+                default:
+                    throw new ArgumentOutOfRangeException("index");
+            }
+        }
+
+        public void SetMP(int value)
+        {
+            mp_ = value % memSize_;
+        }
+
+        public void SetMemValue(int index, int value)
+        {
+            memory_[index] = value & 0xFF;
+        }
+
+        public void SetStackValue(int index, int value)
+        {
+            stack_[index] = value % programSize_;
+        }
+
         public int GetPC()
         {
             return pc_;
@@ -1125,6 +1254,12 @@ namespace mtemu
             return Command.GetPortName(devPtr_);
         }
 
+        // GetPort names the port, the shim needs the device pointer itself
+        public int GetPortNumber()
+        {
+            return devPtr_;
+        }
+
         public int GetMemValue(int index)
         {
             return memory_[index];
@@ -31,10 +31,70 @@
 #include <mono/metadata/image.h>
 #include <mono/metadata/object.h>
 #include <mono/metadata/mono-config.h>
+#include <mono/metadata/threads.h>
 #include <stdbool.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
+
+// Every call into the engine goes through here. A managed exception must not
+// unwind through native frames, so it is caught and kept as "Type: Message"
+// until the caller picks it up with emulator_take_error. Only the first one
+// is kept, the rest are usually fallout from it.
+static void set_error(Emulator *inst, const char *type, const char *message) {
+  if (inst->error) {
+    return;
+  }
+  size_t len = strlen(type) + strlen(message) + 3;
+  inst->error = malloc(len);
+  snprintf(inst->error, len, "%s: %s", type, message);
+}
+
+static void set_exception(Emulator *inst, MonoObject *exception) {
+  MonoClass *ExceptionClass = mono_get_exception_class();
+  MonoProperty *message_prop =
+      mono_class_get_property_from_name(ExceptionClass, "Message");
+  MonoObject *inner = NULL;
+  MonoString *message_obj =
+      (MonoString *)mono_property_get_value(message_prop, exception, NULL, &inner);
+  char *message = message_obj && !inner ? mono_string_to_utf8(message_obj) : NULL;
+  set_error(inst, mono_class_get_name(mono_object_get_class(exception)),
+            message ? message : "unknown error");
+  if (message) {
+    mono_free(message);
+  }
+}
+
+static MonoObject *invoke(Emulator *inst, MonoMethod *method, void *obj, void **args) {
+  if (!method) {
+    set_error(inst, "MissingMethodException", "method is not present in the engine");
+    return NULL;
+  }
+  MonoObject *exception = NULL;
+  MonoObject *res = mono_runtime_invoke(method, obj, args, &exception);
+  if (exception) {
+    set_exception(inst, exception);
+    return NULL;
+  }
+  return res;
+}
+
+static int32_t invoke_int32(Emulator *inst, MonoMethod *method, void **args) {
+  MonoObject *res = invoke(inst, method, inst->emul, args);
+  return res ? *(int32_t *)mono_object_unbox(res) : 0;
+}
+
+static bool invoke_bool(Emulator *inst, MonoMethod *method, void **args) {
+  MonoObject *res = invoke(inst, method, inst->emul, args);
+  return res ? *(bool *)mono_object_unbox(res) : false;
+}
+
+char *emulator_take_error(Emulator *inst) {
+  char *error = inst->error;
+  inst->error = NULL;
+  return error;
+}
 
 void init_emul_methods(Emulator* in, MonoClass* emulator_class) {
   MonoMethodDesc* desc = NULL;
@@ -94,6 +154,10 @@ void init_emul_methods(Emulator* in, MonoClass* emulator_class) {
   in->methods.GetCallIndex = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
 
+  desc = mono_method_desc_new("mtemu.Emulator:GetLastCallCycles()", 1);
+  in->methods.GetLastCallCycles = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
   desc = mono_method_desc_new("mtemu.Emulator:GetPC()", 1);
   in->methods.GetPC = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
@@ -122,7 +186,7 @@ void init_emul_methods(Emulator* in, MonoClass* emulator_class) {
   in->methods.GetMP = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
 
-  desc = mono_method_desc_new("mtemu.Emulator:GetPort()", 1);
+  desc = mono_method_desc_new("mtemu.Emulator:GetPortNumber()", 1);
   in->methods.GetPort = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
 
@@ -198,6 +262,30 @@ void init_emul_methods(Emulator* in, MonoClass* emulator_class) {
   in->methods.GetP = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
 
+  desc = mono_method_desc_new("mtemu.Emulator:SetRegValue(int,int)", 1);
+  in->methods.SetRegValue = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetRegQ(int)", 1);
+  in->methods.SetRegQ = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetFlag(int,bool)", 1);
+  in->methods.SetFlag = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetMP(int)", 1);
+  in->methods.SetMP = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetMemValue(int,int)", 1);
+  in->methods.SetMemValue = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetStackValue(int,int)", 1);
+  in->methods.SetStackValue = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
   desc = mono_method_desc_new("mtemu.Emulator:AddCall", 1);
   in->methods.AddCall = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
@@ -230,6 +318,14 @@ void init_emul_methods(Emulator* in, MonoClass* emulator_class) {
   in->methods.ExportRaw = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
 
+  desc = mono_method_desc_new("mtemu.Emulator:GetExecState", 1);
+  in->methods.GetExecState = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
+  desc = mono_method_desc_new("mtemu.Emulator:SetExecState(int[])", 1);
+  in->methods.SetExecState = mono_method_desc_search_in_class(desc, emulator_class);
+  mono_method_desc_free(desc);
+
   desc = mono_method_desc_new("mtemu.Emulator:AddMapCall", 1);
   in->methods.AddMapCall = mono_method_desc_search_in_class(desc, emulator_class);
   mono_method_desc_free(desc);
@@ -275,6 +371,40 @@ void init_command_methods(Emulator* in, MonoClass* command_class) {
   mono_method_desc_free(desc);
 }
 
+// STORE_DEVICE and LOAD_DEVICE end up here through the engine's internal
+// calls. Without a handler nothing is attached: writes are dropped and
+// reads are 0.
+static PortHandler port_handler = NULL;
+
+void emulator_set_port_handler(PortHandler handler) {
+  port_handler = handler;
+}
+
+static MonoString *device_port(int32_t port, int32_t addr, int32_t width,
+                               bool write, int32_t *value) {
+  char error[256] = "";
+  if (!port_handler) {
+    if (!write) {
+      *value = 0;
+    }
+    return NULL;
+  }
+  if (port_handler(port, addr, width, write, value, error, sizeof(error))) {
+    return NULL;
+  }
+  return mono_string_new(mono_domain_get(), error);
+}
+
+static MonoString *write_device_port(int32_t port, int32_t addr, int32_t width,
+                                     int32_t value) {
+  return device_port(port, addr, width, true, &value);
+}
+
+static MonoString *read_device_port(int32_t port, int32_t addr, int32_t width,
+                                    int32_t *value) {
+  return device_port(port, addr, width, false, value);
+}
+
 Emulator* create_emulator() {
 #if defined (__WIN32__)
   putenv("MONO_PATH=C:\\Program Files\\Mono\\lib\\mono\\4.5\\");
@@ -282,25 +412,19 @@ Emulator* create_emulator() {
   Emulator* instance = malloc(sizeof(Emulator));
   mono_config_parse (NULL);
   instance->dom = mono_jit_init("mtemu");
+  mono_add_internal_call("mtemu.Emulator::WriteDevicePort", write_device_port);
+  mono_add_internal_call("mtemu.Emulator::ReadDevicePort", read_device_port);
   MonoAssembly *mtemu_emu = mono_domain_assembly_open(
       instance->dom, PKGDATADIR "/engine.dll");
   instance->im = mono_assembly_get_image(mtemu_emu);
-  MonoClass *PortExtender = mono_class_from_name(instance->im, "mtemu",
-  "PortExtender");
-  MonoObject *port_extender = mono_object_new(instance->dom, PortExtender);
-  MonoMethodDesc* PortExtenderCtorDesc =
-    mono_method_desc_new("mtemu.PortExtender:.ctor()", 1);
-  instance->methods.PortExtenderCtor =
-  mono_method_desc_search_in_class(PortExtenderCtorDesc, PortExtender);
-  mono_runtime_invoke(instance->methods.PortExtenderCtor, port_extender, NULL, NULL);
+  instance->error = NULL;
   MonoClass *Emulator = mono_class_from_name(instance->im, "mtemu", "Emulator");
   instance->emul = mono_object_new(instance->dom, Emulator);
   MonoMethodDesc* EmulatorCtorDesc =
-    mono_method_desc_new("mtemu.Emulator:.ctor(mtemu.PortExtender)", 1);
+    mono_method_desc_new("mtemu.Emulator:.ctor()", 1);
   instance->methods.EmulatorCtor =
       mono_method_desc_search_in_class(EmulatorCtorDesc, Emulator);
-  void* args[1] = { &port_extender };
-  mono_runtime_invoke(instance->methods.EmulatorCtor, instance->emul, args, NULL);
+  invoke(instance, instance->methods.EmulatorCtor, instance->emul, NULL);
   mono_method_desc_free(EmulatorCtorDesc);
   init_emul_methods(instance, Emulator);
 
@@ -310,12 +434,17 @@ Emulator* create_emulator() {
   return instance;
 }
 
-Emulator* clone_emulator(const Emulator *inst) {
+Emulator* clone_emulator(Emulator *inst) {
+  MonoObject *emul = invoke(inst, inst->methods.Clone, inst->emul, NULL);
+  if (!emul) {
+    return NULL;
+  }
   Emulator* clone = malloc(sizeof(Emulator));
   clone->dom = inst->dom;
   clone->methods = inst->methods;
   clone->im = inst->im;
-  clone->emul = mono_runtime_invoke(inst->methods.Clone, inst->emul, NULL, NULL);
+  clone->error = NULL;
+  clone->emul = emul;
   clone->is_clone = true;
   return clone;
 }
@@ -327,12 +456,12 @@ void emulator_swap(Emulator *lhs, Emulator *rhs) {
 }
 
 void destroy_emulator(Emulator* inst) {
+  free(inst->error);
   if (inst->is_clone) {
     free(inst);
     return;
   }
   mono_free_method(inst->methods.EmulatorCtor);
-  mono_free_method(inst->methods.PortExtenderCtor);
   mono_free_method(inst->methods.Reset);
   mono_free_method(inst->methods.GetCommand);
   mono_free_method(inst->methods.AddCommand);
@@ -347,6 +476,7 @@ void destroy_emulator(Emulator* inst) {
   mono_free_method(inst->methods.GetNextIndex);
   mono_free_method(inst->methods.GetPrevIndex);
   mono_free_method(inst->methods.GetCallIndex);
+  mono_free_method(inst->methods.GetLastCallCycles);
   mono_free_method(inst->methods.GetPC);
   mono_free_method(inst->methods.SetPC);
   mono_free_method(inst->methods.GetSP);
@@ -373,6 +503,12 @@ void destroy_emulator(Emulator* inst) {
   mono_free_method(inst->methods.GetOVR);
   mono_free_method(inst->methods.GetG);
   mono_free_method(inst->methods.GetP);
+  mono_free_method(inst->methods.SetRegValue);
+  mono_free_method(inst->methods.SetRegQ);
+  mono_free_method(inst->methods.SetFlag);
+  mono_free_method(inst->methods.SetMP);
+  mono_free_method(inst->methods.SetMemValue);
+  mono_free_method(inst->methods.SetStackValue);
   mono_free_method(inst->methods.AddCall);
   mono_free_method(inst->methods.GetCall);
   mono_free_method(inst->methods.UpdateCall);
@@ -381,6 +517,8 @@ void destroy_emulator(Emulator* inst) {
   mono_free_method(inst->methods.LastCall);
   mono_free_method(inst->methods.OpenRaw);
   mono_free_method(inst->methods.ExportRaw);
+  mono_free_method(inst->methods.GetExecState);
+  mono_free_method(inst->methods.SetExecState);
   mono_free_method(inst->methods.GetName);
   mono_free_method(inst->methods.GetJumpName);
   mono_free_method(inst->methods.AddMapCall);
@@ -395,23 +533,30 @@ void destroy_emulator(Emulator* inst) {
   free(inst);
 }
 
+MonoThread *emulator_attach_thread(Emulator *inst) {
+  return mono_thread_attach(inst->dom);
+}
+
+void emulator_detach_thread(MonoThread *thread) { mono_thread_detach(thread); }
+
 void emulator_reset(Emulator *inst) {
-  mono_runtime_invoke(inst->methods.Reset, inst->emul, NULL, NULL);
+  invoke(inst, inst->methods.Reset, inst->emul, NULL);
 }
 
 MonoObject *emulator_get_command_managed(Emulator *inst, int32_t index) {
   void *args[1];
   args[0] = &index;
-  MonoObject *exception;
-  MonoObject *res =
-      mono_runtime_invoke(inst->methods.GetCommand, inst->emul, args, &exception);
-  if (exception) {
-    return exception;
-  }
-  return res;
+  return invoke(inst, inst->methods.GetCommand, inst->emul, args);
 }
 
-Command command_unmanage(Emulator *inst, MonoObject *cmd_obj) {
+void command_unmanage(Emulator *inst, MonoObject *cmd_obj, Command *cmd) {
+  size_t capacity = cmd->words_len;
+  cmd->isOffset = 0;
+  cmd->number_ = 0;
+  cmd->words_len = 0;
+  if (!cmd_obj) {
+    return;
+  }
   MonoClass *CommandClass = mono_object_get_class(cmd_obj);
   MonoClassField *isOffset =
       mono_class_get_field_from_name(CommandClass, "isOffset");
@@ -419,21 +564,18 @@ Command command_unmanage(Emulator *inst, MonoObject *cmd_obj) {
       mono_class_get_field_from_name(CommandClass, "number_");
   MonoClassField *words_ =
       mono_class_get_field_from_name(CommandClass, "words_");
-  Command cmd;
-  mono_field_get_value(cmd_obj, isOffset, &cmd.isOffset);
-  mono_field_get_value(cmd_obj, number_, &cmd.number_);
+  mono_field_get_value(cmd_obj, isOffset, &cmd->isOffset);
+  mono_field_get_value(cmd_obj, number_, &cmd->number_);
   MonoArray *words_arr =
       (MonoArray *)mono_field_get_value_object(inst->dom, words_, cmd_obj);
-  cmd.words_len = mono_array_length(words_arr);
-  cmd.words = malloc(cmd.words_len * sizeof(int32_t));
-  for (size_t i = 0; i < cmd.words_len; ++i) {
-    cmd.words[i] = mono_array_get(words_arr, int, i);
+  cmd->words_len = mono_array_length(words_arr);
+  for (size_t i = 0; i < cmd->words_len && i < capacity; ++i) {
+    cmd->words[i] = mono_array_get(words_arr, int, i);
   }
-  return cmd;
 }
 
-Command emulator_get_command(Emulator *inst, int32_t index) {
-  return command_unmanage(inst, emulator_get_command_managed(inst, index));
+void emulator_get_command(Emulator *inst, int32_t index, Command *cmd) {
+  command_unmanage(inst, emulator_get_command_managed(inst, index), cmd);
 }
 
 MonoObject *command_manage(Emulator *inst, Command command) {
@@ -461,130 +603,116 @@ bool emulator_add_command(Emulator *inst, int32_t index, Command command) {
   void *args[2];
   args[0] = &index;
   args[1] = command_manage(inst, command);
-  bool res = mono_object_unbox(
-      mono_runtime_invoke(inst->methods.AddCommand, inst->emul, args, NULL));
-  return res;
+  return invoke_bool(inst, inst->methods.AddCommand, args);
 }
 
 bool emulator_update_command(Emulator *inst, int32_t index, Command command) {
   void *args[2];
   args[0] = &index;
   args[1] = command_manage(inst, command);
-  bool res = mono_object_unbox(
-      mono_runtime_invoke(inst->methods.UpdateCommand, inst->emul, args, NULL));
-  return res;
+  return invoke_bool(inst, inst->methods.UpdateCommand, args);
 }
 
-Command emulator_last_command(Emulator *inst) {
-  return command_unmanage(
-      inst, mono_runtime_invoke(inst->methods.LastCommand, inst->emul, NULL, NULL));
+void emulator_last_command(Emulator *inst, Command *cmd) {
+  command_unmanage(
+      inst, invoke(inst, inst->methods.LastCommand, inst->emul, NULL), cmd);
 }
 
-int *emulator_remove_command(Emulator *inst, int32_t index) {
+bool emulator_remove_command(Emulator *inst, int32_t index) {
   void *args[1];
   args[0] = &index;
-  MonoObject *exception;
-  mono_runtime_invoke(inst->methods.RemoveCommand, inst->emul, args, &exception);
-  return (int *)exception;
+  return invoke_bool(inst, inst->methods.RemoveCommand, args);
 }
 
 int32_t emulator_commands_count(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-    mono_runtime_invoke(inst->methods.CommandCount, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.CommandCount, NULL);
 }
 
-Command emulator_executed_command(Emulator *inst) {
-  return command_unmanage(
-      inst, mono_runtime_invoke(inst->methods.ExecutedCommand, inst->emul, NULL, NULL));
+void emulator_executed_command(Emulator *inst, Command *cmd) {
+  command_unmanage(
+      inst, invoke(inst, inst->methods.ExecutedCommand, inst->emul, NULL), cmd);
 }
 
 ResultCode emulator_exec_one(Emulator *inst) {
-  return *(int *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.ExecOne, inst->emul, NULL, NULL));
+  return (ResultCode)invoke_int32(inst, inst->methods.ExecOne, NULL);
 }
 
 ResultCode emulator_exec_one_call(Emulator *inst) {
-  return (ResultCode)*(int *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.ExecOneCall, inst->emul, NULL, NULL));
+  return (ResultCode)invoke_int32(inst, inst->methods.ExecOneCall, NULL);
 }
 
 ResultCode emulator_exec_all(Emulator *inst) {
-  return (ResultCode)*(int *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.ExecAll, inst->emul, NULL, NULL));
+  return (ResultCode)invoke_int32(inst, inst->methods.ExecAll, NULL);
 }
 
 int32_t emulator_get_next_index(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetNextIndex, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetNextIndex, NULL);
 }
 
 int32_t emulator_get_prev_index(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPrevIndex, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPrevIndex, NULL);
 }
 
 int32_t emulator_get_call_index(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetCallIndex, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetCallIndex, NULL);
+}
+
+int32_t emulator_get_last_call_cycles(Emulator *inst) {
+  return invoke_int32(inst, inst->methods.GetLastCallCycles, NULL);
 }
 
 int32_t emulator_get_pc(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPC, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPC, NULL);
 }
 
 int32_t emulator_set_pc(Emulator *inst, int32_t value) {
   void *args[1] = {&value};
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.SetPC, inst->emul, args, NULL));
+  return invoke_int32(inst, inst->methods.SetPC, args);
 }
 
 int32_t emulator_get_sp(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetSP, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetSP, NULL);
 }
 
 int32_t emulator_set_sp(Emulator *inst, int32_t value) {
   void *args[1] = {&value};
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.SetSP, inst->emul, args, NULL));
+  return invoke_int32(inst, inst->methods.SetSP, args);
 }
 
 int32_t emulator_get_stack_value(Emulator *inst, int32_t addr) {
   void* args[1] = {&addr};
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetStackValue, inst->emul, args, NULL));
+  return invoke_int32(inst, inst->methods.GetStackValue, args);
 }
 
 int32_t emulator_get_stack_length(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetStackLen, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetStackLen, NULL);
 }
 
 int32_t emulator_get_mp(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetMP, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetMP, NULL);
 }
 
 int32_t emulator_get_port(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPort, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPort, NULL);
 }
 
 int32_t emulator_get_mem_value(Emulator *inst, int32_t ind) {
-  void* args = { &ind };
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetMemValue, inst->emul, args, NULL));
+  void* args[1] = {&ind};
+  return invoke_int32(inst, inst->methods.GetMemValue, args);
 }
 
 int32_t emulator_get_mem_length(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetMemLength, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetMemLength, NULL);
 }
 
 void emulator_get_mem(Emulator *inst, int32_t **memory, size_t *mem_cnt) {
   MonoArray *managed_memory =
-      (MonoArray *)mono_runtime_invoke(inst->methods.GetMem, inst->emul, NULL, NULL);
+      (MonoArray *)invoke(inst, inst->methods.GetMem, inst->emul, NULL);
+  if (!managed_memory) {
+    *memory = NULL;
+    *mem_cnt = 0;
+    return;
+  }
   *mem_cnt = mono_array_length(managed_memory);
 
   int32_t *array_local = malloc(*mem_cnt * sizeof(int32_t));
@@ -595,79 +723,96 @@ void emulator_get_mem(Emulator *inst, int32_t **memory, size_t *mem_cnt) {
 }
 
 int32_t emulator_get_reg_q(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetRegQ, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetRegQ, NULL);
 }
 
 int32_t emulator_get_reg_value(Emulator *inst, int32_t index) {
   void *args[1] = {&index};
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetRegValue, inst->emul, args, NULL));
+  return invoke_int32(inst, inst->methods.GetRegValue, args);
 }
 
 int32_t emulator_get_f(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetF, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetF, NULL);
 }
 
 int32_t emulator_get_y(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetY, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetY, NULL);
 }
 
 int32_t emulator_get_prev_reg_q(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPrevRegQ, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPrevRegQ, NULL);
 }
 
 int32_t emulator_get_prev_reg_a(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPrevRegA, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPrevRegA, NULL);
 }
 
 int32_t emulator_get_prev_reg_b(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetPrevRegB, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetPrevRegB, NULL);
 }
 
 int32_t emulator_get_r(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetR, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetR, NULL);
 }
 
 int32_t emulator_get_s(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetS, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.GetS, NULL);
 }
 
+// the flag getters return bool on the managed side
 int32_t emulator_get_z(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetZ, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetZ, NULL);
 }
 
 int32_t emulator_get_f3(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetF3, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetF3, NULL);
 }
 
 int32_t emulator_get_c4(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetC4, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetC4, NULL);
 }
 
 int32_t emulator_get_ovr(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetOVR, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetOVR, NULL);
 }
 
 int32_t emulator_get_g(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetG, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetG, NULL);
 }
 
 int32_t emulator_get_p(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.GetP, inst->emul, NULL, NULL));
+  return invoke_bool(inst, inst->methods.GetP, NULL);
+}
+
+void emulator_set_reg_value(Emulator *inst, int32_t index, int32_t value) {
+  void *args[2] = {&index, &value};
+  invoke(inst, inst->methods.SetRegValue, inst->emul, args);
+}
+
+void emulator_set_reg_q(Emulator *inst, int32_t value) {
+  void *args[1] = {&value};
+  invoke(inst, inst->methods.SetRegQ, inst->emul, args);
+}
+
+void emulator_set_flag(Emulator *inst, int32_t index, bool value) {
+  // MonoBoolean is a single byte, same as bool
+  void *args[2] = {&index, &value};
+  invoke(inst, inst->methods.SetFlag, inst->emul, args);
+}
+
+void emulator_set_mp(Emulator *inst, int32_t value) {
+  void *args[1] = {&value};
+  invoke(inst, inst->methods.SetMP, inst->emul, args);
+}
+
+void emulator_set_mem_value(Emulator *inst, int32_t index, int32_t value) {
+  void *args[2] = {&index, &value};
+  invoke(inst, inst->methods.SetMemValue, inst->emul, args);
+}
+
+void emulator_set_stack_value(Emulator *inst, int32_t index, int32_t value) {
+  void *args[2] = {&index, &value};
+  invoke(inst, inst->methods.SetStackValue, inst->emul, args);
 }
 
 MonoObject *call_manage(Emulator *inst, Call call) {
@@ -686,6 +831,10 @@ MonoObject *call_manage(Emulator *inst, Call call) {
 }
 
 Call call_unmanage(Emulator * inst, MonoObject *cmd_obj) {
+  Call call = {0};
+  if (!cmd_obj) {
+    return call;
+  }
   MonoClass *CallClass = mono_object_get_class(cmd_obj);
   MonoClassField *code_ =
       mono_class_get_field_from_name(CallClass, "code_");
@@ -693,7 +842,6 @@ Call call_unmanage(Emulator * inst, MonoObject *cmd_obj) {
       mono_class_get_field_from_name(CallClass, "arg0_");
   MonoClassField *arg1_ =
       mono_class_get_field_from_name(CallClass, "arg1_");
-  Call call;
   mono_field_get_value(cmd_obj, code_, &call.code_);
   mono_field_get_value(cmd_obj, arg0_, &call.arg0_);
   mono_field_get_value(cmd_obj, arg1_, &call.arg1_);
@@ -702,51 +850,51 @@ Call call_unmanage(Emulator * inst, MonoObject *cmd_obj) {
 
 void emulator_add_call(Emulator *inst, int32_t index, Call call) {
   void *args[4] = {&index, &call.code_, &call.arg0_, &call.arg1_};
-  mono_object_unbox(mono_runtime_invoke(inst->methods.AddCall, inst->emul, args, NULL));
+  invoke_bool(inst, inst->methods.AddCall, args);
 }
 
 Call emulator_get_call(Emulator *inst, int32_t index) {
   void *args[1] = {&index};
-  return call_unmanage(inst, mono_runtime_invoke(inst->methods.GetCall, inst->emul, args, NULL));
+  return call_unmanage(inst, invoke(inst, inst->methods.GetCall, inst->emul, args));
 }
 
 void emulator_update_call(Emulator *inst, int32_t index, Call call) {
   void *args[4] = {&index, &call.code_, &call.arg0_, &call.arg1_};
-  mono_runtime_invoke(inst->methods.UpdateCall, inst->emul, args, NULL);
+  invoke(inst, inst->methods.UpdateCall, inst->emul, args);
 }
 
 void emulator_remove_call(Emulator *inst, int32_t index) {
   void *args[1] = {&index};
-  mono_runtime_invoke(inst->methods.RemoveCall, inst->emul, args, NULL);
+  invoke(inst, inst->methods.RemoveCall, inst->emul, args);
 }
 
 int32_t emulator_calls_count(Emulator *inst) {
-  return *(int32_t *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.CallsCount, inst->emul, NULL, NULL));
+  return invoke_int32(inst, inst->methods.CallsCount, NULL);
 }
 
 bool emulator_add_map_call(Emulator *inst, int32_t code, const char* name, int32_t addr) {
   MonoString* name_ = mono_string_new(inst->dom, name);
   void *args[3] = {&code, name_, &addr};
-  return *(bool*)mono_object_unbox(
-    mono_runtime_invoke(inst->methods.AddMapCall, inst->emul, args, NULL));
+  return invoke_bool(inst, inst->methods.AddMapCall, args);
 }
 
 bool emulator_remove_map_call(Emulator *inst, int32_t code) {
   void *args[1] = {&code};
-  return *(bool*)mono_object_unbox(
-    mono_runtime_invoke(inst->methods.RemoveMapCall, inst->emul, args, NULL));
+  return invoke_bool(inst, inst->methods.RemoveMapCall, args);
 }
 
 bool emulator_update_map_call(Emulator *inst, int32_t code, const char* name, int32_t addr) {
   MonoString* name_ = mono_string_new(inst->dom, name);
   void *args[3] = {&code, name_, &addr};
-  return *(bool*)mono_object_unbox(
-    mono_runtime_invoke(inst->methods.UpdateMapCall, inst->emul, args, NULL));
+  return invoke_bool(inst, inst->methods.UpdateMapCall, args);
 }
 
 int32_t* emulator_get_map_calls_codes(Emulator* inst, uint64_t* cnt) {
-  MonoArray* codes = (MonoArray*)mono_runtime_invoke(inst->methods.GetMapCallCodes, inst->emul, NULL, NULL);
+  MonoArray* codes = (MonoArray*)invoke(inst, inst->methods.GetMapCallCodes, inst->emul, NULL);
+  if (!codes) {
+    *cnt = 0;
+    return NULL;
+  }
   *cnt = mono_array_length(codes);
   int32_t* local_codes = malloc(*cnt * sizeof(int32_t));
   for (uint64_t i = 0; i < *cnt; ++i) {
@@ -757,19 +905,18 @@ int32_t* emulator_get_map_calls_codes(Emulator* inst, uint64_t* cnt) {
 
 char* emulator_get_map_call_name(Emulator* inst, int32_t code) {
   void* args[1] = {&code};
-  MonoObject* exception;
-  MonoString * result = (MonoString*)mono_runtime_invoke(inst->methods.GetMapCallName, inst->emul, args, &exception);
-  return mono_string_to_utf8(result);
+  MonoString * result = (MonoString*)invoke(inst, inst->methods.GetMapCallName, inst->emul, args);
+  return result ? mono_string_to_utf8(result) : NULL;
 }
 
 int32_t emulator_get_map_call_addr(Emulator* inst, int32_t code) {
   void* args[1] = {&code};
-  return *(int32_t*)mono_object_unbox(mono_runtime_invoke(inst->methods.GetMapCallAddr, inst->emul, args, NULL));
+  return invoke_int32(inst, inst->methods.GetMapCallAddr, args);
 }
 
 Call emulator_last_call(Emulator *inst) {
   return call_unmanage(
-      inst, mono_runtime_invoke(inst->methods.LastCall, inst->emul, NULL, NULL));
+      inst, invoke(inst, inst->methods.LastCall, inst->emul, NULL));
 }
 
 bool emulator_open_raw(Emulator *inst, uint8_t *bytes, size_t bytes_cnt) {
@@ -779,13 +926,17 @@ bool emulator_open_raw(Emulator *inst, uint8_t *bytes, size_t bytes_cnt) {
     mono_array_set(managed_bytes, uint8_t, i, bytes[i]);
   }
   void *args[1] = {managed_bytes};
-  return *(bool *)mono_object_unbox(
-      mono_runtime_invoke(inst->methods.OpenRaw, inst->emul, args, NULL));
+  return invoke_bool(inst, inst->methods.OpenRaw, args);
 }
 
 void emulator_export_raw(Emulator *inst, uint8_t **bytes, size_t *bytes_cnt) {
   MonoArray *managed_bytes =
-      (MonoArray *)mono_runtime_invoke(inst->methods.ExportRaw, inst->emul, NULL, NULL);
+      (MonoArray *)invoke(inst, inst->methods.ExportRaw, inst->emul, NULL);
+  if (!managed_bytes) {
+    *bytes = NULL;
+    *bytes_cnt = 0;
+    return;
+  }
   *bytes_cnt = mono_array_length(managed_bytes);
 
   uint8_t* bytes_local = malloc(*bytes_cnt * sizeof(uint8_t));
@@ -795,20 +946,49 @@ void emulator_export_raw(Emulator *inst, uint8_t **bytes, size_t *bytes_cnt) {
   *bytes = bytes_local;
 }
 
+void emulator_get_exec_state(Emulator *inst, int32_t **state, size_t *state_cnt) {
+  MonoArray *managed_state =
+      (MonoArray *)invoke(inst, inst->methods.GetExecState, inst->emul, NULL);
+  if (!managed_state) {
+    *state = NULL;
+    *state_cnt = 0;
+    return;
+  }
+  *state_cnt = mono_array_length(managed_state);
+
+  int32_t *state_local = malloc(*state_cnt * sizeof(int32_t));
+  for (size_t i = 0; i < *state_cnt; ++i) {
+    state_local[i] = mono_array_get(managed_state, int32_t, i);
+  }
+  *state = state_local;
+}
+
+bool emulator_set_exec_state(Emulator *inst, int32_t *state, size_t state_cnt) {
+  MonoArray *managed_state =
+      mono_array_new(inst->dom, mono_get_int32_class(), state_cnt);
+  for (size_t i = 0; i < state_cnt; ++i) {
+    mono_array_set(managed_state, int32_t, i, state[i]);
+  }
+  void *args[1] = {managed_state};
+  return invoke_bool(inst, inst->methods.SetExecState, args);
+}
+
 void emulator_init_library(Emulator *inst) {
-  mono_runtime_invoke(inst->methods.InitLibrary, inst->emul, NULL, NULL);
+  invoke(inst, inst->methods.InitLibrary, inst->emul, NULL);
 }
 
 char *command_get_name(Emulator *inst, Command cmd) {
   MonoObject* command_managed = command_manage(inst, cmd);
-  return mono_string_to_utf8(
-      (MonoString *)mono_runtime_invoke(inst->methods.GetName, command_managed, NULL, NULL));
+  MonoString *name =
+      (MonoString *)invoke(inst, inst->methods.GetName, command_managed, NULL);
+  return name ? mono_string_to_utf8(name) : NULL;
 }
 
 char *command_get_jump_name(Emulator *inst, Command cmd) {
   MonoObject *command_managed = command_manage(inst, cmd);
-  return mono_string_to_utf8((MonoString *)mono_runtime_invoke(
-      inst->methods.GetJumpName, command_managed, NULL, NULL));
+  MonoString *name =
+      (MonoString *)invoke(inst, inst->methods.GetJumpName, command_managed, NULL);
+  return name ? mono_string_to_utf8(name) : NULL;
 }
 
 void free_obj(void *obj) { mono_free(obj); }
@@ -27,6 +27,11 @@
 #include <stddef.h>
 #include <stdint.h>
 
+/* Command words are always owned by the caller. Functions taking a Command
+ * only read `words` for the duration of the call. Functions filling one in
+ * expect `words` to point at a buffer of `words_len` elements, copy as many
+ * words as fit and set `words_len` to the real length, so the caller can
+ * retry with a bigger buffer. The shim never allocates or frees them. */
 typedef struct {
   int32_t isOffset;
   int32_t number_;
@@ -42,12 +47,12 @@ typedef struct {
 
 typedef struct {
   bool is_clone;
+  char *error;
   MonoDomain *dom;
   MonoImage *im;
   MonoObject *emul;
   struct {
     MonoMethod* EmulatorCtor;
-    MonoMethod* PortExtenderCtor;
     MonoMethod* Reset;
     MonoMethod* GetCommand;
     MonoMethod* AddCommand;
@@ -62,6 +67,7 @@ typedef struct {
     MonoMethod* GetNextIndex;
     MonoMethod* GetPrevIndex;
     MonoMethod* GetCallIndex;
+    MonoMethod* GetLastCallCycles;
     MonoMethod* GetPC;
     MonoMethod* SetPC;
     MonoMethod* GetSP;
@@ -88,6 +94,12 @@ typedef struct {
     MonoMethod* GetOVR;
     MonoMethod* GetG;
     MonoMethod* GetP;
+    MonoMethod* SetRegValue;
+    MonoMethod* SetRegQ;
+    MonoMethod* SetFlag;
+    MonoMethod* SetMP;
+    MonoMethod* SetMemValue;
+    MonoMethod* SetStackValue;
     MonoMethod* AddCall;
     MonoMethod* GetCall;
     MonoMethod* UpdateCall;
@@ -102,6 +114,8 @@ typedef struct {
     MonoMethod* LastCall;
     MonoMethod* OpenRaw;
     MonoMethod* ExportRaw;
+    MonoMethod* GetExecState;
+    MonoMethod* SetExecState;
     MonoMethod* GetName;
     MonoMethod* GetJumpName;
     MonoMethod* InitLibrary;
@@ -117,23 +131,41 @@ typedef enum {
   END,
 } ResultCode;
 
+/* Answers STORE_DEVICE (write) and LOAD_DEVICE (read) for the engine. `width`
+ * is 1 for the low nibble, 2 for the high one and 3 for the whole byte, a
+ * read puts the byte into `value`. On failure it writes a message of at most
+ * `error_len` bytes including the terminator into `error` and returns false. */
+typedef bool (*PortHandler)(int32_t port, int32_t addr, int32_t width,
+                            bool write, int32_t *value, char *error,
+                            size_t error_len);
+
+/* Has to be set before the first emulator is created to be in effect for
+ * the whole run, it is shared by every emulator in the process. */
+void emulator_set_port_handler(PortHandler);
 Emulator *create_emulator();
-Emulator *clone_emulator(const Emulator *);
+/* NULL when the engine can't copy itself, the error is left on the
+ * original then. */
+Emulator *clone_emulator(Emulator *);
 void destroy_emulator(Emulator *);
+/* Threads other than the one that created the runtime have to be attached
+ * before they call into the emulator, and detached before they exit. */
+MonoThread *emulator_attach_thread(Emulator *);
+void emulator_detach_thread(MonoThread *);
 void emulator_reset(Emulator *);
-Command emulator_get_command(Emulator *, int32_t);
+void emulator_get_command(Emulator *, int32_t, Command *);
 bool emulator_add_command(Emulator *, int32_t, Command);
 bool emulator_update_command(Emulator *, int32_t, Command);
-Command emulator_last_command(Emulator *);
-int32_t *emulator_remove_command(Emulator *, int32_t);
+void emulator_last_command(Emulator *, Command *);
+bool emulator_remove_command(Emulator *, int32_t);
 int32_t emulator_commands_count(Emulator *);
-Command emulator_executed_command(Emulator *);
+void emulator_executed_command(Emulator *, Command *);
 ResultCode emulator_exec_one(Emulator *);
 ResultCode emulator_exec_one_call(Emulator *);
 ResultCode emulator_exec_all(Emulator *);
 int32_t emulator_get_next_index(Emulator *);
 int32_t emulator_get_prev_index(Emulator *);
 int32_t emulator_get_call_index(Emulator *);
+int32_t emulator_get_last_call_cycles(Emulator *);
 int32_t emulator_get_pc(Emulator *);
 int32_t emulator_set_pc(Emulator *, int32_t);
 int32_t emulator_get_sp(Emulator *);
@@ -160,6 +192,13 @@ int32_t emulator_get_c4(Emulator *);
 int32_t emulator_get_ovr(Emulator *);
 int32_t emulator_get_g(Emulator *);
 int32_t emulator_get_p(Emulator *);
+void emulator_set_reg_value(Emulator *, int32_t, int32_t);
+void emulator_set_reg_q(Emulator *, int32_t);
+/* flags are numbered OVR, C4, F3, Z, G, P */
+void emulator_set_flag(Emulator *, int32_t, bool);
+void emulator_set_mp(Emulator *, int32_t);
+void emulator_set_mem_value(Emulator *, int32_t, int32_t);
+void emulator_set_stack_value(Emulator *, int32_t, int32_t);
 void emulator_add_call(Emulator *, int32_t, Call);
 Call emulator_get_call(Emulator *, int32_t);
 void emulator_update_call(Emulator *, int32_t, Call);
@@ -174,7 +213,10 @@ int32_t emulator_get_map_call_addr(Emulator*, int32_t addr);
 Call emulator_last_call(Emulator *);
 void emulator_init_library(Emulator *);
 bool emulator_open_raw(Emulator *, uint8_t *, size_t);
+void emulator_get_exec_state(Emulator *, int32_t **, size_t *);
+bool emulator_set_exec_state(Emulator *, int32_t *, size_t);
 char *command_get_name(Emulator *, Command);
 void free_obj(void *);
+char *emulator_take_error(Emulator *);
 
 #endif // EMULATOR_SHIM_H_
@@ -0,0 +1,97 @@
+/* emulator/leaks.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Command buffers must not outlive the call that uses them. The shim's side
+// of that is checked by shim_experiments.c, which `meson test shim-leaks`
+// runs under valgrind. Here is the Rust side: words are copied out of the
+// buffers a fetch hands to the shim, and a batch of edits leaves nothing
+// behind in the engine.
+
+use super::native::NativeImplementation;
+use super::{Command, MT1804Emulator, RawCommand};
+
+const ROUNDS: usize = 1000;
+
+fn edit_round(emul: &mut dyn MT1804Emulator, round: usize) {
+    let words = [0, 0, 0, 2, 3, 7, 0, 0, (round & 0xF) as i32, 1];
+    emul.add_command(0, &Command::new(0, &words)).unwrap();
+    emul.update_command(0, &Command::new(0, &words)).unwrap();
+    let cmd = emul.get_command(0).unwrap();
+    assert_eq!(cmd.get_words().unwrap()[8], (round & 0xF) as u32);
+    emul.command_get_name(cmd).unwrap();
+    emul.remove_command(0).unwrap();
+}
+
+#[test]
+fn edits_leave_no_commands_behind() {
+    let mut emul = NativeImplementation::new();
+    for round in 0..ROUNDS {
+        edit_round(&mut emul, round);
+    }
+    assert_eq!(emul.commands_count(), 0);
+}
+
+fn fetch_round(emul: &dyn MT1804Emulator) {
+    drop(emul.executed_command().unwrap());
+    drop(emul.last_command().unwrap());
+}
+
+fn add_program(emul: &mut dyn MT1804Emulator) {
+    for pos in 0..4 {
+        emul.add_command(pos, &Command::new(pos as i32, &[0; 10]))
+            .unwrap();
+    }
+    emul.exec_one().unwrap();
+}
+
+#[test]
+fn fetches_executed_commands() {
+    let mut emul = NativeImplementation::new();
+    add_program(&mut emul);
+    for _ in 0..ROUNDS {
+        fetch_round(&emul);
+    }
+}
+
+// stands in for command_unmanage in the shim
+fn fill(raw: &mut RawCommand, words: &[i32]) {
+    let capacity = raw.words_len;
+    raw.is_offset = 1;
+    raw.number = 7;
+    raw.words_len = words.len();
+    for (i, word) in words.iter().take(capacity).enumerate() {
+        unsafe { *raw.words.add(i) = *word };
+    }
+}
+
+#[test]
+fn fetched_commands_are_owned() {
+    let long = (0..16).collect::<Vec<i32>>();
+    for _ in 0..ROUNDS {
+        let short = RawCommand::fetch(|raw| fill(raw, &[1, 2, 3]));
+        assert_eq!(short.get_words(), Some(vec![1, 2, 3]));
+        // too long for the first buffer, goes through the retry
+        let cmd = RawCommand::fetch(|raw| fill(raw, &long));
+        assert_eq!(cmd.get_num(), 7);
+        assert_eq!(cmd.get_words().unwrap().len(), long.len());
+        let borrowed = RawCommand::borrow(&cmd);
+        assert_eq!(borrowed.words_len, long.len());
+        assert_eq!(cmd.clone(), cmd);
+    }
+}
@@ -0,0 +1,379 @@
+/* memory_image.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Images of the data memory, to fill it before a run and to keep what a run
+// left there. An image starts at address 0, and whatever it doesn't cover is
+// zero once it is loaded.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{EmulatorError, MT1804Emulator};
+
+// Intel HEX can address far more than the memory has, this only keeps a
+// broken file from asking for gigabytes
+const MAX_LEN: usize = 0x10000;
+const BYTES_PER_LINE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // the bytes as they are
+    Binary,
+    // two hex digits per byte separated by blanks, '#' starts a comment
+    HexText,
+    // data and end of file records, with extended addresses
+    IntelHex,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::IntelHex, Format::HexText, Format::Binary];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Binary => "Raw binary",
+            Self::HexText => "Hex text",
+            Self::IntelHex => "Intel HEX",
+        }
+    }
+
+    pub fn pattern(self) -> &'static str {
+        match self {
+            Self::Binary => "*.bin",
+            Self::HexText => "*.txt",
+            Self::IntelHex => "*.hex",
+        }
+    }
+
+    // anything not known to be text is taken as raw bytes
+    pub fn of_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.pattern()[2..].eq_ignore_ascii_case(ext))
+            .unwrap_or(Self::Binary)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    message: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on line {}", self.message, self.line)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MemoryImage(Vec<u8>);
+
+impl MemoryImage {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn of(emul: &dyn MT1804Emulator) -> Self {
+        Self(emul.get_mem().into_iter().map(|val| val as u8).collect())
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn parse(data: &[u8], format: Format) -> Result<Self, ParseError> {
+        if format == Format::Binary {
+            return match data.len() > MAX_LEN {
+                true => Err(ParseError::new(1, "the image is too big")),
+                false => Ok(Self(data.to_vec())),
+            };
+        }
+        let text = std::str::from_utf8(data).map_err(|_| ParseError::new(1, "not a text file"))?;
+        match format {
+            Format::HexText => parse_hex_text(text),
+            _ => parse_intel_hex(text),
+        }
+    }
+
+    pub fn write(&self, format: Format, mut out: impl Write) -> io::Result<()> {
+        match format {
+            Format::Binary => out.write_all(&self.0),
+            Format::HexText => out.write_all(self.hex_text().as_bytes()),
+            Format::IntelHex => {
+                for (line, chunk) in self.0.chunks(BYTES_PER_LINE).enumerate() {
+                    let addr = line * BYTES_PER_LINE;
+                    let mut record = vec![chunk.len() as u8, (addr >> 8) as u8, addr as u8, 0];
+                    record.extend_from_slice(chunk);
+                    writeln!(out, ":{}", with_checksum(record))?;
+                }
+                writeln!(out, ":00000001FF")
+            }
+        }
+    }
+
+    // Fills the whole memory, a bigger image than it is an error and leaves
+    // it as it was.
+    pub fn load(&self, emul: &mut dyn MT1804Emulator) -> Result<(), EmulatorError> {
+        let len = emul.get_mem_length();
+        if self.0.len() > len {
+            return Err(EmulatorError::new(format!(
+                "the image has {} bytes, the memory only {}",
+                self.0.len(),
+                len
+            )));
+        }
+        for addr in 0..len {
+            emul.set_mem_value(addr, self.0.get(addr).copied().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+
+    fn hex_text(&self) -> String {
+        let mut text = String::new();
+        for (line, chunk) in self.0.chunks(BYTES_PER_LINE).enumerate() {
+            let bytes = chunk
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>();
+            text += &format!("{}  # 0x{:02X}\n", bytes.join(" "), line * BYTES_PER_LINE);
+        }
+        text
+    }
+}
+
+impl TryFrom<String> for MemoryImage {
+    type Error = ParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_hex_text(&value)
+    }
+}
+
+impl From<MemoryImage> for String {
+    fn from(image: MemoryImage) -> Self {
+        image.hex_text()
+    }
+}
+
+fn parse_hex_text(text: &str) -> Result<MemoryImage, ParseError> {
+    let mut bytes = Vec::new();
+    for (ind, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        for word in line.split_whitespace() {
+            let byte = match word.len() {
+                1 | 2 => u8::from_str_radix(word, 16).ok(),
+                _ => None,
+            };
+            let Some(byte) = byte else {
+                return Err(ParseError::new(
+                    ind + 1,
+                    format!("\"{}\" is not a hex byte", word),
+                ));
+            };
+            if bytes.len() == MAX_LEN {
+                return Err(ParseError::new(ind + 1, "the image is too big"));
+            }
+            bytes.push(byte);
+        }
+    }
+    Ok(MemoryImage(bytes))
+}
+
+fn parse_intel_hex(text: &str) -> Result<MemoryImage, ParseError> {
+    let mut bytes = Vec::new();
+    let mut base = 0;
+    for (ind, line) in text.lines().enumerate() {
+        let error = |message: &str| ParseError::new(ind + 1, message);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(digits) = line.strip_prefix(':') else {
+            return Err(error("a record has to start with ':'"));
+        };
+        let record = (0..digits.len())
+            .step_by(2)
+            .map(|pos| {
+                digits
+                    .get(pos..pos + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| error("a record has to be hex digits"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error("the record length is wrong"));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("the checksum is wrong"));
+        }
+        let addr = (record[1] as usize) << 8 | record[2] as usize;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0 => {
+                let start = base + addr;
+                if start + data.len() > MAX_LEN {
+                    return Err(error("the image is too big"));
+                }
+                if bytes.len() < start + data.len() {
+                    bytes.resize(start + data.len(), 0);
+                }
+                bytes[start..start + data.len()].copy_from_slice(data);
+            }
+            1 => break,
+            // extended segment and linear addresses
+            2 | 4 => {
+                let [high, low] = *data else {
+                    return Err(error("an extended address takes two bytes"));
+                };
+                let value = (high as usize) << 8 | low as usize;
+                base = match record[3] {
+                    2 => value << 4,
+                    _ => value << 16,
+                };
+            }
+            // where to start running means nothing here
+            3 | 5 => {}
+            _ => return Err(error("unknown record type")),
+        }
+    }
+    Ok(MemoryImage(bytes))
+}
+
+fn with_checksum(record: Vec<u8>) -> String {
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record
+        .iter()
+        .chain([&sum.wrapping_neg()])
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> MemoryImage {
+        MemoryImage::new((0..40u8).map(|byte| byte.wrapping_mul(7)).collect())
+    }
+
+    fn round_trip(image: &MemoryImage, format: Format) -> MemoryImage {
+        let mut out = Vec::new();
+        image.write(format, &mut out).unwrap();
+        MemoryImage::parse(&out, format).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        for format in Format::ALL {
+            assert_eq!(round_trip(&sample(), format), sample(), "{:?}", format);
+            let empty = MemoryImage::default();
+            assert_eq!(round_trip(&empty, format), empty, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn reads_hex_text() {
+        let image = MemoryImage::parse(b"01 a 0F  # comment\n\n# 12\nff\n", Format::HexText);
+        assert_eq!(image.unwrap().bytes(), [0x01, 0x0A, 0x0F, 0xFF]);
+        let error = MemoryImage::parse(b"01\n02 123\n", Format::HexText).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.to_string(), "\"123\" is not a hex byte on line 2");
+        assert!(MemoryImage::parse(b"0g", Format::HexText).is_err());
+    }
+
+    #[test]
+    fn reads_intel_hex() {
+        // data at 0x10, then behind an extended segment address of 0x100
+        let text = ":0200100001A14C\n\
+                    :020000020010EC\n\
+                    :01000000B04F\n\
+                    :00000001FF\n\
+                    :01000000FF00\n";
+        let image = MemoryImage::parse(text.as_bytes(), Format::IntelHex).unwrap();
+        let mut expected = vec![0; 0x101];
+        expected[0x10] = 0x01;
+        expected[0x11] = 0xA1;
+        expected[0x100] = 0xB0;
+        // nothing after the end of file record counts
+        assert_eq!(image.bytes(), expected);
+    }
+
+    #[test]
+    fn checks_intel_hex_records() {
+        let error = |text: &str| {
+            MemoryImage::parse(text.as_bytes(), Format::IntelHex)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error(":01000000B0FF"), "the checksum is wrong on line 1");
+        assert_eq!(
+            error("\n01000000B04F"),
+            "a record has to start with ':' on line 2"
+        );
+        assert_eq!(
+            error(":0100000XB04F"),
+            "a record has to be hex digits on line 1"
+        );
+        assert_eq!(
+            error(":02000000B04F"),
+            "the record length is wrong on line 1"
+        );
+        assert_eq!(error(":0000000AF6"), "unknown record type on line 1");
+        assert_eq!(
+            error(":0100000201FC"),
+            "an extended address takes two bytes on line 1"
+        );
+    }
+
+    #[test]
+    fn limits_the_size() {
+        let too_big = vec![0; MAX_LEN + 1];
+        assert!(MemoryImage::parse(&too_big, Format::Binary).is_err());
+        assert!(MemoryImage::parse(&too_big[1..], Format::Binary).is_ok());
+
+        let text = "00 ".repeat(MAX_LEN);
+        assert!(MemoryImage::parse(text.as_bytes(), Format::HexText).is_ok());
+        let error = MemoryImage::parse((text + "\n00").as_bytes(), Format::HexText).unwrap_err();
+        assert_eq!(error.to_string(), "the image is too big on line 2");
+
+        // a linear address of 0x10000 puts the byte past the end
+        let text = ":020000040001F9\n:0100000000FF\n";
+        let error = MemoryImage::parse(text.as_bytes(), Format::IntelHex).unwrap_err();
+        assert_eq!(error.to_string(), "the image is too big on line 2");
+    }
+}
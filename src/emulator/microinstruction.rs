@@ -0,0 +1,390 @@
+/* emulator/microinstruction.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Decoded form of the 40-bit microinstruction. Every field keeps all of its
+// bits, so going to words and back never loses anything, even for commands
+// the engine would reject.
+
+use std::fmt;
+use std::str::FromStr;
+
+pub const WORDS_COUNT: usize = 10;
+
+const AR_HIGH: usize = 0;
+const AR_MID: usize = 1;
+const AR_LOW: usize = 2;
+const CA: usize = 3;
+const I68: usize = 4;
+const I02: usize = 5;
+const I35: usize = 6;
+const A: usize = 7;
+const B: usize = 8;
+const D: usize = 9;
+
+const FLAG_BIT: u8 = 0b1000;
+const FIELD_MASK: u8 = 0b0111;
+
+macro_rules! nibble_enum {
+    ($name:ident, $mask:expr, { $($variant:ident = $value:expr),+ $(,)? }) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum $name {
+            $($variant = $value),+
+        }
+
+        impl $name {
+            pub fn from_bits(bits: u8) -> Self {
+                match bits & $mask {
+                    $($value => Self::$variant,)+
+                    _ => unreachable!(),
+                }
+            }
+        }
+    };
+}
+
+nibble_enum!(JumpType, 0b1111, {
+    Jnz = 0,
+    Jmp = 1,
+    Jnxt = 2,
+    End = 3,
+    Clnz = 4,
+    Call = 5,
+    Ret = 6,
+    Jsp = 7,
+    Jsnz = 8,
+    Push = 9,
+    Pop = 10,
+    Jsnc4 = 11,
+    Jz = 12,
+    Jf3 = 13,
+    Jovr = 14,
+    Jc4 = 15,
+});
+
+// The arithmetic functions come in pairs differing only in C0 (bit 3)
+nibble_enum!(FuncType, 0b1111, {
+    RPlusS = 0,
+    SMinusRMinus1 = 1,
+    RMinusSMinus1 = 2,
+    ROrS = 3,
+    RAndS = 4,
+    NoRAndS = 5,
+    RXorS = 6,
+    REqS = 7,
+    RPlusSPlus1 = 8,
+    SMinusR = 9,
+    RMinusS = 10,
+    SetPointer = 11,
+    StoreMemory = 12,
+    LoadMemory = 13,
+    StoreDevice = 14,
+    LoadDevice = 15,
+});
+
+nibble_enum!(FromType, FIELD_MASK, {
+    AAndPq = 0,
+    AAndB = 1,
+    ZeroAndQ = 2,
+    ZeroAndB = 3,
+    ZeroAndA = 4,
+    DAndA = 5,
+    DAndQ = 6,
+    DAndZero = 7,
+});
+
+nibble_enum!(ToType, FIELD_MASK, {
+    FInQ = 0,
+    NoLoad = 1,
+    FInBAndAInY = 2,
+    FInB = 3,
+    SrFInBAndSrQInQ = 4,
+    SrFInB = 5,
+    SlFInBAndSlQInQ = 6,
+    SlFInB = 7,
+});
+
+// Built from (M1 << 1) | M0
+nibble_enum!(ShiftType, 0b11, {
+    Logic = 0,
+    Cycle = 1,
+    CycleDouble = 2,
+    ArithmeticDouble = 3,
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemNextPoint {
+    No,
+    Plus,
+    Load,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataPointerType {
+    Low,
+    High,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    Port0,
+    Port1,
+    Port2,
+    Port3,
+}
+
+/// What the command does besides the jump, derived from the function field
+/// the same way the engine picks its view of a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Alu,
+    SetMemPointer(MemNextPoint),
+    SetDevicePointer(Port),
+    StoreMemory(DataPointerType),
+    LoadMemory(DataPointerType),
+    StoreDevice(DataPointerType),
+    LoadDevice(DataPointerType),
+    Incorrect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Microinstruction {
+    pub next_addr: u16,
+    pub jump: JumpType,
+    pub to: ToType,
+    pub m1: bool,
+    pub from: FromType,
+    pub m0: bool,
+    pub func: FuncType,
+    pub a: u8,
+    pub b: u8,
+    pub d: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError;
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} binary digits", WORDS_COUNT * 4)
+    }
+}
+
+impl Microinstruction {
+    pub fn from_words(words: &[u8; WORDS_COUNT]) -> Self {
+        let words = words.map(|word| word & 0b1111);
+        Self {
+            next_addr: ((words[AR_HIGH] as u16) << 8)
+                | ((words[AR_MID] as u16) << 4)
+                | words[AR_LOW] as u16,
+            jump: JumpType::from_bits(words[CA]),
+            to: ToType::from_bits(words[I68]),
+            m1: words[I68] & FLAG_BIT != 0,
+            from: FromType::from_bits(words[I02]),
+            m0: words[I02] & FLAG_BIT != 0,
+            func: FuncType::from_bits(words[I35]),
+            a: words[A],
+            b: words[B],
+            d: words[D],
+        }
+    }
+
+    pub fn to_words(self) -> [u8; WORDS_COUNT] {
+        [
+            ((self.next_addr >> 8) & 0b1111) as u8,
+            ((self.next_addr >> 4) & 0b1111) as u8,
+            (self.next_addr & 0b1111) as u8,
+            self.jump as u8,
+            self.to as u8 | if self.m1 { FLAG_BIT } else { 0 },
+            self.from as u8 | if self.m0 { FLAG_BIT } else { 0 },
+            self.func as u8,
+            self.a & 0b1111,
+            self.b & 0b1111,
+            self.d & 0b1111,
+        ]
+    }
+
+    pub fn from_command(cmd: &super::Command) -> Option<Self> {
+        let words = cmd.get_words()?;
+        let words: [u32; WORDS_COUNT] = words.try_into().ok()?;
+        Some(Self::from_words(&words.map(|word| word as u8)))
+    }
+
+    pub fn to_command_words(self) -> [i32; WORDS_COUNT] {
+        self.to_words().map(|word| word as i32)
+    }
+
+    pub fn shift(&self) -> ShiftType {
+        ShiftType::from_bits(((self.m1 as u8) << 1) | self.m0 as u8)
+    }
+
+    pub fn c0(&self) -> bool {
+        self.func as u8 & FLAG_BIT != 0
+    }
+
+    // memory and device commands reuse the operand field (I0-2) for the
+    // pointer mode and the A field for the port
+    fn pointer_bits(&self) -> u8 {
+        self.to_words()[I02]
+    }
+
+    pub fn operation(&self) -> Operation {
+        let bits = self.pointer_bits();
+        let width = match bits {
+            0 => Some(DataPointerType::Low),
+            1 => Some(DataPointerType::High),
+            2 => Some(DataPointerType::Full),
+            _ => None,
+        };
+        match self.func {
+            FuncType::SetPointer => match bits {
+                0 => Operation::SetMemPointer(MemNextPoint::No),
+                1 => Operation::SetMemPointer(MemNextPoint::Plus),
+                2 => Operation::SetMemPointer(MemNextPoint::Load),
+                8 => match self.a {
+                    0 => Operation::SetDevicePointer(Port::Port0),
+                    1 => Operation::SetDevicePointer(Port::Port1),
+                    2 => Operation::SetDevicePointer(Port::Port2),
+                    3 => Operation::SetDevicePointer(Port::Port3),
+                    _ => Operation::Incorrect,
+                },
+                _ => Operation::Incorrect,
+            },
+            FuncType::StoreMemory => width.map_or(Operation::Incorrect, Operation::StoreMemory),
+            FuncType::LoadMemory => width.map_or(Operation::Incorrect, Operation::LoadMemory),
+            FuncType::StoreDevice => width.map_or(Operation::Incorrect, Operation::StoreDevice),
+            FuncType::LoadDevice => width.map_or(Operation::Incorrect, Operation::LoadDevice),
+            _ => Operation::Alu,
+        }
+    }
+
+    pub fn touches_stack(&self) -> bool {
+        matches!(
+            self.jump,
+            JumpType::Clnz
+                | JumpType::Call
+                | JumpType::Ret
+                | JumpType::Push
+                | JumpType::Pop
+                | JumpType::Jsnz
+                | JumpType::Jsnc4
+        )
+    }
+}
+
+impl Default for Microinstruction {
+    fn default() -> Self {
+        Self::from_words(&[0; WORDS_COUNT])
+    }
+}
+
+// Same text the clipboard has always used: ten 4-bit groups, no separators
+impl fmt::Display for Microinstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for word in self.to_words() {
+            write!(f, "{:0>4b}", word)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Microinstruction {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        if line.len() != WORDS_COUNT * 4 || !line.bytes().all(|c| c == b'0' || c == b'1') {
+            return Err(ParseError);
+        }
+        let mut words = [0u8; WORDS_COUNT];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u8::from_str_radix(&line[i * 4..i * 4 + 4], 2).map_err(|_| ParseError)?;
+        }
+        Ok(Self::from_words(&words))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Command;
+
+    fn round_trip(words: [u8; WORDS_COUNT]) {
+        let instr = Microinstruction::from_words(&words);
+        assert_eq!(instr.to_words(), words);
+        let text = instr.to_string();
+        assert_eq!(text.parse::<Microinstruction>(), Ok(instr), "{}", text);
+        let cmd = Command::new(0, &instr.to_command_words());
+        assert_eq!(Microinstruction::from_command(&cmd), Some(instr));
+    }
+
+    // every jump with every function, destination and source, M0 and M1
+    // included, then the address and operand nibbles on their own
+    #[test]
+    fn encodings_survive_words_and_text() {
+        for jump in 0..16 {
+            for to in 0..16 {
+                for from in 0..16 {
+                    for func in 0..16 {
+                        round_trip([0, 0, 0, jump, to, from, func, 0, 0, 0]);
+                    }
+                }
+            }
+        }
+        for nibble in 0..16 {
+            round_trip([nibble, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            round_trip([0, nibble, 0, 0, 0, 0, 0, 0, 0, 0]);
+            round_trip([0, 0, nibble, 0, 0, 0, 0, 0, 0, 0]);
+            round_trip([0, 0, 0, 0, 0, 0, 0, nibble, nibble, nibble]);
+        }
+    }
+
+    #[test]
+    fn fields_are_decoded_in_place() {
+        let instr = Microinstruction::from_words(&[0xA, 0xB, 0xC, 5, 0b1011, 0b1110, 9, 1, 2, 3]);
+        assert_eq!(instr.next_addr, 0xABC);
+        assert_eq!(instr.jump, JumpType::Call);
+        assert_eq!((instr.to, instr.m1), (ToType::FInB, true));
+        assert_eq!((instr.from, instr.m0), (FromType::DAndQ, true));
+        assert_eq!(instr.shift(), ShiftType::ArithmeticDouble);
+        assert_eq!(instr.func, FuncType::SMinusR);
+        assert!(instr.c0());
+        assert_eq!((instr.a, instr.b, instr.d), (1, 2, 3));
+        // anything above a nibble is not part of the word
+        assert_eq!(
+            Microinstruction::from_words(&[0x1F; WORDS_COUNT]).to_words(),
+            [0xF; WORDS_COUNT]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        let good = "0".repeat(WORDS_COUNT * 4);
+        assert!(good.parse::<Microinstruction>().is_ok());
+        assert_eq!(good[1..].parse::<Microinstruction>(), Err(ParseError));
+        assert_eq!(
+            format!("{}0", good).parse::<Microinstruction>(),
+            Err(ParseError)
+        );
+        assert_eq!(
+            good.replacen('0', "2", 1).parse::<Microinstruction>(),
+            Err(ParseError)
+        );
+    }
+}
@@ -21,45 +21,110 @@
 use std::collections::HashMap;
 
 use libc::{self, c_char};
-
-#[repr(C)]
-#[derive(Clone, Debug)]
+use serde::Serialize;
+
+pub mod call_stack;
+pub mod condition;
+pub mod coverage;
+pub mod history;
+pub mod memory_image;
+pub mod microinstruction;
+mod native;
+pub mod port;
+// the board is found and driven through termios, there is no Windows backend
+#[cfg(unix)]
+pub mod port_extender;
+pub mod port_script;
+pub mod profile;
+pub mod trace;
+pub mod vcd;
+pub mod watch;
+pub mod worker;
+#[cfg(test)]
+mod differential;
+#[cfg(test)]
+mod leaks;
+
+pub use microinstruction::Microinstruction;
+pub use native::{NativeImplementation, MAX_AUTO_COUNT};
+use port::{Access, Ports, SharedDevice, Width};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Command {
-    isOffset: i32,
-    number_: i32,
-    words: *mut i32,
-    words_len: libc::size_t,
-}
-
-impl Default for Command {
-    fn default() -> Self {
-        Self {
-            isOffset: i32::default(),
-            number_: i32::default(),
-            words: std::ptr::null_mut(),
-            words_len: usize::default(),
-        }
-    }
+    is_offset: bool,
+    number: i32,
+    words: Vec<i32>,
 }
 
 impl Command {
-    pub fn new(pos: i32, words: &mut [i32]) -> Command {
+    pub fn new(pos: i32, words: &[i32]) -> Command {
         Command {
-            isOffset: 0,
-            number_: pos,
-            words: words.as_mut_ptr(),
-            words_len: words.len(),
+            is_offset: false,
+            number: pos,
+            words: words.to_vec(),
         }
     }
     pub fn get_num(&self) -> usize {
-        self.number_ as usize
+        self.number as usize
+    }
+    pub fn is_offset(&self) -> bool {
+        self.is_offset
     }
     pub fn get_words(&self) -> Option<Vec<u32>> {
-        if self.words == std::ptr::null_mut() {
+        if self.words.is_empty() {
             return None;
         }
-        let words = std::ptr::slice_from_raw_parts(self.words, self.words_len);
-        unsafe { Some((&*words).iter().map(|elem| { *elem as u32 }).collect()) }
+        Some(self.words.iter().map(|elem| { *elem as u32 }).collect())
+    }
+}
+
+// How a command crosses the shim. The words always belong to the Rust side:
+// commands passed in are only read for the duration of the call, commands
+// coming out are copied into a buffer we provide. The shim never allocates
+// or keeps them, so there is nothing to free on either side.
+#[repr(C)]
+struct RawCommand {
+    is_offset: i32,
+    number: i32,
+    words: *mut i32,
+    words_len: libc::size_t,
+}
+
+impl RawCommand {
+    // the pointer is only valid while `cmd` is borrowed, the shim copies the
+    // words into a managed array and never writes through it
+    fn borrow(cmd: &Command) -> Self {
+        Self {
+            is_offset: cmd.is_offset as i32,
+            number: cmd.number,
+            words: cmd.words.as_ptr() as *mut i32,
+            words_len: cmd.words.len(),
+        }
+    }
+
+    // words_len goes in as the buffer size and comes back as the real
+    // length, retry once if the buffer was too small
+    fn fetch(mut f: impl FnMut(&mut RawCommand)) -> Command {
+        let mut words = vec![0; microinstruction::WORDS_COUNT];
+        loop {
+            let mut raw = RawCommand {
+                is_offset: 0,
+                number: 0,
+                words: words.as_mut_ptr(),
+                words_len: words.len(),
+            };
+            f(&mut raw);
+            if raw.words_len > words.len() {
+                words.resize(raw.words_len, 0);
+                continue;
+            }
+            words.truncate(raw.words_len);
+            return Command {
+                is_offset: raw.is_offset != 0,
+                number: raw.number,
+                words,
+            };
+        }
     }
 }
 
@@ -90,25 +155,31 @@ pub struct Emulator {
     _marker: core::marker::PhantomData<(*mut u8, core::marker::PhantomPinned)>,
 }
 
+type PortHandler = extern "C" fn(i32, i32, i32, bool, *mut i32, *mut c_char, libc::size_t) -> bool;
+
 #[link(name = "engine")]
 extern "C" {
+    fn emulator_set_port_handler(_: PortHandler);
     fn create_emulator() -> *mut Emulator;
-    fn clone_emulator(_: *const Emulator) -> *mut Emulator;
+    fn clone_emulator(_: *mut Emulator) -> *mut Emulator;
     fn destroy_emulator(_: *mut Emulator);
+    fn emulator_attach_thread(_: *mut Emulator) -> *mut libc::c_void;
+    fn emulator_detach_thread(_: *mut libc::c_void);
     fn emulator_reset(_: *mut Emulator);
-    fn emulator_get_command(_: *mut Emulator, _: i32) -> Command;
-    fn emulator_add_command(_: *mut Emulator, _: i32, _: Command) -> u8;
-    fn emulator_update_command(_: *mut Emulator, _: i32, _: Command) -> u8;
-    fn emulator_last_command(_: *mut Emulator) -> Command;
-    fn emulator_remove_command(_: *mut Emulator, _: i32) -> *mut i32;
+    fn emulator_get_command(_: *mut Emulator, _: i32, _: *mut RawCommand);
+    fn emulator_add_command(_: *mut Emulator, _: i32, _: RawCommand) -> u8;
+    fn emulator_update_command(_: *mut Emulator, _: i32, _: RawCommand) -> u8;
+    fn emulator_last_command(_: *mut Emulator, _: *mut RawCommand);
+    fn emulator_remove_command(_: *mut Emulator, _: i32) -> u8;
     fn emulator_commands_count(_: *mut Emulator) -> i32;
-    fn emulator_executed_command(_: *mut Emulator) -> Command;
-    fn emulator_exec_one(_: *mut Emulator) -> usize;
-    fn emulator_exec_one_call(_: *mut Emulator) -> usize;
-    fn emulator_exec_all(_: *mut Emulator) -> usize;
+    fn emulator_executed_command(_: *mut Emulator, _: *mut RawCommand);
+    fn emulator_exec_one(_: *mut Emulator) -> libc::c_int;
+    fn emulator_exec_one_call(_: *mut Emulator) -> libc::c_int;
+    fn emulator_exec_all(_: *mut Emulator) -> libc::c_int;
     fn emulator_get_next_index(_: *mut Emulator) -> i32;
     fn emulator_get_prev_index(_: *mut Emulator) -> i32;
     fn emulator_get_call_index(_: *mut Emulator) -> i32;
+    fn emulator_get_last_call_cycles(_: *mut Emulator) -> i32;
     fn emulator_get_pc(_: *mut Emulator) -> i32;
     fn emulator_set_pc(_: *mut Emulator, _: i32) -> i32;
     fn emulator_get_sp(_: *mut Emulator) -> i32;
@@ -135,6 +206,12 @@ extern "C" {
     fn emulator_get_ovr(_: *mut Emulator) -> i32;
     fn emulator_get_g(_: *mut Emulator) -> i32;
     fn emulator_get_p(_: *mut Emulator) -> i32;
+    fn emulator_set_reg_value(_: *mut Emulator, _: i32, _: i32);
+    fn emulator_set_reg_q(_: *mut Emulator, _: i32);
+    fn emulator_set_flag(_: *mut Emulator, _: i32, _: bool);
+    fn emulator_set_mp(_: *mut Emulator, _: i32);
+    fn emulator_set_mem_value(_: *mut Emulator, _: i32, _: i32);
+    fn emulator_set_stack_value(_: *mut Emulator, _: i32, _: i32);
     fn emulator_add_call(_: *mut Emulator, _: i32, _: Call);
     fn emulator_get_call(_: *mut Emulator, _: i32) -> Call;
     fn emulator_update_call(_: *mut Emulator, _: i32, _: Call);
@@ -150,44 +227,127 @@ extern "C" {
     fn emulator_last_call(_: *mut Emulator) -> Call;
     fn emulator_open_raw(_: *mut Emulator, _: *mut u8, _: libc::size_t) -> u8;
     fn emulator_export_raw(_: *mut Emulator, _: *mut *mut u8, _: *mut libc::size_t);
-    fn command_get_name(_: *mut Emulator, _: Command) -> *mut libc::c_char;
-    fn command_get_jump_name(_: *mut Emulator, _: Command) -> *mut libc::c_char;
+    fn emulator_get_exec_state(_: *mut Emulator, _: *mut *mut i32, _: *mut libc::size_t);
+    fn emulator_set_exec_state(_: *mut Emulator, _: *mut i32, _: libc::size_t) -> u8;
+    fn command_get_name(_: *mut Emulator, _: RawCommand) -> *mut libc::c_char;
+    fn command_get_jump_name(_: *mut Emulator, _: RawCommand) -> *mut libc::c_char;
     fn emulator_swap(_: *mut Emulator, _: *mut Emulator);
     fn free_obj(_: *mut libc::c_void);
+    fn emulator_take_error(_: *mut Emulator) -> *mut libc::c_char;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecResult {
+    Ok,
+    NoCommands,
+    IncorrectCommand(usize),
+    Loop,
+    End,
+}
+
+impl ExecResult {
+    // mirrors ResultCode from the engine, the address is only known to the
+    // caller as PC stays on the rejected command
+    fn from_code(code: libc::c_int, pc: usize) -> Result<Self, EmulatorError> {
+        match code {
+            0 => Ok(Self::Ok),
+            1 => Ok(Self::NoCommands),
+            2 => Ok(Self::IncorrectCommand(pc)),
+            3 => Ok(Self::Loop),
+            4 => Ok(Self::End),
+            _ => Err(EmulatorError::new(format!("the engine returned an unknown result {}", code))),
+        }
+    }
+}
+
+impl std::fmt::Display for ExecResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "Command executed"),
+            Self::NoCommands => write!(f, "No commands to execute"),
+            Self::IncorrectCommand(addr) => write!(f, "Incorrect command at address 0x{:03X}", addr),
+            Self::Loop => write!(f, "Loop limit reached"),
+            Self::End => write!(f, "Program end reached"),
+        }
+    }
+}
+
+const BAD_PROGRAM_FILE: &str = "not a valid program file";
+// the engines only say no, the reason is one of these
+const BAD_COMMAND: &str = "the command is invalid or doesn't fit in the user program";
+const FIXED_COMMAND: &str = "library commands and the last offset can't be removed or moved";
+const BAD_EXEC_STATE: &str = "not an execution state of this engine";
+
+/// Error coming out of the engine. For the original one it carries the
+/// managed exception as "Type: Message".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmulatorError {
+    message: String,
+}
+
+impl EmulatorError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into() }
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
-pub struct Port {}
+impl std::fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+/// A flag as `State::flags` orders them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    Ovr,
+    C4,
+    F3,
+    Z,
+    G,
+    P,
+}
 
-trait SerialPort {}
+impl Flag {
+    pub const ALL: [Flag; 6] = [Flag::Ovr, Flag::C4, Flag::F3, Flag::Z, Flag::G, Flag::P];
+}
 
 pub trait MT1804Emulator {
     fn reset(&mut self);
-    fn get_command(&self, index: usize) -> Command;
-    fn add_command(&mut self, index: usize, cmd: &Command);
-    fn update_command(&mut self, index: usize, cmd: &Command);
-    fn last_command(&self) -> Command;
-    fn remove_command(&mut self, index: usize);
-    fn move_command(&mut self, index: usize, new_pos: usize);
+    fn get_command(&self, index: usize) -> Result<Command, EmulatorError>;
+    fn add_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError>;
+    fn update_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError>;
+    fn last_command(&self) -> Result<Command, EmulatorError>;
+    fn remove_command(&mut self, index: usize) -> Result<(), EmulatorError>;
+    fn move_command(&mut self, index: usize, new_pos: usize) -> Result<(), EmulatorError>;
     fn commands_count(&self) -> usize;
-    fn executed_command(&self) -> Command;
-    fn exec_one(&mut self);
-    fn exec_one_call(&mut self);
-    fn exec_all(&mut self);
+    fn executed_command(&self) -> Result<Command, EmulatorError>;
+    fn exec_one(&mut self) -> Result<ExecResult, EmulatorError>;
+    fn exec_one_call(&mut self) -> Result<ExecResult, EmulatorError>;
+    fn exec_all(&mut self) -> Result<ExecResult, EmulatorError>;
     fn get_next_index(&self) -> usize;
     fn get_prev_index(&self) -> usize;
     fn get_call_index(&self) -> usize;
+    // commands the last exec_one_call ran, including the one it stopped on
+    fn get_last_call_cycles(&self) -> usize;
     fn get_pc(&self) -> usize;
-    fn set_pc(&mut self, index: usize);
+    fn set_pc(&mut self, index: usize) -> Result<(), EmulatorError>;
     fn get_sp(&self) -> usize;
-    fn set_sp(&mut self, index: usize);
+    fn set_sp(&mut self, index: usize) -> Result<(), EmulatorError>;
     fn get_stack(&self) -> Vec<i32>;
     fn get_mp(&self) -> usize;
-    fn get_port(&self) -> usize;
-    fn get_mem_value(&self, index: usize) -> usize;
+    // None until the device pointer picks a port
+    fn get_port(&self) -> Option<usize>;
+    fn get_mem_value(&self, index: usize) -> Result<usize, EmulatorError>;
     fn get_mem_length(&self) -> usize;
     fn get_mem(&self) -> Vec<i32>;
     fn get_reg_q(&self) -> u8;
-    fn get_reg(&self, index: usize) -> u8;
+    fn get_reg(&self, index: usize) -> Result<u8, EmulatorError>;
     fn get_f(&self) -> u8;
     fn get_y(&self) -> u8;
     fn get_prev_reg_q(&self) -> u8;
@@ -201,22 +361,101 @@ pub trait MT1804Emulator {
     fn get_ovr(&self) -> u8;
     fn get_g(&self) -> u8;
     fn get_p(&self) -> u8;
-    fn add_call(&mut self, index: usize, call: Call);
-    fn get_call(&self, index: usize) -> Call;
-    fn update_call(&mut self, index: usize, call: Call);
-    fn remove_call(&mut self, index: usize);
+    // Editing the state by hand. Values are cut down to the width of what
+    // they are written to, a bad index is an error.
+    fn set_reg(&mut self, index: usize, value: u8) -> Result<(), EmulatorError>;
+    fn set_reg_q(&mut self, value: u8) -> Result<(), EmulatorError>;
+    fn set_flag(&mut self, flag: Flag, value: bool) -> Result<(), EmulatorError>;
+    fn set_mp(&mut self, value: usize) -> Result<(), EmulatorError>;
+    fn set_mem_value(&mut self, index: usize, value: u8) -> Result<(), EmulatorError>;
+    fn set_stack_value(&mut self, index: usize, value: usize) -> Result<(), EmulatorError>;
+    // Everything a step reads or writes, to undo it with. The program, the
+    // calls and the ports are not in it, and only the engine it came from
+    // can put it back.
+    fn get_exec_state(&self) -> Vec<i32>;
+    fn set_exec_state(&mut self, state: &[i32]) -> Result<(), EmulatorError>;
+    // What STORE_DEVICE and LOAD_DEVICE at the port talk to, None unplugs it
+    fn attach_device(&mut self, port: usize, device: Option<SharedDevice>) -> Result<(), EmulatorError>;
+    fn add_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError>;
+    fn get_call(&self, index: usize) -> Result<Call, EmulatorError>;
+    fn update_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError>;
+    fn remove_call(&mut self, index: usize) -> Result<(), EmulatorError>;
     fn call_count(&self) -> usize;
-    fn add_map_call(&mut self, libcall: &LibCall);
-    fn remove_map_call(&mut self, code: i32);
-    fn update_map_call(&mut self, libcall: &LibCall);
-    fn get_map_calls(&self) -> Vec<LibCall>;
-    fn last_call(&self) -> Call;
-    fn open_raw(&mut self, bytes: &[u8]);
+    fn add_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError>;
+    fn remove_map_call(&mut self, code: i32) -> Result<(), EmulatorError>;
+    fn update_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError>;
+    fn get_map_calls(&self) -> Result<Vec<LibCall>, EmulatorError>;
+    fn last_call(&self) -> Result<Call, EmulatorError>;
+    fn open_raw(&mut self, bytes: &[u8]) -> Result<(), EmulatorError>;
     fn export_raw(&self) -> Vec<u8>;
-    fn init_library(&self);
-    fn command_get_name(&self, cmd: Command) -> String;
-    fn command_get_jump_name(&self, cmd: Command) -> String;
-    fn get_state(&self) -> State;
+    fn init_library(&mut self) -> Result<(), EmulatorError>;
+    fn command_get_name(&self, cmd: Command) -> Result<String, EmulatorError>;
+    fn command_get_jump_name(&self, cmd: Command) -> Result<String, EmulatorError>;
+    fn get_state(&self) -> State {
+        State {
+            program_counter: self.get_pc(),
+            stack_pointer: self.get_sp(),
+            // stack_value: self.get_stack_value(),
+            multiplexor_value: self.get_mp(),
+            // port_value: self.get_port(),
+            // mem_value: self.get_mem_value(),
+            registers: (0..16)
+                .into_iter()
+                .map(|ind| { self.get_reg(ind).unwrap_or_default() })
+                .chain([self.get_reg_q()].into_iter())
+                .collect(),
+            flags: [
+                self.get_ovr(),
+                self.get_c4(),
+                self.get_f3(),
+                self.get_z(),
+                self.get_g(),
+                self.get_p()
+            ],
+            func_output: self.get_f(),
+            func_value: self.get_y(),
+        }
+    }
+}
+
+// There is one managed runtime and the shim has one handler for all of its
+// emulators, so the devices they see are shared by all of them too
+static ORIGINAL_PORTS: std::sync::Mutex<Ports> = std::sync::Mutex::new(Ports::new());
+
+extern "C" fn original_port_handler(
+    port: i32,
+    addr: i32,
+    width: i32,
+    write: bool,
+    value: *mut i32,
+    error: *mut c_char,
+    error_len: libc::size_t,
+) -> bool {
+    let res = match Width::from_code(width as u8) {
+        None => Err(EmulatorError::new(format!("unknown port width {}", width))),
+        Some(width) => {
+            let access = Access { port: port as usize, addr: addr as u8, width };
+            let ports = ORIGINAL_PORTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            match write {
+                true => ports.write(access, unsafe { *value } as u8),
+                false => ports.read(access).map(|byte| unsafe { *value = byte as i32 }),
+            }
+        }
+    };
+    let Err(err) = res else {
+        return true;
+    };
+    // the shim takes whatever fits, cut on a character boundary
+    let message = err.message();
+    let mut len = message.len().min(error_len.saturating_sub(1));
+    while !message.is_char_boundary(len) {
+        len -= 1;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(message.as_ptr(), error as *mut u8, len);
+        *error.add(len) = 0;
+    }
+    false
 }
 
 #[derive(Default, Debug)]
@@ -227,6 +466,7 @@ pub struct OriginalImplementation {
 impl OriginalImplementation {
     pub fn new() -> Self {
         unsafe {
+            emulator_set_port_handler(original_port_handler);
             Self {
                 inst: Some(create_emulator()),
             }
@@ -235,78 +475,129 @@ impl OriginalImplementation {
     pub fn swap(&mut self, oth: &mut OriginalImplementation) {
         unsafe { emulator_swap(self.inst.as_mut().unwrap().to_owned(), oth.inst.as_mut().unwrap().to_owned()); }
     }
+
+    // The shim keeps the first managed exception until it is taken, drop
+    // whatever a previous unchecked call may have left there
+    fn call<T>(&self, f: impl FnOnce(*mut Emulator) -> T) -> Result<T, EmulatorError> {
+        let inst = self.inst.unwrap();
+        let _ = unsafe { Self::take_error(inst) };
+        let res = f(inst);
+        match unsafe { Self::take_error(inst) } {
+            Some(err) => Err(err),
+            None => Ok(res),
+        }
+    }
+
+    unsafe fn take_error(inst: *mut Emulator) -> Option<EmulatorError> {
+        let error = emulator_take_error(inst);
+        if error.is_null() {
+            return None;
+        }
+        let message = String::from_utf8_lossy(std::ffi::CStr::from_ptr(error).to_bytes()).to_string();
+        libc::free(error as *mut libc::c_void);
+        Some(EmulatorError::new(message))
+    }
+
+    unsafe fn take_string(ptr: *mut libc::c_char) -> String {
+        let res = String::from_utf8_lossy(std::ffi::CStr::from_ptr(ptr).to_bytes()).to_string();
+        free_obj(ptr as *mut libc::c_void);
+        res
+    }
+}
+
+// Everything goes through the shim, which may be called from any thread once
+// it is attached to the managed runtime
+unsafe impl Send for OriginalImplementation {}
+
+/// Keeps the current thread attached to the managed runtime while alive.
+pub struct ThreadAttachment(*mut libc::c_void);
+
+impl std::ops::Drop for ThreadAttachment {
+    fn drop(&mut self) {
+        unsafe { emulator_detach_thread(self.0) }
+    }
 }
 
 impl Clone for OriginalImplementation {
     fn clone(&self) -> Self {
         match self.inst {
             None => Self { inst: None },
-            Some(inst) => Self { inst: Some(unsafe { clone_emulator(inst) }) },
+            // Clone has no way to fail, an engine that can't copy itself is
+            // beyond repair anyway
+            Some(_) => match self.call(|inst| unsafe { clone_emulator(inst) }) {
+                Ok(inst) if !inst.is_null() => Self { inst: Some(inst) },
+                Ok(_) => panic!("cannot clone the emulator"),
+                Err(err) => panic!("cannot clone the emulator: {}", err),
+            },
         }
     }
 }
 
 impl MT1804Emulator for OriginalImplementation {
     fn reset(&mut self) {
-        unsafe {
-            emulator_reset(self.inst.as_mut().unwrap().to_owned());
-        }
+        let _ = self.call(|inst| unsafe { emulator_reset(inst) });
     }
 
-    fn get_command(&self, index: usize) -> Command {
-        unsafe { emulator_get_command(self.inst.as_ref().unwrap().to_owned(), index as i32) }
+    fn get_command(&self, index: usize) -> Result<Command, EmulatorError> {
+        self.call(|inst| RawCommand::fetch(|raw| unsafe { emulator_get_command(inst, index as i32, raw) }))
     }
 
-    fn add_command(&mut self, index: usize, cmd: &Command) {
-        unsafe {
-            emulator_add_command(
-                self.inst.as_mut().unwrap().to_owned(),
-                index as i32,
-                cmd.clone(),
-            );
+    fn add_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError> {
+        let added = self.call(|inst| unsafe { emulator_add_command(inst, index as i32, RawCommand::borrow(cmd)) })?;
+        match added {
+            0 => Err(EmulatorError::new(BAD_COMMAND)),
+            _ => Ok(()),
         }
     }
 
-    fn update_command(&mut self, index: usize, cmd: &Command) {
-        unsafe {
-            emulator_update_command(
-                self.inst.as_mut().unwrap().to_owned(),
-                index as i32,
-                cmd.clone(),
-            );
+    fn update_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError> {
+        let updated = self.call(|inst| unsafe { emulator_update_command(inst, index as i32, RawCommand::borrow(cmd)) })?;
+        match updated {
+            0 => Err(EmulatorError::new(BAD_COMMAND)),
+            _ => Ok(()),
         }
     }
 
-    fn last_command(&self) -> Command {
-        unsafe { emulator_last_command(self.inst.as_ref().unwrap().to_owned()) }
+    fn last_command(&self) -> Result<Command, EmulatorError> {
+        self.call(|inst| RawCommand::fetch(|raw| unsafe { emulator_last_command(inst, raw) }))
     }
 
-    fn remove_command(&mut self, index: usize) {
-        unsafe { emulator_remove_command(self.inst.as_mut().unwrap().to_owned(), index as i32); }
+    // RemoveCommand returns false even when it removed the command, so the
+    // count tells whether it did
+    fn remove_command(&mut self, index: usize) -> Result<(), EmulatorError> {
+        let count = self.commands_count();
+        self.call(|inst| unsafe { emulator_remove_command(inst, index as i32); })?;
+        match self.commands_count() < count {
+            true => Ok(()),
+            false => Err(EmulatorError::new(FIXED_COMMAND)),
+        }
     }
 
-    fn move_command(&mut self, _index: usize, _new_pos: usize) {
-        todo!()
+    fn move_command(&mut self, _index: usize, _new_pos: usize) -> Result<(), EmulatorError> {
+        Err(EmulatorError::new("the original engine can't move commands"))
     }
 
     fn commands_count(&self) -> usize {
         unsafe { emulator_commands_count(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
-    fn executed_command(&self) -> Command {
-        unsafe { emulator_executed_command(self.inst.as_ref().unwrap().to_owned()) }
+    fn executed_command(&self) -> Result<Command, EmulatorError> {
+        self.call(|inst| RawCommand::fetch(|raw| unsafe { emulator_executed_command(inst, raw) }))
     }
 
-    fn exec_one(&mut self) {
-        unsafe { emulator_exec_one(self.inst.as_mut().unwrap().to_owned()); }
+    fn exec_one(&mut self) -> Result<ExecResult, EmulatorError> {
+        let code = self.call(|inst| unsafe { emulator_exec_one(inst) })?;
+        ExecResult::from_code(code, self.get_pc())
     }
 
-    fn exec_one_call(&mut self) {
-        unsafe { emulator_exec_one_call(self.inst.as_mut().unwrap().to_owned()); }
+    fn exec_one_call(&mut self) -> Result<ExecResult, EmulatorError> {
+        let code = self.call(|inst| unsafe { emulator_exec_one_call(inst) })?;
+        ExecResult::from_code(code, self.get_pc())
     }
 
-    fn exec_all(&mut self) {
-        unsafe { emulator_exec_all(self.inst.as_mut().unwrap().to_owned()); }
+    fn exec_all(&mut self) -> Result<ExecResult, EmulatorError> {
+        let code = self.call(|inst| unsafe { emulator_exec_all(inst) })?;
+        ExecResult::from_code(code, self.get_pc())
     }
 
     fn get_next_index(&self) -> usize {
@@ -321,20 +612,24 @@ impl MT1804Emulator for OriginalImplementation {
         unsafe { emulator_get_call_index(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
+    fn get_last_call_cycles(&self) -> usize {
+        unsafe { emulator_get_last_call_cycles(self.inst.as_ref().unwrap().to_owned()) as usize }
+    }
+
     fn get_pc(&self) -> usize {
         unsafe { emulator_get_pc(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
-    fn set_pc(&mut self, pc: usize) {
-        unsafe { emulator_set_pc(self.inst.as_mut().unwrap().to_owned(), pc as i32); }
+    fn set_pc(&mut self, pc: usize) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_pc(inst, pc as i32); })
     }
 
     fn get_sp(&self) -> usize {
         unsafe { emulator_get_sp(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
-    fn set_sp(&mut self, sp: usize) {
-        unsafe { emulator_set_sp(self.inst.as_mut().unwrap().to_owned(), sp as i32); }
+    fn set_sp(&mut self, sp: usize) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_sp(inst, sp as i32); })
     }
 
     fn get_stack(&self) -> Vec<i32> {
@@ -353,12 +648,12 @@ impl MT1804Emulator for OriginalImplementation {
         unsafe { emulator_get_mp(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
-    fn get_port(&self) -> usize {
-        unsafe { emulator_get_port(self.inst.as_ref().unwrap().to_owned()) as usize }
+    fn get_port(&self) -> Option<usize> {
+        port::from_pointer(unsafe { emulator_get_port(self.inst.as_ref().unwrap().to_owned()) })
     }
 
-    fn get_mem_value(&self, index: usize) -> usize {
-        unsafe { emulator_get_mem_value(self.inst.as_ref().unwrap().to_owned(), index as i32) as usize }
+    fn get_mem_value(&self, index: usize) -> Result<usize, EmulatorError> {
+        self.call(|inst| unsafe { emulator_get_mem_value(inst, index as i32) as usize })
     }
 
     fn get_mem_length(&self) -> usize {
@@ -384,8 +679,8 @@ impl MT1804Emulator for OriginalImplementation {
         unsafe { emulator_get_reg_q(self.inst.as_ref().unwrap().to_owned()) as u8 }
     }
 
-    fn get_reg(&self, index: usize) -> u8 {
-        unsafe { emulator_get_reg_value(self.inst.as_ref().unwrap().to_owned(), index as i32) as u8 }
+    fn get_reg(&self, index: usize) -> Result<u8, EmulatorError> {
+        self.call(|inst| unsafe { emulator_get_reg_value(inst, index as i32) as u8 })
     }
 
     fn get_f(&self) -> u8 {
@@ -440,70 +735,129 @@ impl MT1804Emulator for OriginalImplementation {
         unsafe { emulator_get_p(self.inst.as_ref().unwrap().to_owned()) as u8 }
     }
 
-    fn add_call(&mut self, index: usize, call: Call) {
-        unsafe { emulator_add_call(self.inst.as_mut().unwrap().to_owned(), index as i32, call); }
+    fn set_reg(&mut self, index: usize, value: u8) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_reg_value(inst, index as i32, value as i32) })
+    }
+
+    fn set_reg_q(&mut self, value: u8) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_reg_q(inst, value as i32) })
+    }
+
+    fn set_flag(&mut self, flag: Flag, value: bool) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_flag(inst, flag as i32, value) })
+    }
+
+    fn set_mp(&mut self, value: usize) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_mp(inst, value as i32) })
+    }
+
+    fn set_mem_value(&mut self, index: usize, value: u8) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_mem_value(inst, index as i32, value as i32) })
+    }
+
+    fn set_stack_value(&mut self, index: usize, value: usize) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_set_stack_value(inst, index as i32, value as i32) })
+    }
+
+    fn get_exec_state(&self) -> Vec<i32> {
+        let mut state: *mut i32 = std::ptr::null_mut();
+        let mut state_len: libc::size_t = 0;
+        unsafe { emulator_get_exec_state(self.inst.as_ref().unwrap().to_owned(), &mut state, &mut state_len); }
+        let mut state_cpy = Vec::<i32>::with_capacity(state_len);
+        for i in 0..state_len {
+            unsafe { state_cpy.push(state.add(i).read()); }
+        }
+        unsafe { libc::free(state as *mut libc::c_void); }
+        state_cpy
+    }
+
+    fn set_exec_state(&mut self, state: &[i32]) -> Result<(), EmulatorError> {
+        let mut state_copy = state.to_owned();
+        let loaded = self.call(|inst| unsafe {
+            emulator_set_exec_state(inst, state_copy.as_mut_ptr(), state.len())
+        })?;
+        match loaded {
+            0 => Err(EmulatorError::new(BAD_EXEC_STATE)),
+            _ => Ok(()),
+        }
+    }
+
+    fn attach_device(&mut self, port: usize, device: Option<SharedDevice>) -> Result<(), EmulatorError> {
+        ORIGINAL_PORTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner).attach(port, device)
+    }
+
+    fn add_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_add_call(inst, index as i32, call) })
     }
 
-    fn get_call(&self, index: usize) -> Call {
-        unsafe { emulator_get_call(self.inst.as_ref().unwrap().to_owned(), index as i32) }
+    fn get_call(&self, index: usize) -> Result<Call, EmulatorError> {
+        self.call(|inst| unsafe { emulator_get_call(inst, index as i32) })
     }
 
-    fn update_call(&mut self, index: usize, call: Call) {
-        unsafe { emulator_update_call(self.inst.as_mut().unwrap().to_owned(), index as i32, call); }
+    fn update_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_update_call(inst, index as i32, call) })
     }
 
-    fn remove_call(&mut self, index: usize) {
-        unsafe { emulator_remove_call(self.inst.as_mut().unwrap().to_owned(), index as i32); }
+    fn remove_call(&mut self, index: usize) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_remove_call(inst, index as i32) })
     }
 
     fn call_count(&self) -> usize {
         unsafe { emulator_calls_count(self.inst.as_ref().unwrap().to_owned()) as usize }
     }
 
-    fn add_map_call(&mut self, libcall: &LibCall) {
+    fn add_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError> {
         let name = std::ffi::CString::new(libcall.name.as_str()).unwrap();
-        unsafe { emulator_add_map_call(self.inst.as_ref().unwrap().to_owned(), libcall.code, name.as_ptr(), libcall.addr); }
+        self.call(|inst| unsafe { emulator_add_map_call(inst, libcall.code, name.as_ptr(), libcall.addr); })
     }
 
-    fn remove_map_call(&mut self, code: i32) {
-        unsafe { emulator_remove_map_call(self.inst.as_ref().unwrap().to_owned(), code as i32); }
+    fn remove_map_call(&mut self, code: i32) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_remove_map_call(inst, code); })
     }
 
-    fn update_map_call(&mut self, libcall: &LibCall) {
+    fn update_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError> {
         let name = std::ffi::CString::new(libcall.name.as_str()).unwrap();
-        unsafe { emulator_update_map_call(self.inst.as_ref().unwrap().to_owned(), libcall.code, name.as_ptr(), libcall.addr); }
+        self.call(|inst| unsafe { emulator_update_map_call(inst, libcall.code, name.as_ptr(), libcall.addr); })
     }
 
-    fn get_map_calls(&self) -> Vec<LibCall> {
+    fn get_map_calls(&self) -> Result<Vec<LibCall>, EmulatorError> {
         let mut calls_cnt: u64 = 0;
-        let calls = unsafe { emulator_get_map_calls_codes(self.inst.as_ref().unwrap().to_owned(), &mut calls_cnt) };
+        let calls = self.call(|inst| unsafe { emulator_get_map_calls_codes(inst, &mut calls_cnt) })?;
         let mut callmap = Vec::<LibCall>::with_capacity(calls_cnt as usize);
         for i in 0..calls_cnt {
             let code = unsafe { calls.add(i as usize).read() };
-            let name = unsafe { emulator_get_map_call_name(self.inst.as_ref().unwrap().to_owned(), code) };
-            let name_stringified = String::from_utf8_lossy(unsafe { std::ffi::CStr::from_ptr(name).to_bytes() }).to_string();
-            unsafe {
-                free_obj(name as *mut libc::c_void);
-            }
-            let addr = unsafe { emulator_get_map_call_addr(self.inst.as_ref().unwrap().to_owned(), code) };
-            callmap.push(LibCall { code, name: name_stringified, addr });
+            let name = match self.call(|inst| unsafe { emulator_get_map_call_name(inst, code) }) {
+                Ok(name) => unsafe { Self::take_string(name) },
+                Err(err) => {
+                    unsafe { libc::free(calls as *mut libc::c_void); }
+                    return Err(err);
+                }
+            };
+            let addr = match self.call(|inst| unsafe { emulator_get_map_call_addr(inst, code) }) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    unsafe { libc::free(calls as *mut libc::c_void); }
+                    return Err(err);
+                }
+            };
+            callmap.push(LibCall { code, name, addr });
         }
         unsafe { libc::free(calls as *mut libc::c_void); }
-        callmap
+        Ok(callmap)
     }
 
-    fn last_call(&self) -> Call {
-        unsafe { emulator_last_call(self.inst.as_ref().unwrap().to_owned()) }
+    fn last_call(&self) -> Result<Call, EmulatorError> {
+        self.call(|inst| unsafe { emulator_last_call(inst) })
     }
 
-    fn open_raw(&mut self, bytes: &[u8]) {
+    fn open_raw(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
         let mut bytes_copy = bytes.to_owned();
-        unsafe {
-            emulator_open_raw(
-                self.inst.as_mut().unwrap().to_owned(),
-                bytes_copy.as_mut_ptr(),
-                bytes.len(),
-            );
+        let opened = self.call(|inst| unsafe {
+            emulator_open_raw(inst, bytes_copy.as_mut_ptr(), bytes.len())
+        })?;
+        match opened {
+            0 => Err(EmulatorError::new(BAD_PROGRAM_FILE)),
+            _ => Ok(()),
         }
     }
     fn export_raw(&self) -> Vec<u8> {
@@ -517,50 +871,17 @@ impl MT1804Emulator for OriginalImplementation {
         unsafe { libc::free(bytes as *mut libc::c_void); }
         bytes_cpy
     }
-    fn init_library(&self) {
-        unsafe { emulator_init_library(self.inst.as_ref().unwrap().to_owned()); }
+    fn init_library(&mut self) -> Result<(), EmulatorError> {
+        self.call(|inst| unsafe { emulator_init_library(inst) })
     }
-    fn command_get_name(&self, cmd: Command) -> String {
-        let name = unsafe { command_get_name(self.inst.as_ref().unwrap().to_owned(), cmd) };
-        let res = String::from_utf8_lossy(unsafe { std::ffi::CStr::from_ptr(name).to_bytes() }).to_string();
-        unsafe {
-            free_obj(name as *mut libc::c_void);
-        }
-        res
+    fn command_get_name(&self, cmd: Command) -> Result<String, EmulatorError> {
+        let name = self.call(|inst| unsafe { command_get_name(inst, RawCommand::borrow(&cmd)) })?;
+        Ok(unsafe { Self::take_string(name) })
     }
 
-    fn command_get_jump_name(&self, cmd: Command) -> String {
-        let name = unsafe { command_get_jump_name(self.inst.as_ref().unwrap().to_owned(), cmd) };
-        let res = String::from_utf8_lossy(unsafe { std::ffi::CStr::from_ptr(name).to_bytes() }).to_string();
-        unsafe {
-            free_obj(name as *mut libc::c_void);
-        }
-        res
-    }
-    fn get_state(&self) -> State {
-        State {
-            program_counter: self.get_pc(),
-            stack_pointer: self.get_sp(),
-            // stack_value: self.get_stack_value(),
-            multiplexor_value: self.get_mp(),
-            // port_value: self.get_port(),
-            // mem_value: self.get_mem_value(),
-            registers: (0..16)
-                .into_iter()
-                .map(|ind| { self.get_reg(ind) })
-                .chain([self.get_reg_q()].into_iter())
-                .collect(),
-            flags: [
-                self.get_ovr(),
-                self.get_c4(),
-                self.get_f3(),
-                self.get_z(),
-                self.get_g(),
-                self.get_p()
-            ],
-            func_output: self.get_f(),
-            func_value: self.get_y(),
-        }
+    fn command_get_jump_name(&self, cmd: Command) -> Result<String, EmulatorError> {
+        let name = self.call(|inst| unsafe { command_get_jump_name(inst, RawCommand::borrow(&cmd)) })?;
+        Ok(unsafe { Self::take_string(name) })
     }
 }
 
@@ -576,7 +897,52 @@ impl std::ops::Drop for OriginalImplementation {
     }
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Debug)]
+pub enum Implementation {
+    Original(OriginalImplementation),
+    Native(NativeImplementation),
+}
+
+impl Implementation {
+    pub fn swap(&mut self, oth: &mut Implementation) {
+        match (self, oth) {
+            (Self::Original(inst), Self::Original(oth)) => inst.swap(oth),
+            (inst, oth) => std::mem::swap(inst, oth),
+        }
+    }
+
+    // has to be called on a new thread before it uses the emulator
+    pub fn attach_current_thread(&self) -> Option<ThreadAttachment> {
+        match self {
+            Self::Original(OriginalImplementation { inst: Some(inst) }) => {
+                Some(ThreadAttachment(unsafe { emulator_attach_thread(*inst) }))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::Deref for Implementation {
+    type Target = dyn MT1804Emulator;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Original(inst) => inst,
+            Self::Native(inst) => inst,
+        }
+    }
+}
+
+impl std::ops::DerefMut for Implementation {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Original(inst) => inst,
+            Self::Native(inst) => inst,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize)]
 pub struct State {
     pub program_counter: usize,
     pub stack_pointer: usize,
@@ -0,0 +1,2296 @@
+/* emulator/native.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Pure Rust port of implementation/Emulator.cs. Every quirk of the managed
+// engine is kept on purpose, results must stay identical to the C# side.
+
+use super::port::{self, Access, Ports, SharedDevice, Width};
+use super::{Call, Command, EmulatorError, ExecResult, Flag, LibCall, MT1804Emulator};
+
+const WORD_SIZE: i32 = 4;
+const COMMAND_LEN: usize = 10;
+const COMMAND_SIZE: usize = 5;
+const NAME_MAX_SIZE: usize = 16;
+const FILE_HEADER: &[u8] = b"MTEM";
+
+const PROGRAM_SIZE: i32 = 1 << 12;
+const USER_PROGRAM_SIZE: i32 = 0xf00;
+const STACK_SIZE: i32 = 1 << 4;
+const REG_SIZE: usize = 1 << 4;
+const MEM_SIZE: i32 = 1 << 8;
+// commands a call or exec_all may take before it is given up as a loop
+pub const MAX_AUTO_COUNT: usize = 1 << 14;
+
+const AR_HIGH: usize = 0;
+const AR_MID: usize = 1;
+const AR_LOW: usize = 2;
+const CA: usize = 3;
+const I68: usize = 4;
+const I02: usize = 5;
+const I35: usize = 6;
+const A: usize = 7;
+const B: usize = 8;
+const D: usize = 9;
+const PT: usize = I02;
+const PS: usize = I02;
+const DEVICE: usize = A;
+
+const JUMP_NAMES: [&str; 16] = [
+    "JNZ", "JMP", "JNXT", "END/LDM", "CLNZ", "CALL", "RET", "JSP",
+    "JSNZ", "PUSH", "POP", "JSNC4", "JZ", "JF3", "JOVR", "JC4",
+];
+
+const TO_NAMES: [&str; 8] = [
+    "PQ=F",
+    "Нет загрузки",
+    "Y=РОН(A), РОН(B)=F",
+    "РОН(B)=F",
+    "PQ=Q/2, РОН(B)=F/2",
+    "РОН(B)=F/2",
+    "PQ=2Q, РОН(B)=2F",
+    "РОН(B)=2F",
+];
+
+const FROM_NAMES: [(&str, &str); 8] = [
+    ("РОН(A)", "PQ"),
+    ("РОН(A)", "РОН(B)"),
+    ("0", "PQ"),
+    ("0", "РОН(B)"),
+    ("0", "РОН(A)"),
+    ("D", "РОН(A)"),
+    ("D", "PQ"),
+    ("D", "0"),
+];
+
+const FUNC_NAMES: [(&str, &str); 16] = [
+    ("R+S+C0", "0"),
+    ("S-R-1+C0", "0"),
+    ("R-S-1+C0", "0"),
+    ("R∨S", "-"),
+    ("R∧S", "-"),
+    ("¬R∧S", "-"),
+    ("R⊕S", "-"),
+    ("¬(R⊕S)", "-"),
+    ("R+S+C0", "1"),
+    ("S-R-1+C0", "1"),
+    ("R-S-1+C0", "1"),
+    ("Set Ptr", "-"),
+    ("Str Mem", "-"),
+    ("Load Mem", "-"),
+    ("Str Dev", "-"),
+    ("Load Dev", "-"),
+];
+
+const DEVICE_COUNT: i32 = 4;
+
+// the managed engine throws wherever its lists are indexed out of range,
+// report the same cases instead of panicking
+fn check_index(index: usize, len: usize) -> Result<(), EmulatorError> {
+    if index < len {
+        return Ok(());
+    }
+    Err(EmulatorError::new(format!(
+        "index {} is out of range, there are only {} elements",
+        index as i32, len
+    )))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ViewType {
+    MtCommand,
+    MemoryPointer,
+    DevicePointer,
+    Load8Bit,
+    LoadHigh4Bit,
+    LoadLow4Bit,
+    Offset,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JumpType {
+    Jnz,
+    Jmp,
+    Jnxt,
+    End,
+    Clnz,
+    Call,
+    Ret,
+    Jsp,
+    Jsnz,
+    Push,
+    Pop,
+    Jsnc4,
+    Jz,
+    Jf3,
+    Jovr,
+    Jc4,
+    Unknown,
+}
+
+impl From<i32> for JumpType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::Jnz,
+            1 => Self::Jmp,
+            2 => Self::Jnxt,
+            3 => Self::End,
+            4 => Self::Clnz,
+            5 => Self::Call,
+            6 => Self::Ret,
+            7 => Self::Jsp,
+            8 => Self::Jsnz,
+            9 => Self::Push,
+            10 => Self::Pop,
+            11 => Self::Jsnc4,
+            12 => Self::Jz,
+            13 => Self::Jf3,
+            14 => Self::Jovr,
+            15 => Self::Jc4,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FuncType {
+    RPlusS,
+    SMinusRMinus1,
+    RMinusSMinus1,
+    ROrS,
+    RAndS,
+    NoRAndS,
+    RXorS,
+    REqS,
+    RPlusSPlus1,
+    SMinusR,
+    RMinusS,
+    SetPointer,
+    StoreMemory,
+    LoadMemory,
+    StoreDevice,
+    LoadDevice,
+    Unknown,
+}
+
+impl From<i32> for FuncType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::RPlusS,
+            1 => Self::SMinusRMinus1,
+            2 => Self::RMinusSMinus1,
+            3 => Self::ROrS,
+            4 => Self::RAndS,
+            5 => Self::NoRAndS,
+            6 => Self::RXorS,
+            7 => Self::REqS,
+            8 => Self::RPlusSPlus1,
+            9 => Self::SMinusR,
+            10 => Self::RMinusS,
+            11 => Self::SetPointer,
+            12 => Self::StoreMemory,
+            13 => Self::LoadMemory,
+            14 => Self::StoreDevice,
+            15 => Self::LoadDevice,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FromType {
+    AAndPq,
+    AAndB,
+    ZeroAndQ,
+    ZeroAndB,
+    ZeroAndA,
+    DAndA,
+    DAndQ,
+    DAndZero,
+}
+
+impl From<i32> for FromType {
+    fn from(value: i32) -> Self {
+        match value & 0b111 {
+            0 => Self::AAndPq,
+            1 => Self::AAndB,
+            2 => Self::ZeroAndQ,
+            3 => Self::ZeroAndB,
+            4 => Self::ZeroAndA,
+            5 => Self::DAndA,
+            6 => Self::DAndQ,
+            _ => Self::DAndZero,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ToType {
+    FInQ,
+    NoLoad,
+    FInBAndAInY,
+    FInB,
+    SrFInBAndSrQInQ,
+    SrFInB,
+    SlFInBAndSlQInQ,
+    SlFInB,
+}
+
+impl From<i32> for ToType {
+    fn from(value: i32) -> Self {
+        match value & 0b111 {
+            0 => Self::FInQ,
+            1 => Self::NoLoad,
+            2 => Self::FInBAndAInY,
+            3 => Self::FInB,
+            4 => Self::SrFInBAndSrQInQ,
+            5 => Self::SrFInB,
+            6 => Self::SlFInBAndSlQInQ,
+            _ => Self::SlFInB,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShiftType {
+    Logic,
+    Cycle,
+    CycleDouble,
+    ArithmeticDouble,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MemNextPoint {
+    No,
+    Plus,
+    Load,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DataPointerType {
+    Low4Bit,
+    High4Bit,
+    Full8Bit,
+    Unknown,
+}
+
+fn mask(value: i32) -> i32 {
+    mask_to(value, WORD_SIZE)
+}
+
+fn mask_to(value: i32, size: i32) -> i32 {
+    value & ((1 << size) - 1)
+}
+
+fn get_bit(value: i32, number: i32) -> i32 {
+    (value >> number) & 1
+}
+
+fn is_bit_set(value: i32, number: i32) -> bool {
+    get_bit(value, number) != 0
+}
+
+fn low_nibble(value: i32) -> i32 {
+    value & 0x0F
+}
+
+fn high_nibble(value: i32) -> i32 {
+    (value >> 4) & 0x0F
+}
+
+fn make_byte(high: i32, low: i32) -> i32 {
+    ((high << 4) & 0xF0) | (low & 0x0F)
+}
+
+fn port_name(number: i32) -> String {
+    if !(0..DEVICE_COUNT).contains(&number) {
+        return "NONE".to_owned();
+    }
+    format!("PORT{}", number)
+}
+
+#[derive(Clone, Debug)]
+struct NativeCommand {
+    is_offset: bool,
+    number: i32,
+    words: [i32; COMMAND_LEN],
+}
+
+impl NativeCommand {
+    fn incorrect() -> Self {
+        Self {
+            is_offset: false,
+            number: 0,
+            words: [0xF; COMMAND_LEN],
+        }
+    }
+
+    fn from_bin(words: [&str; COMMAND_LEN], is_offset: bool) -> Self {
+        let mut parsed = [0; COMMAND_LEN];
+        for (word, text) in parsed.iter_mut().zip(words) {
+            *word = mask(i32::from_str_radix(text, 2).unwrap_or_default());
+        }
+        Self {
+            is_offset,
+            number: 0,
+            words: parsed,
+        }
+    }
+
+    // the checks of the Command(int[]) constructor
+    fn manage(cmd: &Command) -> Result<Self, EmulatorError> {
+        if cmd.words.len() != COMMAND_LEN {
+            return Err(EmulatorError::new("Count of words must be equal 10!"));
+        }
+        let mut words = [0; COMMAND_LEN];
+        for (word, raw) in words.iter_mut().zip(&cmd.words) {
+            *word = mask(*raw);
+        }
+        Ok(Self {
+            is_offset: cmd.is_offset,
+            number: cmd.number,
+            words,
+        })
+    }
+
+    fn unmanage(&self) -> Command {
+        Command {
+            is_offset: self.is_offset,
+            number: self.number,
+            words: self.words.to_vec(),
+        }
+    }
+
+    fn raw(&self, index: usize) -> i32 {
+        self.words[index]
+    }
+
+    fn flag(&self, index: usize) -> bool {
+        is_bit_set(self.words[index], WORD_SIZE - 1)
+    }
+
+    fn next_addr(&self) -> i32 {
+        (self.raw(AR_HIGH) << (2 * WORD_SIZE)) + (self.raw(AR_MID) << WORD_SIZE) + self.raw(AR_LOW)
+    }
+
+    fn diff_addr(&self) -> i32 {
+        let mut addr = self.next_addr();
+        if is_bit_set(self.raw(AR_HIGH), WORD_SIZE - 1) {
+            addr = -mask_to(!addr + 1, WORD_SIZE * 3 - 1);
+        }
+        addr
+    }
+
+    fn jump_type(&self) -> JumpType {
+        JumpType::from(self.raw(CA))
+    }
+
+    fn func_type(&self) -> FuncType {
+        FuncType::from(self.raw(I35))
+    }
+
+    fn src_type(&self) -> FromType {
+        FromType::from(self.raw(I02))
+    }
+
+    fn to_type(&self) -> ToType {
+        ToType::from(self.raw(I68))
+    }
+
+    fn shift_type(&self) -> ShiftType {
+        match ((self.flag(I68) as u8) << 1) | self.flag(I02) as u8 {
+            0 => ShiftType::Logic,
+            1 => ShiftType::Cycle,
+            2 => ShiftType::CycleDouble,
+            _ => ShiftType::ArithmeticDouble,
+        }
+    }
+
+    fn inc_type(&self) -> MemNextPoint {
+        match self.raw(PT) {
+            0 => MemNextPoint::No,
+            1 => MemNextPoint::Plus,
+            2 => MemNextPoint::Load,
+            _ => MemNextPoint::Unknown,
+        }
+    }
+
+    fn pointer_type(&self) -> DataPointerType {
+        match self.raw(PS) {
+            0 => DataPointerType::Low4Bit,
+            1 => DataPointerType::High4Bit,
+            2 => DataPointerType::Full8Bit,
+            _ => DataPointerType::Unknown,
+        }
+    }
+
+    fn device_sel(&self) -> i32 {
+        match self.raw(DEVICE) {
+            raw if raw < DEVICE_COUNT => raw,
+            _ => -1,
+        }
+    }
+
+    fn check(&self) -> bool {
+        if self.is_offset {
+            return true;
+        }
+        if self.raw(I35) == 11 {
+            if self.raw(PT) > 2 && self.raw(PT) != 8 {
+                return false;
+            }
+            if self.raw(PT) == 8 && self.raw(DEVICE) > 3 {
+                return false;
+            }
+        }
+        if (12..=15).contains(&self.raw(I35)) && self.raw(PS) > 2 {
+            return false;
+        }
+        true
+    }
+
+    fn view(&self) -> ViewType {
+        let i35 = self.raw(I35);
+        if self.is_offset {
+            ViewType::Offset
+        } else if i35 <= 10 {
+            ViewType::MtCommand
+        } else if i35 == 11 {
+            if self.raw(PT) <= 7 {
+                ViewType::MemoryPointer
+            } else {
+                ViewType::DevicePointer
+            }
+        } else if (12..=15).contains(&i35) {
+            match self.raw(PS) {
+                0 => ViewType::LoadLow4Bit,
+                1 => ViewType::LoadHigh4Bit,
+                _ => ViewType::Load8Bit,
+            }
+        } else {
+            ViewType::Unknown
+        }
+    }
+
+    fn name(&self) -> String {
+        let (a, b, d) = (self.raw(A), self.raw(B), self.raw(D));
+        let mut res = String::new();
+        match self.view() {
+            ViewType::Offset => return format!("OFFSET = 0x{:03X}", self.next_addr()),
+            ViewType::MtCommand => {
+                if self.flag(I02) && self.raw(I68) < 5 {
+                    res += &format!("PPC[{}]=F=", self.raw(I68));
+                } else if self.to_type() == ToType::NoLoad {
+                    res += "Y=F=";
+                } else {
+                    let to = format!("{}=", TO_NAMES[(self.raw(I68) % 8) as usize])
+                        .replace("F/2=", "F/2;F=")
+                        .replace("2F=", "2F;F=")
+                        .replace(';', "; ");
+                    res += &to;
+                }
+                let (r, s) = FROM_NAMES[(self.raw(I02) % 8) as usize];
+                let (func, c0) = FUNC_NAMES[self.raw(I35) as usize];
+                res += &func.replace('R', r).replace('S', s).replace("C0", c0);
+                res = res
+                    .replace('A', &a.to_string())
+                    .replace('B', &b.to_string())
+                    .replace('D', &d.to_string())
+                    .replace("+0", "")
+                    .replace("-0", "")
+                    .replace("-1+1", "");
+                res += &format!("; M1={}", self.flag(I68) as u8);
+                res += &format!("; M0={}", self.flag(I02) as u8);
+            }
+            ViewType::MemoryPointer => match self.inc_type() {
+                MemNextPoint::No => res += &format!("MemoryPtr=0x{:02X}", (a << 4) + b),
+                MemNextPoint::Plus => res += "MemoryPtr=MemoryPtr+1",
+                MemNextPoint::Load => {
+                    res += &format!("MemoryPtr=0x(РОН({}) << 4 + РОН({}))", a, b)
+                }
+                MemNextPoint::Unknown => {}
+            },
+            ViewType::DevicePointer => {
+                res += &format!("Interface={}", port_name(self.device_sel()))
+            }
+            ViewType::LoadHigh4Bit | ViewType::LoadLow4Bit | ViewType::Load8Bit => {
+                let ps = self.raw(PS);
+                match self.func_type() {
+                    FuncType::StoreMemory => match ps {
+                        0 => res += &format!("LOW(Memory(Ptr))=РОН({})", b),
+                        1 => res += &format!("HIGH(Memory(Ptr))=РОН({})", a),
+                        _ => res += &format!("Memory(Ptr)=(РОН({})<<4)+РОН({})", a, b),
+                    },
+                    FuncType::LoadMemory => match ps {
+                        0 => res += &format!("РОН({})=LOW(Memory(Ptr))", b),
+                        1 => res += &format!("РОН({})=HIGH(Memory(Ptr))", a),
+                        _ => {
+                            res += &format!("РОН({})=HIGH(Memory(Ptr))", a);
+                            res += &format!("; РОН({})=LOW(Memory(Ptr))", b);
+                        }
+                    },
+                    FuncType::StoreDevice => {
+                        match self.pointer_type() {
+                            DataPointerType::Low4Bit => {
+                                res += &format!("LOW(DEV_PORT)=РОН({}); ", b)
+                            }
+                            DataPointerType::High4Bit => {
+                                res += &format!("HIGH(DEV_PORT)=РОН({}); ", a)
+                            }
+                            DataPointerType::Full8Bit => {
+                                res += &format!("DEV_PORT=(РОН({})<<4)+РОН({}); ", a, b)
+                            }
+                            DataPointerType::Unknown => {}
+                        }
+                        res += &format!("DEVICE_ADDR={}", d);
+                    }
+                    FuncType::LoadDevice => {
+                        match self.pointer_type() {
+                            DataPointerType::Low4Bit => {
+                                res += &format!("РОН({})=LOW(DEV_PORT); ", b)
+                            }
+                            DataPointerType::High4Bit => {
+                                res += &format!("РОН({})=HIGH(DEV_PORT); ", a)
+                            }
+                            DataPointerType::Full8Bit => {
+                                res += &format!("РОН({})=HIGH(DEV_PORT); ", a);
+                                res += &format!("; РОН({})=LOW(DEV_PORT); ", b);
+                            }
+                            DataPointerType::Unknown => {}
+                        }
+                        res += &format!("DEVICE_ADDR={}", d);
+                    }
+                    _ => {}
+                }
+            }
+            ViewType::Unknown => {
+                res += &self
+                    .words
+                    .iter()
+                    .map(|word| word.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ");
+            }
+        }
+        res
+    }
+
+    fn jump_name(&self) -> String {
+        let jump = self.jump_type();
+        if jump == JumpType::End {
+            let diff = self.diff_addr();
+            return match diff {
+                0 => "LDNXT".to_owned(),
+                diff if diff > 0 => format!("LDNXT+0x{:03X}", diff),
+                diff => format!("LDNXT-0x{:03X}", -diff),
+            };
+        }
+        let mut res = JUMP_NAMES[self.raw(CA) as usize % JUMP_NAMES.len()].to_owned();
+        match jump {
+            JumpType::Jnz
+            | JumpType::Jmp
+            | JumpType::Clnz
+            | JumpType::Call
+            | JumpType::Jz
+            | JumpType::Jf3
+            | JumpType::Jovr
+            | JumpType::Jc4 => res += &format!(" 0x{:03X}", self.next_addr()),
+            _ => {}
+        }
+        res
+    }
+}
+
+#[derive(Clone, Debug)]
+struct NativeCall {
+    code: i32,
+    arg0: i32,
+    arg1: i32,
+    alt_command_address: bool,
+    flag: u8,
+}
+
+impl NativeCall {
+    fn new(code: i32, arg0: i32, arg1: i32) -> Self {
+        Self {
+            code,
+            arg0,
+            arg1,
+            alt_command_address: false,
+            flag: 255,
+        }
+    }
+}
+
+// Mimics the ordering of the Dictionary used by the C# engine: removed entries
+// leave a hole which is reused by the next insertion.
+#[derive(Clone, Debug, Default)]
+struct CallMap {
+    entries: Vec<Option<LibCall>>,
+    free: Vec<usize>,
+}
+
+impl CallMap {
+    fn get(&self, code: i32) -> Option<&LibCall> {
+        self.entries.iter().flatten().find(|call| call.code == code)
+    }
+
+    fn contains(&self, code: i32) -> bool {
+        self.get(code).is_some()
+    }
+
+    fn insert(&mut self, call: LibCall) {
+        match self.free.pop() {
+            Some(slot) => self.entries[slot] = Some(call),
+            None => self.entries.push(Some(call)),
+        }
+    }
+
+    fn remove(&mut self, code: i32) {
+        let Some(slot) = self
+            .entries
+            .iter()
+            .position(|call| call.as_ref().is_some_and(|call| call.code == code))
+        else {
+            return;
+        };
+        self.entries[slot] = None;
+        self.free.push(slot);
+    }
+
+    fn update(&mut self, call: LibCall) {
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.code == call.code)
+        else {
+            return;
+        };
+        *entry = call;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &LibCall> {
+        self.entries.iter().flatten()
+    }
+
+    fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.free.clear();
+    }
+
+    fn library() -> Self {
+        let mut map = Self::default();
+        for (code, name, addr) in [
+            (0, "JMP", 0xffb),
+            (1, "JC", 0xffc),
+            (2, "JZ", 0xffd),
+            (3, "JNC", 0xffe),
+            (4, "JNZ", 0xfff),
+            (5, "A + B", 0xf00),
+            (6, "A - B", 0xf0b),
+        ] {
+            map.insert(LibCall::new(code, name.to_owned(), addr));
+        }
+        map
+    }
+}
+
+fn library_commands() -> Vec<NativeCommand> {
+    let c = |words| NativeCommand::from_bin(words, false);
+    let offset = |words| NativeCommand::from_bin(words, true);
+    let jump = || c(["0000", "0000", "0000", "0011", "0001", "0111", "0000", "0000", "0000", "0000"]);
+    vec![
+        // A + B
+        offset(["1111", "0000", "0000", "0000", "0000", "0000", "0000", "0000", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0010", "1101", "0001", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0001", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0010", "1101", "0011", "0010", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0001", "0000", "0010", "0000", "0000"]),
+        c(["1111", "0000", "0111", "1111", "0011", "0010", "0000", "0000", "0100", "0000"]),
+        c(["1111", "0000", "1000", "0001", "0000", "0001", "0000", "0011", "0001", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0001", "1000", "0011", "0001", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0011", "0010", "0000", "0000", "0101", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0010", "0000"]),
+        c(["0000", "0000", "0000", "0011", "0000", "0010", "1100", "0101", "0100", "0000"]),
+        // A - B
+        offset(["1111", "0000", "1011", "0000", "0000", "0000", "0000", "0000", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0010", "1101", "0001", "0000", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0001", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0010", "1101", "0011", "0010", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0001", "1010", "0000", "0010", "0000"]),
+        c(["1111", "0001", "0010", "1111", "0011", "0010", "0000", "0000", "0100", "0000"]),
+        c(["1111", "0001", "0011", "0001", "0000", "0001", "0010", "0001", "0011", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0001", "1010", "0001", "0011", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0011", "0010", "0000", "0000", "0101", "0000"]),
+        c(["0000", "0000", "0000", "0010", "0000", "0000", "1011", "0000", "0010", "0000"]),
+        c(["0000", "0000", "0000", "0011", "0000", "0010", "1100", "0101", "0100", "0000"]),
+        // JUMPS
+        offset(["1111", "1111", "1011", "0000", "0000", "0000", "0000", "0000", "0000", "0000"]),
+        jump(),
+        jump(),
+        jump(),
+        jump(),
+        jump(),
+    ]
+}
+
+#[derive(Clone, Debug)]
+pub struct NativeImplementation {
+    prev_pc: i32,
+    pc: i32,
+    call_index: i32,
+    last_call_cycles: usize,
+    end: bool,
+
+    commands: Vec<NativeCommand>,
+    calls: Vec<NativeCall>,
+    map_calls: CallMap,
+
+    sp: i32,
+    stack: [i32; STACK_SIZE as usize],
+
+    reg_q: i32,
+    reg_common: [i32; REG_SIZE],
+
+    inc: MemNextPoint,
+    mp: i32,
+    memory: [i32; MEM_SIZE as usize],
+
+    dev_ptr: i32,
+    ports: Ports,
+
+    prev_reg_a: i32,
+    prev_reg_b: i32,
+    prev_reg_q: i32,
+    r: i32,
+    s: i32,
+
+    f: i32,
+    y: i32,
+
+    z: bool,
+    f3: bool,
+    c4: bool,
+    ovr: bool,
+    g: bool,
+    p: bool,
+
+    prev_z: bool,
+    prev_f3: bool,
+    prev_c4: bool,
+    prev_ovr: bool,
+    prev_g: bool,
+    prev_p: bool,
+}
+
+impl Default for NativeImplementation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeImplementation {
+    pub fn new() -> Self {
+        let mut emul = Self {
+            prev_pc: -1,
+            pc: -1,
+            call_index: 0,
+            last_call_cycles: 0,
+            end: false,
+            commands: Vec::new(),
+            calls: Vec::new(),
+            map_calls: CallMap::default(),
+            sp: 0,
+            stack: [0; STACK_SIZE as usize],
+            reg_q: 0,
+            reg_common: [0; REG_SIZE],
+            inc: MemNextPoint::No,
+            mp: 0,
+            memory: [0; MEM_SIZE as usize],
+            dev_ptr: -1,
+            ports: Ports::new(),
+            prev_reg_a: 0,
+            prev_reg_b: 0,
+            prev_reg_q: 0,
+            r: 0,
+            s: 0,
+            f: 0,
+            y: 0,
+            z: false,
+            f3: false,
+            c4: false,
+            ovr: false,
+            g: false,
+            p: false,
+            prev_z: false,
+            prev_f3: false,
+            prev_c4: false,
+            prev_ovr: false,
+            prev_g: false,
+            prev_p: false,
+        };
+        emul.reset_state();
+        emul
+    }
+
+    fn reset_state(&mut self) {
+        self.prev_pc = -1;
+        self.pc = -1;
+        self.call_index = 0;
+        self.last_call_cycles = 0;
+        self.end = false;
+
+        self.sp = 0;
+        self.reg_q = 0;
+        self.reg_common = [0; REG_SIZE];
+        self.inc = MemNextPoint::No;
+        self.mp = 0;
+        self.dev_ptr = -1;
+
+        self.prev_reg_a = 0;
+        self.prev_reg_b = 0;
+        self.prev_reg_q = 0;
+        self.r = 0;
+        self.s = 0;
+
+        self.f = 0;
+        self.y = 0;
+
+        self.z = false;
+        self.f3 = false;
+        self.c4 = false;
+        self.ovr = false;
+        self.g = false;
+        self.p = false;
+
+        self.prev_z = false;
+        self.prev_f3 = false;
+        self.prev_c4 = false;
+        self.prev_ovr = false;
+        self.prev_g = false;
+        self.prev_p = false;
+    }
+
+    fn backup_flags(&mut self) {
+        self.prev_z = self.z;
+        self.prev_f3 = self.f3;
+        self.prev_c4 = self.c4;
+        self.prev_ovr = self.ovr;
+        self.prev_g = self.g;
+        self.prev_p = self.p;
+    }
+
+    fn restore_flags(&mut self) {
+        self.z = self.prev_z;
+        self.f3 = self.prev_f3;
+        self.c4 = self.prev_c4;
+        self.ovr = self.prev_ovr;
+        self.g = self.prev_g;
+        self.p = self.prev_p;
+    }
+
+    // the library and the offset in front of it
+    fn is_fixed(&self, index: usize) -> bool {
+        let cmd = &self.commands[index];
+        cmd.number >= USER_PROGRAM_SIZE || cmd.is_offset && cmd.number >= USER_PROGRAM_SIZE - 1
+    }
+
+    fn last_command_before_offset(&self, mut index: usize) -> usize {
+        while index < self.commands.len() {
+            if self.commands[index].is_offset {
+                break;
+            }
+            index += 1;
+        }
+        index
+    }
+
+    fn offset_of(&self, index: usize) -> i32 {
+        match index {
+            0 => 0,
+            index => self.commands[index - 1].number + 1,
+        }
+    }
+
+    fn update_offsets(&mut self, first: usize) {
+        for i in first..self.commands.len() {
+            self.commands[i].number = match self.commands[i].is_offset {
+                true => self.commands[i].next_addr() - 1,
+                false => self.offset_of(i),
+            };
+        }
+    }
+
+    fn number_for(&self, index: usize, cmd: &NativeCommand) -> i32 {
+        match cmd.is_offset {
+            true => cmd.next_addr() - 1,
+            false => self.offset_of(index),
+        }
+    }
+
+    fn fits_user_program(&self, index: usize, number: i32) -> bool {
+        if number >= USER_PROGRAM_SIZE {
+            return false;
+        }
+        let tail = self.last_command_before_offset(index + 1) as i32 - index as i32 - 1;
+        tail + number < USER_PROGRAM_SIZE
+    }
+
+    fn add_user_command(&mut self, index: usize, mut cmd: NativeCommand) -> bool {
+        if !cmd.check() || index > self.commands.len() {
+            return false;
+        }
+        cmd.number = self.number_for(index, &cmd);
+        if !self.fits_user_program(index, cmd.number) {
+            return false;
+        }
+        self.commands.insert(index, cmd);
+        self.update_offsets(index + 1);
+        true
+    }
+
+    fn add_lib_command(&mut self, mut cmd: NativeCommand) -> bool {
+        let index = self.commands.len();
+        if !cmd.check() {
+            return false;
+        }
+        cmd.number = self.number_for(index, &cmd);
+        self.commands.push(cmd);
+        true
+    }
+
+    fn update_user_command(&mut self, index: usize, mut cmd: NativeCommand) -> bool {
+        if !cmd.check() || index >= self.commands.len() {
+            return false;
+        }
+        cmd.number = self.number_for(index, &cmd);
+        if !self.fits_user_program(index, cmd.number) {
+            return false;
+        }
+        self.commands[index] = cmd;
+        self.update_offsets(index + 1);
+        true
+    }
+
+    fn index_of(&self, addr: i32) -> Option<usize> {
+        self.commands
+            .iter()
+            .position(|cmd| cmd.number == addr && !cmd.is_offset)
+    }
+
+    fn command_at(&self, addr: i32) -> NativeCommand {
+        match self.index_of(addr) {
+            Some(index) => self.commands[index].clone(),
+            None => NativeCommand::incorrect(),
+        }
+    }
+
+    fn current(&self) -> NativeCommand {
+        self.command_at(self.pc)
+    }
+
+    fn stack_addr(sp: i32) -> i32 {
+        (sp + STACK_SIZE) % STACK_SIZE
+    }
+
+    fn addr_by_code(&self, code: i32) -> i32 {
+        self.map_calls.get(code).map(|call| call.addr).unwrap_or(-1)
+    }
+
+    fn push(&mut self, value: i32) {
+        self.stack[self.sp as usize] = value;
+        self.sp = Self::stack_addr(self.sp + 1);
+    }
+
+    fn jump(&mut self, cmd: &NativeCommand) {
+        self.prev_pc = self.pc;
+
+        match cmd.jump_type() {
+            JumpType::End => {
+                if self.calls.is_empty() || self.calls.len() as i32 <= self.call_index {
+                    self.end = true;
+                } else {
+                    self.pc = self.addr_by_code(self.calls[self.call_index as usize].code);
+                }
+                return;
+            }
+            JumpType::Jmp => {
+                self.pc = cmd.next_addr();
+                return;
+            }
+            JumpType::Jnxt => {
+                self.pc += 1;
+                return;
+            }
+            JumpType::Jnz => self.jump_if(!self.prev_z, cmd),
+            JumpType::Jz => self.jump_if(self.prev_z, cmd),
+            JumpType::Jf3 => self.jump_if(self.prev_f3, cmd),
+            JumpType::Jovr => self.jump_if(self.prev_ovr, cmd),
+            JumpType::Jc4 => self.jump_if(self.prev_c4, cmd),
+            JumpType::Call => {
+                self.push(self.pc + 1);
+                self.pc = cmd.next_addr();
+                return;
+            }
+            JumpType::Ret => {
+                self.sp = Self::stack_addr(self.sp - 1);
+                self.pc = self.stack[self.sp as usize];
+                return;
+            }
+            JumpType::Jsp => {
+                self.pc = self.stack[Self::stack_addr(self.sp - 1) as usize];
+                return;
+            }
+            JumpType::Push => {
+                self.push(self.pc + 1);
+                self.pc += 1;
+                return;
+            }
+            JumpType::Pop => {
+                self.sp = Self::stack_addr(self.sp - 1);
+                self.pc += 1;
+                return;
+            }
+            JumpType::Clnz => {
+                if !self.prev_z {
+                    self.push(self.pc + 1);
+                    self.pc = cmd.next_addr();
+                } else {
+                    self.pc += 1;
+                }
+            }
+            JumpType::Jsnz => self.loop_if(!self.prev_z),
+            JumpType::Jsnc4 => self.loop_if(!self.prev_c4),
+            JumpType::Unknown => {}
+        }
+
+        self.restore_flags();
+    }
+
+    fn jump_if(&mut self, cond: bool, cmd: &NativeCommand) {
+        if cond {
+            self.pc = cmd.next_addr();
+        } else {
+            self.pc += 1;
+        }
+    }
+
+    fn loop_if(&mut self, cond: bool) {
+        if cond {
+            self.pc = self.stack[Self::stack_addr(self.sp - 1) as usize];
+        } else {
+            self.sp = Self::stack_addr(self.sp - 1);
+            self.pc += 1;
+        }
+    }
+
+    fn count_flags(&mut self, alu: FuncType) {
+        let c0 = matches!(
+            alu,
+            FuncType::RPlusSPlus1 | FuncType::SMinusR | FuncType::RMinusS
+        );
+
+        let mut r = self.r;
+        let mut s = self.s;
+        match alu {
+            FuncType::SMinusRMinus1 | FuncType::SMinusR | FuncType::NoRAndS | FuncType::RXorS => {
+                r = mask(!r)
+            }
+            FuncType::RMinusSMinus1 | FuncType::RMinusS => s = mask(!s),
+            _ => {}
+        }
+
+        let mut p = r | s;
+        let mut g = r & s;
+        let p30 = p == 15;
+        let g30 = g > 0;
+
+        match alu {
+            FuncType::RPlusS
+            | FuncType::RPlusSPlus1
+            | FuncType::SMinusRMinus1
+            | FuncType::SMinusR
+            | FuncType::RMinusSMinus1
+            | FuncType::RMinusS => {
+                self.p = !p30;
+
+                let g1 = is_bit_set(g, 1) || (is_bit_set(p, 1) && is_bit_set(g, 0));
+                let g2 = is_bit_set(g, 2) || (is_bit_set(p, 2) && g1);
+                let g3 = is_bit_set(g, 3) || (is_bit_set(p, 3) && g2);
+                self.g = !g3;
+
+                let c1 = is_bit_set(g, 0) || (is_bit_set(p, 0) && c0);
+                let c2 = is_bit_set(g, 1) || (is_bit_set(p, 1) && c1);
+                let c3 = is_bit_set(g, 2) || (is_bit_set(p, 2) && c2);
+                let c4 = is_bit_set(g, 3) || (is_bit_set(p, 3) && c3);
+                self.c4 = c4;
+                self.ovr = c3 != c4;
+            }
+            FuncType::ROrS => {
+                self.p = false;
+                self.g = p30;
+                self.c4 = !p30 || c0;
+                self.ovr = self.c4;
+            }
+            FuncType::RAndS | FuncType::NoRAndS => {
+                self.p = false;
+                self.g = !g30;
+                self.c4 = g30 || c0;
+                self.ovr = self.c4;
+            }
+            FuncType::RXorS | FuncType::REqS => {
+                self.p = g30;
+
+                let g1 = is_bit_set(g, 1) || (is_bit_set(p, 1) && is_bit_set(p, 0));
+                let g2 = is_bit_set(g, 2) || (is_bit_set(p, 2) && g1);
+                let g3 = is_bit_set(g, 3) || (is_bit_set(p, 3) && g2);
+                self.g = g3;
+
+                let c4_1 = is_bit_set(g, 1)
+                    || (is_bit_set(p, 1) && is_bit_set(p, 0) && (is_bit_set(g, 0) || !c0));
+                let c4_2 = is_bit_set(g, 2) || (is_bit_set(p, 2) && c4_1);
+                let c4_3 = is_bit_set(g, 3) || (is_bit_set(p, 3) && c4_2);
+                self.c4 = !c4_3;
+
+                p = mask(!p);
+                g = mask(!g);
+                let ovr_0 = is_bit_set(p, 0) || (is_bit_set(g, 0) && c0);
+                let ovr_1 = is_bit_set(p, 1) || (is_bit_set(g, 1) && ovr_0);
+                let ovr_2 = is_bit_set(p, 2) || (is_bit_set(g, 2) && ovr_1);
+                let ovr_3 = is_bit_set(p, 3) || (is_bit_set(g, 3) && ovr_2);
+                self.ovr = ovr_2 != ovr_3;
+            }
+            _ => {}
+        }
+
+        self.f3 = is_bit_set(self.f, WORD_SIZE - 1);
+        self.z = self.f == 0;
+    }
+
+    fn exec_mt_command(&mut self, cmd: &NativeCommand) {
+        let from = cmd.src_type();
+        let alu = cmd.func_type();
+        let to = cmd.to_type();
+        let shift = cmd.shift_type();
+
+        let a = cmd.raw(A) as usize;
+        let b = cmd.raw(B) as usize;
+        let d = cmd.raw(D);
+
+        self.prev_reg_q = self.reg_q;
+        self.prev_reg_a = self.reg_common[a];
+        self.prev_reg_b = self.reg_common[b];
+
+        (self.r, self.s) = match from {
+            FromType::AAndPq => (self.reg_common[a], self.reg_q),
+            FromType::AAndB => (self.reg_common[a], self.reg_common[b]),
+            FromType::ZeroAndQ => (0, self.reg_q),
+            FromType::ZeroAndB => (0, self.reg_common[b]),
+            FromType::ZeroAndA => (0, self.reg_common[a]),
+            FromType::DAndA => (d, self.reg_common[a]),
+            FromType::DAndQ => (d, self.reg_q),
+            FromType::DAndZero => (d, 0),
+        };
+
+        let (r, s) = (self.r, self.s);
+        self.f = match alu {
+            FuncType::RPlusS => r + s,
+            FuncType::RPlusSPlus1 => r + s + 1,
+            FuncType::SMinusRMinus1 => s + mask(!r),
+            FuncType::SMinusR => s + mask(!r) + 1,
+            FuncType::RMinusSMinus1 => r + mask(!s),
+            FuncType::RMinusS => r + mask(!s) + 1,
+            FuncType::ROrS => r | s,
+            FuncType::RAndS => r & s,
+            FuncType::NoRAndS => mask(!r) & s,
+            FuncType::RXorS => r ^ s,
+            FuncType::REqS => mask(!(r ^ s)),
+            _ => self.f,
+        };
+        self.f = mask(self.f);
+
+        self.count_flags(alu);
+
+        let f = self.f;
+        let q_low = get_bit(self.reg_q, 0);
+        let q_high = get_bit(self.reg_q, WORD_SIZE - 1);
+        let f_low = get_bit(f, 0);
+        let f_high = get_bit(f, WORD_SIZE - 1);
+
+        match to {
+            ToType::FInQ => {
+                if shift == ShiftType::Cycle {
+                    self.call_index &= 0xfff0;
+                    self.call_index |= f;
+                } else {
+                    self.reg_q = f;
+                }
+            }
+            ToType::NoLoad => {
+                if shift == ShiftType::Cycle {
+                    self.call_index &= 0xff0f;
+                    self.call_index |= f << 4;
+                }
+            }
+            ToType::FInBAndAInY => {
+                if shift == ShiftType::Cycle {
+                    self.call_index &= 0xf0ff;
+                    self.call_index |= f << 8;
+                }
+            }
+            ToType::FInB => {
+                if shift == ShiftType::Cycle {
+                    self.call_index &= 0xfff;
+                    self.call_index |= f << 12;
+                }
+                self.reg_common[b] = f;
+            }
+            ToType::SrFInBAndSrQInQ => {
+                self.reg_q >>= 1;
+                self.reg_common[b] = f >> 1;
+                match shift {
+                    ShiftType::Cycle => {
+                        self.reg_common[b] |= f_low << 3;
+                        self.reg_q |= q_low << 3;
+                    }
+                    ShiftType::CycleDouble => {
+                        self.reg_common[b] |= q_low << 3;
+                        self.reg_q |= f_low << 3;
+                    }
+                    ShiftType::ArithmeticDouble => {
+                        self.reg_common[b] |= f_high << 3;
+                        self.reg_q |= f_low << 3;
+                    }
+                    ShiftType::Logic => {}
+                }
+            }
+            ToType::SrFInB => {
+                self.reg_common[b] = f >> 1;
+                if shift == ShiftType::Cycle {
+                    self.reg_common[b] |= f_low << 3;
+                }
+            }
+            ToType::SlFInBAndSlQInQ => {
+                self.reg_q = mask(self.reg_q << 1);
+                self.reg_common[b] = mask(f << 1);
+                match shift {
+                    ShiftType::Cycle => {
+                        self.reg_common[b] |= f_high;
+                        self.reg_q |= q_high;
+                    }
+                    ShiftType::CycleDouble => {
+                        self.reg_common[b] |= q_high;
+                        self.reg_q |= f_high;
+                    }
+                    ShiftType::ArithmeticDouble => {
+                        self.reg_common[b] |= q_high;
+                    }
+                    ShiftType::Logic => {}
+                }
+            }
+            ToType::SlFInB => {
+                self.reg_common[b] = mask(f << 1);
+                if shift == ShiftType::Cycle {
+                    self.reg_common[b] |= f_high;
+                }
+            }
+        }
+
+        self.y = match to {
+            ToType::FInBAndAInY => self.reg_common[a],
+            _ => f,
+        };
+    }
+
+    fn set_mem_ptr(&mut self, cmd: &NativeCommand) {
+        self.inc = cmd.inc_type();
+        let (a, b) = (cmd.raw(A), cmd.raw(B));
+        self.mp = match self.inc {
+            MemNextPoint::Load => (self.reg_common[a as usize] << 4) + self.reg_common[b as usize],
+            _ => (a << 4) + b,
+        };
+    }
+
+    fn set_device_ptr(&mut self, cmd: &NativeCommand) {
+        self.dev_ptr = cmd.raw(A);
+    }
+
+    // The port the device pointer picked, with the part of it the command
+    // moves. Like the original, without one a device command can't run.
+    fn device_access(&self, cmd: &NativeCommand) -> Result<Access, EmulatorError> {
+        let width = match cmd.pointer_type() {
+            DataPointerType::Low4Bit => Some(Width::Low4),
+            DataPointerType::High4Bit => Some(Width::High4),
+            DataPointerType::Full8Bit => Some(Width::Full8),
+            DataPointerType::Unknown => None,
+        };
+        match width {
+            Some(width) if (0..DEVICE_COUNT).contains(&self.dev_ptr) => Ok(Access {
+                port: self.dev_ptr as usize,
+                addr: cmd.raw(D) as u8,
+                width,
+            }),
+            _ => Err(EmulatorError::new(match cmd.func_type() {
+                FuncType::StoreDevice => "Cannot write to this device, no port is selected",
+                _ => "Cannot read from this device, no port is selected",
+            })),
+        }
+    }
+
+    fn load_data(&mut self, cmd: &NativeCommand) -> Result<(), EmulatorError> {
+        let func = cmd.func_type();
+        let pointer_type = cmd.pointer_type();
+        let a = cmd.raw(A) as usize;
+        let b = cmd.raw(B) as usize;
+        let mp = self.mp as usize;
+        match func {
+            FuncType::StoreMemory => match pointer_type {
+                DataPointerType::Low4Bit => {
+                    self.memory[mp] = make_byte(high_nibble(self.memory[mp]), self.reg_common[b])
+                }
+                DataPointerType::High4Bit => {
+                    self.memory[mp] = make_byte(self.reg_common[a], low_nibble(self.memory[mp]))
+                }
+                DataPointerType::Full8Bit => {
+                    self.memory[mp] = make_byte(self.reg_common[a], self.reg_common[b])
+                }
+                DataPointerType::Unknown => {}
+            },
+            FuncType::LoadMemory => match pointer_type {
+                DataPointerType::Low4Bit => self.reg_common[b] = low_nibble(self.memory[mp]),
+                DataPointerType::High4Bit => self.reg_common[a] = high_nibble(self.memory[mp]),
+                DataPointerType::Full8Bit => {
+                    self.reg_common[a] = high_nibble(self.memory[mp]);
+                    self.reg_common[b] = low_nibble(self.memory[mp]);
+                }
+                DataPointerType::Unknown => {}
+            },
+            FuncType::StoreDevice => {
+                let access = self.device_access(cmd)?;
+                let value = match access.width {
+                    Width::Low4 => low_nibble(self.reg_common[b]),
+                    Width::High4 => make_byte(self.reg_common[a], 0),
+                    Width::Full8 => make_byte(self.reg_common[a], self.reg_common[b]),
+                };
+                self.ports.write(access, value as u8)?;
+            }
+            FuncType::LoadDevice => {
+                let access = self.device_access(cmd)?;
+                let value = self.ports.read(access)? as i32;
+                match access.width {
+                    Width::Low4 => self.reg_common[b] = low_nibble(value),
+                    Width::High4 => self.reg_common[a] = high_nibble(value),
+                    Width::Full8 => {
+                        self.reg_common[a] = high_nibble(value);
+                        self.reg_common[b] = low_nibble(value);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if matches!(func, FuncType::StoreMemory | FuncType::LoadMemory)
+            && self.inc == MemNextPoint::Plus
+        {
+            self.mp = (self.mp + 1) % MEM_SIZE;
+        }
+        Ok(())
+    }
+
+    fn exec_one_code(&mut self) -> Result<ExecResult, EmulatorError> {
+        if self.commands.is_empty() {
+            return Ok(ExecResult::NoCommands);
+        }
+        if self.end {
+            return Ok(ExecResult::End);
+        }
+        if self.pc == -1 {
+            if let Some(call) = self.calls.first().cloned() {
+                self.memory[0] = call.arg0;
+                self.memory[1] = call.arg1;
+                self.pc = self.addr_by_code(call.code);
+                self.call_index = 1;
+            } else {
+                self.pc = 0;
+            }
+            return Ok(ExecResult::Ok);
+        }
+
+        let cmd = self.current();
+        if !cmd.check() {
+            return Ok(ExecResult::IncorrectCommand(self.pc as usize));
+        }
+
+        self.backup_flags();
+
+        match cmd.view() {
+            ViewType::MtCommand => self.exec_mt_command(&cmd),
+            ViewType::MemoryPointer => self.set_mem_ptr(&cmd),
+            ViewType::DevicePointer => self.set_device_ptr(&cmd),
+            ViewType::LoadHigh4Bit | ViewType::LoadLow4Bit | ViewType::Load8Bit => {
+                self.load_data(&cmd)?
+            }
+            _ => {}
+        }
+
+        self.jump(&cmd);
+        self.pc %= PROGRAM_SIZE;
+
+        Ok(ExecResult::Ok)
+    }
+
+    fn exec_one_call_code(&mut self) -> Result<ExecResult, EmulatorError> {
+        self.last_call_cycles = 0;
+        if self.call_index as usize >= self.calls.len() {
+            return Ok(ExecResult::End);
+        }
+        let old_index = self.call_index;
+        let call = self.calls[self.call_index as usize].clone();
+        self.memory[0] = call.arg0;
+        self.memory[1] = call.arg1;
+        self.pc = self.addr_by_code(call.code);
+        for i in 0..MAX_AUTO_COUNT {
+            let rc = self.exec_one_code()?;
+            self.last_call_cycles = i + 1;
+            if rc != ExecResult::Ok {
+                return Ok(rc);
+            }
+            if self.command_at(self.prev_pc).jump_type() == JumpType::End || self.prev_pc == self.pc {
+                if old_index == self.call_index {
+                    self.call_index = (self.call_index + 1) % 0x10000;
+                }
+                return Ok(ExecResult::Ok);
+            }
+        }
+        Ok(ExecResult::Loop)
+    }
+
+    fn exec_all_code(&mut self) -> Result<ExecResult, EmulatorError> {
+        for _ in 0..MAX_AUTO_COUNT {
+            let rc = self.exec_one_code()?;
+            if rc != ExecResult::Ok {
+                return Ok(rc);
+            }
+            if self.call_index as usize >= self.calls.len() || self.prev_pc == self.pc {
+                return Ok(ExecResult::Ok);
+            }
+        }
+        Ok(ExecResult::Loop)
+    }
+
+    fn add_native_call(&mut self, index: usize, call: NativeCall) -> bool {
+        if !self.map_calls.contains(call.code) {
+            return false;
+        }
+        if call.arg0 > 0xff || call.arg1 > 0xff || index > self.calls.len() {
+            return false;
+        }
+        self.calls.insert(index, call);
+        true
+    }
+
+    fn check_map_call(&self, libcall: &LibCall) -> bool {
+        if self.map_calls.contains(libcall.code) {
+            return false;
+        }
+        if libcall.name.encode_utf16().count() > NAME_MAX_SIZE {
+            return false;
+        }
+        !self.map_calls.iter().any(|call| call.name == libcall.name)
+    }
+
+    fn open(&mut self, input: &[u8]) -> Option<()> {
+        if !input.starts_with(FILE_HEADER) {
+            return None;
+        }
+        let seek = std::cell::Cell::new(FILE_HEADER.len());
+        let next = || -> Option<i32> {
+            let byte = *input.get(seek.get())?;
+            seek.set(seek.get() + 1);
+            Some(byte as i32)
+        };
+
+        self.map_calls.clear();
+        self.calls.clear();
+        self.commands.clear();
+        self.reset_state();
+
+        let map_call_count = (next()? << 8) + next()?;
+        for _ in 0..map_call_count {
+            let code = (next()? << 8) + next()?;
+            let mut name = [0u8; NAME_MAX_SIZE];
+            for byte in name.iter_mut() {
+                *byte = next()? as u8;
+            }
+            let name = String::from_utf8_lossy(&name).to_string();
+            let addr = (next()? << 8) + next()?;
+            let libcall = LibCall::new(code, name, addr);
+            if !self.check_map_call(&libcall) {
+                return None;
+            }
+            self.map_calls.insert(libcall);
+        }
+
+        let calls_count = (next()? << 8) + next()?;
+        for i in 0..calls_count {
+            let code = (next()? << 8) + next()?;
+            let arg0 = (next()? << 8) + next()?;
+            let arg1 = (next()? << 8) + next()?;
+            let alt_command_address = next()? == 1;
+            let flag = next()? as u8;
+            let call = NativeCall {
+                code,
+                arg0,
+                arg1,
+                alt_command_address,
+                flag,
+            };
+            self.add_native_call(i as usize, call);
+        }
+
+        let commands_count = ((next()? << 8) + next()?) as usize;
+        let mut seek = seek.get();
+        if seek + commands_count * (COMMAND_SIZE + 1) > input.len() {
+            return None;
+        }
+        for _ in 0..commands_count {
+            let is_offset = input[seek] == 1;
+            seek += 1;
+            let mut words = [0; COMMAND_LEN];
+            for j in 0..COMMAND_SIZE {
+                words[2 * j] = (input[seek] >> 4) as i32;
+                words[2 * j + 1] = (input[seek] % 16) as i32;
+                seek += 1;
+            }
+            self.commands.push(NativeCommand {
+                is_offset,
+                number: 0,
+                words,
+            });
+        }
+        self.update_offsets(0);
+        Some(())
+    }
+}
+
+impl MT1804Emulator for NativeImplementation {
+    fn reset(&mut self) {
+        self.reset_state();
+    }
+
+    fn get_command(&self, index: usize) -> Result<Command, EmulatorError> {
+        check_index(index, self.commands.len())?;
+        Ok(self.commands[index].unmanage())
+    }
+
+    fn add_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError> {
+        check_index(index, self.commands.len() + 1)?;
+        match self.add_user_command(index, NativeCommand::manage(cmd)?) {
+            true => Ok(()),
+            false => Err(EmulatorError::new(super::BAD_COMMAND)),
+        }
+    }
+
+    fn update_command(&mut self, index: usize, cmd: &Command) -> Result<(), EmulatorError> {
+        check_index(index, self.commands.len())?;
+        match self.update_user_command(index, NativeCommand::manage(cmd)?) {
+            true => Ok(()),
+            false => Err(EmulatorError::new(super::BAD_COMMAND)),
+        }
+    }
+
+    fn last_command(&self) -> Result<Command, EmulatorError> {
+        match self.commands.last() {
+            Some(cmd) => Ok(cmd.unmanage()),
+            None => Err(EmulatorError::new("there are no commands")),
+        }
+    }
+
+    fn remove_command(&mut self, index: usize) -> Result<(), EmulatorError> {
+        check_index(index, self.commands.len())?;
+        if self.is_fixed(index) {
+            return Err(EmulatorError::new(super::FIXED_COMMAND));
+        }
+        self.commands.remove(index);
+        self.update_offsets(index);
+        Ok(())
+    }
+
+    // only within the user program, which has to fit afterwards as well
+    fn move_command(&mut self, index: usize, new_pos: usize) -> Result<(), EmulatorError> {
+        check_index(index, self.commands.len())?;
+        check_index(new_pos, self.commands.len())?;
+        if self.is_fixed(index) || self.is_fixed(new_pos) {
+            return Err(EmulatorError::new(super::FIXED_COMMAND));
+        }
+        let commands = self.commands.clone();
+        let cmd = self.commands.remove(index);
+        self.commands.insert(new_pos, cmd);
+        self.update_offsets(index.min(new_pos));
+        if (0..=index.max(new_pos)).any(|ind| self.is_fixed(ind)) {
+            self.commands = commands;
+            return Err(EmulatorError::new(super::BAD_COMMAND));
+        }
+        Ok(())
+    }
+
+    fn commands_count(&self) -> usize {
+        self.commands.len()
+    }
+
+    fn executed_command(&self) -> Result<Command, EmulatorError> {
+        Ok(self.command_at(self.prev_pc).unmanage())
+    }
+
+    fn exec_one(&mut self) -> Result<ExecResult, EmulatorError> {
+        self.exec_one_code()
+    }
+
+    fn exec_one_call(&mut self) -> Result<ExecResult, EmulatorError> {
+        self.exec_one_call_code()
+    }
+
+    fn exec_all(&mut self) -> Result<ExecResult, EmulatorError> {
+        self.exec_all_code()
+    }
+
+    fn get_next_index(&self) -> usize {
+        self.index_of(self.pc).map(|i| i as i32).unwrap_or(-1) as usize
+    }
+
+    fn get_prev_index(&self) -> usize {
+        self.index_of(self.prev_pc).map(|i| i as i32).unwrap_or(-1) as usize
+    }
+
+    fn get_call_index(&self) -> usize {
+        if self.call_index as usize >= self.calls.len() {
+            return -1i32 as usize;
+        }
+        self.call_index as usize
+    }
+
+    fn get_last_call_cycles(&self) -> usize {
+        self.last_call_cycles
+    }
+
+    fn get_pc(&self) -> usize {
+        self.pc as usize
+    }
+
+    fn set_pc(&mut self, index: usize) -> Result<(), EmulatorError> {
+        self.pc = index as i32;
+        Ok(())
+    }
+
+    fn get_sp(&self) -> usize {
+        self.sp as usize
+    }
+
+    fn set_sp(&mut self, index: usize) -> Result<(), EmulatorError> {
+        self.sp = Self::stack_addr(index as i32);
+        Ok(())
+    }
+
+    fn get_stack(&self) -> Vec<i32> {
+        self.stack.to_vec()
+    }
+
+    fn get_mp(&self) -> usize {
+        self.mp as usize
+    }
+
+    fn get_port(&self) -> Option<usize> {
+        port::from_pointer(self.dev_ptr)
+    }
+
+    fn get_mem_value(&self, index: usize) -> Result<usize, EmulatorError> {
+        check_index(index, self.memory.len())?;
+        Ok(self.memory[index] as usize)
+    }
+
+    fn get_mem_length(&self) -> usize {
+        self.memory.len()
+    }
+
+    fn get_mem(&self) -> Vec<i32> {
+        self.memory.to_vec()
+    }
+
+    fn get_reg_q(&self) -> u8 {
+        self.reg_q as u8
+    }
+
+    fn get_reg(&self, index: usize) -> Result<u8, EmulatorError> {
+        check_index(index, self.reg_common.len())?;
+        Ok(self.reg_common[index] as u8)
+    }
+
+    fn get_f(&self) -> u8 {
+        self.f as u8
+    }
+
+    fn get_y(&self) -> u8 {
+        self.y as u8
+    }
+
+    fn get_prev_reg_q(&self) -> u8 {
+        self.prev_reg_q as u8
+    }
+
+    fn get_prev_reg_a(&self) -> u8 {
+        self.prev_reg_a as u8
+    }
+
+    fn get_prev_reg_b(&self) -> u8 {
+        self.prev_reg_b as u8
+    }
+
+    fn get_r(&self) -> u8 {
+        self.r as u8
+    }
+
+    fn get_s(&self) -> u8 {
+        self.s as u8
+    }
+
+    fn get_z(&self) -> u8 {
+        self.z as u8
+    }
+
+    fn get_f3(&self) -> u8 {
+        self.f3 as u8
+    }
+
+    fn get_c4(&self) -> u8 {
+        self.c4 as u8
+    }
+
+    fn get_ovr(&self) -> u8 {
+        self.ovr as u8
+    }
+
+    fn get_g(&self) -> u8 {
+        self.g as u8
+    }
+
+    fn get_p(&self) -> u8 {
+        self.p as u8
+    }
+
+    fn set_reg(&mut self, index: usize, value: u8) -> Result<(), EmulatorError> {
+        check_index(index, self.reg_common.len())?;
+        self.reg_common[index] = mask(value as i32);
+        Ok(())
+    }
+
+    fn set_reg_q(&mut self, value: u8) -> Result<(), EmulatorError> {
+        self.reg_q = mask(value as i32);
+        Ok(())
+    }
+
+    fn set_flag(&mut self, flag: Flag, value: bool) -> Result<(), EmulatorError> {
+        *match flag {
+            Flag::Ovr => &mut self.ovr,
+            Flag::C4 => &mut self.c4,
+            Flag::F3 => &mut self.f3,
+            Flag::Z => &mut self.z,
+            Flag::G => &mut self.g,
+            Flag::P => &mut self.p,
+        } = value;
+        Ok(())
+    }
+
+    fn set_mp(&mut self, value: usize) -> Result<(), EmulatorError> {
+        self.mp = (value % self.memory.len()) as i32;
+        Ok(())
+    }
+
+    fn set_mem_value(&mut self, index: usize, value: u8) -> Result<(), EmulatorError> {
+        check_index(index, self.memory.len())?;
+        self.memory[index] = value as i32;
+        Ok(())
+    }
+
+    fn set_stack_value(&mut self, index: usize, value: usize) -> Result<(), EmulatorError> {
+        check_index(index, self.stack.len())?;
+        self.stack[index] = (value % PROGRAM_SIZE as usize) as i32;
+        Ok(())
+    }
+
+    // the scalars in field order, then the registers, the stack and memory
+    fn get_exec_state(&self) -> Vec<i32> {
+        let mut state = vec![
+            self.prev_pc,
+            self.pc,
+            self.call_index,
+            self.last_call_cycles as i32,
+            self.end as i32,
+            self.sp,
+            self.reg_q,
+            self.inc as i32,
+            self.mp,
+            self.dev_ptr,
+            self.prev_reg_a,
+            self.prev_reg_b,
+            self.prev_reg_q,
+            self.r,
+            self.s,
+            self.f,
+            self.y,
+        ];
+        let flags = [
+            self.z,
+            self.f3,
+            self.c4,
+            self.ovr,
+            self.g,
+            self.p,
+            self.prev_z,
+            self.prev_f3,
+            self.prev_c4,
+            self.prev_ovr,
+            self.prev_g,
+            self.prev_p,
+        ];
+        state.extend(flags.map(i32::from));
+        state.extend(self.reg_common);
+        state.extend(self.stack);
+        state.extend(self.memory);
+        state
+    }
+
+    fn set_exec_state(&mut self, state: &[i32]) -> Result<(), EmulatorError> {
+        const SCALARS: usize = 29;
+        let arrays = [REG_SIZE, STACK_SIZE as usize, MEM_SIZE as usize];
+        if state.len() != SCALARS + arrays.iter().sum::<usize>() {
+            return Err(EmulatorError::new(super::BAD_EXEC_STATE));
+        }
+        let (scalars, arrays) = state.split_at(SCALARS);
+        let (regs, arrays) = arrays.split_at(REG_SIZE);
+        let (stack, memory) = arrays.split_at(STACK_SIZE as usize);
+        // whatever is used as an index or in the arithmetic has to be in range,
+        // or the next step panics
+        let within = |values: &[i32], min: i32, max: i32| {
+            values.iter().all(|value| (min..max).contains(value))
+        };
+        let valid = within(&scalars[..2], -1, PROGRAM_SIZE)
+            && within(&scalars[2..4], 0, i32::MAX)
+            && within(&scalars[5..6], 0, STACK_SIZE)
+            && within(&scalars[6..8], 0, 1 << WORD_SIZE)
+            && within(&scalars[8..9], 0, MEM_SIZE)
+            && within(&scalars[9..10], -1, DEVICE_COUNT)
+            && within(&scalars[10..17], 0, 1 << WORD_SIZE)
+            && within(&scalars[4..5], 0, 2)
+            && within(&scalars[17..], 0, 2)
+            && within(regs, 0, 1 << WORD_SIZE)
+            && within(stack, 0, PROGRAM_SIZE)
+            && within(memory, 0, 1 << (2 * WORD_SIZE));
+        if !valid {
+            return Err(EmulatorError::new(super::BAD_EXEC_STATE));
+        }
+        self.prev_pc = scalars[0];
+        self.pc = scalars[1];
+        self.call_index = scalars[2];
+        self.last_call_cycles = scalars[3] as usize;
+        self.end = scalars[4] != 0;
+        self.sp = scalars[5];
+        self.reg_q = scalars[6];
+        self.inc = match scalars[7] {
+            0 => MemNextPoint::No,
+            1 => MemNextPoint::Plus,
+            2 => MemNextPoint::Load,
+            _ => MemNextPoint::Unknown,
+        };
+        self.mp = scalars[8];
+        self.dev_ptr = scalars[9];
+        self.prev_reg_a = scalars[10];
+        self.prev_reg_b = scalars[11];
+        self.prev_reg_q = scalars[12];
+        self.r = scalars[13];
+        self.s = scalars[14];
+        self.f = scalars[15];
+        self.y = scalars[16];
+        let flag = |i: usize| scalars[17 + i] != 0;
+        (self.z, self.f3, self.c4, self.ovr, self.g, self.p) =
+            (flag(0), flag(1), flag(2), flag(3), flag(4), flag(5));
+        (self.prev_z, self.prev_f3, self.prev_c4) = (flag(6), flag(7), flag(8));
+        (self.prev_ovr, self.prev_g, self.prev_p) = (flag(9), flag(10), flag(11));
+        self.reg_common.copy_from_slice(regs);
+        self.stack.copy_from_slice(stack);
+        self.memory.copy_from_slice(memory);
+        Ok(())
+    }
+
+    fn attach_device(
+        &mut self,
+        port: usize,
+        device: Option<SharedDevice>,
+    ) -> Result<(), EmulatorError> {
+        self.ports.attach(port, device)
+    }
+
+    fn add_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError> {
+        check_index(index, self.calls.len() + 1)?;
+        self.add_native_call(index, NativeCall::new(call.code_, call.arg0_, call.arg1_));
+        Ok(())
+    }
+
+    fn get_call(&self, index: usize) -> Result<Call, EmulatorError> {
+        check_index(index, self.calls.len())?;
+        let call = &self.calls[index];
+        Ok(Call {
+            code_: call.code,
+            arg0_: call.arg0,
+            arg1_: call.arg1,
+        })
+    }
+
+    fn update_call(&mut self, index: usize, call: Call) -> Result<(), EmulatorError> {
+        if call.arg0_ > 0xff || call.arg1_ > 0xff {
+            return Ok(());
+        }
+        check_index(index, self.calls.len() + 1)?;
+        if !self.add_native_call(index, NativeCall::new(call.code_, call.arg0_, call.arg1_)) {
+            return Ok(());
+        }
+        self.remove_call(index + 1)
+    }
+
+    fn remove_call(&mut self, index: usize) -> Result<(), EmulatorError> {
+        check_index(index, self.calls.len())?;
+        self.calls.remove(index);
+        Ok(())
+    }
+
+    fn call_count(&self) -> usize {
+        self.calls.len()
+    }
+
+    fn add_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError> {
+        if self.check_map_call(libcall) {
+            self.map_calls.insert(libcall.clone());
+        }
+        Ok(())
+    }
+
+    fn remove_map_call(&mut self, code: i32) -> Result<(), EmulatorError> {
+        if self.map_calls.contains(code) && !self.calls.iter().any(|call| call.code == code) {
+            self.map_calls.remove(code);
+        }
+        Ok(())
+    }
+
+    fn update_map_call(&mut self, libcall: &LibCall) -> Result<(), EmulatorError> {
+        if libcall.name.encode_utf16().count() <= NAME_MAX_SIZE {
+            self.map_calls.update(libcall.clone());
+        }
+        Ok(())
+    }
+
+    fn get_map_calls(&self) -> Result<Vec<LibCall>, EmulatorError> {
+        // names read from files keep their NUL padding, the shim cuts them at
+        // the first NUL on the way out
+        Ok(self
+            .map_calls
+            .iter()
+            .map(|call| {
+                let name = call.name.split('\0').next().unwrap_or_default().to_owned();
+                LibCall::new(call.code, name, call.addr)
+            })
+            .collect())
+    }
+
+    fn last_call(&self) -> Result<Call, EmulatorError> {
+        match self.calls.last() {
+            Some(call) => Ok(Call {
+                code_: call.code,
+                arg0_: call.arg0,
+                arg1_: call.arg1,
+            }),
+            None => Err(EmulatorError::new("there are no calls")),
+        }
+    }
+
+    fn open_raw(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        self.open(bytes)
+            .ok_or_else(|| EmulatorError::new(super::BAD_PROGRAM_FILE))
+    }
+
+    fn export_raw(&self) -> Vec<u8> {
+        let map_calls_size = self.map_calls.len() * (2 * 2 + NAME_MAX_SIZE);
+        let calls_size = self.calls.len() * (3 * 2 + 2);
+        let commands_size = self.commands.len() * (COMMAND_SIZE + 1);
+        let mut output = Vec::<u8>::with_capacity(
+            FILE_HEADER.len() + map_calls_size + calls_size + commands_size + 3 * 2,
+        );
+        output.extend_from_slice(FILE_HEADER);
+
+        output.extend_from_slice(&(self.map_calls.len() as u16).to_be_bytes());
+        for call in self.map_calls.iter() {
+            output.extend_from_slice(&(call.code as u16).to_be_bytes());
+            let name = call.name.as_bytes();
+            for c in 0..NAME_MAX_SIZE {
+                output.push(name.get(c).copied().unwrap_or(0));
+            }
+            output.extend_from_slice(&(call.addr as u16).to_be_bytes());
+        }
+
+        output.extend_from_slice(&(self.calls.len() as u16).to_be_bytes());
+        for call in self.calls.iter() {
+            output.extend_from_slice(&(call.code as u16).to_be_bytes());
+            output.extend_from_slice(&(call.arg0 as u16).to_be_bytes());
+            output.extend_from_slice(&(call.arg1 as u16).to_be_bytes());
+            output.push(call.alt_command_address as u8);
+            output.push(call.flag);
+        }
+
+        output.extend_from_slice(&(self.commands.len() as u16).to_be_bytes());
+        for cmd in self.commands.iter() {
+            output.push(cmd.is_offset as u8);
+            for j in 0..COMMAND_SIZE {
+                output.push(((cmd.words[2 * j] << 4) + cmd.words[2 * j + 1]) as u8);
+            }
+        }
+        output
+    }
+
+    fn init_library(&mut self) -> Result<(), EmulatorError> {
+        for cmd in library_commands() {
+            self.add_lib_command(cmd);
+        }
+        self.map_calls = CallMap::library();
+        self.reset_state();
+        Ok(())
+    }
+
+    fn command_get_name(&self, cmd: Command) -> Result<String, EmulatorError> {
+        Ok(NativeCommand::manage(&cmd)?.name())
+    }
+
+    fn command_get_jump_name(&self, cmd: Command) -> Result<String, EmulatorError> {
+        Ok(NativeCommand::manage(&cmd)?.jump_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // next address, jump, then I6-8, I0-2, I3-5, A, B, D as in the words
+    fn program(words: &[[i32; COMMAND_LEN]]) -> NativeImplementation {
+        let mut emul = NativeImplementation::new();
+        for (ind, words) in words.iter().enumerate() {
+            emul.add_command(ind, &Command::new(0, words)).unwrap();
+        }
+        emul.reset();
+        // the first step only picks where to start
+        emul.exec_one().unwrap();
+        emul
+    }
+
+    fn run(emul: &mut NativeImplementation, steps: usize) {
+        for _ in 0..steps {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        }
+    }
+
+    fn flags(emul: &NativeImplementation) -> [u8; 4] {
+        [emul.get_z(), emul.get_f3(), emul.get_c4(), emul.get_ovr()]
+    }
+
+    #[test]
+    fn alu_sets_the_flags() {
+        let mut emul = program(&[
+            // R0 = 7
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 7],
+            // R2 = 9 + R0, wraps to zero with a carry
+            [0, 0, 0, 2, 3, 5, 0, 0, 2, 9],
+            // R3 = 7 - R0 - 1 with a borrow, the sign set
+            [0, 0, 0, 2, 3, 5, 2, 0, 3, 7],
+            // R4 = 7 - 9 overflows
+            [0, 0, 0, 2, 3, 5, 10, 0, 4, 7],
+            [0, 0, 0, 3, 1, 0, 0, 0, 0, 0],
+        ]);
+        run(&mut emul, 1);
+        assert_eq!(emul.get_reg(0), Ok(7));
+        run(&mut emul, 1);
+        assert_eq!((emul.get_reg(2), emul.get_f()), (Ok(0), 0));
+        assert_eq!(flags(&emul), [1, 0, 1, 0]);
+        run(&mut emul, 1);
+        assert_eq!(emul.get_reg(3), Ok(0xF));
+        assert_eq!(flags(&emul), [0, 1, 0, 0]);
+        emul.set_reg(0, 9).unwrap();
+        emul.set_pc(3).unwrap();
+        run(&mut emul, 1);
+        assert_eq!(emul.get_reg(4), Ok(0xE));
+        assert_eq!(flags(&emul), [0, 1, 0, 1]);
+        run(&mut emul, 1);
+        assert_eq!(emul.exec_one(), Ok(ExecResult::End));
+    }
+
+    #[test]
+    fn stack_pushes_and_pops() {
+        let mut emul = program(&[
+            // CALL 3
+            [0, 0, 3, 5, 1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 3, 1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 2, 1, 0, 0, 0, 0, 0],
+            // PUSH, POP, RET
+            [0, 0, 0, 9, 1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 10, 1, 0, 0, 0, 0, 0],
+            [0, 0, 0, 6, 1, 0, 0, 0, 0, 0],
+        ]);
+        run(&mut emul, 1);
+        assert_eq!((emul.get_pc(), emul.get_sp()), (3, 1));
+        assert_eq!(emul.get_stack()[0], 1);
+        run(&mut emul, 1);
+        assert_eq!((emul.get_pc(), emul.get_sp()), (4, 2));
+        assert_eq!(emul.get_stack()[1], 4);
+        run(&mut emul, 1);
+        assert_eq!((emul.get_pc(), emul.get_sp()), (5, 1));
+        run(&mut emul, 1);
+        assert_eq!((emul.get_pc(), emul.get_sp()), (1, 0));
+        // popping an empty stack wraps around
+        emul.set_pc(4).unwrap();
+        run(&mut emul, 1);
+        assert_eq!(emul.get_sp(), STACK_SIZE as usize - 1);
+    }
+
+    #[test]
+    fn memory_loads_and_stores() {
+        let mut emul = program(&[
+            // R0 = A, R1 = 5
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 0xA],
+            [0, 0, 0, 2, 3, 7, 0, 0, 1, 5],
+            // MP = 0x12 moving on, store R0 R1, MP = 0x12, load R2 R3
+            [0, 0, 0, 2, 1, 1, 11, 1, 2, 0],
+            [0, 0, 0, 2, 1, 2, 12, 0, 1, 0],
+            [0, 0, 0, 2, 1, 0, 11, 1, 2, 0],
+            [0, 0, 0, 2, 1, 2, 13, 2, 3, 0],
+            // store the low nibble of R0 only
+            [0, 0, 0, 2, 1, 0, 12, 0, 0, 0],
+        ]);
+        run(&mut emul, 4);
+        assert_eq!(emul.get_mem_value(0x12), Ok(0xA5));
+        assert_eq!(emul.get_mp(), 0x13);
+        run(&mut emul, 2);
+        assert_eq!(emul.get_mp(), 0x12);
+        assert_eq!((emul.get_reg(2), emul.get_reg(3)), (Ok(0xA), Ok(5)));
+        run(&mut emul, 1);
+        assert_eq!(emul.get_mem_value(0x12), Ok(0xAA));
+        assert!(emul.get_mem_value(MEM_SIZE as usize).is_err());
+    }
+
+    #[test]
+    fn state_edits_stay_in_range() {
+        let mut emul = NativeImplementation::new();
+        emul.set_mp(0x123).unwrap();
+        assert_eq!(emul.get_mp(), 0x23);
+        emul.set_mp(usize::MAX).unwrap();
+        assert_eq!(emul.get_mp(), 0xFF);
+        emul.set_stack_value(0, usize::MAX).unwrap();
+        assert_eq!(emul.get_stack()[0], PROGRAM_SIZE - 1);
+        assert!(emul.set_stack_value(STACK_SIZE as usize, 0).is_err());
+        assert!(emul.set_reg(REG_SIZE, 0).is_err());
+    }
+
+    #[test]
+    fn rejects_an_exec_state_out_of_range() {
+        let mut emul = program(&[[0, 0, 0, 2, 3, 7, 0, 0, 0, 7]]);
+        let state = emul.get_exec_state();
+        // pc, call index, sp, mp, the device pointer, a register, the stack
+        // and memory
+        for (pos, value) in [
+            (1, PROGRAM_SIZE),
+            (2, -1),
+            (5, STACK_SIZE),
+            (8, MEM_SIZE),
+            (9, DEVICE_COUNT),
+            (29, 16),
+            (45, -1),
+            (state.len() - 1, 0x100),
+        ] {
+            let mut bad = state.clone();
+            bad[pos] = value;
+            assert_eq!(
+                emul.set_exec_state(&bad),
+                Err(EmulatorError::new(super::super::BAD_EXEC_STATE)),
+                "{} at {}",
+                value,
+                pos
+            );
+        }
+        assert_eq!(emul.get_exec_state(), state);
+        assert_eq!(emul.set_exec_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn takes_back_every_state_a_run_reaches() {
+        for (name, bytes) in crate::emulator::differential::SAMPLES {
+            let mut emul = NativeImplementation::new();
+            emul.open_raw(bytes).unwrap();
+            emul.reset();
+            for _ in 0..2000 {
+                let state = emul.get_exec_state();
+                assert_eq!(emul.set_exec_state(&state), Ok(()), "{}", name);
+                if emul.exec_one() != Ok(ExecResult::Ok) {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moves_only_user_commands() {
+        let mut emul = program(&[
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 1],
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 2],
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 3],
+        ]);
+        emul.init_library().unwrap();
+        let len = emul.commands_count();
+        emul.move_command(0, 2).unwrap();
+        let d = |emul: &NativeImplementation, ind| emul.get_command(ind).unwrap().words[D];
+        assert_eq!([d(&emul, 0), d(&emul, 1), d(&emul, 2)], [2, 3, 1]);
+        assert_eq!(emul.get_command(2).unwrap().get_num(), 2);
+
+        let fixed = Err(EmulatorError::new(super::super::FIXED_COMMAND));
+        assert_eq!(emul.move_command(0, 3), fixed);
+        assert_eq!(emul.move_command(len - 1, 0), fixed);
+        assert!(emul.move_command(0, len).is_err());
+        assert!(emul.move_command(len, 0).is_err());
+        assert_eq!(emul.commands_count(), len);
+        assert_eq!(emul.remove_command(3), fixed);
+    }
+
+    #[test]
+    fn rejects_a_short_command() {
+        let mut emul = NativeImplementation::new();
+        assert!(emul.add_command(0, &Command::new(0, &[0; 9])).is_err());
+        assert_eq!(emul.commands_count(), 0);
+        assert!(emul.command_get_name(Command::new(0, &[0; 11])).is_err());
+    }
+}
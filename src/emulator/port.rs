@@ -0,0 +1,398 @@
+/* port.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// What STORE_DEVICE and LOAD_DEVICE talk to. The pointer set with the device
+// pointer command picks one of PORT0-PORT3, and whatever device is attached
+// there answers. A port without a device drops writes and reads as 0.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::EmulatorError;
+
+pub const PORT_COUNT: usize = 4;
+
+// what the device pointer holds, -1 until a command picks a port
+pub fn from_pointer(value: i32) -> Option<usize> {
+    usize::try_from(value)
+        .ok()
+        .filter(|port| *port < PORT_COUNT)
+}
+
+/// The part of the port a command moves, after its pointer type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Width {
+    Low4,
+    High4,
+    Full8,
+}
+
+impl Width {
+    // PortExtender numbers them like this in the low bits of a port
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Low4 => 1,
+            Self::High4 => 2,
+            Self::Full8 => 3,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Low4),
+            2 => Some(Self::High4),
+            3 => Some(Self::Full8),
+            _ => None,
+        }
+    }
+
+    pub fn mask(self) -> u8 {
+        match self {
+            Self::Low4 => 0x0F,
+            Self::High4 => 0xF0,
+            Self::Full8 => 0xFF,
+        }
+    }
+
+    // what a byte wide latch holds after `value` is written into `old`
+    pub fn merge(self, old: u8, value: u8) -> u8 {
+        old & !self.mask() | value & self.mask()
+    }
+}
+
+/// One STORE_DEVICE or LOAD_DEVICE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Access {
+    pub port: usize,
+    // the D field of the command, for the extender to pass on to the device
+    pub addr: u8,
+    pub width: Width,
+}
+
+pub trait PortDevice: Send {
+    // `value` has the written nibble in its place and zeros elsewhere
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError>;
+    // the engine picks the nibble it asked for out of the result
+    fn read(&mut self, access: Access) -> Result<u8, EmulatorError>;
+}
+
+pub type SharedDevice = Arc<Mutex<dyn PortDevice>>;
+
+// Every copy of an engine, the ones kept for stepping back included, shares
+// the devices. Going back in time doesn't take a write back from the board.
+#[derive(Clone, Default)]
+pub struct Ports([Option<SharedDevice>; PORT_COUNT]);
+
+impl Ports {
+    pub const fn new() -> Self {
+        Self([None, None, None, None])
+    }
+
+    pub fn attach(
+        &mut self,
+        port: usize,
+        device: Option<SharedDevice>,
+    ) -> Result<(), EmulatorError> {
+        let Some(slot) = self.0.get_mut(port) else {
+            return Err(EmulatorError::new(format!("there is no PORT{}", port)));
+        };
+        *slot = device;
+        Ok(())
+    }
+
+    pub fn write(&self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        match self.device(access.port) {
+            Some(device) => lock(device).write(access, value & access.width.mask()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read(&self, access: Access) -> Result<u8, EmulatorError> {
+        match self.device(access.port) {
+            Some(device) => lock(device).read(access),
+            None => Ok(0),
+        }
+    }
+
+    fn device(&self, port: usize) -> Option<&SharedDevice> {
+        self.0.get(port).and_then(Option::as_ref)
+    }
+}
+
+impl fmt::Debug for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(Option::is_some))
+            .finish()
+    }
+}
+
+// a device that panicked once still has its latches, keep using them
+fn lock(device: &SharedDevice) -> MutexGuard<'_, dyn PortDevice + 'static> {
+    device.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Debug, Default)]
+struct Latches {
+    input: AtomicU8,
+    output: AtomicU8,
+}
+
+/// A port worked from the UI. LOAD_DEVICE reads whatever was set as its
+/// input, STORE_DEVICE is latched as its output for the UI to show. Clones
+/// are the same port, so the UI keeps one while the engine has another.
+#[derive(Clone, Debug, Default)]
+pub struct SimulatedPort(Arc<Latches>);
+
+impl SimulatedPort {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn device(&self) -> SharedDevice {
+        Arc::new(Mutex::new(self.clone()))
+    }
+
+    pub fn input(&self) -> u8 {
+        self.0.input.load(Ordering::Relaxed)
+    }
+
+    pub fn set_input(&self, value: u8) {
+        self.0.input.store(value, Ordering::Relaxed);
+    }
+
+    pub fn output(&self) -> u8 {
+        self.0.output.load(Ordering::Relaxed)
+    }
+}
+
+impl PortDevice for SimulatedPort {
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        let old = self.output();
+        self.0
+            .output
+            .store(access.width.merge(old, value), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read(&mut self, _: Access) -> Result<u8, EmulatorError> {
+        Ok(self.input())
+    }
+}
+
+/// Which way a port of the front panel goes, after the interface table of
+/// the line builder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl Direction {
+    pub const PANEL: [Direction; PORT_COUNT] = [
+        Direction::Input,
+        Direction::Input,
+        Direction::Output,
+        Direction::Output,
+    ];
+}
+
+/// A port of the front panel. It keeps its value in a `SimulatedPort` but
+/// only works the way the panel has it wired, going against that is an
+/// error in the microprogram.
+#[derive(Clone, Debug)]
+pub struct PanelPort {
+    port: SimulatedPort,
+    direction: Direction,
+}
+
+impl PanelPort {
+    pub fn new(port: SimulatedPort, direction: Direction) -> Self {
+        Self { port, direction }
+    }
+
+    pub fn device(&self) -> SharedDevice {
+        Arc::new(Mutex::new(self.clone()))
+    }
+}
+
+impl PortDevice for PanelPort {
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        match self.direction {
+            Direction::Output => self.port.write(access, value),
+            Direction::Input => Err(EmulatorError::new(format!(
+                "PORT{} is an input, STORE_DEVICE can't write to it",
+                access.port
+            ))),
+        }
+    }
+
+    fn read(&mut self, access: Access) -> Result<u8, EmulatorError> {
+        match self.direction {
+            Direction::Input => self.port.read(access),
+            Direction::Output => Err(EmulatorError::new(format!(
+                "PORT{} is an output, LOAD_DEVICE can't read from it",
+                access.port
+            ))),
+        }
+    }
+}
+
+/// What the ports of an engine are plugged into. The engine gets a socket
+/// per port once, after that devices are swapped here without getting the
+/// engine back from a run or going through every copy of it.
+#[derive(Clone, Debug, Default)]
+pub struct Patchbay(Arc<Mutex<Ports>>);
+
+impl Patchbay {
+    pub fn connect(&self, port: usize, device: Option<SharedDevice>) -> Result<(), EmulatorError> {
+        self.ports().attach(port, device)
+    }
+
+    pub fn socket(&self) -> SharedDevice {
+        Arc::new(Mutex::new(self.clone()))
+    }
+
+    fn ports(&self) -> MutexGuard<'_, Ports> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// every socket is the whole bay, the access says which port it is for
+impl PortDevice for Patchbay {
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        self.ports().write(access, value)
+    }
+
+    fn read(&mut self, access: Access) -> Result<u8, EmulatorError> {
+        self.ports().read(access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Command, ExecResult, MT1804Emulator, NativeImplementation};
+
+    fn access(port: usize, width: Width) -> Access {
+        Access {
+            port,
+            addr: 0,
+            width,
+        }
+    }
+
+    #[test]
+    fn merges_nibbles_into_the_latch() {
+        let port = SimulatedPort::new();
+        let mut ports = Ports::new();
+        ports.attach(1, Some(port.device())).unwrap();
+        // the part outside the width is dropped before the device sees it
+        ports.write(access(1, Width::Low4), 0xFA).unwrap();
+        assert_eq!(port.output(), 0x0A);
+        ports.write(access(1, Width::High4), 0x5F).unwrap();
+        assert_eq!(port.output(), 0x5A);
+        ports.write(access(1, Width::Low4), 0x03).unwrap();
+        assert_eq!(port.output(), 0x53);
+        ports.write(access(1, Width::Full8), 0xC4).unwrap();
+        assert_eq!(port.output(), 0xC4);
+        port.set_input(0x7E);
+        assert_eq!(ports.read(access(1, Width::High4)), Ok(0x7E));
+        // nothing attached
+        assert_eq!(ports.write(access(0, Width::Full8), 0xFF), Ok(()));
+        assert_eq!(ports.read(access(0, Width::Full8)), Ok(0));
+    }
+
+    #[test]
+    fn panel_ports_go_one_way() {
+        let ports = [SimulatedPort::new(), SimulatedPort::new()];
+        let mut input = PanelPort::new(ports[0].clone(), Direction::PANEL[0]);
+        let mut output = PanelPort::new(ports[1].clone(), Direction::PANEL[2]);
+        ports[0].set_input(0x21);
+        assert_eq!(input.read(access(0, Width::Full8)), Ok(0x21));
+        output.write(access(2, Width::Full8), 0x34).unwrap();
+        assert_eq!(ports[1].output(), 0x34);
+
+        let err = input.write(access(0, Width::Low4), 0x01).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "PORT0 is an input, STORE_DEVICE can't write to it"
+        );
+        let err = output.read(access(2, Width::Low4)).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "PORT2 is an output, LOAD_DEVICE can't read from it"
+        );
+        assert_eq!(ports[0].output(), 0);
+    }
+
+    #[test]
+    fn swaps_devices_under_the_engine() {
+        let mut emul = NativeImplementation::new();
+        let words = [
+            // pick PORT2
+            [0, 0, 0, 2, 0, 8, 11, 2, 0, 0],
+            // R0 = 5, R1 = 0xA
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 5],
+            [0, 0, 0, 2, 3, 7, 0, 0, 1, 0xA],
+            // PORT2 = R0 R1
+            [0, 0, 0, 2, 0, 2, 14, 0, 1, 0],
+            // R0 = 3
+            [0, 0, 0, 2, 3, 7, 0, 0, 0, 3],
+            [0, 0, 0, 2, 0, 2, 14, 0, 1, 0],
+            [0, 0, 0, 3, 1, 0, 0, 0, 0, 0],
+        ];
+        for (ind, words) in words.iter().enumerate() {
+            emul.add_command(ind, &Command::new(0, words)).unwrap();
+        }
+        let patchbay = Patchbay::default();
+        emul.attach_device(2, Some(patchbay.socket())).unwrap();
+        let (first, second) = (SimulatedPort::new(), SimulatedPort::new());
+        patchbay.connect(2, Some(first.device())).unwrap();
+        emul.reset();
+        // positioning and the commands up to the first store
+        for _ in 0..5 {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        }
+        assert_eq!(first.output(), 0x5A);
+        patchbay.connect(2, Some(second.device())).unwrap();
+        for _ in 0..2 {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        }
+        assert_eq!((first.output(), second.output()), (0x5A, 0x3A));
+    }
+
+    #[test]
+    fn keeps_the_device_a_plug_fails_to_replace() {
+        let patchbay = Patchbay::default();
+        let mut socket = patchbay.clone();
+        let (plugged, other) = (SimulatedPort::new(), SimulatedPort::new());
+        plugged.set_input(0x42);
+        patchbay.connect(1, Some(plugged.device())).unwrap();
+        // what the toast shows after "Could not plug PORT4: "
+        let err = patchbay
+            .connect(PORT_COUNT, Some(other.device()))
+            .unwrap_err();
+        assert_eq!(err.message(), "there is no PORT4");
+        assert_eq!(socket.read(access(1, Width::Full8)), Ok(0x42));
+        socket.write(access(1, Width::Full8), 0x17).unwrap();
+        assert_eq!((plugged.output(), other.output()), (0x17, 0));
+    }
+}
@@ -0,0 +1,510 @@
+/* port_extender.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// The port extender board, which puts the ports on real hardware over a
+// serial line. This is the protocol of PortExtender.cs. A request and a
+// response are a frame: a start byte, the data, and an end byte. The top
+// three bits of both markers hold the command, and the end marker sets the
+// highest of them. The low five bits hold the data length. Boards are found
+// by asking every USB serial tty for its serial number, not through WMI.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::port::{Access, PortDevice, SharedDevice};
+use super::EmulatorError;
+
+const CMD_RESPONSE: u8 = 0x00;
+const CMD_PORT_READ: u8 = 0x01;
+const CMD_PORT_WRITE: u8 = 0x02;
+const CMD_SERIAL_GET: u8 = 0x03;
+const CMD_MASK: u8 = 0x03;
+const CMD_DATA_LEN_MASK: u8 = 0x1F;
+const CMD_SHIFT: u8 = 5;
+
+const CMD_MARKER_START: u8 = 0x00;
+const CMD_MARKER_END: u8 = 0x04;
+const CMD_MARKER_MASK: u8 = 0x04;
+
+const SERIAL_LENGTH: usize = 16;
+const MAX_FRAME: usize = CMD_DATA_LEN_MASK as usize + 2;
+
+// PortExtender.cs waits ten seconds. The board answers within milliseconds,
+// so a second is plenty and a single step doesn't hang for long.
+const TIMEOUT: Duration = Duration::from_secs(1);
+// for every tty that is asked while looking for boards
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+// what boards show up as, the on-board serial ports never have one
+const TTY_PREFIXES: [&str; 2] = ["ttyUSB", "ttyACM"];
+
+fn marker(cmd: u8, marker: u8, len: usize) -> u8 {
+    (cmd | marker) << CMD_SHIFT | len as u8 & CMD_DATA_LEN_MASK
+}
+
+fn request(cmd: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![marker(cmd, CMD_MARKER_START, data.len())];
+    frame.extend_from_slice(data);
+    frame.push(marker(cmd, CMD_MARKER_END, data.len()));
+    frame
+}
+
+// the data of a response frame
+fn parse_response(frame: &[u8]) -> Result<&[u8], EmulatorError> {
+    let bad = || EmulatorError::new("the port extender sent a broken response");
+    let start = *frame.first().ok_or_else(bad)?;
+    if (start >> CMD_SHIFT) & CMD_MARKER_MASK != CMD_MARKER_START
+        || (start >> CMD_SHIFT) & CMD_MASK != CMD_RESPONSE
+    {
+        return Err(bad());
+    }
+    let len = (start & CMD_DATA_LEN_MASK) as usize;
+    let end = *frame.get(len + 1).ok_or_else(bad)?;
+    if (end >> CMD_SHIFT) & CMD_MARKER_MASK != CMD_MARKER_END
+        || (end & CMD_DATA_LEN_MASK) as usize != len
+    {
+        return Err(bad());
+    }
+    let data = &frame[1..=len];
+    // a lone negative byte is how the board refuses a request
+    if let [status] = data {
+        if (*status as i8) < 0 {
+            return Err(EmulatorError::new(format!(
+                "the port extender failed the request with {}",
+                *status as i8
+            )));
+        }
+    }
+    Ok(data)
+}
+
+// The board has to call itself "TM...EP", the twelve bytes in between are
+// its number.
+fn parse_serial(data: &[u8]) -> Option<String> {
+    match data {
+        [b'T', b'M', number @ .., b'E', b'P'] if data.len() == SERIAL_LENGTH => {
+            Some(number.iter().map(|byte| format!("{:02X}", byte)).collect())
+        }
+        _ => None,
+    }
+}
+
+// the port and the pointer type in the low nibble, the D field in the high
+fn addr_port(access: Access) -> u8 {
+    access.addr << 4 | (access.port as u8) << 2 | access.width.code()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub serial: String,
+}
+
+/// A board on an open tty. Attached to a port, STORE_DEVICE and LOAD_DEVICE
+/// go to the board's port of the same number.
+#[derive(Debug)]
+pub struct PortExtender {
+    tty: File,
+    info: DeviceInfo,
+    timeout: Duration,
+}
+
+impl PortExtender {
+    pub fn open(path: &Path) -> Result<Self, EmulatorError> {
+        Self::open_with(path, TIMEOUT)
+    }
+
+    fn open_with(path: &Path, timeout: Duration) -> Result<Self, EmulatorError> {
+        let tty = open_tty(path).map_err(|err| {
+            EmulatorError::new(format!("cannot open {}: {}", path.display(), err))
+        })?;
+        let mut extender = Self {
+            tty,
+            info: DeviceInfo {
+                path: path.to_path_buf(),
+                serial: String::new(),
+            },
+            timeout,
+        };
+        extender.info.serial = extender.serial_get()?;
+        Ok(extender)
+    }
+
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// One board for every port, each access says which port it is.
+    pub fn device(self) -> SharedDevice {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn serial_get(&mut self) -> Result<String, EmulatorError> {
+        let data = self.send_recv(CMD_SERIAL_GET, &[0, 0])?;
+        parse_serial(&data).ok_or_else(|| {
+            EmulatorError::new(format!(
+                "{} is not a port extender",
+                self.info.path.display()
+            ))
+        })
+    }
+
+    pub fn write_port(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        self.send_recv(CMD_PORT_WRITE, &[addr_port(access), value])
+            .map(|_| ())
+    }
+
+    pub fn read_port(&mut self, access: Access) -> Result<u8, EmulatorError> {
+        let addr_port = addr_port(access);
+        let data = self.send_recv(CMD_PORT_READ, &[addr_port, 0])?;
+        // PortExtender.cs masks the echo with the length mask, which loses
+        // every address above 1. The whole byte has to match here.
+        match *data {
+            [echo, value] if echo == addr_port => Ok(value),
+            _ => Err(EmulatorError::new(
+                "the port extender answered for another port",
+            )),
+        }
+    }
+
+    fn send_recv(&mut self, cmd: u8, data: &[u8]) -> Result<Vec<u8>, EmulatorError> {
+        let io_error = |err: io::Error| {
+            EmulatorError::new(format!("the port extender is not responding: {}", err))
+        };
+        // whatever came after an earlier timeout would be taken as the answer
+        unsafe { libc::tcflush(self.tty.as_raw_fd(), libc::TCIFLUSH) };
+        self.tty.write_all(&request(cmd, data)).map_err(io_error)?;
+        let frame = self.recv_frame().map_err(io_error)?;
+        parse_response(&frame).map(<[u8]>::to_vec)
+    }
+
+    // Reads up to the end marker the start byte promises, rather than
+    // sleeping and taking whatever arrived.
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut frame = Vec::with_capacity(MAX_FRAME);
+        let mut buf = [0; MAX_FRAME];
+        loop {
+            let len = match frame.first() {
+                Some(start) => (start & CMD_DATA_LEN_MASK) as usize + 2,
+                None => 1,
+            };
+            if frame.len() >= len {
+                return Ok(frame);
+            }
+            wait_readable(&self.tty, deadline)?;
+            let read = self.tty.read(&mut buf[..len - frame.len()])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            frame.extend_from_slice(&buf[..read]);
+        }
+    }
+}
+
+impl PortDevice for PortExtender {
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        self.write_port(access, value)
+    }
+
+    fn read(&mut self, access: Access) -> Result<u8, EmulatorError> {
+        self.read_port(access)
+    }
+}
+
+/// Every board plugged in, a tty that doesn't answer the serial number
+/// request like one is skipped.
+pub fn available_devices() -> Vec<DeviceInfo> {
+    let Ok(entries) = fs::read_dir("/dev") else {
+        return Vec::new();
+    };
+    let mut paths = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            TTY_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        })
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    probe(paths, PROBE_TIMEOUT)
+}
+
+fn probe(paths: impl IntoIterator<Item = PathBuf>, timeout: Duration) -> Vec<DeviceInfo> {
+    paths
+        .into_iter()
+        .filter_map(|path| PortExtender::open_with(&path, timeout).ok())
+        .map(|extender| extender.info)
+        .collect()
+}
+
+// 115200 8N1 and raw, nothing the line discipline would make of the bytes
+fn open_tty(path: &Path) -> io::Result<File> {
+    // a tty without carrier blocks the open unless asked not to
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)?;
+    let fd = tty.as_raw_fd();
+    let check = |ret: libc::c_int| match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        check(libc::tcgetattr(fd, &mut termios))?;
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        check(libc::cfsetispeed(&mut termios, libc::B115200))?;
+        check(libc::cfsetospeed(&mut termios, libc::B115200))?;
+        check(libc::tcsetattr(fd, libc::TCSANOW, &termios))?;
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        check(libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK))?;
+    }
+    Ok(tty)
+}
+
+fn wait_readable(tty: &File, deadline: Instant) -> io::Result<()> {
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let mut poll = libc::pollfd {
+            fd: tty.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = left.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            0 => continue,
+            ready if ready > 0 => return Ok(()),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    use super::super::port::Width;
+    use super::*;
+
+    const SERIAL: [u8; SERIAL_LENGTH] = *b"TM\x01\x23\x45\x67\x89\xAB\xCD\xEF\x00\x11\x22\x33EP";
+
+    // What the board does with a request frame, None leaves it unanswered.
+    type Answer = fn(&mut Latches, u8, &[u8]) -> Option<Vec<u8>>;
+    // one for every address and port
+    type Latches = [u8; 64];
+
+    // A board behind the master side of a pty, the extender opens the slave
+    // the way it opens a USB serial tty. It keeps a latch for every address
+    // and port, and stops once the extender closes the slave.
+    struct MockBoard {
+        path: PathBuf,
+    }
+
+    impl MockBoard {
+        fn spawn(answer: Answer) -> Self {
+            let (master, path) = open_pty();
+            thread::spawn(move || serve(master, answer));
+            Self { path }
+        }
+    }
+
+    fn open_pty() -> (File, PathBuf) {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0, "{}", io::Error::last_os_error());
+            let master = File::from_raw_fd(fd);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let mut name = [0 as libc::c_char; 64];
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().into();
+            (master, path)
+        }
+    }
+
+    fn serve(mut master: File, answer: Answer) {
+        let mut latches = [0; 64];
+        let mut start = [0];
+        // EIO once the slave is closed
+        while master.read_exact(&mut start).is_ok() {
+            let mut rest = vec![0; (start[0] & CMD_DATA_LEN_MASK) as usize + 1];
+            if master.read_exact(&mut rest).is_err() {
+                return;
+            }
+            let cmd = start[0] >> CMD_SHIFT & CMD_MASK;
+            let data = &rest[..rest.len() - 1];
+            if let Some(data) = answer(&mut latches, cmd, data) {
+                if master.write_all(&request(CMD_RESPONSE, &data)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn board(latches: &mut Latches, cmd: u8, data: &[u8]) -> Option<Vec<u8>> {
+        // the pointer type doesn't pick a latch
+        let latch = |addr_port: u8| (addr_port >> 2) as usize;
+        match (cmd, data) {
+            (CMD_SERIAL_GET, _) => Some(SERIAL.to_vec()),
+            (CMD_PORT_WRITE, [addr_port, value]) => {
+                latches[latch(*addr_port)] = *value;
+                Some(vec![0])
+            }
+            (CMD_PORT_READ, [addr_port, _]) => Some(vec![*addr_port, latches[latch(*addr_port)]]),
+            _ => Some(vec![0xFF]),
+        }
+    }
+
+    fn not_a_board(_: &mut Latches, _: u8, _: &[u8]) -> Option<Vec<u8>> {
+        Some(b"XX345678901234YY".to_vec())
+    }
+
+    fn read_only(latches: &mut Latches, cmd: u8, data: &[u8]) -> Option<Vec<u8>> {
+        match cmd {
+            CMD_PORT_WRITE => Some(vec![0x80]),
+            _ => board(latches, cmd, data),
+        }
+    }
+
+    fn wrong_port(latches: &mut Latches, cmd: u8, data: &[u8]) -> Option<Vec<u8>> {
+        match (cmd, data) {
+            (CMD_PORT_READ, [addr_port, _]) => Some(vec![addr_port ^ 0x10, 0]),
+            _ => board(latches, cmd, data),
+        }
+    }
+
+    fn mute_ports(latches: &mut Latches, cmd: u8, data: &[u8]) -> Option<Vec<u8>> {
+        match cmd {
+            CMD_SERIAL_GET => board(latches, cmd, data),
+            _ => None,
+        }
+    }
+
+    fn access(port: usize, addr: u8, width: Width) -> Access {
+        Access { port, addr, width }
+    }
+
+    #[test]
+    fn frames_match_port_extender_cs() {
+        let write = request(
+            CMD_PORT_WRITE,
+            &[addr_port(access(2, 3, Width::Full8)), 0x5A],
+        );
+        assert_eq!(write, [0x42, 0x3B, 0x5A, 0xC2]);
+        let serial = request(CMD_SERIAL_GET, &[0, 0]);
+        assert_eq!(serial, [0x62, 0x00, 0x00, 0xE2]);
+        assert_eq!(
+            parse_response(&[0x02, 0x3B, 0x07, 0x82]).unwrap(),
+            [0x3B, 0x07]
+        );
+        // no end marker, wrong length, a command instead of a response
+        assert!(parse_response(&[0x02, 0x3B, 0x07, 0x02]).is_err());
+        assert!(parse_response(&[0x02, 0x3B, 0x07, 0x81]).is_err());
+        assert!(parse_response(&[0x42, 0x3B, 0x07, 0x82]).is_err());
+        assert!(parse_response(&[0x02, 0x3B]).is_err());
+        assert!(parse_response(&[0x01, 0xFF, 0x81]).is_err());
+        assert_eq!(parse_response(&[0x01, 0x7F, 0x81]).unwrap(), [0x7F]);
+    }
+
+    #[test]
+    fn checks_the_serial() {
+        let mut board = PortExtender::open(&MockBoard::spawn(board).path).unwrap();
+        assert_eq!(board.info().serial, "0123456789ABCDEF00112233");
+        assert_eq!(board.serial_get().unwrap(), board.info().serial);
+        assert!(PortExtender::open(&MockBoard::spawn(not_a_board).path).is_err());
+    }
+
+    #[test]
+    fn finds_only_boards() {
+        let good = MockBoard::spawn(board);
+        let bad = MockBoard::spawn(not_a_board);
+        let (_silent, silent) = open_pty();
+        let paths = [
+            bad.path,
+            good.path.clone(),
+            silent,
+            "/nonexistent/tty".into(),
+        ];
+        let found = probe(paths, PROBE_TIMEOUT);
+        assert_eq!(
+            found,
+            [DeviceInfo {
+                path: good.path,
+                serial: "0123456789ABCDEF00112233".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn writes_and_reads_ports() {
+        let mut board = PortExtender::open(&MockBoard::spawn(board).path).unwrap();
+        board.write(access(2, 3, Width::Full8), 0xA7).unwrap();
+        board.write(access(3, 3, Width::Low4), 0x05).unwrap();
+        assert_eq!(board.read(access(2, 3, Width::High4)).unwrap(), 0xA7);
+        assert_eq!(board.read(access(3, 3, Width::Full8)).unwrap(), 0x05);
+        assert_eq!(board.read(access(2, 0, Width::Full8)).unwrap(), 0);
+    }
+
+    #[test]
+    fn refused_requests_are_errors() {
+        let mut board = PortExtender::open(&MockBoard::spawn(read_only).path).unwrap();
+        let err = board.write(access(0, 0, Width::Full8), 1).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "the port extender failed the request with -128"
+        );
+        let mut board = PortExtender::open(&MockBoard::spawn(wrong_port).path).unwrap();
+        assert!(board.read(access(1, 2, Width::Low4)).is_err());
+    }
+
+    #[test]
+    fn silent_board_times_out() {
+        let path = MockBoard::spawn(mute_ports).path;
+        let mut board = PortExtender::open_with(&path, PROBE_TIMEOUT).unwrap();
+        let started = Instant::now();
+        assert!(board.read(access(0, 0, Width::Full8)).is_err());
+        assert!(started.elapsed() >= PROBE_TIMEOUT);
+        // nothing is left over to be taken for the next answer
+        assert_eq!(board.serial_get().unwrap(), "0123456789ABCDEF00112233");
+    }
+}
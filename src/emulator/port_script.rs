@@ -0,0 +1,455 @@
+/* port_script.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Ports driven by files, to grade a program without anyone at the panel.
+// LOAD_DEVICE takes the next value of a stimulus, and STORE_DEVICE is
+// appended to a capture that is then compared with the expected one. Both
+// are text. A line names a port and a device address, the D field of the
+// command, and then hex values. '#' starts a comment:
+//
+//     PORT0 3  A7 05 FF  # read in this order from PORT0 at address 3
+//
+// A capture has a line for every write, in the order they came. A nibble is
+// logged in its place with the other one zero.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::memory_image::ParseError;
+use super::port::{Access, PortDevice, SharedDevice, PORT_COUNT};
+use super::EmulatorError;
+
+const VALUES_PER_LINE: usize = 16;
+
+type Line = (usize, u8, Vec<u8>);
+
+fn parse_lines(text: &str) -> Result<Vec<Line>, ParseError> {
+    let mut lines = Vec::new();
+    for (ind, line) in text.lines().enumerate() {
+        let error = |message: String| ParseError::new(ind + 1, message);
+        let mut words = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace();
+        let Some(port) = words.next() else {
+            continue;
+        };
+        let port = port
+            .strip_prefix("PORT")
+            .and_then(|num| num.parse::<usize>().ok())
+            .filter(|num| *num < PORT_COUNT)
+            .ok_or_else(|| error(format!("\"{}\" is not a port", port)))?;
+        let Some(addr) = words.next() else {
+            return Err(error("a device address has to follow the port".to_string()));
+        };
+        let addr = match addr.len() {
+            1 => u8::from_str_radix(addr, 16).ok(),
+            _ => None,
+        }
+        .ok_or_else(|| error(format!("\"{}\" is not a device address", addr)))?;
+        let values = words
+            .map(|word| match word.len() {
+                1 | 2 => u8::from_str_radix(word, 16)
+                    .map_err(|_| error(format!("\"{}\" is not a hex byte", word))),
+                _ => Err(error(format!("\"{}\" is not a hex byte", word))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        lines.push((port, addr, values));
+    }
+    Ok(lines)
+}
+
+fn write_line(mut out: impl Write, port: usize, addr: u8, values: &[u8]) -> io::Result<()> {
+    let values = values
+        .iter()
+        .map(|value| format!("{:02X}", value))
+        .collect::<Vec<_>>();
+    writeln!(out, "PORT{} {:X}  {}", port, addr, values.join(" "))
+}
+
+/// What LOAD_DEVICE reads, in order, for every port and device address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stimulus(BTreeMap<(usize, u8), Vec<u8>>);
+
+impl Stimulus {
+    // lines for the same port and address go on one after another
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut stimulus = Self::default();
+        for (port, addr, values) in parse_lines(text)? {
+            stimulus.0.entry((port, addr)).or_default().extend(values);
+        }
+        Ok(stimulus)
+    }
+
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        for ((port, addr), values) in &self.0 {
+            for chunk in values.chunks(VALUES_PER_LINE) {
+                write_line(&mut out, *port, *addr, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// One STORE_DEVICE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Store {
+    pub port: usize,
+    pub addr: u8,
+    pub value: u8,
+}
+
+impl fmt::Display for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PORT{} {:X}  {:02X}", self.port, self.addr, self.value)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capture(Vec<Store>);
+
+impl Capture {
+    // an expected capture may have a run of values for a port on one line
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let stores = parse_lines(text)?
+            .into_iter()
+            .flat_map(|(port, addr, values)| {
+                values
+                    .into_iter()
+                    .map(move |value| Store { port, addr, value })
+            })
+            .collect();
+        Ok(Self(stores))
+    }
+
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        for store in &self.0 {
+            write_line(&mut out, store.port, store.addr, &[store.value])?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Every write that differs from the expected one, a missing or an extra
+    /// write included.
+    pub fn compare(&self, expected: &Capture) -> Vec<Mismatch> {
+        (0..self.0.len().max(expected.0.len()))
+            .map(|index| Mismatch {
+                index,
+                expected: expected.0.get(index).copied(),
+                captured: self.0.get(index).copied(),
+            })
+            .filter(|mismatch| mismatch.expected != mismatch.captured)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub index: usize,
+    pub expected: Option<Store>,
+    pub captured: Option<Store>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_nothing = |store: Option<Store>| match store {
+            Some(store) => store.to_string(),
+            None => "nothing".to_string(),
+        };
+        write!(
+            f,
+            "write {}: expected {}, captured {}",
+            self.index + 1,
+            or_nothing(self.expected),
+            or_nothing(self.captured)
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    stimulus: Stimulus,
+    // how far every sequence of the stimulus has been read
+    read: HashMap<(usize, u8), usize>,
+    capture: Capture,
+}
+
+/// How far a script has got, for the history to step back to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScriptPosition {
+    read: HashMap<(usize, u8), usize>,
+    captured: usize,
+}
+
+/// A device for all the ports at once, playing a stimulus and capturing
+/// what the program writes. Clones are the same script, so the UI keeps one
+/// to save and compare the capture while the engine writes to another.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedPorts(Arc<Mutex<Script>>);
+
+impl ScriptedPorts {
+    pub fn new(stimulus: Stimulus) -> Self {
+        Self(Arc::new(Mutex::new(Script {
+            stimulus,
+            ..Default::default()
+        })))
+    }
+
+    pub fn device(&self) -> SharedDevice {
+        Arc::new(Mutex::new(self.clone()))
+    }
+
+    pub fn stimulus(&self) -> Stimulus {
+        self.script().stimulus.clone()
+    }
+
+    pub fn capture(&self) -> Capture {
+        self.script().capture.clone()
+    }
+
+    // back to the first value of every sequence, with nothing captured
+    pub fn rewind(&self) {
+        let mut script = self.script();
+        script.read.clear();
+        script.capture = Capture::default();
+    }
+
+    pub fn position(&self) -> ScriptPosition {
+        let script = self.script();
+        ScriptPosition {
+            read: script.read.clone(),
+            captured: script.capture.len(),
+        }
+    }
+
+    // back to where `position` was taken, what was captured since is dropped
+    pub fn seek(&self, position: &ScriptPosition) {
+        let mut script = self.script();
+        script.read = position.read.clone();
+        script.capture.0.truncate(position.captured);
+    }
+
+    fn script(&self) -> MutexGuard<'_, Script> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PortDevice for ScriptedPorts {
+    fn write(&mut self, access: Access, value: u8) -> Result<(), EmulatorError> {
+        self.script().capture.0.push(Store {
+            port: access.port,
+            addr: access.addr,
+            value,
+        });
+        Ok(())
+    }
+
+    // running out of values is an error, there is nothing sensible to make up
+    fn read(&mut self, access: Access) -> Result<u8, EmulatorError> {
+        let mut script = self.script();
+        let key = (access.port, access.addr);
+        let next = script.read.get(&key).copied().unwrap_or_default();
+        let Some(value) = script
+            .stimulus
+            .0
+            .get(&key)
+            .and_then(|values| values.get(next))
+        else {
+            return Err(EmulatorError::new(format!(
+                "the stimulus has no more values for PORT{} at address {:X}",
+                access.port, access.addr
+            )));
+        };
+        let value = *value;
+        script.read.insert(key, next + 1);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::port::Width;
+
+    fn access(port: usize, addr: u8) -> Access {
+        Access {
+            port,
+            addr,
+            width: Width::Full8,
+        }
+    }
+
+    fn store(port: usize, addr: u8, value: u8) -> Store {
+        Store { port, addr, value }
+    }
+
+    #[test]
+    fn parses_a_stimulus() {
+        let text = "# header\n\
+                    PORT0 3  A7 5  # first\n\
+                    \n\
+                    PORT2 F\n\
+                    PORT0 3 FF\n";
+        let stimulus = Stimulus::parse(text).unwrap();
+        assert_eq!(stimulus.len(), 3);
+        let mut out = Vec::new();
+        stimulus.write(&mut out).unwrap();
+        // a port without values has nothing to write
+        assert_eq!(String::from_utf8(out).unwrap(), "PORT0 3  A7 05 FF\n");
+        assert_eq!(Stimulus::parse("").unwrap(), Stimulus::default());
+        assert!(Stimulus::default().is_empty());
+    }
+
+    #[test]
+    fn rejects_a_bad_line() {
+        let error = |text: &str| Stimulus::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("PORT0 0 1\nPORT9 0"),
+            "\"PORT9\" is not a port on line 2"
+        );
+        assert_eq!(error("IN0 0"), "\"IN0\" is not a port on line 1");
+        assert_eq!(
+            error("PORT1"),
+            "a device address has to follow the port on line 1"
+        );
+        assert_eq!(
+            error("PORT1 10"),
+            "\"10\" is not a device address on line 1"
+        );
+        assert_eq!(
+            error("PORT1 0 1 2 345"),
+            "\"345\" is not a hex byte on line 1"
+        );
+        assert_eq!(error("PORT1 0 xy"), "\"xy\" is not a hex byte on line 1");
+        assert!(Capture::parse("PORT1 0 G").is_err());
+    }
+
+    #[test]
+    fn parses_a_capture_in_order() {
+        let capture = Capture::parse("PORT1 2  0A 0B\nPORT0 0  C0\nPORT1 2  0C\n").unwrap();
+        assert_eq!(
+            capture.0,
+            [
+                store(1, 2, 0x0A),
+                store(1, 2, 0x0B),
+                store(0, 0, 0xC0),
+                store(1, 2, 0x0C)
+            ]
+        );
+        let mut out = Vec::new();
+        capture.write(&mut out).unwrap();
+        assert_eq!(
+            Capture::parse(&String::from_utf8(out).unwrap()),
+            Ok(capture)
+        );
+    }
+
+    #[test]
+    fn compares_captures() {
+        let expected = Capture::parse("PORT0 0  01 02 03").unwrap();
+        assert!(expected.compare(&expected).is_empty());
+
+        let captured = Capture::parse("PORT0 0  01 04").unwrap();
+        let mismatches = captured.compare(&expected);
+        assert_eq!(
+            mismatches,
+            [
+                Mismatch {
+                    index: 1,
+                    expected: Some(store(0, 0, 2)),
+                    captured: Some(store(0, 0, 4)),
+                },
+                Mismatch {
+                    index: 2,
+                    expected: Some(store(0, 0, 3)),
+                    captured: None,
+                },
+            ]
+        );
+        assert_eq!(
+            mismatches[1].to_string(),
+            "write 3: expected PORT0 0  03, captured nothing"
+        );
+
+        let extra = Capture::parse("PORT0 0  01 02 03\nPORT1 0  01").unwrap();
+        assert_eq!(
+            extra.compare(&expected),
+            [Mismatch {
+                index: 3,
+                expected: None,
+                captured: Some(store(1, 0, 1)),
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_until_the_stimulus_runs_out() {
+        let script = ScriptedPorts::new(Stimulus::parse("PORT0 1  0A 0B\nPORT0 2  0C").unwrap());
+        let mut device = script.clone();
+        assert_eq!(device.read(access(0, 2)), Ok(0x0C));
+        assert_eq!(device.read(access(0, 1)), Ok(0x0A));
+        assert_eq!(device.read(access(0, 1)), Ok(0x0B));
+        assert_eq!(
+            device.read(access(0, 1)).unwrap_err().to_string(),
+            "the stimulus has no more values for PORT0 at address 1"
+        );
+        assert!(device.read(access(0, 2)).is_err());
+        assert!(device.read(access(3, 1)).is_err());
+
+        script.rewind();
+        assert_eq!(device.read(access(0, 1)), Ok(0x0A));
+    }
+
+    #[test]
+    fn seeks_back_to_a_position() {
+        let script = ScriptedPorts::new(Stimulus::parse("PORT0 0  01 02 03").unwrap());
+        let mut device = script.clone();
+        device.read(access(0, 0)).unwrap();
+        device.write(access(1, 0), 0x10).unwrap();
+        let position = script.position();
+        device.read(access(0, 0)).unwrap();
+        device.write(access(1, 0), 0x20).unwrap();
+
+        script.seek(&position);
+        assert_eq!(script.capture().0, [store(1, 0, 0x10)]);
+        assert_eq!(device.read(access(0, 0)), Ok(0x02));
+        script.seek(&ScriptPosition::default());
+        assert!(script.capture().is_empty());
+        assert_eq!(device.read(access(0, 0)), Ok(0x01));
+    }
+}
@@ -0,0 +1,166 @@
+/* emulator/profile.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Profiler: how many times every command address executes when stepping
+// through commands, and how many microcycles every call of the call list
+// takes when stepping through calls. Unlike coverage it only counts while
+// profiling is on, so that one operation can be measured on its own.
+
+use std::collections::BTreeMap;
+
+use super::MT1804Emulator;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallCycles {
+    pub runs: usize,
+    pub last: usize,
+    pub total: usize,
+}
+
+impl CallCycles {
+    pub fn average(&self) -> f64 {
+        match self.runs {
+            0 => 0.0,
+            runs => self.total as f64 / runs as f64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    counts: BTreeMap<usize, usize>,
+    // by index in the call list
+    calls: BTreeMap<usize, CallCycles>,
+    call_index: usize,
+}
+
+impl Profile {
+    pub fn before_step(&mut self, emul: &dyn MT1804Emulator) {
+        self.call_index = emul.get_call_index();
+    }
+
+    pub fn after_command(&mut self, emul: &dyn MT1804Emulator) {
+        if let Ok(cmd) = emul.executed_command() {
+            *self.counts.entry(cmd.get_num()).or_default() += 1;
+        }
+    }
+
+    pub fn after_call(&mut self, emul: &dyn MT1804Emulator) {
+        let cycles = emul.get_last_call_cycles();
+        let call = self.calls.entry(self.call_index).or_default();
+        call.runs += 1;
+        call.last = cycles;
+        call.total += cycles;
+    }
+
+    // (address, executions) in address order
+    pub fn counts(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.counts.iter().map(|(addr, count)| (*addr, *count))
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    // (index in the call list, cycles)
+    pub fn calls(&self) -> impl Iterator<Item = (usize, CallCycles)> + '_ {
+        self.calls.iter().map(|(ind, cycles)| (*ind, *cycles))
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+        self.calls.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, NativeImplementation};
+
+    fn open(index: usize) -> NativeImplementation {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[index].1).unwrap();
+        emul.reset();
+        emul
+    }
+
+    #[test]
+    fn counts_every_address() {
+        // jumps.mte calls the subroutine at 0x020 from 0x008 in a loop
+        let mut emul = open(0);
+        // the first step only positions PC and isn't a command
+        assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        let mut profile = Profile::default();
+        let mut steps = 0;
+        loop {
+            profile.before_step(&emul);
+            if emul.exec_one() != Ok(ExecResult::Ok) {
+                break;
+            }
+            profile.after_command(&emul);
+            steps += 1;
+        }
+        let counts = profile.counts().collect::<BTreeMap<_, _>>();
+        // the countdown loops run three and two times, the call and its
+        // subroutine up to RET once
+        let count = |addrs: &[usize]| addrs.iter().map(|addr| counts[addr]).collect::<Vec<_>>();
+        assert_eq!(count(&[2, 3, 6, 7]), [3, 3, 2, 2]);
+        assert_eq!(count(&[8, 32, 33]), [1, 1, 1]);
+        // skipped over
+        assert!(!counts.contains_key(&12) && !counts.contains_key(&31));
+        assert_eq!(profile.total(), steps);
+        profile.clear();
+        assert_eq!(profile.total(), 0);
+    }
+
+    #[test]
+    fn gives_cycles_to_the_call_that_ran() {
+        // library.mte calls A + B, A - B, A + B, A - B and then JZ
+        let mut emul = open(3);
+        let mut profile = Profile::default();
+        for _ in 0..2 {
+            emul.reset();
+            loop {
+                profile.before_step(&emul);
+                if emul.exec_one_call() != Ok(ExecResult::Ok) {
+                    break;
+                }
+                profile.after_call(&emul);
+            }
+        }
+        let subroutine = CallCycles {
+            runs: 2,
+            last: 10,
+            total: 20,
+        };
+        let jump = CallCycles {
+            runs: 2,
+            last: 1,
+            total: 2,
+        };
+        let calls = profile.calls().collect::<Vec<_>>();
+        let expected = [subroutine, subroutine, subroutine, subroutine, jump];
+        assert_eq!(calls, expected.into_iter().enumerate().collect::<Vec<_>>());
+        assert_eq!(calls[0].1.average(), 10.0);
+        profile.clear();
+        assert_eq!(profile.calls().count(), 0);
+    }
+}
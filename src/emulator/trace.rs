@@ -0,0 +1,348 @@
+/* emulator/trace.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Execution trace, one row per step: a command, or in the calls window the
+// last command of a call. Nothing here needs the UI: `worker::StepObserver`
+// calls `before_step` and `after_step` around every step and the result is
+// written out as CSV or JSON.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use super::microinstruction::{DataPointerType, Operation};
+use super::{MT1804Emulator, Microinstruction, State};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Access {
+    // the cell MP pointed at and what is in it after the step
+    MemoryRead {
+        width: &'static str,
+        address: usize,
+        value: usize,
+    },
+    MemoryWrite {
+        width: &'static str,
+        address: usize,
+        value: usize,
+    },
+    // the port is None when the device pointer picked none
+    PortRead {
+        port: Option<usize>,
+        width: &'static str,
+    },
+    PortWrite {
+        port: Option<usize>,
+        width: &'static str,
+    },
+}
+
+fn width_name(width: DataPointerType) -> &'static str {
+    match width {
+        DataPointerType::Low => "low",
+        DataPointerType::High => "high",
+        DataPointerType::Full => "full",
+    }
+}
+
+fn port_name(port: Option<usize>) -> String {
+    match port {
+        Some(port) => format!("PORT{}", port),
+        None => "NONE".to_owned(),
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MemoryRead {
+                width,
+                address,
+                value,
+            } => write!(f, "read mem[0x{:02X}]=0x{:02X} ({})", address, value, width),
+            Self::MemoryWrite {
+                width,
+                address,
+                value,
+            } => write!(
+                f,
+                "write mem[0x{:02X}]=0x{:02X} ({})",
+                address, value, width
+            ),
+            Self::PortRead { port, width } => write!(f, "read {} ({})", port_name(*port), width),
+            Self::PortWrite { port, width } => write!(f, "write {} ({})", port_name(*port), width),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceRow {
+    pub step: usize,
+    pub address: usize,
+    pub name: String,
+    pub jump: String,
+    // state after the command
+    #[serde(flatten)]
+    pub state: State,
+    pub access: Option<Access>,
+}
+
+// written as on the board, the differential reports use them too
+pub(super) const FLAG_NAMES: [&str; 6] = ["OVR", "C4", "F3", "Z", "/G", "/P"];
+
+impl TraceRow {
+    fn csv_header() -> Vec<String> {
+        let mut header = ["step", "address", "name", "jump", "F", "Y"]
+            .map(String::from)
+            .to_vec();
+        header.extend(FLAG_NAMES.map(String::from));
+        header.extend(["PC", "SP", "MP"].map(String::from));
+        header.extend((0..16).map(|ind| format!("R{}", ind)));
+        header.extend(["Q".to_owned(), "access".to_owned()]);
+        header
+    }
+
+    fn csv_record(&self) -> Vec<String> {
+        let state = &self.state;
+        let mut record = vec![
+            self.step.to_string(),
+            self.address.to_string(),
+            self.name.clone(),
+            self.jump.clone(),
+            state.func_output.to_string(),
+            state.func_value.to_string(),
+        ];
+        record.extend(state.flags.iter().map(u8::to_string));
+        record.extend([
+            state.program_counter.to_string(),
+            state.stack_pointer.to_string(),
+            state.multiplexor_value.to_string(),
+        ]);
+        record.extend(state.registers.iter().map(u8::to_string));
+        record.push(
+            self.access
+                .as_ref()
+                .map(Access::to_string)
+                .unwrap_or_default(),
+        );
+        record
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+fn write_csv_line(out: &mut impl Write, fields: &[String]) -> io::Result<()> {
+    let line = fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(out, "{}", line)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    rows: Vec<TraceRow>,
+    // MP before the step, it moves on after a load or a store with +1
+    mp: usize,
+}
+
+impl Trace {
+    pub fn rows(&self) -> &[TraceRow] {
+        &self.rows
+    }
+
+    pub fn before_step(&mut self, emul: &dyn MT1804Emulator) {
+        self.mp = emul.get_mp();
+    }
+
+    pub fn after_step(&mut self, emul: &dyn MT1804Emulator) {
+        let Ok(cmd) = emul.executed_command() else {
+            return;
+        };
+        let access =
+            Microinstruction::from_command(&cmd).and_then(|instr| self.access(instr, emul));
+        self.rows.push(TraceRow {
+            step: self.rows.len() + 1,
+            address: cmd.get_num(),
+            name: emul.command_get_name(cmd.clone()).unwrap_or_default(),
+            jump: emul.command_get_jump_name(cmd).unwrap_or_default(),
+            state: emul.get_state(),
+            access,
+        });
+    }
+
+    fn access(&self, instr: Microinstruction, emul: &dyn MT1804Emulator) -> Option<Access> {
+        let value = || emul.get_mem_value(self.mp).unwrap_or_default();
+        Some(match instr.operation() {
+            Operation::LoadMemory(width) => Access::MemoryRead {
+                width: width_name(width),
+                address: self.mp,
+                value: value(),
+            },
+            Operation::StoreMemory(width) => Access::MemoryWrite {
+                width: width_name(width),
+                address: self.mp,
+                value: value(),
+            },
+            Operation::LoadDevice(width) => Access::PortRead {
+                port: emul.get_port(),
+                width: width_name(width),
+            },
+            Operation::StoreDevice(width) => Access::PortWrite {
+                port: emul.get_port(),
+                width: width_name(width),
+            },
+            _ => return None,
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+    }
+
+    pub fn write_csv(&self, mut out: impl Write) -> io::Result<()> {
+        write_csv_line(&mut out, &TraceRow::csv_header())?;
+        for row in &self.rows {
+            write_csv_line(&mut out, &row.csv_record())?;
+        }
+        out.flush()
+    }
+
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, &self.rows)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, NativeImplementation};
+
+    // memory.mte up to its end, stores and loads included
+    fn memory_trace() -> Trace {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[2].1).unwrap();
+        emul.reset();
+        emul.exec_one().unwrap();
+        let mut trace = Trace::default();
+        loop {
+            trace.before_step(&emul);
+            if emul.exec_one().unwrap() != ExecResult::Ok {
+                return trace;
+            }
+            trace.after_step(&emul);
+        }
+    }
+
+    #[test]
+    fn memory_accesses_name_the_cell() {
+        let trace = memory_trace();
+        let accesses = trace
+            .rows()
+            .iter()
+            .filter_map(|row| match row.access {
+                Some(Access::MemoryRead { address, value, .. }) => Some(('r', address, value)),
+                Some(Access::MemoryWrite { address, value, .. }) => Some(('w', address, value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // the two halves of mem[0x12] one after the other, where the cells
+        // end up is in differential::END_STATES
+        let expected = [
+            ('w', 0x12, 0x05),
+            ('w', 0x12, 53),
+            ('w', 0x00, 202),
+            ('r', 0x01, 0),
+            ('r', 53, 0),
+            ('r', 53, 0),
+            ('w', 53, 172),
+            ('r', 0x00, 202),
+        ];
+        assert_eq!(accesses, expected);
+    }
+
+    #[test]
+    fn writes_csv() {
+        let trace = memory_trace();
+        let mut out = Vec::new();
+        trace.write_csv(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), trace.rows().len() + 1);
+        let header = lines[0].split(',').collect::<Vec<_>>();
+        assert_eq!(header[..6], ["step", "address", "name", "jump", "F", "Y"]);
+        assert_eq!(header.last(), Some(&"access"));
+        // names with commas in them are quoted, so every line splits the same
+        for (line, row) in lines[1..].iter().zip(trace.rows()) {
+            let first = line.split(',').take(2).collect::<Vec<_>>();
+            assert_eq!(first, [row.step.to_string(), row.address.to_string()]);
+            assert_eq!(csv_line_len(line), header.len());
+        }
+    }
+
+    fn csv_line_len(line: &str) -> usize {
+        let mut quoted = false;
+        1 + line
+            .chars()
+            .filter(|ch| {
+                if *ch == '"' {
+                    quoted = !quoted;
+                }
+                *ch == ',' && !quoted
+            })
+            .count()
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("R1"), "R1");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn writes_json() {
+        let trace = memory_trace();
+        let mut out = Vec::new();
+        trace.write_json(&mut out).unwrap();
+        let rows: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let rows = rows.as_array().unwrap();
+        assert_eq!(rows.len(), trace.rows().len());
+        assert_eq!(rows[0]["step"], 1);
+        let write = rows
+            .iter()
+            .find(|row| row["access"]["kind"] == "memory_write")
+            .unwrap();
+        assert!(write["access"]["address"].is_u64());
+        assert!(write["access"]["value"].is_u64());
+        assert!(rows
+            .iter()
+            .all(|row| row["access"].is_null() || row["access"]["kind"].is_string()));
+    }
+}
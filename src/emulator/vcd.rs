@@ -0,0 +1,286 @@
+/* emulator/vcd.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Value Change Dump of the machine signals for GTKWave and friends. Every
+// state is one timestep, so the time axis counts microinstructions.
+// `Waveform` takes the state after every executed command from
+// `worker::StepObserver`. The engine runs a call of the calls window as a
+// whole, so only command steps make it in.
+
+use std::io::{self, Write};
+
+use super::{MT1804Emulator, State};
+
+#[derive(Clone, Copy)]
+enum Source {
+    // index in `State::flags`: ovr, c4, f3, z, g, p
+    Flag(usize),
+    F,
+    Y,
+    PC,
+    SP,
+    // 16 is Q
+    Reg(usize),
+}
+
+impl Source {
+    fn value(self, state: &State) -> u64 {
+        match self {
+            Self::Flag(ind) => state.flags[ind] as u64,
+            Self::F => state.func_output as u64,
+            Self::Y => state.func_value as u64,
+            Self::PC => state.program_counter as u64,
+            Self::SP => state.stack_pointer as u64,
+            Self::Reg(ind) => state.registers.get(ind).copied().unwrap_or_default() as u64,
+        }
+    }
+}
+
+struct Signal {
+    name: String,
+    width: u32,
+    source: Source,
+}
+
+fn signals() -> Vec<Signal> {
+    // G and P are active low, the names say so the way VCD allows
+    let fixed = [
+        ("OVR", 1, Source::Flag(0)),
+        ("C4", 1, Source::Flag(1)),
+        ("F3", 1, Source::Flag(2)),
+        ("Z", 1, Source::Flag(3)),
+        ("nG", 1, Source::Flag(4)),
+        ("nP", 1, Source::Flag(5)),
+        ("F", 4, Source::F),
+        ("Y", 4, Source::Y),
+        ("PC", 12, Source::PC),
+        ("SP", 8, Source::SP),
+    ];
+    let fixed = fixed.into_iter().map(|(name, width, source)| Signal {
+        name: name.to_owned(),
+        width,
+        source,
+    });
+    let registers = (0..17).map(|ind| Signal {
+        name: match ind {
+            16 => "Q".to_owned(),
+            ind => format!("R{}", ind),
+        },
+        width: 4,
+        source: Source::Reg(ind),
+    });
+    fixed.chain(registers).collect()
+}
+
+// short printable identifiers, '!' and up
+fn identifier(mut ind: usize) -> String {
+    const FIRST: u8 = b'!';
+    const COUNT: usize = (b'~' - b'!' + 1) as usize;
+    let mut id = String::new();
+    loop {
+        id.push((FIRST + (ind % COUNT) as u8) as char);
+        ind /= COUNT;
+        if ind == 0 {
+            return id;
+        }
+        ind -= 1;
+    }
+}
+
+fn write_value(out: &mut impl Write, signal: &Signal, id: &str, value: u64) -> io::Result<()> {
+    // anything wider than declared is cut off
+    let value = value & ((1u64 << signal.width) - 1);
+    match signal.width {
+        1 => writeln!(out, "{}{}", value, id),
+        width => writeln!(out, "b{:0width$b} {}", value, id, width = width as usize),
+    }
+}
+
+pub fn write_vcd<'a>(
+    states: impl IntoIterator<Item = &'a State>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let signals = signals();
+    let ids = (0..signals.len()).map(identifier).collect::<Vec<_>>();
+    writeln!(out, "$version mtemu $end")?;
+    writeln!(out, "$timescale 1 us $end")?;
+    writeln!(out, "$scope module mt1804 $end")?;
+    for (signal, id) in signals.iter().zip(&ids) {
+        match signal.width {
+            1 => writeln!(out, "$var wire 1 {} {} $end", id, signal.name)?,
+            width => writeln!(
+                out,
+                "$var wire {} {} {} [{}:0] $end",
+                width,
+                id,
+                signal.name,
+                width - 1
+            )?,
+        }
+    }
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")?;
+    let mut last: Option<Vec<u64>> = None;
+    for (time, state) in states.into_iter().enumerate() {
+        let values = signals
+            .iter()
+            .map(|signal| signal.source.value(state))
+            .collect::<Vec<_>>();
+        writeln!(out, "#{}", time)?;
+        match last {
+            None => {
+                writeln!(out, "$dumpvars")?;
+                for ((signal, id), value) in signals.iter().zip(&ids).zip(&values) {
+                    write_value(&mut out, signal, id, *value)?;
+                }
+                writeln!(out, "$end")?;
+            }
+            Some(ref last) => {
+                for (((signal, id), value), prev) in signals.iter().zip(&ids).zip(&values).zip(last)
+                {
+                    if value != prev {
+                        write_value(&mut out, signal, id, *value)?;
+                    }
+                }
+            }
+        }
+        last = Some(values);
+    }
+    out.flush()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Waveform {
+    states: Vec<State>,
+}
+
+impl Waveform {
+    pub fn after_command(&mut self, emul: &dyn MT1804Emulator) {
+        self.states.push(emul.get_state());
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    pub fn write_vcd(&self, out: impl Write) -> io::Result<()> {
+        write_vcd(&self.states, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, NativeImplementation};
+
+    fn state(pc: usize, f: u8, z: u8) -> State {
+        State {
+            program_counter: pc,
+            stack_pointer: 0,
+            multiplexor_value: 0,
+            func_output: f,
+            func_value: f,
+            registers: vec![0; 17],
+            flags: [0, 0, 0, z, 1, 1],
+        }
+    }
+
+    fn dump(states: &[State]) -> String {
+        let mut out = Vec::new();
+        write_vcd(states, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn identifiers_are_unique_and_printable() {
+        let ids = (0..10_000).map(identifier).collect::<Vec<_>>();
+        assert_eq!(ids[0], "!");
+        assert_eq!(ids[93], "~");
+        assert_eq!(ids[94], "!!");
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+        assert!(ids
+            .iter()
+            .flat_map(|id| id.bytes())
+            .all(|ch| ch.is_ascii_graphic()));
+    }
+
+    #[test]
+    fn declares_every_signal() {
+        let text = dump(&[state(0, 0, 1)]);
+        let (header, body) = text.split_once("$enddefinitions $end\n").unwrap();
+        let vars = header
+            .lines()
+            .filter(|line| line.starts_with("$var"))
+            .collect::<Vec<_>>();
+        assert_eq!(vars.len(), signals().len());
+        assert_eq!(vars[0], "$var wire 1 ! OVR $end");
+        assert!(vars.contains(&"$var wire 12 ) PC [11:0] $end"));
+        assert!(vars.last().unwrap().ends_with(" Q [3:0] $end"));
+        assert!(body.starts_with("#0\n$dumpvars\n"));
+        assert!(body.contains("b000000000000 )\n"));
+    }
+
+    #[test]
+    fn dumps_only_changes() {
+        let text = dump(&[state(0, 5, 1), state(1, 5, 1), state(1, 0x1A, 0)]);
+        let body = text.split_once("$enddefinitions $end\n").unwrap().1;
+        // F3 is '#' itself, only a timestep starts a line with it
+        let body = format!("\n{}", body.trim_end());
+        let steps = body.split("\n#").skip(1).collect::<Vec<_>>();
+        assert_eq!(steps.len(), 3);
+        // PC is ')' and Z is '$'
+        assert_eq!(steps[1], "1\nb000000000001 )");
+        // F and Y are cut down to four bits
+        assert_eq!(steps[2], "2\n0$\nb1010 '\nb1010 (");
+    }
+
+    #[test]
+    fn records_every_command() {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[0].1).unwrap();
+        emul.reset();
+        let mut waveform = Waveform::default();
+        let mut commands = 0;
+        while emul.exec_one().unwrap() == ExecResult::Ok {
+            waveform.after_command(&emul);
+            commands += 1;
+        }
+        assert_eq!(waveform.len(), commands);
+        let text = {
+            let mut out = Vec::new();
+            waveform.write_vcd(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(text.contains(&format!("\n#{}\n", commands - 1)));
+        waveform.clear();
+        assert!(waveform.is_empty());
+    }
+}
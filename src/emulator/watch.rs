@@ -0,0 +1,231 @@
+/* emulator/watch.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Watchpoints stop on any write that changes a memory cell, a register or Q,
+// and tell which command did it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::MT1804Emulator;
+
+const REG_COUNT: usize = 16;
+const MEM_COUNT: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Watch {
+    Mem(usize),
+    Reg(usize),
+    Q,
+}
+
+impl Watch {
+    pub fn read(&self, emul: &dyn MT1804Emulator) -> Option<i64> {
+        match *self {
+            Self::Mem(addr) if addr < emul.get_mem_length() => {
+                emul.get_mem_value(addr).ok().map(|val| val as i64)
+            }
+            Self::Mem(_) => None,
+            Self::Reg(ind) => emul.get_reg(ind).ok().map(|val| val as i64),
+            Self::Q => Some(emul.get_reg_q() as i64),
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mem(addr) => write!(f, "mem[0x{:02X}]", addr),
+            Self::Reg(ind) => write!(f, "R{}", ind),
+            Self::Q => write!(f, "Q"),
+        }
+    }
+}
+
+impl FromStr for Watch {
+    type Err = String;
+
+    // the same names conditions use
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_uppercase();
+        if name == "Q" {
+            return Ok(Self::Q);
+        }
+        if let Some(ind) = name.strip_prefix('R').and_then(|ind| ind.parse::<usize>().ok()) {
+            if ind < REG_COUNT {
+                return Ok(Self::Reg(ind));
+            }
+        }
+        let addr = name
+            .strip_prefix("MEM[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|addr| match addr.strip_prefix("0X") {
+                Some(hex) => usize::from_str_radix(hex, 16).ok(),
+                None => addr.parse().ok(),
+            });
+        match addr {
+            Some(addr) if addr < MEM_COUNT => Ok(Self::Mem(addr)),
+            _ => Err(format!("\"{}\" cannot be watched", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Watch {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Watch> for String {
+    fn from(watch: Watch) -> Self {
+        watch.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watch: Watch,
+    pub old: i64,
+    pub new: i64,
+    // the command that did the write
+    pub address: usize,
+    pub command: String,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed from 0x{:X} to 0x{:X} by 0x{:03X} {}",
+            self.watch, self.old, self.new, self.address, self.command
+        )
+    }
+}
+
+// Remembers the watched values between steps
+#[derive(Clone, Debug, Default)]
+pub struct Watcher {
+    watches: Vec<Watch>,
+    values: Vec<Option<i64>>,
+}
+
+impl Watcher {
+    pub fn new(watches: Vec<Watch>, emul: &dyn MT1804Emulator) -> Self {
+        let values = watches.iter().map(|watch| watch.read(emul)).collect();
+        Self { watches, values }
+    }
+
+    // call after every executed command
+    pub fn check(&mut self, emul: &dyn MT1804Emulator) -> Option<WatchHit> {
+        let mut hit = None;
+        for (watch, value) in self.watches.iter().zip(self.values.iter_mut()) {
+            let new = watch.read(emul);
+            if let (None, Some(old), Some(new)) = (&hit, *value, new) {
+                if old != new {
+                    hit = Some((*watch, old, new));
+                }
+            }
+            *value = new;
+        }
+        let (watch, old, new) = hit?;
+        let cmd = emul.executed_command().ok();
+        Some(WatchHit {
+            watch,
+            old,
+            new,
+            address: cmd.as_ref().map_or(0, |cmd| cmd.get_num()),
+            command: cmd
+                .and_then(|cmd| emul.command_get_name(cmd).ok())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{ExecResult, NativeImplementation};
+
+    #[test]
+    fn parses_names() {
+        let parse = |name: &str| name.parse::<Watch>();
+        assert_eq!(parse("Q"), Ok(Watch::Q));
+        assert_eq!(parse(" r15 "), Ok(Watch::Reg(15)));
+        assert_eq!(parse("mem[0x1f]"), Ok(Watch::Mem(0x1F)));
+        assert_eq!(parse("MEM[255]"), Ok(Watch::Mem(255)));
+        for watch in [Watch::Q, Watch::Reg(3), Watch::Mem(0xAB)] {
+            assert_eq!(parse(&watch.to_string()), Ok(watch));
+        }
+    }
+
+    #[test]
+    fn rejects_what_is_not_there() {
+        for name in [
+            "R16",
+            "mem[0x100]",
+            "mem[256]",
+            "mem[]",
+            "mem[0xZ]",
+            "PC",
+            "",
+        ] {
+            assert_eq!(
+                name.parse::<Watch>(),
+                Err(format!("\"{}\" cannot be watched", name))
+            );
+        }
+    }
+
+    // the first change of `watch` in sample `index`
+    fn first_hit(index: usize, watch: Watch) -> WatchHit {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[index].1).unwrap();
+        emul.reset();
+        let mut watcher = Watcher::new(vec![Watch::Reg(15), watch], &emul);
+        loop {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+            if let Some(hit) = watcher.check(&emul) {
+                return hit;
+            }
+        }
+    }
+
+    #[test]
+    fn hits_on_a_register_write() {
+        let hit = first_hit(1, Watch::Reg(3));
+        assert_eq!((hit.watch, hit.old, hit.new), (Watch::Reg(3), 0, 8));
+        assert_eq!(hit.address, 3);
+        assert!(!hit.command.is_empty());
+    }
+
+    #[test]
+    fn hits_on_a_memory_write() {
+        let hit = first_hit(2, Watch::Mem(0x12));
+        assert_eq!((hit.watch, hit.old, hit.new), (Watch::Mem(0x12), 0, 0x05));
+        assert!(hit
+            .to_string()
+            .starts_with("mem[0x12] changed from 0x0 to 0x5 by 0x"));
+    }
+}
@@ -0,0 +1,601 @@
+/* emulator/worker.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Run mode off the main loop. The worker takes the emulator for the whole
+// run, executes steps in batches and reports snapshots no more often than
+// asked to. Stopping hands the emulator back to whoever started the run,
+// together with the history the run has added to.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::coverage::Coverage;
+use super::history::History;
+use super::microinstruction::JumpType;
+use super::profile::Profile;
+use super::trace::Trace;
+use super::vcd::Waveform;
+use super::watch::{Watch, WatchHit, Watcher};
+use super::condition::{Condition, ConditionSet};
+use super::{
+    Command, EmulatorError, ExecResult, Implementation, Microinstruction, State, MAX_AUTO_COUNT,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    Commands,
+    Calls,
+}
+
+// (steps per batch, delay after a batch in ms) for every speed level, from
+// watching single steps to as fast as the engine goes
+const SPEEDS: [(usize, u64); 8] = [
+    (1, 500),
+    (1, 200),
+    (1, 50),
+    (1, 20),
+    (16, 20),
+    (256, 20),
+    (512, 0),
+    // a batch is as long as a pause has to wait
+    (4096, 0),
+];
+
+pub const SPEED_LEVELS: u32 = SPEEDS.len() as u32;
+// no snapshots at all until the run stops
+pub const TURBO: u32 = SPEED_LEVELS - 1;
+const FAST: u32 = TURBO - 1;
+
+pub fn speed_name(level: u32) -> String {
+    match level.min(TURBO) {
+        TURBO => "Turbo".to_owned(),
+        FAST => "Max".to_owned(),
+        level => {
+            let (batch, delay) = SPEEDS[level as usize];
+            format!("{} steps/s", batch as u64 * 1000 / delay)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    pub mode: RunMode,
+    // steps between looking at the control channel
+    pub batch: usize,
+    // pause after every batch
+    pub delay: Duration,
+    // None means turbo
+    pub snapshot_interval: Option<Duration>,
+    // command addresses to stop before
+    pub breakpoints: HashSet<usize>,
+    // checked after every command, the run stops when one becomes true
+    pub conditions: Vec<Condition>,
+    // the run stops after a command that changes one of these, never in
+    // calls mode
+    pub watches: Vec<Watch>,
+    // rows are added for every executed command and handed back on stop
+    pub trace: Option<Trace>,
+    // a state for every executed command, never in calls mode
+    pub waveform: Option<Waveform>,
+    // always counted in commands mode, handed back on stop like the trace
+    pub coverage: Coverage,
+    // only while profiling, handed back the same way
+    pub profile: Option<Profile>,
+    // Step Over and Step Out: stop as soon as SP is back down to this
+    pub until_depth: Option<usize>,
+    // Run to Cursor: stop before the command at this address, like a
+    // breakpoint that is gone after the run
+    pub until_address: Option<usize>,
+}
+
+impl RunConfig {
+    pub fn new(mode: RunMode) -> Self {
+        Self {
+            mode,
+            batch: 512,
+            delay: Duration::ZERO,
+            snapshot_interval: Some(Duration::from_millis(50)),
+            breakpoints: HashSet::new(),
+            conditions: Vec::new(),
+            watches: Vec::new(),
+            trace: None,
+            waveform: None,
+            coverage: Coverage::default(),
+            profile: None,
+            until_depth: None,
+            until_address: None,
+        }
+    }
+
+    pub fn with_speed(mut self, level: u32) -> Self {
+        self.set_speed(level);
+        self
+    }
+
+    pub fn set_speed(&mut self, level: u32) {
+        let level = level.min(TURBO);
+        let (batch, delay) = SPEEDS[level as usize];
+        self.batch = batch;
+        self.delay = Duration::from_millis(delay);
+        self.snapshot_interval = match level {
+            TURBO => None,
+            // the UI keeps up with anything slower than that
+            FAST => Some(Duration::from_millis(50)),
+            _ => Some(Duration::ZERO),
+        };
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub state: State,
+    pub stack: Vec<u32>,
+    pub memory: Vec<u32>,
+    pub call_index: usize,
+    // the command at PC, None when no command has that address
+    pub command: Option<Command>,
+}
+
+impl Snapshot {
+    pub fn take(emul: &Implementation) -> Self {
+        Self {
+            state: emul.get_state(),
+            stack: emul.get_stack().into_iter().map(|val| val as u32).collect(),
+            memory: emul.get_mem().into_iter().map(|val| val as u32).collect(),
+            call_index: emul.get_call_index(),
+            command: next_command(emul),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Paused,
+    Halted(ExecResult),
+    Breakpoint(usize),
+    // index in `RunConfig::conditions` and its text
+    Condition(usize, String),
+    Watch(WatchHit),
+    // SP got down to `RunConfig::until_depth`
+    Returned(usize),
+    // PC got to `RunConfig::until_address`
+    Reached(usize),
+    Failed(EmulatorError),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Paused => write!(f, "Paused"),
+            Self::Halted(result) => write!(f, "{}", result),
+            Self::Breakpoint(addr) => write!(f, "Breakpoint at 0x{:03X}", addr),
+            Self::Condition(_, source) => write!(f, "Condition \"{}\" became true", source),
+            Self::Watch(hit) => write!(f, "{}", hit),
+            Self::Returned(depth) => write!(f, "Returned to stack depth {}", depth),
+            Self::Reached(addr) => write!(f, "Reached 0x{:03X}", addr),
+            Self::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Report {
+    Snapshot(Snapshot),
+    // the run is over, `Worker::stop` returns immediately now
+    Stopped,
+}
+
+enum Control {
+    Stop,
+    Speed(u32),
+    Breakpoints(HashSet<usize>),
+    Conditions(Vec<Condition>),
+    Watches(Vec<Watch>),
+}
+
+pub struct Stopped {
+    pub emul: Implementation,
+    pub history: History,
+    pub trace: Option<Trace>,
+    pub waveform: Option<Waveform>,
+    pub coverage: Coverage,
+    pub profile: Option<Profile>,
+    pub reason: StopReason,
+}
+
+pub struct Worker {
+    control: mpsc::Sender<Control>,
+    handle: JoinHandle<Stopped>,
+}
+
+impl Worker {
+    pub fn spawn(
+        emul: Implementation,
+        history: History,
+        config: RunConfig,
+        report: impl FnMut(Report) + Send + 'static,
+    ) -> Self {
+        let (control, requests) = mpsc::channel();
+        let handle = thread::spawn(move || run(emul, history, config, requests, report));
+        Self { control, handle }
+    }
+
+    // Takes effect after the current batch
+    pub fn set_speed(&self, level: u32) {
+        let _ = self.control.send(Control::Speed(level));
+    }
+
+    pub fn set_breakpoints(&self, breakpoints: HashSet<usize>) {
+        let _ = self.control.send(Control::Breakpoints(breakpoints));
+    }
+
+    pub fn set_conditions(&self, conditions: Vec<Condition>) {
+        let _ = self.control.send(Control::Conditions(conditions));
+    }
+
+    pub fn set_watches(&self, watches: Vec<Watch>) {
+        let _ = self.control.send(Control::Watches(watches));
+    }
+
+    // Waits for the current batch to finish
+    pub fn stop(self) -> Stopped {
+        let _ = self.control.send(Control::Stop);
+        match self.handle.join() {
+            Ok(res) => res,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+pub fn next_address(emul: &Implementation) -> usize {
+    // PC stays at -1 until the first step positions it
+    match emul.get_pc() {
+        usize::MAX => 0,
+        pc => pc,
+    }
+}
+
+// PC is an address, offsets and the library make it differ from the index
+// in the program. None before the first step and where no command is.
+pub fn next_command(emul: &Implementation) -> Option<Command> {
+    match emul.get_next_index() {
+        usize::MAX => None,
+        index => emul.get_command(index).ok(),
+    }
+}
+
+// The depth Step Over runs down to, None when the next command is not a
+// subroutine call or a stack loop and a single step will do. CALL pushes the
+// return address, so its body is over once that is popped again; JSP, JSNZ
+// and JSNC4 go to the address already on the stack and are done with it when
+// they pop it.
+pub fn step_over_depth(emul: &Implementation) -> Option<usize> {
+    let cmd = next_command(emul)?;
+    let sp = emul.get_sp();
+    match Microinstruction::from_command(&cmd)?.jump {
+        JumpType::Call => Some(sp),
+        JumpType::Jsp | JumpType::Jsnz | JumpType::Jsnc4 => sp.checked_sub(1),
+        _ => None,
+    }
+}
+
+// The depth Step Out runs down to, None at the top level
+pub fn step_out_depth(emul: &Implementation) -> Option<usize> {
+    emul.get_sp().checked_sub(1)
+}
+
+// Whatever looks at single steps: the history to step back with, the trace,
+// the waveform, coverage and the profiler. Runs and the step buttons both go through it,
+// so they all see the same steps.
+pub struct StepObserver<'a> {
+    pub history: &'a mut History,
+    pub trace: Option<&'a mut Trace>,
+    pub waveform: Option<&'a mut Waveform>,
+    pub coverage: &'a mut Coverage,
+    pub profile: Option<&'a mut Profile>,
+}
+
+impl StepObserver<'_> {
+    pub fn step(
+        &mut self,
+        emul: &mut Implementation,
+        mode: RunMode,
+    ) -> Result<ExecResult, EmulatorError> {
+        // the first command step after a reset only moves PC from -1 to the
+        // start, a call sets PC itself
+        let positioning = mode == RunMode::Commands && emul.get_pc() == usize::MAX;
+        self.history.record(&**emul);
+        if let Some(ref mut trace) = self.trace {
+            trace.before_step(&**emul);
+        }
+        if let Some(ref mut profile) = self.profile {
+            profile.before_step(&**emul);
+        }
+        let result = match mode {
+            RunMode::Calls => emul.exec_one_call(),
+            RunMode::Commands => emul.exec_one(),
+        };
+        match result {
+            Ok(ExecResult::Ok) if !positioning => {}
+            // nothing was executed, no reason to step back there
+            Ok(ExecResult::End | ExecResult::NoCommands) => {
+                self.history.back();
+                return result;
+            }
+            _ => return result,
+        }
+        if let Some(ref mut trace) = self.trace {
+            trace.after_step(&**emul);
+        }
+        if mode == RunMode::Commands {
+            self.coverage.after_command(&**emul);
+        }
+        if let (Some(waveform), RunMode::Commands) = (&mut self.waveform, mode) {
+            waveform.after_command(&**emul);
+        }
+        match (&mut self.profile, mode) {
+            (Some(profile), RunMode::Commands) => profile.after_command(&**emul),
+            (Some(profile), RunMode::Calls) => profile.after_call(&**emul),
+            (None, _) => {}
+        }
+        result
+    }
+}
+
+// The engine runs a whole call at once, by the time a watch is checked the
+// command that changed the value is gone and the hit would name another one
+fn watcher(config: &RunConfig, emul: &Implementation) -> Watcher {
+    match config.mode {
+        RunMode::Commands => Watcher::new(config.watches.clone(), &**emul),
+        RunMode::Calls => Watcher::default(),
+    }
+}
+
+fn run(
+    mut emul: Implementation,
+    mut history: History,
+    mut config: RunConfig,
+    requests: mpsc::Receiver<Control>,
+    mut report: impl FnMut(Report),
+) -> Stopped {
+    let attachment = emul.attach_current_thread();
+    let mut last_snapshot = Instant::now();
+    // a run resumed from a breakpoint has to get past it first
+    let mut first = true;
+    let mut steps = 0;
+    // Step Over, Step Out and Run to Cursor are a single step to the user,
+    // they give up where the engine gives up on a call
+    let bounded = config.until_depth.is_some() || config.until_address.is_some();
+    let mut conditions = ConditionSet::new(config.conditions.clone(), &*emul);
+    let mut watcher = watcher(&config, &emul);
+    let reason = 'run: loop {
+        for _ in 0..config.batch.max(1) {
+            if config.mode == RunMode::Commands && !first {
+                let addr = next_address(&emul);
+                if config.breakpoints.contains(&addr) {
+                    break 'run StopReason::Breakpoint(addr);
+                }
+                if config.until_address == Some(addr) {
+                    break 'run StopReason::Reached(addr);
+                }
+            }
+            first = false;
+            if bounded && steps == MAX_AUTO_COUNT {
+                break 'run StopReason::Halted(ExecResult::Loop);
+            }
+            steps += 1;
+            // positioning PC after a reset does not count as getting anywhere
+            let positioning = emul.get_pc() == usize::MAX;
+            let mut observer = StepObserver {
+                history: &mut history,
+                trace: config.trace.as_mut(),
+                waveform: config.waveform.as_mut(),
+                coverage: &mut config.coverage,
+                profile: config.profile.as_mut(),
+            };
+            match observer.step(&mut emul, config.mode) {
+                Ok(ExecResult::Ok) => {}
+                Ok(result) => break 'run StopReason::Halted(result),
+                Err(err) => break 'run StopReason::Failed(err),
+            }
+            if let Some(hit) = watcher.check(&*emul) {
+                break 'run StopReason::Watch(hit);
+            }
+            if let (Some(depth), false) = (config.until_depth, positioning) {
+                if emul.get_sp() <= depth {
+                    break 'run StopReason::Returned(depth);
+                }
+            }
+            if let Some((ind, cond)) = conditions.check(&*emul) {
+                break 'run StopReason::Condition(ind, cond.source().to_owned());
+            }
+        }
+        if let Some(interval) = config.snapshot_interval {
+            if last_snapshot.elapsed() >= interval {
+                report(Report::Snapshot(Snapshot::take(&emul)));
+                last_snapshot = Instant::now();
+            }
+        }
+        match requests.recv_timeout(config.delay) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(Control::Speed(level)) => config.set_speed(level),
+            Ok(Control::Breakpoints(breakpoints)) => config.breakpoints = breakpoints,
+            Ok(Control::Conditions(new)) => {
+                conditions = ConditionSet::new(new.clone(), &*emul);
+                config.conditions = new;
+            }
+            Ok(Control::Watches(watches)) => {
+                config.watches = watches;
+                watcher = self::watcher(&config, &emul);
+            }
+            Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => break StopReason::Paused,
+        }
+    };
+    report(Report::Stopped);
+    drop(attachment);
+    Stopped {
+        emul,
+        history,
+        trace: config.trace,
+        waveform: config.waveform,
+        coverage: config.coverage,
+        profile: config.profile,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::differential::SAMPLES;
+    use crate::emulator::{MT1804Emulator, NativeImplementation};
+    use std::cmp::Reverse;
+
+    fn open(index: usize) -> Implementation {
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(SAMPLES[index].1).unwrap();
+        emul.reset();
+        Implementation::Native(emul)
+    }
+
+    // library.mte goes around in a loop forever
+    fn run_library(config: RunConfig) -> Stopped {
+        run_worker(open(3), config)
+    }
+
+    fn run_worker(emul: Implementation, config: RunConfig) -> Stopped {
+        let (sender, stopped) = mpsc::channel();
+        let worker = Worker::spawn(emul, History::default(), config, move |report| {
+            if let Report::Stopped = report {
+                let _ = sender.send(());
+            }
+        });
+        stopped.recv().unwrap();
+        worker.stop()
+    }
+
+    #[test]
+    fn reaches_the_cursor() {
+        let mut config = RunConfig::new(RunMode::Commands).with_speed(TURBO);
+        config.until_address = Some(0xF05);
+        let stopped = run_library(config);
+        assert_eq!(stopped.reason, StopReason::Reached(0xF05));
+    }
+
+    // jumps.mte stepped up to the command at `addr`
+    fn jumps_at(addr: usize) -> Implementation {
+        let mut emul = open(0);
+        while next_address(&emul) != addr || emul.get_pc() == usize::MAX {
+            assert_eq!(emul.exec_one(), Ok(ExecResult::Ok));
+        }
+        emul
+    }
+
+    fn step_to_depth(emul: Implementation, depth: usize) -> Stopped {
+        let mut config = RunConfig::new(RunMode::Commands).with_speed(TURBO);
+        config.until_depth = Some(depth);
+        let stopped = run_worker(emul, config);
+        assert_eq!(stopped.reason, StopReason::Returned(depth));
+        stopped
+    }
+
+    #[test]
+    fn steps_over_a_call() {
+        // CALL 0x020 at 0x008, the subroutine returns with RET at 0x021
+        let emul = jumps_at(0x008);
+        let sp = emul.get_sp();
+        assert_eq!(step_over_depth(&emul), Some(sp));
+        let stopped = step_to_depth(emul, sp);
+        assert_eq!(stopped.emul.get_pc(), 0x009);
+        assert_eq!(stopped.emul.get_sp(), sp);
+    }
+
+    #[test]
+    fn steps_over_a_stack_loop() {
+        // JSNZ at 0x003 goes back to 0x002 until R0 counts down to zero
+        let emul = jumps_at(0x003);
+        let sp = emul.get_sp();
+        assert_eq!(step_over_depth(&emul), Some(sp - 1));
+        let stopped = step_to_depth(emul, sp - 1);
+        assert_eq!(stopped.emul.get_pc(), 0x004);
+        assert_eq!(stopped.emul.get_state().registers[0], 0);
+    }
+
+    #[test]
+    fn steps_out_of_a_call() {
+        let emul = jumps_at(0x020);
+        let sp = emul.get_sp();
+        assert_eq!(step_over_depth(&emul), None);
+        assert_eq!(step_out_depth(&emul), Some(sp - 1));
+        let stopped = step_to_depth(emul, sp - 1);
+        assert_eq!(stopped.emul.get_pc(), 0x009);
+    }
+
+    #[test]
+    fn gives_up_on_an_unreachable_cursor() {
+        let mut config = RunConfig::new(RunMode::Commands).with_speed(TURBO);
+        config.until_address = Some(0x123);
+        let stopped = run_library(config);
+        assert_eq!(stopped.reason, StopReason::Halted(ExecResult::Loop));
+    }
+
+    #[test]
+    fn names_the_speeds() {
+        let names = (0..SPEED_LEVELS).map(speed_name).collect::<Vec<_>>();
+        let expected = [
+            "2 steps/s",
+            "5 steps/s",
+            "20 steps/s",
+            "50 steps/s",
+            "800 steps/s",
+            "12800 steps/s",
+            "Max",
+            "Turbo",
+        ];
+        assert_eq!(names, expected);
+        assert_eq!(speed_name(TURBO + 1), "Turbo");
+    }
+
+    #[test]
+    fn every_level_is_faster() {
+        let config = |level| RunConfig::new(RunMode::Commands).with_speed(level);
+        let slowest = config(0);
+        assert_eq!(
+            (slowest.batch, slowest.delay),
+            (1, Duration::from_millis(500))
+        );
+        assert_eq!(slowest.snapshot_interval, Some(Duration::ZERO));
+        for level in 1..SPEED_LEVELS {
+            let (slower, faster) = (config(level - 1), config(level));
+            // a shorter pause, or a longer batch with the same one
+            let pace = |config: &RunConfig| (Reverse(config.delay), config.batch);
+            assert!(pace(&faster) > pace(&slower), "level {}", level);
+        }
+        assert_eq!(
+            config(FAST).snapshot_interval,
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(config(TURBO).snapshot_interval, None);
+        // anything above turbo is turbo
+        assert_eq!(config(TURBO + 3).batch, config(TURBO).batch);
+    }
+}
@@ -23,6 +23,7 @@ mod application;
 mod config;
 mod ui;
 mod emulator;
+mod project;
 mod utils;
 
 use self::application::MtemuApplication;
@@ -32,6 +33,15 @@ use gettextrs::{bind_textdomain_codeset, bindtextdomain, textdomain};
 use gtk::{gio, glib};
 use gtk::prelude::*;
 
+// GApplication owns argv, so the engine is picked through the environment:
+// MTEMU_ENGINE=native runs the pure Rust port instead of the managed one
+fn select_emulator() -> emulator::Implementation {
+    match std::env::var("MTEMU_ENGINE").as_deref() {
+        Ok("native") => emulator::Implementation::Native(emulator::NativeImplementation::new()),
+        _ => emulator::Implementation::Original(emulator::OriginalImplementation::new()),
+    }
+}
+
 fn main() -> glib::ExitCode {
     // Set up gettext translations
     bindtextdomain(GETTEXT_PACKAGE, LOCALEDIR).expect("Unable to bind the text domain");
@@ -49,7 +59,7 @@ fn main() -> glib::ExitCode {
     // desktop features such as file opening and single-instance applications.
     let app = MtemuApplication::new("org.bmstu.mtemu",
                                     &gio::ApplicationFlags::empty(),
-                                    emulator::OriginalImplementation::new());
+                                    select_emulator());
 
     // Run the application. This function will block until the application
     // exits. Upon return, we have our exit code to return to the shell. (This
@@ -0,0 +1,237 @@
+/* project.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+// Everything the .mte format has no room for lives in a JSON file next to
+// the program, "program.mte" gets "program.mte.json".
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::emulator::{
+    condition::Condition, memory_image::MemoryImage, watch::Watch, MT1804Emulator,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Breakpoints(BTreeSet<usize>);
+
+impl Breakpoints {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.0.contains(&addr)
+    }
+    pub fn set(&mut self, addr: usize, enabled: bool) {
+        match enabled {
+            true => self.0.insert(addr),
+            false => self.0.remove(&addr),
+        };
+    }
+    pub fn addresses(&self) -> HashSet<usize> {
+        self.0.iter().copied().collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    // A breakpoint belongs to its command, but it is kept by address and an
+    // edit renumbers every command after it up to the next offset. `before`
+    // and `after` are `command_addresses` around the edit.
+    pub fn command_inserted(
+        &mut self,
+        index: usize,
+        before: &[Option<usize>],
+        after: &[Option<usize>],
+    ) {
+        self.renumber(before, after, |ind| match ind < index {
+            true => Some(ind),
+            false => Some(ind + 1),
+        });
+    }
+    // the command at `index` is gone together with its breakpoint
+    pub fn command_removed(
+        &mut self,
+        index: usize,
+        before: &[Option<usize>],
+        after: &[Option<usize>],
+    ) {
+        self.renumber(before, after, |ind| match ind.cmp(&index) {
+            Ordering::Less => Some(ind),
+            Ordering::Equal => None,
+            Ordering::Greater => Some(ind - 1),
+        });
+    }
+    // replacing an offset moves everything after it
+    pub fn command_replaced(&mut self, before: &[Option<usize>], after: &[Option<usize>]) {
+        self.renumber(before, after, Some);
+    }
+    // `moved` is where the command at an old index is now
+    fn renumber(
+        &mut self,
+        before: &[Option<usize>],
+        after: &[Option<usize>],
+        moved: impl Fn(usize) -> Option<usize>,
+    ) {
+        self.0 = before
+            .iter()
+            .enumerate()
+            .filter(|(_, addr)| addr.is_some_and(|addr| self.0.contains(&addr)))
+            .filter_map(|(ind, _)| *after.get(moved(ind)?)?)
+            .collect();
+    }
+}
+
+// The address of every command in program order. Offsets are never executed,
+// so there is nothing for a breakpoint to stop at.
+pub fn command_addresses(emul: &dyn MT1804Emulator) -> Vec<Option<usize>> {
+    (0..emul.commands_count())
+        .map(|ind| {
+            emul.get_command(ind)
+                .ok()
+                .filter(|cmd| !cmd.is_offset())
+                .map(|cmd| cmd.get_num())
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    #[serde(default, skip_serializing_if = "Breakpoints::is_empty")]
+    pub breakpoints: Breakpoints,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watches: Vec<Watch>,
+    // what the data memory holds when the program is opened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryImage>,
+}
+
+impl Project {
+    pub fn path(program: &Path) -> PathBuf {
+        let mut name = program.as_os_str().to_owned();
+        name.push(".json");
+        PathBuf::from(name)
+    }
+
+    // a program without a project file just has nothing extra
+    pub fn load(program: &Path) -> io::Result<Self> {
+        match std::fs::read(Self::path(program)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::from),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, program: &Path) -> io::Result<()> {
+        let path = Self::path(program);
+        if *self == Self::default() {
+            // don't leave a stale one behind to be picked up on the next load
+            return match std::fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Command, NativeImplementation};
+
+    const COMMAND_SIZE: usize = 6;
+
+    // A, B, an offset to 0x10, C and D: C and D stay at 0x10 and 0x11 while
+    // whatever comes before the offset is edited
+    fn program() -> NativeImplementation {
+        let mut emul = NativeImplementation::new();
+        for pos in 0..4 {
+            emul.add_command(pos, &Command::new(pos as i32, &[0; 10]))
+                .unwrap();
+        }
+        let mut raw = emul.export_raw();
+        let commands = raw.len() - 4 * COMMAND_SIZE;
+        raw[commands - 1] += 1;
+        let offset = commands + 2 * COMMAND_SIZE;
+        raw.splice(offset..offset, [1, 0x01, 0, 0, 0, 0]);
+        let mut emul = NativeImplementation::new();
+        emul.open_raw(&raw).unwrap();
+        emul
+    }
+
+    fn breakpoints(addresses: &[usize]) -> Breakpoints {
+        let mut breakpoints = Breakpoints::default();
+        for addr in addresses {
+            breakpoints.set(*addr, true);
+        }
+        breakpoints
+    }
+
+    #[test]
+    fn offsets_have_no_address() {
+        let addresses = command_addresses(&program());
+        assert_eq!(addresses, [Some(0), Some(1), None, Some(0x10), Some(0x11)]);
+    }
+
+    #[test]
+    fn inserting_moves_breakpoints_up_to_the_offset() {
+        let mut emul = program();
+        let mut bps = breakpoints(&[1, 0x10, 0x11]);
+        let before = command_addresses(&emul);
+        emul.add_command(0, &Command::new(0, &[0; 10])).unwrap();
+        bps.command_inserted(0, &before, &command_addresses(&emul));
+        assert_eq!(bps, breakpoints(&[2, 0x10, 0x11]));
+    }
+
+    #[test]
+    fn removing_drops_the_breakpoint_of_the_command() {
+        let mut emul = program();
+        let mut bps = breakpoints(&[0, 1, 0x10, 0x11]);
+        let before = command_addresses(&emul);
+        emul.remove_command(0).unwrap();
+        bps.command_removed(0, &before, &command_addresses(&emul));
+        assert_eq!(bps, breakpoints(&[0, 0x10, 0x11]));
+        // after the offset it is the commands up to the next one that move
+        let before = command_addresses(&emul);
+        emul.remove_command(2).unwrap();
+        bps.command_removed(2, &before, &command_addresses(&emul));
+        assert_eq!(bps, breakpoints(&[0, 0x10]));
+    }
+
+    #[test]
+    fn replacing_the_offset_takes_breakpoints_along() {
+        let mut emul = program();
+        let mut bps = breakpoints(&[1, 0x10]);
+        let before = command_addresses(&emul);
+        emul.update_command(2, &Command::new(2, &[0; 10])).unwrap();
+        bps.command_replaced(&before, &command_addresses(&emul));
+        assert_eq!(bps, breakpoints(&[1, 3]));
+    }
+
+    #[test]
+    fn breakpoints_are_saved_as_addresses() {
+        let bps = breakpoints(&[3, 0x10]);
+        let json = serde_json::to_string(&bps).unwrap();
+        assert_eq!(json, "[3,16]");
+        assert_eq!(serde_json::from_str::<Breakpoints>(&json).unwrap(), bps);
+    }
+}
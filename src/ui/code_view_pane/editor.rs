@@ -260,25 +260,20 @@ impl InstructionEditor {
 
 impl PlainCommandRepr for CommandRepr {
     fn from_command(cmd: &emulator::Command) -> Self {
-        let words = cmd.get_words().unwrap();
+        let instr = emulator::Microinstruction::from_command(cmd).unwrap();
+        let [_, _, _, jump, load, args, func, a_arg, b_arg, d_arg] = instr.to_words();
         glib::Object::builder()
-            .property("addr", (words[2] & 0b1111) | ((words[1] & 0b1111) << 4) | ((words[0] & 0b1111) << 8))
-            .property("jump", words[3] as u8)
-            .property("load", words[4] as u8)
-            .property("args", words[5] as u8)
-            .property("func", words[6] as u8)
-            .property("flag", words[4] as u8)
-            .property("pointer", {
-                let mut res = words[5];
-                if words[5] == 8 { res = 3; }
-                res as u8
-            }) // 8 => 3 && <3 || -1
-            .property("pointer-size", {
-                words[5] as u8
-            }) // <3 || -1
-            .property("a-arg", words[7] as u8)
-            .property("b-arg", words[8] as u8)
-            .property("d-arg", words[9] as u8)
+            .property("addr", instr.next_addr as u32)
+            .property("jump", jump)
+            .property("load", load)
+            .property("args", args)
+            .property("func", func)
+            .property("flag", load)
+            .property("pointer", if args == 8 { 3 } else { args }) // 8 => 3 && <3 || -1
+            .property("pointer-size", args) // <3 || -1
+            .property("a-arg", a_arg)
+            .property("b-arg", b_arg)
+            .property("d-arg", d_arg)
             .build()
     }
     fn get_words(&self) -> [u8;10]  {
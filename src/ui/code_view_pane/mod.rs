@@ -20,16 +20,19 @@
 pub mod editor;
 use adw::subclass::prelude::*;
 
-use gtk::{gio, glib, prelude::ObjectExt};
-use crate::emulator::MT1804Emulator;
+use gtk::{gdk, gio, glib, prelude::{ListModelExt, ObjectExt}};
+use crate::emulator::{coverage::{Coverage, Level}, MT1804Emulator};
 
 mod imp {
     use std::cell::{Cell, RefCell};
 
     use gtk::{
-        glib::Properties,
-        prelude::{Cast, CastNone},
-        traits::ListItemExt,
+        glib::{once_cell::sync::Lazy, subclass::Signal, Properties},
+        prelude::{
+            Cast, CastNone, GObjectPropertyExpressionExt, GestureExt, GestureSingleExt, IsA,
+            PopoverExt, SelectionModelExt, StaticType, ToggleButtonExt,
+        },
+        traits::{ListItemExt, WidgetExt},
     };
 
     use crate::{application::MtemuApplication, emulator::Command};
@@ -47,6 +50,13 @@ mod imp {
         jump: RefCell<String>,
         #[property(get, set)]
         binary: RefCell<String>,
+        #[property(get, set)]
+        breakpoint: Cell<bool>,
+        // css classes of the address cell
+        #[property(get, set)]
+        coverage: RefCell<Vec<String>>,
+        #[property(get, set)]
+        coverage_info: RefCell<String>,
     }
 
     #[glib::object_subclass]
@@ -68,9 +78,12 @@ mod imp {
             });
             Some(Self {
                 addr: Cell::new(cmd.get_num() as i32),
-                name: RefCell::new(emul.command_get_name(cmd.clone())),
-                jump: RefCell::new(emul.command_get_jump_name(cmd.clone())),
+                name: RefCell::new(emul.command_get_name(cmd.clone()).unwrap_or_default()),
+                jump: RefCell::new(emul.command_get_jump_name(cmd.clone()).unwrap_or_default()),
                 binary: RefCell::new(words),
+                breakpoint: Cell::new(false),
+                coverage: Default::default(),
+                coverage_info: Default::default(),
             })
         }
     }
@@ -81,6 +94,8 @@ mod imp {
         #[template_child]
         pub code_list: TemplateChild<gtk::ColumnView>,
         #[template_child]
+        pub code_list_breakpoint: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
         pub code_list_addr: TemplateChild<gtk::ColumnViewColumn>,
         #[template_child]
         pub code_list_command: TemplateChild<gtk::ColumnViewColumn>,
@@ -96,6 +111,8 @@ mod imp {
         pub add_button: TemplateChild<gtk::Button>,
         #[template_child]
         pub update_button: TemplateChild<gtk::Button>,
+        // shared by all rows, pointed at the one clicked
+        row_menu: RefCell<Option<gtk::PopoverMenu>>,
     }
 
     #[glib::object_subclass]
@@ -114,10 +131,29 @@ mod imp {
     }
 
     impl ObjectImpl for CodeViewPane {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("breakpoint-toggled")
+                     .param_types([i32::static_type(), bool::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
         fn constructed(&self) {
             self.parent_constructed();
+            let menu = gio::Menu::new();
+            menu.append(Some("Run to Cursor"), Some("app.run-to-cursor"));
+            let popover = gtk::PopoverMenu::from_model(Some(&menu));
+            popover.set_has_arrow(false);
+            popover.set_parent(&*self.code_list);
+            self.row_menu.replace(Some(popover));
             self.instance_factories();
         }
+        fn dispose(&self) {
+            if let Some(popover) = self.row_menu.take() {
+                popover.unparent();
+            }
+        }
     }
     impl WidgetImpl for CodeViewPane {}
     impl BoxImpl for CodeViewPane {}
@@ -145,14 +181,88 @@ mod imp {
             self.code_list_command.set_visible(false);
             self.code_list_jump.set_visible(false);
         }
+        // Right click on a row selects it and opens the row menu, whose
+        // actions work on the selection
+        fn attach_row_menu(&self, item: &gtk::ListItem, child: &impl IsA<gtk::Widget>) {
+            let gesture = gtk::GestureClick::builder().button(gdk::BUTTON_SECONDARY).build();
+            let pane = self.obj().clone();
+            gesture.connect_pressed(glib::clone!(@weak item, @weak pane => move |gesture, _, x, y| {
+                let imp = pane.imp();
+                let Some(model) = imp.code_list.model() else {
+                    return;
+                };
+                model.select_item(item.position(), true);
+                let Some(popover) = imp.row_menu.borrow().clone() else {
+                    return;
+                };
+                let Some((x, y)) = gesture
+                    .widget()
+                    .translate_coordinates(&*imp.code_list, x, y)
+                else {
+                    return;
+                };
+                popover.set_pointing_to(Some(&gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+                popover.popup();
+                gesture.set_state(gtk::EventSequenceState::Claimed);
+            }));
+            child.add_controller(gesture);
+        }
         fn instance_factories(&self) {
+            let pane = self.obj().clone();
+            self.code_list_breakpoint.set_factory(Some(&{
+                let factory = gtk::SignalListItemFactory::new();
+                factory.connect_setup(move |_, obj| {
+                    let Some(item) = obj.downcast_ref::<gtk::ListItem>() else {
+                        return;
+                    };
+                    let marker = gtk::ToggleButton::builder()
+                        .icon_name("media-record-symbolic")
+                        .tooltip_text("Breakpoint")
+                        .css_classes(["flat", "circular"])
+                        .build();
+                    // keep the row selectable, the marker is only a gutter
+                    marker.set_focus_on_click(false);
+                    marker.connect_toggled(glib::clone!(@weak item, @weak pane => move |marker| {
+                        let Some(model) = item.item().and_downcast::<super::CommandRepr>() else {
+                            return;
+                        };
+                        marker.set_opacity(if marker.is_active() { 1.0 } else { 0.2 });
+                        if model.breakpoint() == marker.is_active() {
+                            return;
+                        }
+                        model.set_breakpoint(marker.is_active());
+                        pane.emit_by_name::<()>("breakpoint-toggled", &[&model.addr(), &marker.is_active()]);
+                    }));
+                    item.set_child(Some(&marker));
+                });
+                factory.connect_bind(move |_, obj| {
+                    let Some(item) = obj.downcast_ref::<gtk::ListItem>() else {
+                        return;
+                    };
+                    let Some(model) = item.item().and_downcast::<super::CommandRepr>() else {
+                        return;
+                    };
+                    let marker = item.child().and_downcast::<gtk::ToggleButton>().unwrap();
+                    marker.set_active(model.breakpoint());
+                    marker.set_opacity(if model.breakpoint() { 1.0 } else { 0.2 });
+                });
+                factory
+            }));
             self.code_list_addr.set_factory(Some(&{
                 let factory = gtk::SignalListItemFactory::new();
                 factory.connect_setup(move |_, obj| {
                     let Some(item) = obj.downcast_ref::<gtk::ListItem>() else {
                         return;
                     };
-                    item.set_child(Some(&gtk::Label::builder().build()));
+                    let label = gtk::Label::builder().build();
+                    // coverage changes while the row is shown, so it is not set on bind
+                    let repr = item.property_expression("item");
+                    repr.chain_property::<super::CommandRepr>("coverage")
+                        .bind(&label, "css-classes", gtk::Widget::NONE);
+                    repr.chain_property::<super::CommandRepr>("coverage-info")
+                        .bind(&label, "tooltip-text", gtk::Widget::NONE);
+                    pane.imp().attach_row_menu(item, &label);
+                    item.set_child(Some(&label));
                 });
                 factory.connect_bind(move |_, obj| {
                     let Some(item) = obj.downcast_ref::<gtk::ListItem>() else {
@@ -170,9 +280,12 @@ mod imp {
             }));
             self.code_list_command.set_factory(Some(&{
                 let factory = gtk::SignalListItemFactory::new();
+                let pane = self.obj().clone();
                 factory.connect_setup(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                    item.set_child(Some(&gtk::Label::builder().build()));
+                    let label = gtk::Label::builder().build();
+                    pane.imp().attach_row_menu(item, &label);
+                    item.set_child(Some(&label));
                 });
                 factory.connect_bind(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
@@ -189,9 +302,12 @@ mod imp {
             }));
             self.code_list_jump.set_factory(Some(&{
                 let factory = gtk::SignalListItemFactory::new();
+                let pane = self.obj().clone();
                 factory.connect_setup(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                    item.set_child(Some(&gtk::Label::builder().build()));
+                    let label = gtk::Label::builder().build();
+                    pane.imp().attach_row_menu(item, &label);
+                    item.set_child(Some(&label));
                 });
                 factory.connect_bind(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
@@ -208,9 +324,12 @@ mod imp {
             }));
             self.code_list_command_binary.set_factory(Some(&{
                 let factory = gtk::SignalListItemFactory::new();
+                let pane = self.obj().clone();
                 factory.connect_setup(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                    item.set_child(Some(&gtk::Label::builder().build()));
+                    let label = gtk::Label::builder().build();
+                    pane.imp().attach_row_menu(item, &label);
+                    item.set_child(Some(&label));
                 });
                 factory.connect_bind(move |_, obj| {
                     let item = obj.downcast_ref::<gtk::ListItem>().unwrap();
@@ -250,6 +369,26 @@ impl CodeViewPane {
     pub fn get_codes(&self) -> [u8;10] {
         self.imp().get_codes()
     }
+    // The row of the command at the address. Offsets and the library make
+    // that differ from the index, and an offset has the address of the
+    // command before it, so the first row with it wins.
+    pub fn row_of_address(&self, addr: u32) -> Option<u32> {
+        let model = self.imp().code_list.model()?;
+        (0..model.n_items()).find(|row| {
+            model
+                .item(*row)
+                .and_downcast::<CommandRepr>()
+                .is_some_and(|cmd| cmd.addr() == addr as i32)
+        })
+    }
+    // selects the command and scrolls the list to it
+    pub fn show_address(&self, addr: u32) {
+        let Some(row) = self.row_of_address(addr) else {
+            return;
+        };
+        let flags = gtk::ListScrollFlags::SELECT | gtk::ListScrollFlags::FOCUS;
+        self.imp().code_list.scroll_to(row, None::<&gtk::ColumnViewColumn>, flags, None);
+    }
 }
 
 glib::wrapper! {
@@ -261,8 +400,8 @@ impl CommandRepr {
         let emul = app.get_emulator();
         let Some(ref emul) = *emul.borrow() else { return glib::Object::builder().build() };
         let number = cmd.get_num();
-        let name = emul.command_get_name(cmd.clone());
-        let jump = emul.command_get_jump_name(cmd.clone());
+        let name = emul.command_get_name(cmd.clone()).unwrap_or_default();
+        let jump = emul.command_get_jump_name(cmd.clone()).unwrap_or_default();
 
         let mut binary = String::new();
         cmd.get_words().unwrap().iter().for_each(|word| {
@@ -275,4 +414,29 @@ impl CommandRepr {
             .property("binary", binary)
             .build()
     }
+    // no shading at all until something has been executed, and never for
+    // offsets as they don't run
+    pub fn show_coverage(&self, coverage: &Coverage, cmd: &crate::emulator::Command) {
+        if coverage.is_empty() || cmd.is_offset() {
+            self.set_coverage(Vec::<String>::new());
+            self.set_coverage_info("");
+            return;
+        }
+        let class = match coverage.level(cmd) {
+            Level::Missed => "coverage-missed",
+            Level::Partial => "coverage-partial",
+            Level::Full => "coverage-full",
+        };
+        self.set_coverage(vec![class.to_owned()]);
+        let addr = cmd.get_num();
+        let info = match (coverage.hits(addr), coverage.branches(addr)) {
+            (0, _) => "Never executed".to_owned(),
+            (hits, Some(branches)) => format!(
+                "Executed {} times, jump taken {}, fell through {}",
+                hits, branches.taken, branches.fell_through
+            ),
+            (hits, None) => format!("Executed {} times", hits),
+        };
+        self.set_coverage_info(info);
+    }
 }
@@ -0,0 +1,162 @@
+/* condition_view.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use adw::subclass::prelude::*;
+use gtk::{gio, glib};
+
+use crate::emulator::condition::Condition;
+
+mod imp {
+    use gtk::{glib::{subclass::Signal, once_cell::sync::Lazy}, prelude::*};
+
+    use super::*;
+
+    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[template(resource = "/org/bmstu/mtemu/ui/debug_pane/condition_view.ui")]
+    pub struct ConditionView {
+        #[template_child]
+        condition_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        add_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        condition_list: TemplateChild<gtk::ListBox>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for ConditionView {
+        const NAME: &'static str = "ConditionView";
+        type Type = super::ConditionView;
+        type ParentType = gtk::Box;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for ConditionView {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("condition-added")
+                     .param_types([String::static_type()])
+                     .build(),
+                     Signal::builder("condition-removed")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("condition-toggled")
+                     .param_types([u32::static_type(), bool::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
+        fn constructed(&self) {
+            self.parent_constructed();
+            let view = self.obj().clone();
+            self.add_button.connect_clicked(move |_| view.imp().add_condition());
+            let view = self.obj().clone();
+            self.condition_entry.connect_activate(move |_| view.imp().add_condition());
+            self.condition_entry.connect_changed(|entry| {
+                entry.remove_css_class("error");
+                entry.set_tooltip_text(None);
+            });
+        }
+    }
+    impl WidgetImpl for ConditionView {}
+    impl BoxImpl for ConditionView {}
+    impl ConditionView {
+        fn add_condition(&self) {
+            let text = self.condition_entry.text().to_string();
+            self.obj().emit_by_name::<()>("condition-added", &[&text]);
+        }
+        pub fn set_conditions(&self, conditions: &[Condition]) {
+            while let Some(row) = self.condition_list.row_at_index(0) {
+                self.condition_list.remove(&row);
+            }
+            for (ind, cond) in conditions.iter().enumerate() {
+                let row = gtk::Box::builder()
+                    .orientation(gtk::Orientation::Horizontal)
+                    .spacing(10)
+                    .build();
+                let enabled = gtk::CheckButton::builder()
+                    .active(cond.enabled)
+                    .tooltip_text("Enabled")
+                    .build();
+                let view = self.obj().clone();
+                enabled.connect_toggled(move |check| {
+                    view.emit_by_name::<()>("condition-toggled", &[&(ind as u32), &check.is_active()]);
+                });
+                let source = gtk::Label::builder()
+                    .label(cond.source())
+                    .hexpand(true)
+                    .xalign(0.0)
+                    .build();
+                let remove = gtk::Button::builder()
+                    .icon_name("user-trash-symbolic")
+                    .tooltip_text("Remove")
+                    .css_classes(["flat"])
+                    .build();
+                let view = self.obj().clone();
+                remove.connect_clicked(move |_| {
+                    view.emit_by_name::<()>("condition-removed", &[&(ind as u32)]);
+                });
+                row.append(&enabled);
+                row.append(&source);
+                row.append(&remove);
+                self.condition_list.append(&row);
+            }
+        }
+        pub fn condition_accepted(&self) {
+            self.condition_entry.set_text("");
+        }
+        pub fn condition_rejected(&self, message: &str) {
+            self.condition_entry.add_css_class("error");
+            self.condition_entry.set_tooltip_text(Some(message));
+        }
+        // the condition that stopped the run, None clears it
+        pub fn highlight(&self, index: Option<usize>) {
+            match index.and_then(|ind| self.condition_list.row_at_index(ind as i32)) {
+                Some(row) => self.condition_list.select_row(Some(&row)),
+                None => self.condition_list.unselect_all(),
+            }
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct ConditionView(ObjectSubclass<imp::ConditionView>)
+        @extends gtk::Widget,        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl ConditionView {
+    pub fn set_conditions(&self, conditions: &[Condition]) {
+        self.imp().set_conditions(conditions);
+    }
+    pub fn condition_accepted(&self) {
+        self.imp().condition_accepted();
+    }
+    pub fn condition_rejected(&self, message: &str) {
+        self.imp().condition_rejected(message);
+    }
+    pub fn highlight(&self, index: Option<usize>) {
+        self.imp().highlight(index);
+    }
+}
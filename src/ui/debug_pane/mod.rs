@@ -20,14 +20,18 @@
 mod stepping_view;
 mod output_view;
 mod register_view;
+mod port_view;
+mod condition_view;
 
 use adw::subclass::prelude::*;
 use gtk::{gio, glib};
 use stepping_view::SteppingView;
 use output_view::OutputView;
 use register_view::RegisterView;
+use port_view::PortView;
+use condition_view::ConditionView;
 
-use crate::emulator;
+use crate::emulator::{self, condition::Condition, port::SimulatedPort};
 
 mod imp {
     use gtk::{prelude::*, glib::once_cell::sync::Lazy};
@@ -45,6 +49,10 @@ mod imp {
         output_view: TemplateChild<OutputView>,
         #[template_child]
         register_view: TemplateChild<RegisterView>,
+        #[template_child]
+        port_view: TemplateChild<PortView>,
+        #[template_child]
+        condition_view: TemplateChild<ConditionView>,
     }
 
     #[glib::object_subclass]
@@ -66,13 +74,46 @@ mod imp {
         fn signals() -> &'static [Signal] {
             static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
                 vec![Signal::builder("reset-clicked")
+                     .param_types([gtk::Button::static_type()])
+                     .build(),
+                     Signal::builder("reverse-run-clicked")
+                     .param_types([gtk::Button::static_type()])
+                     .build(),
+                     Signal::builder("step-back-clicked")
                      .param_types([gtk::Button::static_type()])
                      .build(),
                      Signal::builder("step-clicked")
                      .param_types([gtk::Button::static_type()])
                      .build(),
+                     Signal::builder("step-over-clicked")
+                     .param_types([gtk::ToggleButton::static_type()])
+                     .build(),
+                     Signal::builder("step-out-clicked")
+                     .param_types([gtk::ToggleButton::static_type()])
+                     .build(),
                      Signal::builder("run-toggled")
                      .param_types([gtk::ToggleButton::static_type()])
+                     .build(),
+                     Signal::builder("condition-added")
+                     .param_types([String::static_type()])
+                     .build(),
+                     Signal::builder("condition-removed")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("condition-toggled")
+                     .param_types([u32::static_type(), bool::static_type()])
+                     .build(),
+                     Signal::builder("register-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build(),
+                     Signal::builder("flag-toggled")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("mp-edited")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("port-input-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
                      .build()]
             });
             SIGNALS.as_ref()
@@ -89,6 +130,15 @@ mod imp {
             self.output_view.renew_state(new_state);
             self.register_view.renew_state(new_state);
         }
+        pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+            self.port_view.renew_ports(ports);
+        }
+        pub fn condition_view(&self) -> ConditionView {
+            self.condition_view.clone()
+        }
+        pub fn stepping_view(&self) -> SteppingView {
+            self.stepping_view.clone()
+        }
         fn propagate_signals(&self) {
             let pane = self.obj().clone();
             self.stepping_view.connect_closure("reset-clicked",
@@ -97,17 +147,83 @@ mod imp {
                                                    pane.emit_by_name::<()>("reset-clicked", &[button]);
                                                }));
             let pane = self.obj().clone();
+            self.stepping_view.connect_closure("reverse-run-clicked",
+                                               false,
+                                               glib::closure_local!(move |_: SteppingView, button: &gtk::Button| {
+                                                   pane.emit_by_name::<()>("reverse-run-clicked", &[button]);
+                                               }));
+            let pane = self.obj().clone();
+            self.stepping_view.connect_closure("step-back-clicked",
+                                               false,
+                                               glib::closure_local!(move |_: SteppingView, button: &gtk::Button| {
+                                                   pane.emit_by_name::<()>("step-back-clicked", &[button]);
+                                               }));
+            let pane = self.obj().clone();
             self.stepping_view.connect_closure("step-clicked",
                                                false,
                                                glib::closure_local!(move |_: SteppingView, button: &gtk::Button| {
                                                    pane.emit_by_name::<()>("step-clicked", &[button]);
                                                }));
             let pane = self.obj().clone();
+            self.stepping_view.connect_closure("step-over-clicked",
+                                               false,
+                                               glib::closure_local!(move |_: SteppingView, button: &gtk::ToggleButton| {
+                                                   pane.emit_by_name::<()>("step-over-clicked", &[button]);
+                                               }));
+            let pane = self.obj().clone();
+            self.stepping_view.connect_closure("step-out-clicked",
+                                               false,
+                                               glib::closure_local!(move |_: SteppingView, button: &gtk::ToggleButton| {
+                                                   pane.emit_by_name::<()>("step-out-clicked", &[button]);
+                                               }));
+            let pane = self.obj().clone();
             self.stepping_view.connect_closure("run-toggled",
                                                false,
                                                glib::closure_local!(move |_: SteppingView, button: &gtk::ToggleButton| {
                                                    pane.emit_by_name::<()>("run-toggled", &[button]);
                                                }));
+            let pane = self.obj().clone();
+            self.condition_view.connect_closure("condition-added",
+                                                false,
+                                                glib::closure_local!(move |_: ConditionView, text: String| {
+                                                    pane.emit_by_name::<()>("condition-added", &[&text]);
+                                                }));
+            let pane = self.obj().clone();
+            self.condition_view.connect_closure("condition-removed",
+                                                false,
+                                                glib::closure_local!(move |_: ConditionView, index: u32| {
+                                                    pane.emit_by_name::<()>("condition-removed", &[&index]);
+                                                }));
+            let pane = self.obj().clone();
+            self.condition_view.connect_closure("condition-toggled",
+                                                false,
+                                                glib::closure_local!(move |_: ConditionView, index: u32, enabled: bool| {
+                                                    pane.emit_by_name::<()>("condition-toggled", &[&index, &enabled]);
+                                                }));
+            let pane = self.obj().clone();
+            self.register_view.connect_closure("register-edited",
+                                               false,
+                                               glib::closure_local!(move |_: RegisterView, index: u32, value: u32| {
+                                                   pane.emit_by_name::<()>("register-edited", &[&index, &value]);
+                                               }));
+            let pane = self.obj().clone();
+            self.output_view.connect_closure("flag-toggled",
+                                             false,
+                                             glib::closure_local!(move |_: OutputView, index: u32| {
+                                                 pane.emit_by_name::<()>("flag-toggled", &[&index]);
+                                             }));
+            let pane = self.obj().clone();
+            self.output_view.connect_closure("mp-edited",
+                                             false,
+                                             glib::closure_local!(move |_: OutputView, value: u32| {
+                                                 pane.emit_by_name::<()>("mp-edited", &[&value]);
+                                             }));
+            let pane = self.obj().clone();
+            self.port_view.connect_closure("port-input-edited",
+                                           false,
+                                           glib::closure_local!(move |_: PortView, port: u32, value: u32| {
+                                               pane.emit_by_name::<()>("port-input-edited", &[&port, &value]);
+                                           }));
         }
     }
 }
@@ -127,4 +243,23 @@ impl DebugPane {
     pub fn renew_state(&self, new_state: &emulator::State) {
         self.imp().renew_state(new_state);
     }
+    pub fn set_conditions(&self, conditions: &[Condition]) {
+        self.imp().condition_view().set_conditions(conditions);
+    }
+    pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+        self.imp().renew_ports(ports);
+    }
+    pub fn condition_accepted(&self) {
+        self.imp().condition_view().condition_accepted();
+    }
+    pub fn condition_rejected(&self, message: &str) {
+        self.imp().condition_view().condition_rejected(message);
+    }
+    pub fn highlight_condition(&self, index: Option<usize>) {
+        self.imp().condition_view().highlight(index);
+    }
+    // runs started from elsewhere still show up as this being pressed
+    pub fn run_button(&self) -> gtk::ToggleButton {
+        self.imp().stepping_view().run_button()
+    }
 }
@@ -18,28 +18,32 @@
  */
 
 use adw::subclass::prelude::*;
+use gtk::prelude::*;
 use gtk::{gio, glib};
 
 use crate::emulator;
+use crate::ui;
 
 mod imp {
+    use glib::{once_cell::sync::Lazy, subclass::Signal};
+
     use super::*;
 
     #[derive(Debug, Default, gtk::CompositeTemplate)]
     #[template(resource = "/org/bmstu/mtemu/ui/debug_pane/output_view.ui")]
     pub struct OutputView {
         #[template_child]
-        ovr_flag: TemplateChild<gtk::Label>,
+        ovr_flag: TemplateChild<gtk::Button>,
         #[template_child]
-        c4_flag: TemplateChild<gtk::Label>,
+        c4_flag: TemplateChild<gtk::Button>,
         #[template_child]
-        f3_flag: TemplateChild<gtk::Label>,
+        f3_flag: TemplateChild<gtk::Button>,
         #[template_child]
-        z_flag: TemplateChild<gtk::Label>,
+        z_flag: TemplateChild<gtk::Button>,
         #[template_child]
-        g_flag: TemplateChild<gtk::Label>,
+        g_flag: TemplateChild<gtk::Button>,
         #[template_child]
-        p_flag: TemplateChild<gtk::Label>,
+        p_flag: TemplateChild<gtk::Button>,
         #[template_child]
         f_out: TemplateChild<gtk::Label>,
         #[template_child]
@@ -49,7 +53,7 @@ mod imp {
         #[template_child]
         sp_out: TemplateChild<gtk::Label>,
         #[template_child]
-        mp_out: TemplateChild<gtk::Label>,
+        mp_out: TemplateChild<gtk::EditableLabel>,
         #[template_child]
         port_out: TemplateChild<gtk::Label>,
     }
@@ -69,7 +73,41 @@ mod imp {
         }
     }
 
-    impl ObjectImpl for OutputView {}
+    impl ObjectImpl for OutputView {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                // flags are numbered as in `State::flags`
+                vec![Signal::builder("flag-toggled")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("mp-edited")
+                     .param_types([u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
+        fn constructed(&self) {
+            self.parent_constructed();
+            let flags = [
+                &self.ovr_flag, &self.c4_flag, &self.f3_flag,
+                &self.z_flag, &self.g_flag, &self.p_flag,
+            ];
+            for (ind, button) in flags.into_iter().enumerate() {
+                let view = self.obj().downgrade();
+                button.connect_clicked(move |_| {
+                    if let Some(view) = view.upgrade() {
+                        view.emit_by_name::<()>("flag-toggled", &[&(ind as u32)]);
+                    }
+                });
+            }
+            let view = self.obj().downgrade();
+            ui::connect_edit(&self.mp_out, 2, move |value| {
+                if let Some(view) = view.upgrade() {
+                    view.emit_by_name::<()>("mp-edited", &[&value]);
+                }
+            });
+        }
+    }
     impl WidgetImpl for OutputView {}
     impl BoxImpl for OutputView {}
     impl OutputView {
@@ -90,7 +128,7 @@ mod imp {
             self.y_out.set_label(&format!("{:0>4b}", &new_state.func_value));
             self.pc_out.set_label(&format!("{:0>4b}", &new_state.program_counter));
             self.sp_out.set_label(&format!("{:0>4b}", &new_state.stack_pointer));
-            self.mp_out.set_label(&format!("{:0>4b}", &new_state.multiplexor_value));
+            self.mp_out.set_text(&format!("{:0>4b}", &new_state.multiplexor_value));
             // self.port_out.set_label(&format!("{:0>4b}", &new_state.port_value));
         }
     }
@@ -0,0 +1,121 @@
+/* port_view.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use adw::subclass::prelude::*;
+use gtk::prelude::*;
+use gtk::{gio, glib};
+
+use crate::emulator::port::SimulatedPort;
+use crate::ui;
+
+mod imp {
+    use glib::{once_cell::sync::Lazy, subclass::Signal};
+
+    use super::*;
+
+    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[template(resource = "/org/bmstu/mtemu/ui/debug_pane/port_view.ui")]
+    pub struct PortView {
+        #[template_child]
+        port0_in: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port1_in: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port2_in: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port3_in: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port0_out: TemplateChild<gtk::Label>,
+        #[template_child]
+        port1_out: TemplateChild<gtk::Label>,
+        #[template_child]
+        port2_out: TemplateChild<gtk::Label>,
+        #[template_child]
+        port3_out: TemplateChild<gtk::Label>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for PortView {
+        const NAME: &'static str = "PortView";
+        type Type = super::PortView;
+        type ParentType = gtk::Box;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for PortView {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("port-input-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
+        fn constructed(&self) {
+            self.parent_constructed();
+            let inputs = [&self.port0_in, &self.port1_in, &self.port2_in, &self.port3_in];
+            for (ind, label) in inputs.into_iter().enumerate() {
+                let view = self.obj().downgrade();
+                ui::connect_edit(label, 2, move |value| {
+                    if let Some(view) = view.upgrade() {
+                        view.emit_by_name::<()>("port-input-edited", &[&(ind as u32), &value]);
+                    }
+                });
+            }
+        }
+    }
+    impl WidgetImpl for PortView {}
+    impl BoxImpl for PortView {}
+    impl PortView {
+        pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+            let inputs = [&self.port0_in, &self.port1_in, &self.port2_in, &self.port3_in];
+            let outputs = [&self.port0_out, &self.port1_out, &self.port2_out, &self.port3_out];
+            for (port, (input, output)) in ports.iter().zip(inputs.into_iter().zip(outputs)) {
+                // don't pull the text from under someone typing a value
+                if !input.is_editing() {
+                    input.set_text(&format!("{:0>8b}", port.input()));
+                }
+                output.set_text(&format!("{:0>8b}", port.output()));
+            }
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct PortView(ObjectSubclass<imp::PortView>)
+        @extends gtk::Widget,        @implements gio::ActionGroup, gio::ActionMap;
+}
+
+impl PortView {
+    pub fn new<P: glib::IsA<gtk::Application>>(application: &P) -> Self {
+        glib::Object::builder()
+            .property("application", application)
+            .build()
+    }
+    pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+        self.imp().renew_ports(ports);
+    }
+}
@@ -18,50 +18,54 @@
  */
 
 use adw::subclass::prelude::*;
+use gtk::prelude::*;
 use gtk::{gio, glib};
 
-use crate::emulator;
+use crate::emulator::{self, watch::Watch};
+use crate::ui;
 
 mod imp {
+    use glib::{once_cell::sync::Lazy, subclass::Signal};
+
     use super::*;
 
     #[derive(Debug, Default, gtk::CompositeTemplate)]
     #[template(resource = "/org/bmstu/mtemu/ui/debug_pane/register_view.ui")]
     pub struct RegisterView {
         #[template_child]
-        pq_reg: TemplateChild<gtk::Label>,
+        pq_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r0_reg: TemplateChild<gtk::Label>,
+        r0_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r1_reg: TemplateChild<gtk::Label>,
+        r1_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r2_reg: TemplateChild<gtk::Label>,
+        r2_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r3_reg: TemplateChild<gtk::Label>,
+        r3_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r4_reg: TemplateChild<gtk::Label>,
+        r4_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r5_reg: TemplateChild<gtk::Label>,
+        r5_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r6_reg: TemplateChild<gtk::Label>,
+        r6_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r7_reg: TemplateChild<gtk::Label>,
+        r7_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r8_reg: TemplateChild<gtk::Label>,
+        r8_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r9_reg: TemplateChild<gtk::Label>,
+        r9_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r10_reg: TemplateChild<gtk::Label>,
+        r10_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r11_reg: TemplateChild<gtk::Label>,
+        r11_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r12_reg: TemplateChild<gtk::Label>,
+        r12_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r13_reg: TemplateChild<gtk::Label>,
+        r13_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r14_reg: TemplateChild<gtk::Label>,
+        r14_reg: TemplateChild<gtk::EditableLabel>,
         #[template_child]
-        r15_reg: TemplateChild<gtk::Label>,
+        r15_reg: TemplateChild<gtk::EditableLabel>,
     }
 
     #[glib::object_subclass]
@@ -79,28 +83,64 @@ mod imp {
         }
     }
 
-    impl ObjectImpl for RegisterView {}
+    impl ObjectImpl for RegisterView {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                // the index follows `State::registers`, 16 is PQ
+                vec![Signal::builder("register-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
+        fn constructed(&self) {
+            self.parent_constructed();
+            let registers = [
+                &self.r0_reg, &self.r1_reg, &self.r2_reg, &self.r3_reg,
+                &self.r4_reg, &self.r5_reg, &self.r6_reg, &self.r7_reg,
+                &self.r8_reg, &self.r9_reg, &self.r10_reg, &self.r11_reg,
+                &self.r12_reg, &self.r13_reg, &self.r14_reg, &self.r15_reg,
+            ];
+            // the whole box with the name is the target, not just the value
+            for (ind, label) in registers.into_iter().enumerate() {
+                if let Some(cell) = label.parent() {
+                    ui::attach_watch_menu(&cell, move || Some(Watch::Reg(ind)));
+                }
+            }
+            if let Some(cell) = self.pq_reg.parent() {
+                ui::attach_watch_menu(&cell, || Some(Watch::Q));
+            }
+            for (ind, label) in registers.into_iter().chain([&self.pq_reg]).enumerate() {
+                let view = self.obj().downgrade();
+                ui::connect_edit(label, 2, move |value| {
+                    if let Some(view) = view.upgrade() {
+                        view.emit_by_name::<()>("register-edited", &[&(ind as u32), &value]);
+                    }
+                });
+            }
+        }
+    }
     impl WidgetImpl for RegisterView {}
     impl BoxImpl for RegisterView {}
     impl RegisterView {
         pub fn renew_state(&self, new_state: &emulator::State) {
-            self.r0_reg.set_label(&format!("{:0>4b}", new_state.registers[0]));
-            self.r1_reg.set_label(&format!("{:0>4b}", new_state.registers[1]));
-            self.r2_reg.set_label(&format!("{:0>4b}", new_state.registers[2]));
-            self.r3_reg.set_label(&format!("{:0>4b}", new_state.registers[3]));
-            self.r4_reg.set_label(&format!("{:0>4b}", new_state.registers[4]));
-            self.r5_reg.set_label(&format!("{:0>4b}", new_state.registers[5]));
-            self.r6_reg.set_label(&format!("{:0>4b}", new_state.registers[6]));
-            self.r7_reg.set_label(&format!("{:0>4b}", new_state.registers[7]));
-            self.r8_reg.set_label(&format!("{:0>4b}", new_state.registers[8]));
-            self.r9_reg.set_label(&format!("{:0>4b}", new_state.registers[9]));
-            self.r10_reg.set_label(&format!("{:0>4b}", new_state.registers[10]));
-            self.r11_reg.set_label(&format!("{:0>4b}", new_state.registers[11]));
-            self.r12_reg.set_label(&format!("{:0>4b}", new_state.registers[12]));
-            self.r13_reg.set_label(&format!("{:0>4b}", new_state.registers[13]));
-            self.r14_reg.set_label(&format!("{:0>4b}", new_state.registers[14]));
-            self.r15_reg.set_label(&format!("{:0>4b}", new_state.registers[15]));
-            self.pq_reg.set_label(&format!("{:0>4b}", new_state.registers[16]));
+            self.r0_reg.set_text(&format!("{:0>4b}", new_state.registers[0]));
+            self.r1_reg.set_text(&format!("{:0>4b}", new_state.registers[1]));
+            self.r2_reg.set_text(&format!("{:0>4b}", new_state.registers[2]));
+            self.r3_reg.set_text(&format!("{:0>4b}", new_state.registers[3]));
+            self.r4_reg.set_text(&format!("{:0>4b}", new_state.registers[4]));
+            self.r5_reg.set_text(&format!("{:0>4b}", new_state.registers[5]));
+            self.r6_reg.set_text(&format!("{:0>4b}", new_state.registers[6]));
+            self.r7_reg.set_text(&format!("{:0>4b}", new_state.registers[7]));
+            self.r8_reg.set_text(&format!("{:0>4b}", new_state.registers[8]));
+            self.r9_reg.set_text(&format!("{:0>4b}", new_state.registers[9]));
+            self.r10_reg.set_text(&format!("{:0>4b}", new_state.registers[10]));
+            self.r11_reg.set_text(&format!("{:0>4b}", new_state.registers[11]));
+            self.r12_reg.set_text(&format!("{:0>4b}", new_state.registers[12]));
+            self.r13_reg.set_text(&format!("{:0>4b}", new_state.registers[13]));
+            self.r14_reg.set_text(&format!("{:0>4b}", new_state.registers[14]));
+            self.r15_reg.set_text(&format!("{:0>4b}", new_state.registers[15]));
+            self.pq_reg.set_text(&format!("{:0>4b}", new_state.registers[16]));
         }
     }
 }
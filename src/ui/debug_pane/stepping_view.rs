@@ -21,19 +21,51 @@ use adw::subclass::prelude::*;
 use gtk::{gio, glib};
 
 mod imp {
-    use gtk::{glib::{subclass::Signal, once_cell::sync::Lazy}, prelude::{StaticType, ObjectExt}, traits::{ButtonExt, ToggleButtonExt}};
+    use gtk::{glib::{subclass::Signal, once_cell::sync::Lazy}, prelude::{StaticType, ObjectExt, RangeExt, SettingsExt}, traits::{ButtonExt, ToggleButtonExt}};
+
+    use crate::emulator::worker;
 
     use super::*;
 
-    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[derive(Debug, gtk::CompositeTemplate)]
     #[template(resource = "/org/bmstu/mtemu/ui/debug_pane/stepping_view.ui")]
     pub struct SteppingView {
         #[template_child]
         reset_button: TemplateChild<gtk::Button>,
         #[template_child]
+        reverse_run_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        step_back_button: TemplateChild<gtk::Button>,
+        #[template_child]
         step_button: TemplateChild<gtk::Button>,
         #[template_child]
-        run_button: TemplateChild<gtk::ToggleButton>,
+        step_over_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        step_out_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub run_button: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
+        speed_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        speed_label: TemplateChild<gtk::Label>,
+        settings: gio::Settings,
+    }
+
+    impl Default for SteppingView {
+        fn default() -> Self {
+            Self {
+                reset_button: Default::default(),
+                reverse_run_button: Default::default(),
+                step_back_button: Default::default(),
+                step_button: Default::default(),
+                step_over_button: Default::default(),
+                step_out_button: Default::default(),
+                run_button: Default::default(),
+                speed_scale: Default::default(),
+                speed_label: Default::default(),
+                settings: gio::Settings::new("org.bmstu.mtemu"),
+            }
+        }
     }
 
     #[glib::object_subclass]
@@ -55,11 +87,24 @@ mod imp {
         fn signals() -> &'static [Signal] {
             static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
                 vec![Signal::builder("reset-clicked")
+                     .param_types([gtk::Button::static_type()])
+                     .build(),
+                     Signal::builder("reverse-run-clicked")
+                     .param_types([gtk::Button::static_type()])
+                     .build(),
+                     Signal::builder("step-back-clicked")
                      .param_types([gtk::Button::static_type()])
                      .build(),
                      Signal::builder("step-clicked")
                      .param_types([gtk::Button::static_type()])
                      .build(),
+                     // these carry the run button, it stays pressed while they run
+                     Signal::builder("step-over-clicked")
+                     .param_types([gtk::ToggleButton::static_type()])
+                     .build(),
+                     Signal::builder("step-out-clicked")
+                     .param_types([gtk::ToggleButton::static_type()])
+                     .build(),
                      Signal::builder("run-toggled")
                      .param_types([gtk::ToggleButton::static_type()])
                      .build()]
@@ -71,9 +116,31 @@ mod imp {
             let pane = self.obj().clone();
             self.reset_button.connect_clicked(move |obj: &gtk::Button| { pane.emit_by_name::<()>("reset-clicked", &[obj]) });
             let pane = self.obj().clone();
+            self.reverse_run_button.connect_clicked(move |obj: &gtk::Button| { pane.emit_by_name::<()>("reverse-run-clicked", &[obj]) });
+            let pane = self.obj().clone();
+            self.step_back_button.connect_clicked(move |obj: &gtk::Button| { pane.emit_by_name::<()>("step-back-clicked", &[obj]) });
+            let pane = self.obj().clone();
             self.step_button.connect_clicked(move |obj: &gtk::Button| { pane.emit_by_name("step-clicked", &[obj]) });
             let pane = self.obj().clone();
+            let run_button = self.run_button.get();
+            self.step_over_button.connect_clicked(move |_: &gtk::Button| { pane.emit_by_name::<()>("step-over-clicked", &[&run_button]) });
+            let pane = self.obj().clone();
+            let run_button = self.run_button.get();
+            self.step_out_button.connect_clicked(move |_: &gtk::Button| { pane.emit_by_name::<()>("step-out-clicked", &[&run_button]) });
+            let pane = self.obj().clone();
             self.run_button.connect_toggled(move |obj: &gtk::ToggleButton| { pane.emit_by_name("run-toggled", &[obj]) });
+            // the app picks the speed up from the settings, even mid-run
+            let speed = self.settings.uint("run-speed");
+            self.speed_scale.set_range(0.0, (worker::SPEED_LEVELS - 1) as f64);
+            self.speed_scale.set_value(speed as f64);
+            self.speed_label.set_label(&worker::speed_name(speed));
+            let settings = self.settings.clone();
+            let label = self.speed_label.clone();
+            self.speed_scale.connect_value_changed(move |scale: &gtk::Scale| {
+                let speed = scale.value().round() as u32;
+                label.set_label(&worker::speed_name(speed));
+                let _ = settings.set_uint("run-speed", speed);
+            });
         }
     }
     impl WidgetImpl for SteppingView {}
@@ -84,3 +151,9 @@ glib::wrapper! {
     pub struct SteppingView(ObjectSubclass<imp::SteppingView>)
         @extends gtk::Widget,        @implements gio::ActionGroup, gio::ActionMap;
 }
+
+impl SteppingView {
+    pub fn run_button(&self) -> gtk::ToggleButton {
+        self.imp().run_button.get()
+    }
+}
@@ -21,9 +21,12 @@ use adw::subclass::prelude::*;
 use gtk::prelude::*;
 use gtk::glib;
 
+use crate::emulator::watch::Watch;
+use crate::ui;
+
 mod imp {
     use std::cell::Cell;
-    use glib::Properties;
+    use glib::{once_cell::sync::Lazy, subclass::Signal, Properties};
     use gtk::{prelude::{Cast, CastNone}, traits::ListItemExt};
 
     use super::*;
@@ -76,6 +79,14 @@ mod imp {
     }
 
     impl ObjectImpl for MemoryWindow {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("value-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
         fn constructed(&self) {
             self.parent_constructed();
             self.instance_factories();
@@ -95,7 +106,9 @@ mod imp {
             let factory = gtk::SignalListItemFactory::new();
             factory.connect_setup(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                obj.set_child(Some(&gtk::Label::builder().build()));
+                let label = gtk::Label::builder().build();
+                super::attach_watch_menu(&label, obj);
+                obj.set_child(Some(&label));
             });
             factory.connect_bind(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
@@ -108,36 +121,43 @@ mod imp {
             self.memory_addr.set_factory(Some(&factory));
         }
         fn instance_bin_factory(&self) {
-            let factory = gtk::SignalListItemFactory::new();
-            factory.connect_setup(move |_, obj| {
-                let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                obj.set_child(Some(&gtk::Label::builder().build()));
-            });
-            factory.connect_bind(move |_, obj| {
-                let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                let Some(item) = obj.item().and_downcast::<super::MemoryValueRepr>() else { return };
-                obj.child()
-                    .and_downcast_ref::<gtk::Label>()
-                    .unwrap()
-                    .set_label(&format!("0b{:0>8b}", item.value()));
-            });
-            self.memory_bin.set_factory(Some(&factory));
+            self.memory_bin.set_factory(Some(&self.value_factory(2, |value| format!("0b{:0>8b}", value))));
         }
         fn instance_hex_factory(&self) {
+            self.memory_hex.set_factory(Some(&self.value_factory(16, |value| format!("0x{:0>2X}", value))));
+        }
+        // the value columns can be edited in place, each in its own radix
+        fn value_factory(&self, radix: u32, text: fn(u32) -> String) -> gtk::SignalListItemFactory {
             let factory = gtk::SignalListItemFactory::new();
+            let window = self.obj().downgrade();
             factory.connect_setup(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                obj.set_child(Some(&gtk::Label::builder().build()));
+                let label = gtk::EditableLabel::builder().xalign(0.5).build();
+                super::attach_watch_menu(&label, obj);
+                let list_item = obj.downgrade();
+                let window = window.clone();
+                ui::connect_edit(&label, radix, move |value| {
+                    let Some(window) = window.upgrade() else { return };
+                    let Some(item) = list_item
+                        .upgrade()
+                        .and_then(|obj| obj.item())
+                        .and_downcast::<super::MemoryValueRepr>()
+                    else {
+                        return;
+                    };
+                    window.emit_by_name::<()>("value-edited", &[&item.addr(), &value]);
+                });
+                obj.set_child(Some(&label));
             });
             factory.connect_bind(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
                 let Some(item) = obj.item().and_downcast::<super::MemoryValueRepr>() else { return };
                 obj.child()
-                    .and_downcast_ref::<gtk::Label>()
+                    .and_downcast_ref::<gtk::EditableLabel>()
                     .unwrap()
-                    .set_label(&format!("0b{:0>2X}", item.value()));
+                    .set_text(&text(item.value()));
             });
-            self.memory_hex.set_factory(Some(&factory));
+            factory
         }
         pub fn set_memory(&self, memory: gtk::gio::ListStore) {
             self.memory_list.set_model(Some(&gtk::SingleSelection::new(Some(memory))));
@@ -145,6 +165,15 @@ mod imp {
     }
 }
 
+// every column of a row watches that row's cell
+fn attach_watch_menu(label: &impl IsA<gtk::Widget>, list_item: &gtk::ListItem) {
+    let list_item = list_item.downgrade();
+    ui::attach_watch_menu(label, move || {
+        let item = list_item.upgrade()?.item().and_downcast::<MemoryValueRepr>()?;
+        Some(Watch::Mem(item.addr() as usize))
+    });
+}
+
 glib::wrapper! {
     pub struct MemoryWindow(ObjectSubclass<imp::MemoryWindow>)
         @extends gtk::Widget, gtk::Window, gtk::ApplicationWindow, adw::ApplicationWindow;
@@ -17,7 +17,10 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use crate::emulator;
+use gtk::prelude::*;
+use gtk::{gdk, gio, glib};
+
+use crate::emulator::{self, watch::Watch};
 
 pub mod window;
 pub mod code_view_pane;
@@ -26,8 +29,81 @@ pub mod line_builder_pane;
 pub mod stack_view;
 pub mod memory_view;
 pub mod command_view;
+pub mod profile_view;
+pub mod panel_view;
 
 pub trait PlainCommandRepr {
     fn from_command(_: &emulator::Command) -> Self;
     fn get_words(&self) -> [u8; 10];
 }
+
+// Right click on `widget` offers to watch or stop watching whatever `target`
+// picks at that moment, through the app.toggle-watch action
+pub fn attach_watch_menu(widget: &impl IsA<gtk::Widget>, target: impl Fn() -> Option<Watch> + 'static) {
+    let popover = gtk::PopoverMenu::from_model(None::<&gio::MenuModel>);
+    popover.set_has_arrow(false);
+    popover.set_parent(widget);
+    widget.connect_destroy(glib::clone!(@weak popover => move |_| popover.unparent()));
+    let gesture = gtk::GestureClick::builder().button(gdk::BUTTON_SECONDARY).build();
+    gesture.connect_pressed(move |gesture, _, x, y| {
+        let Some(watch) = target() else {
+            return;
+        };
+        let name = watch.to_string();
+        let watched = popover
+            .root()
+            .and_downcast::<gtk::Window>()
+            .and_then(|window| window.application())
+            .and_then(|app| app.action_state("toggle-watch"))
+            .and_then(|state| state.get::<Vec<String>>())
+            .is_some_and(|names| names.contains(&name));
+        let label = match watched {
+            true => format!("Stop Watching {}", name),
+            false => format!("Watch {}", name),
+        };
+        let item = gio::MenuItem::new(Some(&label), None);
+        item.set_action_and_target_value(Some("app.toggle-watch"), Some(&name.to_variant()));
+        let menu = gio::Menu::new();
+        menu.append_item(&item);
+        popover.set_menu_model(Some(&menu));
+        popover.set_pointing_to(Some(&gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+        popover.popup();
+        gesture.set_state(gtk::EventSequenceState::Claimed);
+    });
+    widget.add_controller(gesture);
+}
+
+// Lets `label` be edited in place. A finished edit that reads as a number in
+// `radix`, with or without its 0b/0x prefix, goes to `commit`; anything else
+// puts back what was shown before the edit.
+pub fn connect_edit(label: &gtk::EditableLabel, radix: u32, commit: impl Fn(u32) + 'static) {
+    let shown = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+    label.connect_editing_notify(move |label| {
+        if label.is_editing() {
+            shown.replace(label.text().to_string());
+            return;
+        }
+        let text = label.text();
+        if text == *shown.borrow() {
+            return;
+        }
+        match parse_value(&text, radix) {
+            Some(value) => commit(value),
+            None => label.set_text(&shown.borrow()),
+        }
+    });
+}
+
+fn parse_value(text: &str, radix: u32) -> Option<u32> {
+    let text = text.trim();
+    let prefix = match radix {
+        2 => "0b",
+        16 => "0x",
+        _ => "",
+    };
+    let digits = match text.get(..prefix.len()) {
+        Some(head) if !prefix.is_empty() && head.eq_ignore_ascii_case(prefix) => &text[prefix.len()..],
+        _ => text,
+    };
+    u32::from_str_radix(digits, radix).ok()
+}
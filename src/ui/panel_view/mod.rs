@@ -0,0 +1,263 @@
+/* panel_view/mod.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use adw::subclass::prelude::*;
+use gtk::prelude::*;
+use gtk::{cairo, glib};
+
+use crate::emulator::port::SimulatedPort;
+use crate::ui;
+
+// segments a to g in the low bits
+const SEGMENTS: [u8; 16] = [
+    0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F, 0x77, 0x7C, 0x39, 0x5E, 0x79, 0x71,
+];
+const DIGIT_WIDTH: f64 = 24.0;
+const DIGIT_HEIGHT: f64 = 44.0;
+const SEGMENT_WIDTH: f64 = 4.0;
+const DIGIT_GAP: f64 = 8.0;
+
+mod imp {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use glib::{once_cell::sync::Lazy, subclass::Signal};
+
+    use super::*;
+
+    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[template(resource = "/org/bmstu/mtemu/ui/panel_view/window.ui")]
+    pub struct PanelWindow {
+        #[template_child]
+        port0_switches: TemplateChild<gtk::Box>,
+        #[template_child]
+        port1_switches: TemplateChild<gtk::Box>,
+        #[template_child]
+        port0_value: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port1_value: TemplateChild<gtk::EditableLabel>,
+        #[template_child]
+        port2_leds: TemplateChild<gtk::Box>,
+        #[template_child]
+        port3_leds: TemplateChild<gtk::Box>,
+        #[template_child]
+        port2_display: TemplateChild<gtk::DrawingArea>,
+        #[template_child]
+        port3_display: TemplateChild<gtk::DrawingArea>,
+        // what the displays draw, set before they are queued
+        shown: [Rc<Cell<u8>>; 2],
+        // switches flipped to show a new value must not send it back
+        renewing: Cell<bool>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for PanelWindow {
+        const NAME: &'static str = "PanelWindow";
+        type Type = super::PanelWindow;
+        type ParentType = adw::ApplicationWindow;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for PanelWindow {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("input-changed")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
+        fn constructed(&self) {
+            self.parent_constructed();
+            self.instance_inputs();
+            self.instance_outputs();
+        }
+    }
+    impl WidgetImpl for PanelWindow {}
+    impl WindowImpl for PanelWindow {}
+    impl ApplicationWindowImpl for PanelWindow {}
+    impl AdwApplicationWindowImpl for PanelWindow {}
+    impl PanelWindow {
+        fn inputs(&self) -> [(&gtk::Box, &gtk::EditableLabel); 2] {
+            [
+                (&*self.port0_switches, &*self.port0_value),
+                (&*self.port1_switches, &*self.port1_value),
+            ]
+        }
+        fn outputs(&self) -> [(&gtk::Box, &gtk::DrawingArea); 2] {
+            [
+                (&*self.port2_leds, &*self.port2_display),
+                (&*self.port3_leds, &*self.port3_display),
+            ]
+        }
+        fn instance_inputs(&self) {
+            for (port, (switches, value)) in self.inputs().into_iter().enumerate() {
+                for bit in (0..8).rev() {
+                    let switch = gtk::ToggleButton::builder()
+                        .label(bit.to_string())
+                        .tooltip_text(format!("Bit {}", bit))
+                        .build();
+                    let window = self.obj().downgrade();
+                    let switches_clone = switches.clone();
+                    switch.connect_toggled(move |_| {
+                        let Some(window) = window.upgrade() else {
+                            return;
+                        };
+                        if window.imp().renewing.get() {
+                            return;
+                        }
+                        let value = switch_values(&switches_clone)
+                            .fold(0, |value, (bit, on)| value | (on as u32) << bit);
+                        window.emit_by_name::<()>("input-changed", &[&(port as u32), &value]);
+                    });
+                    switches.append(&switch);
+                }
+                let window = self.obj().downgrade();
+                ui::connect_edit(value, 16, move |value| {
+                    let Some(window) = window.upgrade() else {
+                        return;
+                    };
+                    // too wide for the port, the next renew puts the old value back
+                    if value > 0xFF {
+                        return;
+                    }
+                    window.emit_by_name::<()>("input-changed", &[&(port as u32), &value]);
+                });
+            }
+        }
+        fn instance_outputs(&self) {
+            for ((leds, display), shown) in self.outputs().into_iter().zip(&self.shown) {
+                for bit in (0..8).rev() {
+                    let led = gtk::Box::builder()
+                        .css_classes(["led"])
+                        .tooltip_text(format!("Bit {}", bit))
+                        .valign(gtk::Align::Center)
+                        .build();
+                    leds.append(&led);
+                }
+                display.set_content_width((2.0 * DIGIT_WIDTH + DIGIT_GAP) as i32);
+                display.set_content_height(DIGIT_HEIGHT as i32);
+                let shown = shown.clone();
+                display.set_draw_func(move |area, cr, _, _| {
+                    let value = shown.get();
+                    draw_digit(area, cr, 0.0, value >> 4);
+                    draw_digit(area, cr, DIGIT_WIDTH + DIGIT_GAP, value & 0x0F);
+                });
+            }
+        }
+        pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+            self.renewing.set(true);
+            for ((switches, value), port) in self.inputs().into_iter().zip(ports) {
+                let input = port.input();
+                let mut child = switches.first_child();
+                for bit in (0..8).rev() {
+                    let Some(switch) = child.and_downcast::<gtk::ToggleButton>() else {
+                        break;
+                    };
+                    switch.set_active(input >> bit & 1 != 0);
+                    child = switch.next_sibling();
+                }
+                if !value.is_editing() {
+                    value.set_text(&format!("0x{:02X}", input));
+                }
+            }
+            self.renewing.set(false);
+            let outputs = self.outputs().into_iter().zip(&self.shown);
+            for (((leds, display), shown), port) in outputs.zip(ports.iter().skip(2)) {
+                let output = port.output();
+                let mut child = leds.first_child();
+                for bit in (0..8).rev() {
+                    let Some(led) = child else {
+                        break;
+                    };
+                    match output >> bit & 1 {
+                        0 => led.remove_css_class("on"),
+                        _ => led.add_css_class("on"),
+                    }
+                    child = led.next_sibling();
+                }
+                if shown.replace(output) != output {
+                    display.queue_draw();
+                }
+            }
+        }
+    }
+
+    // the switches go from bit 7 down to bit 0
+    fn switch_values(switches: &gtk::Box) -> impl Iterator<Item = (u32, bool)> {
+        let mut child = switches.first_child();
+        (0..8).rev().map_while(move |bit| {
+            let switch = child.clone().and_downcast::<gtk::ToggleButton>()?;
+            child = switch.next_sibling();
+            Some((bit, switch.is_active()))
+        })
+    }
+
+    fn draw_digit(area: &gtk::DrawingArea, cr: &cairo::Context, left: f64, digit: u8) {
+        let (w, h, t) = (DIGIT_WIDTH, DIGIT_HEIGHT, SEGMENT_WIDTH);
+        // a, b, c, d, e, f, g as x, y, width, height
+        let segments = [
+            (t, 0.0, w - 2.0 * t, t),
+            (w - t, t, t, h / 2.0 - t),
+            (w - t, h / 2.0, t, h / 2.0 - t),
+            (t, h - t, w - 2.0 * t, t),
+            (0.0, h / 2.0, t, h / 2.0 - t),
+            (0.0, t, t, h / 2.0 - t),
+            (t, (h - t) / 2.0, w - 2.0 * t, t),
+        ];
+        let lit = SEGMENTS[digit as usize & 0x0F];
+        let color = area.color();
+        for (ind, (x, y, width, height)) in segments.into_iter().enumerate() {
+            match lit >> ind & 1 {
+                0 => cr.set_source_rgba(
+                    color.red() as f64,
+                    color.green() as f64,
+                    color.blue() as f64,
+                    0.1,
+                ),
+                _ => cr.set_source_rgb(0.9, 0.1, 0.1),
+            }
+            cr.rectangle(left + x, y, width, height);
+            let _ = cr.fill();
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct PanelWindow(ObjectSubclass<imp::PanelWindow>)
+        @extends gtk::Widget, gtk::Window, gtk::ApplicationWindow, adw::ApplicationWindow;
+}
+
+impl PanelWindow {
+    pub fn new<P: glib::IsA<gtk::Application>>(application: &P) -> Self {
+        glib::Object::builder()
+            .property("application", application)
+            .build()
+    }
+    pub fn renew_ports(&self, ports: &[SimulatedPort]) {
+        self.imp().renew_ports(ports);
+    }
+}
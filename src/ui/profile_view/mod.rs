@@ -0,0 +1,280 @@
+/* profile_view/mod.rs
+ *
+ * Copyright 2024 Anton Klimanov
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * 	http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use adw::subclass::prelude::*;
+use gtk::prelude::*;
+use gtk::{gio, glib};
+
+mod imp {
+    use std::cell::{Cell, RefCell};
+
+    use glib::Properties;
+
+    use super::*;
+
+    #[derive(Debug, Default, Properties)]
+    #[properties(wrapper_type = super::HotspotRepr)]
+    pub struct HotspotRepr {
+        #[property(get, set)]
+        pub addr: Cell<u32>,
+        #[property(get, set)]
+        pub name: RefCell<String>,
+        #[property(get, set)]
+        pub jump: RefCell<String>,
+        #[property(get, set)]
+        pub count: Cell<u32>,
+        // percent of everything executed
+        #[property(get, set)]
+        pub share: Cell<f64>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for HotspotRepr {
+        const NAME: &'static str = "HotspotRepr";
+        type Type = super::HotspotRepr;
+        type ParentType = glib::Object;
+    }
+
+    #[glib::derived_properties]
+    impl ObjectImpl for HotspotRepr {}
+
+    #[derive(Debug, Default, Properties)]
+    #[properties(wrapper_type = super::CallCyclesRepr)]
+    pub struct CallCyclesRepr {
+        #[property(get, set)]
+        pub index: Cell<u32>,
+        #[property(get, set)]
+        pub name: RefCell<String>,
+        #[property(get, set)]
+        pub runs: Cell<u32>,
+        // cycles of the latest run
+        #[property(get, set)]
+        pub last: Cell<u32>,
+        #[property(get, set)]
+        pub average: Cell<f64>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for CallCyclesRepr {
+        const NAME: &'static str = "CallCyclesRepr";
+        type Type = super::CallCyclesRepr;
+        type ParentType = glib::Object;
+    }
+
+    #[glib::derived_properties]
+    impl ObjectImpl for CallCyclesRepr {}
+
+    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[template(resource = "/org/bmstu/mtemu/ui/profile_view/window.ui")]
+    pub struct ProfileWindow {
+        #[template_child]
+        pub hotspot_list: TemplateChild<gtk::ColumnView>,
+        #[template_child]
+        pub hotspot_addr: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub hotspot_name: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub hotspot_jump: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub hotspot_count: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub hotspot_share: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_list: TemplateChild<gtk::ColumnView>,
+        #[template_child]
+        pub call_index: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_name: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_runs: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_last: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_average: TemplateChild<gtk::ColumnViewColumn>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for ProfileWindow {
+        const NAME: &'static str = "ProfileWindow";
+        type Type = super::ProfileWindow;
+        type ParentType = adw::ApplicationWindow;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for ProfileWindow {
+        fn constructed(&self) {
+            self.parent_constructed();
+            self.instance_factories();
+            // hottest first
+            self.hotspot_list
+                .sort_by_column(Some(&*self.hotspot_count), gtk::SortType::Descending);
+            self.call_list
+                .sort_by_column(Some(&*self.call_index), gtk::SortType::Ascending);
+        }
+    }
+    impl WidgetImpl for ProfileWindow {}
+    impl WindowImpl for ProfileWindow {}
+    impl ApplicationWindowImpl for ProfileWindow {}
+    impl AdwApplicationWindowImpl for ProfileWindow {}
+    impl ProfileWindow {
+        fn instance_factories(&self) {
+            type Hotspot = super::HotspotRepr;
+            type Call = super::CallCyclesRepr;
+            column(
+                &self.hotspot_addr,
+                by_number::<Hotspot>("addr"),
+                |item: &Hotspot| format!("0x{:03X}", item.addr()),
+            );
+            column(
+                &self.hotspot_name,
+                by_text::<Hotspot>("name"),
+                Hotspot::name,
+            );
+            column(
+                &self.hotspot_jump,
+                by_text::<Hotspot>("jump"),
+                Hotspot::jump,
+            );
+            column(
+                &self.hotspot_count,
+                by_number::<Hotspot>("count"),
+                |item: &Hotspot| item.count().to_string(),
+            );
+            column(
+                &self.hotspot_share,
+                by_number::<Hotspot>("share"),
+                |item: &Hotspot| format!("{:.1}%", item.share()),
+            );
+            column(
+                &self.call_index,
+                by_number::<Call>("index"),
+                |item: &Call| item.index().to_string(),
+            );
+            column(&self.call_name, by_text::<Call>("name"), Call::name);
+            column(&self.call_runs, by_number::<Call>("runs"), |item: &Call| {
+                item.runs().to_string()
+            });
+            column(&self.call_last, by_number::<Call>("last"), |item: &Call| {
+                item.last().to_string()
+            });
+            column(
+                &self.call_average,
+                by_number::<Call>("average"),
+                |item: &Call| format!("{:.1}", item.average()),
+            );
+        }
+        pub fn set_profile(&self, hotspots: gio::ListStore, calls: gio::ListStore) {
+            for (list, model) in [(&*self.hotspot_list, hotspots), (&*self.call_list, calls)] {
+                let sorted = gtk::SortListModel::new(Some(model), list.sorter());
+                list.set_model(Some(&gtk::NoSelection::new(Some(sorted))));
+            }
+        }
+    }
+
+    fn expression<T: StaticType>(property: &str) -> gtk::PropertyExpression {
+        gtk::PropertyExpression::new(T::static_type(), None::<&gtk::Expression>, property)
+    }
+
+    fn by_number<T: StaticType>(property: &str) -> gtk::Sorter {
+        gtk::NumericSorter::new(Some(expression::<T>(property))).upcast()
+    }
+
+    fn by_text<T: StaticType>(property: &str) -> gtk::Sorter {
+        gtk::StringSorter::new(Some(expression::<T>(property))).upcast()
+    }
+
+    fn column<T: IsA<glib::Object>>(
+        column: &gtk::ColumnViewColumn,
+        sorter: gtk::Sorter,
+        text: impl Fn(&T) -> String + 'static,
+    ) {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(move |_, obj| {
+            let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
+            obj.set_child(Some(&gtk::Label::builder().xalign(0.0).build()));
+        });
+        factory.connect_bind(move |_, obj| {
+            let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
+            let Some(item) = obj.item().and_downcast::<T>() else {
+                return;
+            };
+            obj.child()
+                .and_downcast_ref::<gtk::Label>()
+                .unwrap()
+                .set_label(&text(&item));
+        });
+        column.set_factory(Some(&factory));
+        column.set_sorter(Some(&sorter));
+    }
+}
+
+glib::wrapper! {
+    pub struct ProfileWindow(ObjectSubclass<imp::ProfileWindow>)
+        @extends gtk::Widget, gtk::Window, gtk::ApplicationWindow, adw::ApplicationWindow;
+}
+
+impl ProfileWindow {
+    pub fn new<P: glib::IsA<gtk::Application>>(application: &P) -> Self {
+        glib::Object::builder()
+            .property("application", application)
+            .build()
+    }
+    pub fn set_profile(&self, hotspots: gio::ListStore, calls: gio::ListStore) {
+        self.imp().set_profile(hotspots, calls);
+    }
+}
+
+glib::wrapper! {
+    pub struct HotspotRepr(ObjectSubclass<imp::HotspotRepr>);
+}
+
+impl HotspotRepr {
+    pub fn new(addr: usize, name: String, jump: String, count: usize, share: f64) -> Self {
+        glib::Object::builder()
+            .property("addr", addr as u32)
+            .property("name", name)
+            .property("jump", jump)
+            .property("count", count as u32)
+            .property("share", share)
+            .build()
+    }
+}
+
+glib::wrapper! {
+    pub struct CallCyclesRepr(ObjectSubclass<imp::CallCyclesRepr>);
+}
+
+impl CallCyclesRepr {
+    pub fn new(index: usize, name: String, cycles: crate::emulator::profile::CallCycles) -> Self {
+        glib::Object::builder()
+            .property("index", index as u32)
+            .property("name", name)
+            .property("runs", cycles.runs as u32)
+            .property("last", cycles.last as u32)
+            .property("average", cycles.average())
+            .build()
+    }
+}
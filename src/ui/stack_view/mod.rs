@@ -21,9 +21,12 @@ use adw::subclass::prelude::*;
 use gtk::prelude::*;
 use gtk::glib;
 
+use crate::emulator::call_stack::Frame;
+use crate::ui;
+
 mod imp {
-    use std::cell::Cell;
-    use glib::Properties;
+    use std::cell::{Cell, RefCell};
+    use glib::{once_cell::sync::Lazy, subclass::Signal, Properties};
     use gtk::{prelude::{Cast, CastNone}, traits::ListItemExt};
 
     use super::*;
@@ -47,6 +50,33 @@ mod imp {
     #[glib::derived_properties]
     impl ObjectImpl for StackValueRepr {}
 
+    #[derive(Debug, Default, Properties)]
+    #[properties(wrapper_type = super::CallFrameRepr)]
+    pub struct CallFrameRepr {
+        #[property(get, set)]
+        pub depth: Cell<u32>,
+        // -1 when the return address can't have come from a command
+        #[property(get, set)]
+        pub call_site: Cell<i32>,
+        #[property(get, set)]
+        pub name: RefCell<String>,
+        #[property(get, set)]
+        pub return_addr: Cell<u32>,
+        // the frame right under SP, where RET goes next
+        #[property(get, set)]
+        pub current: Cell<bool>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for CallFrameRepr {
+        const NAME: &'static str = "CallFrameRepr";
+        type Type = super::CallFrameRepr;
+        type ParentType = glib::Object;
+    }
+
+    #[glib::derived_properties]
+    impl ObjectImpl for CallFrameRepr {}
+
     #[derive(Debug, Default, gtk::CompositeTemplate)]
     #[template(resource = "/org/bmstu/mtemu/ui/stack_view/window.ui")]
     pub struct StackWindow {
@@ -56,6 +86,16 @@ mod imp {
         pub stack_addr: TemplateChild<gtk::ColumnViewColumn>,
         #[template_child]
         pub stack_pointer: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_list: TemplateChild<gtk::ColumnView>,
+        #[template_child]
+        pub call_depth: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_site: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_name: TemplateChild<gtk::ColumnViewColumn>,
+        #[template_child]
+        pub call_return: TemplateChild<gtk::ColumnViewColumn>,
     }
 
     #[glib::object_subclass]
@@ -74,9 +114,33 @@ mod imp {
     }
 
     impl ObjectImpl for StackWindow {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: Lazy<Vec<Signal>> = Lazy::new(|| {
+                vec![Signal::builder("frame-activated")
+                     .param_types([u32::static_type()])
+                     .build(),
+                     Signal::builder("value-edited")
+                     .param_types([u32::static_type(), u32::static_type()])
+                     .build()]
+            });
+            SIGNALS.as_ref()
+        }
         fn constructed(&self) {
             self.parent_constructed();
             self.instance_factories();
+            let window = self.obj().clone();
+            self.call_list.connect_activate(move |list, position| {
+                let Some(frame) = list
+                    .model()
+                    .and_then(|model| model.item(position))
+                    .and_downcast::<super::CallFrameRepr>()
+                else {
+                    return;
+                };
+                if let Ok(site) = u32::try_from(frame.call_site()) {
+                    window.emit_by_name::<()>("frame-activated", &[&site]);
+                }
+            });
         }
     }
     impl WidgetImpl for StackWindow {}
@@ -87,6 +151,14 @@ mod imp {
         fn instance_factories(&self) {
             self.instance_addr_factory();
             self.instance_pointer_factory();
+            type Frame = super::CallFrameRepr;
+            call_column(&self.call_depth, |frame: &Frame| frame.depth().to_string());
+            call_column(&self.call_site, |frame: &Frame| match frame.call_site() {
+                -1 => "-".to_owned(),
+                site => format!("0x{:03X}", site),
+            });
+            call_column(&self.call_name, Frame::name);
+            call_column(&self.call_return, |frame: &Frame| format!("0x{:03X}", frame.return_addr()));
         }
         fn instance_addr_factory(&self) {
             let factory = gtk::SignalListItemFactory::new();
@@ -106,23 +178,61 @@ mod imp {
         }
         fn instance_pointer_factory(&self) {
             let factory = gtk::SignalListItemFactory::new();
+            let window = self.obj().downgrade();
             factory.connect_setup(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
-                obj.set_child(Some(&gtk::Label::builder().build()));
+                let label = gtk::EditableLabel::builder().xalign(0.5).build();
+                let list_item = obj.downgrade();
+                let window = window.clone();
+                ui::connect_edit(&label, 2, move |value| {
+                    let Some(window) = window.upgrade() else { return };
+                    let Some(item) = list_item
+                        .upgrade()
+                        .and_then(|obj| obj.item())
+                        .and_downcast::<super::StackValueRepr>()
+                    else {
+                        return;
+                    };
+                    window.emit_by_name::<()>("value-edited", &[&item.addr(), &value]);
+                });
+                obj.set_child(Some(&label));
             });
             factory.connect_bind(move |_, obj| {
                 let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
                 let Some(item) = obj.item().and_downcast::<super::StackValueRepr>() else { return };
                 obj.child()
-                    .and_downcast_ref::<gtk::Label>()
+                    .and_downcast_ref::<gtk::EditableLabel>()
                     .unwrap()
-                    .set_label(&format!("0b{:0>4b}", item.pointer()));
+                    .set_text(&format!("0b{:0>4b}", item.pointer()));
             });
             self.stack_pointer.set_factory(Some(&factory));
         }
         pub fn set_stack(&self, stack: gtk::gio::ListStore) {
             self.stack_list.set_model(Some(&gtk::SingleSelection::new(Some(stack))));
         }
+        pub fn set_frames(&self, frames: gtk::gio::ListStore) {
+            self.call_list.set_model(Some(&gtk::NoSelection::new(Some(frames))));
+        }
+    }
+
+    // the current frame stands out in every column
+    fn call_column(column: &gtk::ColumnViewColumn, text: impl Fn(&super::CallFrameRepr) -> String + 'static) {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(move |_, obj| {
+            let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
+            obj.set_child(Some(&gtk::Label::builder().xalign(0.0).build()));
+        });
+        factory.connect_bind(move |_, obj| {
+            let obj = obj.downcast_ref::<gtk::ListItem>().unwrap();
+            let Some(item) = obj.item().and_downcast::<super::CallFrameRepr>() else { return };
+            let label = obj.child().and_downcast::<gtk::Label>().unwrap();
+            label.set_label(&text(&item));
+            match item.current() {
+                true => label.add_css_class("accent"),
+                false => label.remove_css_class("accent"),
+            }
+        });
+        column.set_factory(Some(&factory));
     }
 }
 
@@ -140,6 +250,9 @@ impl StackWindow {
     pub fn set_stack(&self, stack: gtk::gio::ListStore) {
         self.imp().set_stack(stack);
     }
+    pub fn set_frames(&self, frames: gtk::gio::ListStore) {
+        self.imp().set_frames(frames);
+    }
 }
 
 glib::wrapper! {
@@ -153,3 +266,18 @@ impl StackValueRepr {
             .build()
     }
 }
+
+glib::wrapper! {
+    pub struct CallFrameRepr(ObjectSubclass<imp::CallFrameRepr>);
+}
+impl CallFrameRepr {
+    pub fn new(frame: &Frame, name: String, current: bool) -> Self {
+        glib::Object::builder()
+            .property("depth", frame.depth as u32)
+            .property("call-site", frame.call_site().map_or(-1, |site| site as i32))
+            .property("name", name)
+            .property("return-addr", frame.return_addr as u32)
+            .property("current", current)
+            .build()
+    }
+}
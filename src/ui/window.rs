@@ -44,6 +44,8 @@ mod imp {
         pub line_builder_pane: TemplateChild<LineBuilderPane>,
         #[template_child]
         pub primary_menu_button: TemplateChild<gtk::MenuButton>,
+        #[template_child]
+        pub toast_overlay: TemplateChild<adw::ToastOverlay>,
     }
 
     #[glib::object_subclass]
@@ -99,6 +101,9 @@ impl MtemuWindow {
     pub fn disable_libinit_button(&self) {
         self.imp().disable_libinit_button();
     }
+    pub fn show_toast(&self, text: &str) {
+        self.imp().toast_overlay.add_toast(adw::Toast::new(text));
+    }
     pub fn new<P: glib::IsA<gtk::Application>>(application: &P) -> Self {
         glib::Object::builder()
             .property("application", application)
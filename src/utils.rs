@@ -20,15 +20,15 @@
 use std::{sync::Arc, cell::RefCell};
 
 use crate::emulator;
-use crate::emulator::MT1804Emulator;
 
-pub type EmulatorStored = Arc<RefCell<Option<emulator::OriginalImplementation>>>;
+pub type EmulatorStored = Arc<RefCell<Option<emulator::Implementation>>>;
 
 pub fn get_commands(emul: EmulatorStored) -> Vec<emulator::Command> {
     let Some(ref emul) = *emul.as_ref().borrow() else { return Vec::new() };
     let mut commands = Vec::<emulator::Command>::with_capacity(emul.commands_count());
     for i in 0..emul.commands_count() {
-        commands.push(emul.get_command(i));
+        let Ok(cmd) = emul.get_command(i) else { break };
+        commands.push(cmd);
     }
     commands
 }
@@ -37,12 +37,13 @@ pub fn get_calls(emul: EmulatorStored) -> Vec<emulator::Call> {
     let Some(ref emul) = *emul.as_ref().borrow() else { return Vec::new() };
     let mut calls = Vec::<emulator::Call>::with_capacity(emul.call_count());
     for i in 0..emul.call_count() {
-        calls.push(emul.get_call(i));
+        let Ok(call) = emul.get_call(i) else { break };
+        calls.push(call);
     }
     calls
 }
 
 pub fn get_libcalls(emul: EmulatorStored) -> Vec<emulator::LibCall> {
     let Some(ref emul) = *emul.as_ref().borrow() else { return Vec::new() };
-    emul.get_map_calls()
+    emul.get_map_calls().unwrap_or_default()
 }